@@ -0,0 +1,219 @@
+//! Entry points for the host activity on Android.
+//!
+//! Audio focus, the output becoming noisy and the burst size of the device
+//! are only known on the Java side. Once a manager has called
+//! [`AudioManager::install_android_hooks`](crate::AudioManager::install_android_hooks),
+//! the activity forwards them through these functions, e.g. from its JNI
+//! native methods. Calls made before that or after the manager is dropped
+//! are ignored.
+
+use crate::mixer::StreamInfo;
+use atomic_float::AtomicF32;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    Arc, Mutex, Weak,
+};
+
+// Values of `android.media.AudioManager.AUDIOFOCUS_*`.
+const AUDIOFOCUS_GAIN: i32 = 1;
+const AUDIOFOCUS_GAIN_TRANSIENT_EXCLUSIVE: i32 = 4;
+const AUDIOFOCUS_LOSS: i32 = -1;
+const AUDIOFOCUS_LOSS_TRANSIENT: i32 = -2;
+const AUDIOFOCUS_LOSS_TRANSIENT_CAN_DUCK: i32 = -3;
+
+/// Host audio state forwarded by the hooks and followed by the mixer.
+pub(crate) struct HostState {
+    /// Paused until the focus comes back.
+    pub(crate) focus_lost: AtomicBool,
+    /// Paused by the output becoming noisy, until resumed by the game.
+    pub(crate) noisy: AtomicBool,
+    /// Gain the output is ducked to, 1 when not ducked.
+    pub(crate) duck: AtomicF32,
+    /// Burst size reported by the activity, 0 if unknown.
+    pub(crate) frames_per_burst: AtomicU32,
+}
+impl Default for HostState {
+    fn default() -> Self {
+        Self {
+            focus_lost: AtomicBool::default(),
+            noisy: AtomicBool::default(),
+            duck: AtomicF32::new(1.),
+            frames_per_burst: AtomicU32::default(),
+        }
+    }
+}
+impl HostState {
+    pub(crate) fn paused(&self) -> bool {
+        self.focus_lost.load(Ordering::Relaxed) || self.noisy.load(Ordering::Relaxed)
+    }
+}
+
+struct Hooks {
+    stream: Weak<StreamInfo>,
+    /// Gain applied while another app may play over this one.
+    duck: f32,
+}
+
+static HOOKS: Mutex<Option<Hooks>> = Mutex::new(None);
+
+pub(crate) fn install(stream: &Arc<StreamInfo>, duck: f32) {
+    *HOOKS.lock().unwrap_or_else(|it| it.into_inner()) = Some(Hooks {
+        stream: Arc::downgrade(stream),
+        duck,
+    });
+}
+
+/// Removes the hooks if they forward to `stream`.
+pub(crate) fn uninstall(stream: &Arc<StreamInfo>) {
+    let mut hooks = HOOKS.lock().unwrap_or_else(|it| it.into_inner());
+    if hooks
+        .as_ref()
+        .is_some_and(|it| it.stream.as_ptr() == Arc::as_ptr(stream))
+    {
+        *hooks = None;
+    }
+}
+
+fn with_hooks(f: impl FnOnce(&HostState, f32)) {
+    let hooks = HOOKS.lock().unwrap_or_else(|it| it.into_inner());
+    if let Some(hooks) = &*hooks {
+        if let Some(stream) = hooks.stream.upgrade() {
+            f(&stream.host, hooks.duck);
+        }
+    }
+}
+
+/// Forwards `AudioManager.OnAudioFocusChangeListener.onAudioFocusChange`.
+///
+/// Losing the focus pauses the whole output until it is gained again, and
+/// a transient loss that allows ducking lowers it by the depth given to
+/// [`AudioManager::install_android_hooks`](crate::AudioManager::install_android_hooks).
+#[cfg(target_os = "android")]
+#[no_mangle]
+pub extern "C" fn sasa_on_audio_focus_change(focus_change: i32) {
+    on_audio_focus_change(focus_change);
+}
+
+/// Forwards `AudioManager.ACTION_AUDIO_BECOMING_NOISY`, e.g. headphones
+/// being unplugged. The output stays paused until
+/// [`AudioManager::resume_after_noisy`](crate::AudioManager::resume_after_noisy).
+#[cfg(target_os = "android")]
+#[no_mangle]
+pub extern "C" fn sasa_on_becoming_noisy() {
+    on_becoming_noisy();
+}
+
+/// Forwards `AudioManager.PROPERTY_OUTPUT_FRAMES_PER_BUFFER`, see
+/// [`AudioManager::frames_per_burst`](crate::AudioManager::frames_per_burst).
+#[cfg(target_os = "android")]
+#[no_mangle]
+pub extern "C" fn sasa_set_frames_per_burst(frames: i32) {
+    set_frames_per_burst(frames);
+}
+
+// The bodies of the exports above, compiled on every target so that the
+// hooks can be tested on the host.
+
+#[cfg_attr(not(target_os = "android"), allow(dead_code))]
+pub(crate) fn on_audio_focus_change(focus_change: i32) {
+    with_hooks(|host, duck| match focus_change {
+        AUDIOFOCUS_GAIN..=AUDIOFOCUS_GAIN_TRANSIENT_EXCLUSIVE => {
+            host.focus_lost.store(false, Ordering::Relaxed);
+            host.duck.store(1., Ordering::Relaxed);
+        }
+        AUDIOFOCUS_LOSS | AUDIOFOCUS_LOSS_TRANSIENT => {
+            host.focus_lost.store(true, Ordering::Relaxed)
+        }
+        AUDIOFOCUS_LOSS_TRANSIENT_CAN_DUCK => host.duck.store(duck, Ordering::Relaxed),
+        _ => eprintln!("unknown audio focus change {focus_change}"),
+    });
+}
+
+#[cfg_attr(not(target_os = "android"), allow(dead_code))]
+pub(crate) fn on_becoming_noisy() {
+    with_hooks(|host, _| host.noisy.store(true, Ordering::Relaxed));
+}
+
+#[cfg_attr(not(target_os = "android"), allow(dead_code))]
+pub(crate) fn set_frames_per_burst(frames: i32) {
+    with_hooks(|host, _| {
+        host.frames_per_burst
+            .store(frames.max(0) as u32, Ordering::Relaxed)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db_to_amplitude,
+        test_util::{constant, manager, peak, render, BLOCK_SIZE, SAMPLE_RATE},
+        MusicParams,
+    };
+
+    /// The hooks are global, so tests installing them must not overlap.
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    fn lock() -> std::sync::MutexGuard<'static, ()> {
+        LOCK.lock().unwrap_or_else(|it| it.into_inner())
+    }
+
+    #[test]
+    fn focus_changes_pause_and_duck() {
+        let _lock = lock();
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize * 4),
+                MusicParams::default(),
+            )
+            .unwrap();
+        music.play().unwrap();
+        manager.install_android_hooks(12.).unwrap();
+        assert_eq!(peak(&render(&mut out, BLOCK_SIZE)), 0.5);
+
+        on_audio_focus_change(AUDIOFOCUS_LOSS_TRANSIENT);
+        assert!(manager.host_paused());
+        let position = music.position();
+        assert_eq!(peak(&render(&mut out, BLOCK_SIZE * 4)), 0.);
+        assert_eq!(music.position(), position);
+
+        on_audio_focus_change(AUDIOFOCUS_GAIN);
+        assert!(!manager.host_paused());
+        on_audio_focus_change(AUDIOFOCUS_LOSS_TRANSIENT_CAN_DUCK);
+        assert!(!manager.host_paused());
+        render(&mut out, BLOCK_SIZE * 16);
+        let ducked = peak(&render(&mut out, BLOCK_SIZE));
+        assert!((ducked - 0.5 * db_to_amplitude(-12.)).abs() < 1e-4);
+
+        on_audio_focus_change(AUDIOFOCUS_GAIN);
+        render(&mut out, BLOCK_SIZE * 16);
+        assert_eq!(peak(&render(&mut out, BLOCK_SIZE)), 0.5);
+
+        on_becoming_noisy();
+        assert!(manager.host_paused());
+        manager.resume_after_noisy();
+        assert!(!manager.host_paused());
+        set_frames_per_burst(192);
+        assert_eq!(manager.frames_per_burst(), Some(192));
+    }
+
+    #[test]
+    fn calls_without_hooks_are_ignored() {
+        let _lock = lock();
+        let (mut manager, _out) = manager();
+        on_audio_focus_change(AUDIOFOCUS_LOSS);
+        on_becoming_noisy();
+        set_frames_per_burst(192);
+        assert!(!manager.host_paused());
+        assert_eq!(manager.frames_per_burst(), None);
+
+        manager.install_android_hooks(6.).unwrap();
+        let stream = Arc::clone(&manager.stream);
+        drop(manager);
+        on_audio_focus_change(AUDIOFOCUS_LOSS);
+        on_becoming_noisy();
+        assert!(!stream.host.paused());
+        assert!(HOOKS.lock().unwrap().is_none());
+    }
+}
@@ -4,30 +4,65 @@ pub mod cpal;
 #[cfg(feature = "oboe")]
 pub mod oboe;
 
+pub mod offline;
+
+#[cfg(any(feature = "cpal", feature = "oboe"))]
+use crate::mixer::Mixer;
 use crate::{
-    mixer::{Mixer, MixerCommand},
-    LatencyRecorder,
+    mixer::{MixerCommand, StreamInfo},
+    AudioManagerSettings, LatencyRecorder,
 };
 use anyhow::Result;
 use ringbuf::HeapConsumer;
+use std::sync::Arc;
 
 pub struct BackendSetup {
     pub(crate) mixer_cons: HeapConsumer<MixerCommand>,
+    // Only read by the device backends.
+    #[cfg_attr(not(any(feature = "cpal", feature = "oboe")), allow(dead_code))]
     pub(crate) latency_rec: LatencyRecorder,
+    pub(crate) stream: Arc<StreamInfo>,
+    pub(crate) settings: AudioManagerSettings,
 }
 
 pub trait Backend {
     fn setup(&mut self, setup: BackendSetup) -> Result<()>;
     fn start(&mut self) -> Result<()>;
+    /// Stops and releases the output stream.
+    fn stop(&mut self) -> Result<()>;
     fn consume_broken(&self) -> bool;
+
+    /// Whether an output stream is open and started.
+    fn is_running(&self) -> bool {
+        true
+    }
+
+    /// Name of the output device, if known.
+    fn device_name(&self) -> Option<String> {
+        None
+    }
+
+    /// Rebuilds the output stream with the stream preferences of
+    /// `settings`, keeping the mixer and its renderers. On failure the
+    /// previous stream should keep running.
+    ///
+    /// The default implementation restarts the stream and ignores the
+    /// preferences.
+    fn reconfigure(&mut self, settings: &AudioManagerSettings) -> Result<()> {
+        let _ = settings;
+        self.start()
+    }
 }
 
+#[cfg(any(feature = "cpal", feature = "oboe"))]
 #[repr(transparent)]
 struct StateCell {
     _data: (Mixer, LatencyRecorder),
 }
 
+#[cfg(any(feature = "cpal", feature = "oboe"))]
 impl StateCell {
+    #[allow(clippy::mut_from_ref)]
     pub fn get(&self) -> &mut (Mixer, LatencyRecorder) {
         #[allow(invalid_reference_casting)]
         unsafe {
@@ -36,10 +71,14 @@ impl StateCell {
     }
 }
 
+#[cfg(any(feature = "cpal", feature = "oboe"))]
 impl From<BackendSetup> for StateCell {
     fn from(value: BackendSetup) -> Self {
         Self {
-            _data: (Mixer::new(0, value.mixer_cons), value.latency_rec),
+            _data: (
+                Mixer::new(0, value.stream, value.mixer_cons, &value.settings),
+                value.latency_rec,
+            ),
         }
     }
 }
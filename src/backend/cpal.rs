@@ -1,8 +1,8 @@
-use crate::Backend;
+use crate::{AudioManagerSettings, Backend};
 use anyhow::{Context, Result};
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    BufferSize, OutputCallbackInfo, Stream, StreamError,
+    BufferSize, Device, OutputCallbackInfo, SampleRate, Stream, StreamConfig, StreamError,
 };
 use std::sync::{
     atomic::{AtomicBool, Ordering},
@@ -21,6 +21,9 @@ pub struct CpalBackend {
     stream: Option<Stream>,
     broken: Arc<AtomicBool>,
     state: Option<Arc<StateCell>>,
+    sample_rate: Option<u32>,
+    buffer_size: Option<u32>,
+    device_name: Option<String>,
 }
 
 impl CpalBackend {
@@ -30,33 +33,53 @@ impl CpalBackend {
             stream: None,
             broken: Arc::default(),
             state: None,
+            sample_rate: None,
+            buffer_size: None,
+            device_name: None,
         }
     }
+
+    fn output_config(&self, device: &Device) -> Result<StreamConfig> {
+        let default = device
+            .default_output_config()
+            .context("cannot get output config")?;
+        let mut config = self
+            .sample_rate
+            .and_then(|rate| {
+                device
+                    .supported_output_configs()
+                    .ok()?
+                    .filter(|it| it.channels() == default.channels())
+                    .find_map(|it| it.try_with_sample_rate(SampleRate(rate)))
+            })
+            .unwrap_or(default)
+            .config();
+        config.buffer_size = self
+            .buffer_size
+            .or(self.settings.buffer_size)
+            .map_or(BufferSize::Default, BufferSize::Fixed);
+        Ok(config)
+    }
 }
 
 impl Backend for CpalBackend {
     fn setup(&mut self, setup: BackendSetup) -> Result<()> {
+        self.sample_rate = setup.settings.sample_rate;
+        self.buffer_size = setup.settings.buffer_size;
         self.state = Some(Arc::new(setup.into()));
         Ok(())
     }
 
     fn start(&mut self) -> Result<()> {
         let host = cpal::default_host();
-        let device = match host.default_output_device() { 
-            Some(device) => device, 
-            None => { 
-                eprintln!("no default output device is found"); 
+        let device = match host.default_output_device() {
+            Some(device) => device,
+            None => {
+                eprintln!("no default output device is found");
                 return Ok(());
-            },
+            }
         };
-        let mut config = device
-            .default_output_config()
-            .context("cannot get output config")?
-            .config();
-        config.buffer_size = self
-            .settings
-            .buffer_size
-            .map_or(BufferSize::Default, |it| BufferSize::Fixed(it));
+        let config = self.output_config(&device)?;
 
         let broken = Arc::clone(&self.broken);
         let error_callback = move |err| {
@@ -66,13 +89,13 @@ impl Backend for CpalBackend {
             }
         };
         let state = Arc::clone(self.state.as_ref().unwrap());
-        state.get().0.sample_rate = config.sample_rate.0;
-        let stream = (if config.channels == 1 {
-            device.build_output_stream(
+        let format_state = Arc::clone(&state);
+        let stream = device
+            .build_output_stream(
                 &config,
                 move |data: &mut [f32], info: &OutputCallbackInfo| {
                     let (mixer, rec) = state.get();
-                    mixer.render_mono(data);
+                    mixer.render(data);
                     let ts = info.timestamp();
                     if let Some(delay) = ts.playback.duration_since(&ts.callback) {
                         rec.push(delay.as_secs_f64());
@@ -81,28 +104,46 @@ impl Backend for CpalBackend {
                 error_callback,
                 None,
             )
-        } else {
-            device.build_output_stream(
-                &config,
-                move |data: &mut [f32], info: &OutputCallbackInfo| {
-                    let (mixer, rec) = state.get();
-                    mixer.render_stereo(data);
-                    let ts = info.timestamp();
-                    if let Some(delay) = ts.playback.duration_since(&ts.callback) {
-                        rec.push(delay.as_secs_f64());
-                    }
-                },
-                error_callback,
-                None
-            )
-        })
-        .context("failed to build stream")?;
+            .context("failed to build stream")?;
+        // The previous stream must be gone before touching the mixer, as
+        // its callback may still be running.
+        self.stop()?;
+        let mixer = &mut format_state.get().0;
+        mixer.set_format(config.sample_rate.0, config.channels);
+        mixer.soft_start();
         stream.play()?;
         self.stream = Some(stream);
+        self.device_name = device.name().ok();
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if let Some(stream) = self.stream.take() {
+            stream.pause().context("failed to pause stream")?;
+        }
         Ok(())
     }
 
     fn consume_broken(&self) -> bool {
         self.broken.fetch_and(false, Ordering::Relaxed)
     }
+
+    fn is_running(&self) -> bool {
+        self.stream.is_some() && !self.broken.load(Ordering::Relaxed)
+    }
+
+    fn device_name(&self) -> Option<String> {
+        self.device_name.clone()
+    }
+
+    fn reconfigure(&mut self, settings: &AudioManagerSettings) -> Result<()> {
+        let previous = (self.sample_rate, self.buffer_size);
+        self.sample_rate = settings.sample_rate;
+        self.buffer_size = settings.buffer_size;
+        let result = self.start();
+        if result.is_err() {
+            (self.sample_rate, self.buffer_size) = previous;
+        }
+        result
+    }
 }
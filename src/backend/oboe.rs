@@ -4,7 +4,8 @@ use super::{BackendSetup, StateCell};
 use crate::Backend;
 use anyhow::Result;
 use oboe::{
-    AudioFormat, AudioOutputCallback, AudioOutputStreamSafe, AudioStream, AudioStreamAsync, AudioStreamBuilder, DataCallbackResult, Output, Stereo, Unspecified
+    AudioFormat, AudioOutputCallback, AudioOutputStreamSafe, AudioStream, AudioStreamAsync,
+    AudioStreamBuilder, DataCallbackResult, Output, Stereo, Unspecified,
 };
 use std::sync::{
     atomic::{AtomicBool, Ordering},
@@ -71,15 +72,28 @@ impl Backend for OboeBackend {
         Ok(())
     }
 
+    fn stop(&mut self) -> Result<()> {
+        if let Some(mut stream) = self.stream.take() {
+            stream.stop()?;
+        }
+        Ok(())
+    }
+
     fn consume_broken(&self) -> bool {
         self.broken.fetch_and(false, Ordering::Relaxed)
     }
+
+    fn is_running(&self) -> bool {
+        self.stream.is_some() && !self.broken.load(Ordering::Relaxed)
+    }
 }
 
 struct OboeCallback {
     state: Arc<StateCell>,
     broken: Arc<AtomicBool>,
     buffer_size: Option<u32>,
+    /// Whether the first callback of the stream has run.
+    started: bool,
 }
 
 impl OboeCallback {
@@ -88,6 +102,7 @@ impl OboeCallback {
             state,
             broken,
             buffer_size,
+            started: false,
         }
     }
 }
@@ -110,11 +125,13 @@ impl AudioOutputCallback for OboeCallback {
         if let Ok(latency) = stream.calculate_latency_millis() {
             rec.push(latency / 1000.);
         }
-        mixer.sample_rate = stream.get_sample_rate() as u32;
+        mixer.set_format(stream.get_sample_rate() as u32, 2);
+        if !self.started {
+            self.started = true;
+            mixer.soft_start();
+        }
         let raw = frames.as_mut_ptr();
-        mixer.render_stereo(unsafe {
-            std::slice::from_raw_parts_mut(raw as *mut f32, frames.len() * 2)
-        });
+        mixer.render(unsafe { std::slice::from_raw_parts_mut(raw as *mut f32, frames.len() * 2) });
 
         DataCallbackResult::Continue
     }
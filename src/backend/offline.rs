@@ -0,0 +1,149 @@
+use super::BackendSetup;
+use crate::{mixer::Mixer, AudioManagerSettings, Backend};
+use anyhow::{bail, Result};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone)]
+pub struct OfflineSettings {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Number of frames the mixer renders at a time, regardless of how the
+    /// output is requested from [`OfflineRenderer::render`].
+    pub block_size: usize,
+}
+impl Default for OfflineSettings {
+    fn default() -> Self {
+        Self {
+            sample_rate: 48000,
+            channels: 2,
+            block_size: 512,
+        }
+    }
+}
+
+/// A backend without an output device, rendered on demand through the
+/// paired [`OfflineRenderer`].
+///
+/// Given the same commands, the output is identical across runs.
+pub struct OfflineBackend {
+    settings: OfflineSettings,
+    mixer: Arc<Mutex<Option<Mixer>>>,
+}
+
+impl OfflineBackend {
+    pub fn new(settings: OfflineSettings) -> (Self, OfflineRenderer) {
+        let mixer: Arc<Mutex<Option<Mixer>>> = Arc::default();
+        let block_size = settings.block_size.max(1);
+        let block_len = block_size * settings.channels as usize;
+        (
+            Self {
+                settings,
+                mixer: Arc::clone(&mixer),
+            },
+            OfflineRenderer {
+                mixer,
+                block_size,
+                block: vec![0.; block_len],
+                offset: block_len,
+            },
+        )
+    }
+}
+
+impl Backend for OfflineBackend {
+    fn setup(&mut self, setup: BackendSetup) -> Result<()> {
+        if self.settings.channels == 0 {
+            bail!("offline output needs at least one channel");
+        }
+        if let Some(sample_rate) = setup.settings.sample_rate {
+            self.settings.sample_rate = sample_rate;
+        }
+        let mut mixer = Mixer::new(0, setup.stream, setup.mixer_cons, &setup.settings);
+        // Rendered on demand, so gaps between blocks mean nothing.
+        mixer.watch_underruns = false;
+        *self.mixer.lock().unwrap() = Some(mixer);
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<()> {
+        if let Some(mixer) = self.mixer.lock().unwrap().as_mut() {
+            mixer.set_format(self.settings.sample_rate, self.settings.channels);
+            mixer.soft_start();
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn consume_broken(&self) -> bool {
+        false
+    }
+
+    fn reconfigure(&mut self, settings: &AudioManagerSettings) -> Result<()> {
+        if let Some(sample_rate) = settings.sample_rate {
+            self.settings.sample_rate = sample_rate;
+        }
+        self.start()
+    }
+}
+
+pub struct OfflineRenderer {
+    mixer: Arc<Mutex<Option<Mixer>>>,
+    block_size: usize,
+    block: Vec<f32>,
+    offset: usize,
+}
+
+impl OfflineRenderer {
+    /// Fills `data` with interleaved output, rendering as many blocks as
+    /// needed. Samples of a block not consumed by this call are returned by
+    /// the next one, so the output does not depend on how it is split into
+    /// calls.
+    ///
+    /// Outputs silence until the backend is set up.
+    pub fn render(&mut self, mut data: &mut [f32]) {
+        let mut guard = self.mixer.lock().unwrap();
+        let Some(mixer) = guard.as_mut() else {
+            data.fill(0.);
+            return;
+        };
+        while !data.is_empty() {
+            if self.offset == self.block.len() {
+                mixer.render(&mut self.block);
+                self.offset = 0;
+            }
+            let len = data.len().min(self.block.len() - self.offset);
+            data[..len].copy_from_slice(&self.block[self.offset..self.offset + len]);
+            self.offset += len;
+            data = &mut data[len..];
+        }
+    }
+
+    /// Switches the output to `channels` channels from the next sample on,
+    /// as a device changing its layout mid-stream would. Samples of the
+    /// current block not returned yet are dropped. The layout of the
+    /// backend settings is restored by a reconfiguration.
+    pub fn set_channels(&mut self, channels: u16) -> Result<()> {
+        if channels == 0 {
+            bail!("offline output needs at least one channel");
+        }
+        let mut guard = self.mixer.lock().unwrap();
+        let Some(mixer) = guard.as_mut() else {
+            bail!("offline backend is not set up");
+        };
+        mixer.set_format(mixer.sample_rate, channels);
+        self.block = vec![0.; self.block_size * channels as usize];
+        self.offset = self.block.len();
+        Ok(())
+    }
+
+    /// Like [`AudioManager::next_activity`](crate::AudioManager::next_activity),
+    /// including the commands sent since the latest block. Frames of a block
+    /// already rendered and not yet returned by [`OfflineRenderer::render`]
+    /// count as rendered.
+    pub fn next_activity(&self) -> Option<u64> {
+        self.mixer.lock().unwrap().as_ref()?.next_activity()
+    }
+}
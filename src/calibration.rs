@@ -0,0 +1,228 @@
+use crate::{AudioClip, RenderContext, Renderer};
+use anyhow::{bail, Result};
+use std::{
+    sync::{Arc, Mutex, Weak},
+    time::Instant,
+};
+
+/// Taps further than this many seconds from the median offset count
+/// against the confidence.
+const TAP_TOLERANCE: f64 = 0.03;
+
+/// Taps needed for full confidence.
+const CONFIDENT_TAPS: usize = 8;
+
+/// Latest correlation between the mixer clock and the wall clock.
+#[derive(Clone, Copy)]
+struct ClockPoint {
+    instant: Instant,
+    /// Mixer frame rendered at `instant`.
+    frame: u64,
+    sample_rate: u32,
+}
+
+#[derive(Default)]
+struct Shared {
+    /// Mixer frame of the first click.
+    start: Option<u64>,
+    clock: Option<ClockPoint>,
+}
+
+pub(crate) struct CalibrationRenderer {
+    clip: AudioClip,
+    period: f64,
+    shared: Weak<Mutex<Shared>>,
+    start: Option<u64>,
+}
+
+impl CalibrationRenderer {
+    fn render(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+        let start = *self.start.get_or_insert(ctx.sample_time);
+        if let Some(shared) = self.shared.upgrade() {
+            // Never block the audio thread; a missed update only delays
+            // the correlation by a block.
+            if let Ok(mut shared) = shared.try_lock() {
+                shared.start = Some(start);
+                shared.clock = Some(ClockPoint {
+                    instant: Instant::now(),
+                    frame: ctx.sample_time,
+                    sample_rate: ctx.sample_rate,
+                });
+            }
+        }
+        let period = ((self.period * ctx.sample_rate as f64).round() as u64).max(1);
+        let channels = ctx.channels as usize;
+        for (i, sample) in data.chunks_exact_mut(channels).enumerate() {
+            let offset = (ctx.sample_time + i as u64 - start) % period;
+            let Some(frame) = self.clip.get(offset as usize) else {
+                continue;
+            };
+            if channels == 1 {
+                sample[0] += frame.avg();
+            } else {
+                sample[0] += frame.0;
+                sample[1] += frame.1;
+            }
+        }
+    }
+}
+
+impl Renderer for CalibrationRenderer {
+    fn alive(&self) -> bool {
+        self.shared.strong_count() != 0
+    }
+
+    fn render_mono(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+        self.render(ctx, data);
+    }
+
+    fn render_stereo(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+        self.render(ctx, data);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationResult {
+    /// Seconds between a click being rendered and the player tapping to
+    /// it, i.e. the offset to subtract from tap times to line them up with
+    /// the audio clock.
+    pub offset: f64,
+    /// From 0 to 1, how consistent and numerous the taps were.
+    pub confidence: f32,
+}
+
+/// A running "tap along with the clicks" latency calibration, see
+/// [`AudioManager::start_calibration`](crate::AudioManager::start_calibration).
+///
+/// The clicks stop when this is finished or dropped.
+pub struct Calibration {
+    shared: Arc<Mutex<Shared>>,
+    period: f64,
+    taps: Vec<Instant>,
+}
+
+impl Calibration {
+    pub(crate) fn new(clip: AudioClip, bpm: f64) -> (Calibration, CalibrationRenderer) {
+        let shared = Arc::<Mutex<Shared>>::default();
+        let period = 60. / bpm;
+        (
+            Self {
+                shared: Arc::clone(&shared),
+                period,
+                taps: Vec::new(),
+            },
+            CalibrationRenderer {
+                clip,
+                period,
+                shared: Arc::downgrade(&shared),
+                start: None,
+            },
+        )
+    }
+
+    pub fn record_tap(&mut self, at: Instant) {
+        self.taps.push(at);
+    }
+
+    pub fn tap_count(&self) -> usize {
+        self.taps.len()
+    }
+
+    /// Estimates the offset as the median difference between each tap and
+    /// the click nearest to it.
+    pub fn finish(self) -> Result<CalibrationResult> {
+        if self.taps.is_empty() {
+            bail!("no taps recorded");
+        }
+        let shared = self.shared.lock().unwrap_or_else(|it| it.into_inner());
+        let (Some(start), Some(clock)) = (shared.start, shared.clock) else {
+            bail!("no click has been rendered yet");
+        };
+        let start = start as f64 / clock.sample_rate as f64;
+        let reference = clock.frame as f64 / clock.sample_rate as f64;
+        let mut offsets: Vec<f64> = self
+            .taps
+            .iter()
+            .map(|&tap| {
+                let time = if tap >= clock.instant {
+                    reference + (tap - clock.instant).as_secs_f64()
+                } else {
+                    reference - (clock.instant - tap).as_secs_f64()
+                };
+                let beats = (time - start) / self.period;
+                (beats - beats.round()) * self.period
+            })
+            .collect();
+        offsets.sort_by(f64::total_cmp);
+        let mid = offsets.len() / 2;
+        let offset = if offsets.len().is_multiple_of(2) {
+            (offsets[mid - 1] + offsets[mid]) / 2.
+        } else {
+            offsets[mid]
+        };
+        let consistent = offsets
+            .iter()
+            .filter(|it| (*it - offset).abs() <= TAP_TOLERANCE)
+            .count();
+        let confidence = consistent as f32 / offsets.len() as f32
+            * (offsets.len() as f32 / CONFIDENT_TAPS as f32).min(1.);
+        Ok(CalibrationResult { offset, confidence })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Frame;
+    use std::time::Duration;
+
+    /// A calibration at 120 bpm whose first click was rendered at `instant`.
+    fn clicking(instant: Instant) -> Calibration {
+        let clip = AudioClip::from_raw(vec![Frame::from_mono(1.); 16], 48000);
+        let (calibration, _renderer) = Calibration::new(clip, 120.);
+        *calibration.shared.lock().unwrap() = Shared {
+            start: Some(0),
+            clock: Some(ClockPoint {
+                instant,
+                frame: 0,
+                sample_rate: 48000,
+            }),
+        };
+        calibration
+    }
+
+    #[test]
+    fn estimates_the_median_offset() {
+        let base = Instant::now();
+        let mut calibration = clicking(base);
+        // 50 ms late with a few ms of jitter, and one tap off the beat.
+        for (beat, jitter) in [0., 2., -3., 1., -1., 4., -2.].into_iter().enumerate() {
+            let at = 0.5 * (beat + 1) as f64 + 0.05 + jitter / 1000.;
+            calibration.record_tap(base + Duration::from_secs_f64(at));
+        }
+        calibration.record_tap(base + Duration::from_secs_f64(4.2));
+        assert_eq!(calibration.tap_count(), 8);
+        let result = calibration.finish().unwrap();
+        assert!((result.offset - 0.0505).abs() < 1e-9, "{}", result.offset);
+        assert_eq!(result.confidence, 7. / 8.);
+
+        // Early taps give a negative offset; few taps lower the confidence.
+        let mut calibration = clicking(base);
+        for beat in 1..=4 {
+            let at = 0.5 * beat as f64 - 0.02;
+            calibration.record_tap(base + Duration::from_secs_f64(at));
+        }
+        let result = calibration.finish().unwrap();
+        assert!((result.offset + 0.02).abs() < 1e-9);
+        assert_eq!(result.confidence, 0.5);
+    }
+
+    #[test]
+    fn needs_taps_and_a_rendered_click() {
+        assert!(clicking(Instant::now()).finish().is_err());
+        let clip = AudioClip::from_raw(vec![Frame::default(); 16], 48000);
+        let (mut calibration, _renderer) = Calibration::new(clip, 120.);
+        calibration.record_tap(Instant::now());
+        assert!(calibration.finish().is_err());
+    }
+}
@@ -1,16 +1,238 @@
-use crate::Frame;
+use crate::{Error, Frame};
 use anyhow::{anyhow, bail, Result};
-use std::{io::Cursor, sync::Arc};
+use std::{
+    io::Cursor,
+    sync::{Arc, Weak},
+};
 use symphonia::core::{
-    audio::{AudioBufferRef, Signal},
+    audio::{AudioBufferRef, Channels, Signal},
+    codecs::{CodecParameters, Decoder},
+    formats::{FormatReader, Packet},
     io::MediaSourceStream,
 };
 
+/// Center and surround gain used when folding multichannel sources down to
+/// stereo, following ITU-R BS.775.
+const DOWNMIX_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Left and right gains of a source channel in the stereo downmix.
+fn downmix_coefficients(channel: Channels) -> (f32, f32) {
+    const LEFT: Channels = Channels::FRONT_LEFT
+        .union(Channels::FRONT_LEFT_WIDE)
+        .union(Channels::FRONT_LEFT_HIGH)
+        .union(Channels::TOP_FRONT_LEFT);
+    const RIGHT: Channels = Channels::FRONT_RIGHT
+        .union(Channels::FRONT_RIGHT_WIDE)
+        .union(Channels::FRONT_RIGHT_HIGH)
+        .union(Channels::TOP_FRONT_RIGHT);
+    const SURROUND_LEFT: Channels = Channels::REAR_LEFT
+        .union(Channels::SIDE_LEFT)
+        .union(Channels::REAR_LEFT_CENTRE)
+        .union(Channels::FRONT_LEFT_CENTRE)
+        .union(Channels::TOP_REAR_LEFT);
+    const SURROUND_RIGHT: Channels = Channels::REAR_RIGHT
+        .union(Channels::SIDE_RIGHT)
+        .union(Channels::REAR_RIGHT_CENTRE)
+        .union(Channels::FRONT_RIGHT_CENTRE)
+        .union(Channels::TOP_REAR_RIGHT);
+    if LEFT.contains(channel) {
+        (1., 0.)
+    } else if RIGHT.contains(channel) {
+        (0., 1.)
+    } else if SURROUND_LEFT.contains(channel) {
+        (DOWNMIX_GAIN, 0.)
+    } else if SURROUND_RIGHT.contains(channel) {
+        (0., DOWNMIX_GAIN)
+    } else if channel == Channels::LFE1 || channel == Channels::LFE2 {
+        (0., 0.)
+    } else {
+        (DOWNMIX_GAIN, DOWNMIX_GAIN)
+    }
+}
+
+fn load_frames_from_buffer(
+    frames: &mut Vec<Frame>,
+    buffer: &symphonia::core::audio::AudioBuffer<f32>,
+) {
+    match buffer.spec().channels.count() {
+        1 => {
+            let chan = buffer.chan(0);
+            frames.reserve(chan.len());
+            frames.extend(chan.iter().map(|&it| Frame(it, it)));
+        }
+        2 => {
+            let iter = buffer.chan(0).iter().zip(buffer.chan(1));
+            frames.reserve(iter.len());
+            frames.extend(iter.map(|(left, right)| Frame(*left, *right)))
+        }
+        _ => {
+            let start = frames.len();
+            frames.resize(start + buffer.frames(), Frame::default());
+            for (index, channel) in buffer.spec().channels.iter().enumerate() {
+                let (left, right) = downmix_coefficients(channel);
+                for (frame, &sample) in frames[start..].iter_mut().zip(buffer.chan(index)) {
+                    frame.0 += sample * left;
+                    frame.1 += sample * right;
+                }
+            }
+        }
+    }
+}
+
+fn load_frames_from_buffer_ref(frames: &mut Vec<Frame>, buffer: &AudioBufferRef) -> Result<()> {
+    macro_rules! conv {
+        ($buffer:ident) => {{
+            let mut dest = symphonia::core::audio::AudioBuffer::new(
+                buffer.capacity() as u64,
+                buffer.spec().clone(),
+            );
+            $buffer.convert(&mut dest);
+            load_frames_from_buffer(frames, &dest);
+        }};
+    }
+    use AudioBufferRef::*;
+    match buffer {
+        F32(buffer) => load_frames_from_buffer(frames, buffer),
+        U8(buffer) => conv!(buffer),
+        U16(buffer) => conv!(buffer),
+        U24(buffer) => conv!(buffer),
+        U32(buffer) => conv!(buffer),
+        S8(buffer) => conv!(buffer),
+        S16(buffer) => conv!(buffer),
+        S24(buffer) => conv!(buffer),
+        S32(buffer) => conv!(buffer),
+        F64(buffer) => conv!(buffer),
+    }
+    Ok(())
+}
+
+/// Probes `data`, returning its format reader, the parameters of its
+/// default track and the sample rate.
+fn open(data: Vec<u8>) -> Result<(Box<dyn FormatReader>, CodecParameters, u32)> {
+    let probe = symphonia::default::get_probe();
+    let mss = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
+    let format_reader = probe
+        .format(
+            &Default::default(),
+            mss,
+            &Default::default(),
+            &Default::default(),
+        )?
+        .format;
+    let codec_params = format_reader
+        .default_track()
+        .ok_or_else(|| anyhow!("default track not found"))?
+        .codec_params
+        .clone();
+    let sample_rate = codec_params
+        .sample_rate
+        .ok_or_else(|| anyhow!("unknown sample rate"))?;
+    Ok((format_reader, codec_params, sample_rate))
+}
+
+/// The next packet, or `None` at the end of the stream.
+fn next_packet(format_reader: &mut dyn FormatReader) -> Result<Option<Packet>> {
+    match format_reader.next_packet() {
+        Ok(packet) => Ok(Some(packet)),
+        Err(symphonia::core::errors::Error::IoError(error))
+            if error.kind() == std::io::ErrorKind::UnexpectedEof =>
+        {
+            Ok(None)
+        }
+        Err(error) => bail!(error),
+    }
+}
+
+/// Decodes every remaining packet. An error past the first frames is taken
+/// as a corrupt or truncated tail: decoding stops there, keeping the frames
+/// decoded so far.
+fn decode_all(
+    format_reader: &mut dyn FormatReader,
+    decoder: &mut dyn Decoder,
+    frames: &mut Vec<Frame>,
+    channels: &mut u16,
+) -> Result<()> {
+    loop {
+        let result = next_packet(format_reader).and_then(|packet| {
+            let Some(packet) = packet else {
+                return Ok(false);
+            };
+            decode_packet(decoder, &packet, frames, channels)?;
+            Ok(true)
+        });
+        match result {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(err) if !frames.is_empty() => {
+                eprintln!("stopped decoding at a corrupt tail: {err:?}");
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Decodes `packet` and appends its frames, filling in `channels` from the
+/// first decoded buffer if still unknown.
+fn decode_packet(
+    decoder: &mut dyn Decoder,
+    packet: &Packet,
+    frames: &mut Vec<Frame>,
+    channels: &mut u16,
+) -> Result<()> {
+    let buffer = match decoder.decode(packet) {
+        Ok(buffer) => buffer,
+        Err(symphonia::core::errors::Error::DecodeError(s))
+            if s.contains("invalid main_data offset") =>
+        {
+            return Ok(());
+        }
+        Err(err) => return Err(err.into()),
+    };
+    if *channels == 0 {
+        *channels = buffer.spec().channels.count() as u16;
+    }
+    load_frames_from_buffer_ref(frames, &buffer)
+}
+
 struct ClipInner {
     frames: Vec<Frame>,
     sample_rate: u32,
+    channels: u16,
 }
 pub struct AudioClip(Arc<ClipInner>);
+
+/// How two clips differ, see [`AudioClip::compare`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClipDiff {
+    /// The clips have different sample rates.
+    Incomparable,
+    Compared {
+        /// Frames of the other clip minus frames of this one.
+        length_difference: i64,
+        /// Largest difference of a sample, over the frames both clips have.
+        max_deviation: f32,
+        /// Root mean square of the difference of the samples, over the
+        /// frames both clips have.
+        rms: f32,
+        /// First frame deviating by more than the tolerance, or the end of
+        /// the shorter clip if the lengths differ and no frame before does.
+        first_divergence: Option<usize>,
+    },
+}
+impl ClipDiff {
+    /// Whether the clips have the same length and no frame deviates by
+    /// more than the tolerance.
+    pub fn is_identical(&self) -> bool {
+        matches!(
+            self,
+            Self::Compared {
+                first_divergence: None,
+                ..
+            }
+        )
+    }
+}
 impl Clone for AudioClip {
     fn clone(&self) -> Self {
         Self(Arc::clone(&self.0))
@@ -18,130 +240,215 @@ impl Clone for AudioClip {
 }
 
 impl AudioClip {
+    /// Wraps frames produced by the caller. Unlike [`AudioClip::new`], empty
+    /// `frames` are accepted, as nothing was lost decoding them: such a clip
+    /// is a silent placeholder, whose music pauses on its first block and
+    /// whose sound effect instances finish right away.
     pub fn from_raw(frames: Vec<Frame>, sample_rate: u32) -> Self {
+        Self::from_raw_channels(frames, sample_rate, 2)
+    }
+
+    fn from_raw_channels(frames: Vec<Frame>, sample_rate: u32, channels: u16) -> Self {
         Self(Arc::new(ClipInner {
             frames,
             sample_rate,
+            channels,
         }))
     }
 
     pub fn decode(data: Vec<u8>) -> Result<(Vec<Frame>, u32)> {
-        fn load_frames_from_buffer(
-            frames: &mut Vec<Frame>,
-            buffer: &symphonia::core::audio::AudioBuffer<f32>,
-        ) {
-            match buffer.spec().channels.count() {
-                1 => {
-                    let chan = buffer.chan(0);
-                    frames.reserve(chan.len());
-                    frames.extend(chan.iter().map(|&it| Frame(it, it)));
-                }
-                _ => {
-                    let iter = buffer.chan(0).iter().zip(buffer.chan(1));
-                    frames.reserve(iter.len());
-                    frames.extend(iter.map(|(left, right)| Frame(*left, *right)))
-                }
-            }
-        }
+        Self::decode_with_channels(data).map(|(frames, sample_rate, _)| (frames, sample_rate))
+    }
 
-        fn load_frames_from_buffer_ref(
-            frames: &mut Vec<Frame>,
-            buffer: &AudioBufferRef,
-        ) -> Result<()> {
-            macro_rules! conv {
-                ($buffer:ident) => {{
-                    let mut dest = symphonia::core::audio::AudioBuffer::new(
-                        buffer.capacity() as u64,
-                        buffer.spec().clone(),
-                    );
-                    $buffer.convert(&mut dest);
-                    load_frames_from_buffer(frames, &dest);
-                }};
-            }
-            use AudioBufferRef::*;
-            match buffer {
-                F32(buffer) => load_frames_from_buffer(frames, buffer),
-                U8(buffer) => conv!(buffer),
-                U16(buffer) => conv!(buffer),
-                U24(buffer) => conv!(buffer),
-                U32(buffer) => conv!(buffer),
-                S8(buffer) => conv!(buffer),
-                S16(buffer) => conv!(buffer),
-                S24(buffer) => conv!(buffer),
-                S32(buffer) => conv!(buffer),
-                F64(buffer) => conv!(buffer),
-            }
-            Ok(())
-        }
-        let codecs = symphonia::default::get_codecs();
-        let probe = symphonia::default::get_probe();
-        let mss = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
-        let mut format_reader = probe
-            .format(
-                &Default::default(),
-                mss,
-                &Default::default(),
-                &Default::default(),
-            )?
-            .format;
-        let codec_params = &format_reader
-            .default_track()
-            .ok_or_else(|| anyhow!("default track not found"))?
-            .codec_params;
-        let sample_rate = codec_params
-            .sample_rate
-            .ok_or_else(|| anyhow!("unknown sample rate"))?;
-        let mut decoder = codecs.make(codec_params, &Default::default())?;
+    /// Decodes the data into stereo frames, also returning the channel count
+    /// of the source. Sources with more than two channels are downmixed with
+    /// ITU-R BS.775 coefficients; the LFE channel is dropped.
+    pub fn decode_with_channels(data: Vec<u8>) -> Result<(Vec<Frame>, u32, u16)> {
+        let (mut format_reader, codec_params, sample_rate) = open(data)?;
+        let mut channels = codec_params.channels.map_or(0, |it| it.count() as u16);
+        let mut decoder =
+            symphonia::default::get_codecs().make(&codec_params, &Default::default())?;
         let mut frames = Vec::new();
+        decode_all(
+            format_reader.as_mut(),
+            decoder.as_mut(),
+            &mut frames,
+            &mut channels,
+        )?;
+        Ok((frames, sample_rate, channels))
+    }
+
+    /// Like [`AudioClip::new`], decoding on up to `threads` threads.
+    ///
+    /// Only codecs whose packets decode independently of each other (PCM
+    /// and FLAC) are split; others are decoded serially. The output is
+    /// identical to [`AudioClip::new`] either way.
+    pub fn load_parallel(data: Vec<u8>, threads: usize) -> Result<Self> {
+        let (mut format_reader, codec_params, sample_rate) = open(data)?;
+        let codecs = symphonia::default::get_codecs();
+        let splittable = codecs
+            .get_codec(codec_params.codec)
+            .is_some_and(|it| it.short_name == "flac" || it.short_name.starts_with("pcm_"));
+        let mut channels = codec_params.channels.map_or(0, |it| it.count() as u16);
+        if !splittable || threads <= 1 {
+            let mut decoder = codecs.make(&codec_params, &Default::default())?;
+            let mut frames = Vec::new();
+            decode_all(
+                format_reader.as_mut(),
+                decoder.as_mut(),
+                &mut frames,
+                &mut channels,
+            )?;
+            return Self::from_decoded(frames, sample_rate, channels);
+        }
+        let mut packets = Vec::new();
         loop {
-            match format_reader.next_packet() {
-                Ok(packet) => {
-                    let buffer = match decoder.decode(&packet) {
-                        Ok(buffer) => buffer,
-                        Err(symphonia::core::errors::Error::DecodeError(s))
-                            if s.contains("invalid main_data offset") =>
-                        {
-                            continue;
+            match next_packet(format_reader.as_mut()) {
+                Ok(Some(packet)) => packets.push(packet),
+                Ok(None) => break,
+                Err(err) if !packets.is_empty() => {
+                    eprintln!("stopped reading at a corrupt tail: {err:?}");
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        let chunk = packets.len().div_ceil(threads).max(1);
+        // Each chunk stops at its first error, and chunks after the first
+        // truncated one are dropped, as in `decode_all`.
+        type Chunk = (Vec<Frame>, u16, Option<anyhow::Error>);
+        let chunks: Vec<Chunk> = std::thread::scope(|scope| {
+            let handles: Vec<_> = packets
+                .chunks(chunk)
+                .map(|packets| {
+                    let codec_params = &codec_params;
+                    scope.spawn(move || -> Result<Chunk> {
+                        let mut decoder = symphonia::default::get_codecs()
+                            .make(codec_params, &Default::default())?;
+                        let mut frames = Vec::new();
+                        let mut channels = 0;
+                        for packet in packets {
+                            if let Err(err) =
+                                decode_packet(decoder.as_mut(), packet, &mut frames, &mut channels)
+                            {
+                                return Ok((frames, channels, Some(err)));
+                            }
                         }
-                        Err(err) => return Err(err.into()),
-                    };
-                    load_frames_from_buffer_ref(&mut frames, &buffer)?;
+                        Ok((frames, channels, None))
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|it| it.join().map_err(|_| anyhow!("decoder thread panicked"))?)
+                .collect::<Result<_>>()
+        })?;
+        if channels == 0 {
+            channels = chunks
+                .iter()
+                .map(|it| it.1)
+                .find(|&it| it != 0)
+                .unwrap_or(0);
+        }
+        let mut frames = Vec::with_capacity(chunks.iter().map(|it| it.0.len()).sum());
+        for (chunk, _, err) in chunks {
+            frames.extend(chunk);
+            if let Some(err) = err {
+                // Nothing decoded before a corrupt head, as in `decode_all`.
+                if frames.is_empty() {
+                    return Err(err);
                 }
-                Err(error) => match error {
-                    symphonia::core::errors::Error::IoError(error)
-                        if error.kind() == std::io::ErrorKind::UnexpectedEof =>
-                    {
-                        break;
-                    }
-                    _ => bail!(error),
-                },
+                eprintln!("stopped decoding at a corrupt packet: {err:?}");
+                break;
             }
         }
-        Ok((frames, sample_rate))
+        Self::from_decoded(frames, sample_rate, channels)
     }
 
+    /// Decodes a clip. Decoding stops at a corrupt or truncated tail,
+    /// keeping what was decoded before it; a source without any audio is
+    /// rejected with [`Error::EmptyClip`].
     #[inline]
     pub fn new(data: Vec<u8>) -> Result<Self> {
-        let (frames, sample_rate) = Self::decode(data)?;
-        Ok(Self::from_raw(frames, sample_rate))
+        let (frames, sample_rate, channels) = Self::decode_with_channels(data)?;
+        Self::from_decoded(frames, sample_rate, channels)
+    }
+
+    fn from_decoded(frames: Vec<Frame>, sample_rate: u32, channels: u16) -> Result<Self> {
+        if frames.is_empty() {
+            bail!(Error::EmptyClip);
+        }
+        Ok(Self::from_raw_channels(frames, sample_rate, channels))
     }
 
+    /// Frame at `position` seconds, linearly interpolated between its two
+    /// neighbours, or `None` past the end. The last frame is held.
     pub fn sample(&self, position: f64) -> Option<Frame> {
+        self.sample_index(position * self.0.sample_rate as f64)
+    }
+
+    /// Whether both handles share the same frames.
+    pub(crate) fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    /// Like [`AudioClip::sample`], without interpolation.
+    pub(crate) fn sample_nearest(&self, position: f64) -> Option<Frame> {
+        self.get((position * self.0.sample_rate as f64) as usize)
+    }
+
+    /// Like [`AudioClip::sample`], with the position in frames of the clip.
+    pub(crate) fn sample_index(&self, position: f64) -> Option<Frame> {
+        let actual_index = position as usize;
+        let frame = self.0.frames.get(actual_index)?;
+        let next_frame = self.0.frames.get(actual_index + 1).unwrap_or(frame);
+        Some(frame.interpolate(next_frame, (position - actual_index as f64) as f32))
+    }
+
+    /// Like [`AudioClip::sample`], interpolating the last frame towards
+    /// `after`, the frame played once the clip wraps around.
+    pub(crate) fn sample_then(&self, position: f64, after: Frame) -> Option<Frame> {
         let position = position * self.0.sample_rate as f64;
         let actual_index = position as usize;
-        if let Some(frame) = self.0.frames.get(actual_index) {
-            let next_frame = self.0.frames.get(actual_index + 1).unwrap_or(frame);
-            Some(frame.interpolate(next_frame, (position - actual_index as f64) as f32))
-        } else {
-            None
-        }
+        let frame = self.0.frames.get(actual_index)?;
+        let next_frame = self.0.frames.get(actual_index + 1).unwrap_or(&after);
+        Some(frame.interpolate(next_frame, (position - actual_index as f64) as f32))
     }
 
+    /// The decoded frames, exactly as produced by [`AudioClip::decode`]:
+    /// mono sources have both channels equal and sources with more channels
+    /// are downmixed, but no other processing is applied. Useful to compare
+    /// decoder output across dependency updates.
     #[inline(always)]
     pub fn frames(&self) -> &[Frame] {
         &self.0.frames
     }
 
+    /// Every frame of the clip, for bulk access from a custom
+    /// [`Renderer`](crate::Renderer). Clips are never views into another
+    /// clip, so this is the same slice as [`AudioClip::frames`].
+    #[inline(always)]
+    pub fn samples(&self) -> &[Frame] {
+        &self.0.frames
+    }
+
+    /// Frame `index` without interpolation, or `None` past the end.
+    #[inline(always)]
+    pub fn get(&self, index: usize) -> Option<Frame> {
+        self.0.frames.get(index).copied()
+    }
+
+    /// Frame `index` without bounds checking, for hot loops on the audio
+    /// thread that already know the index is in range.
+    ///
+    /// # Safety
+    ///
+    /// `index` must be less than [`AudioClip::frame_count`].
+    #[inline(always)]
+    pub unsafe fn get_unchecked(&self, index: usize) -> Frame {
+        *self.0.frames.get_unchecked(index)
+    }
+
     #[inline(always)]
     pub fn to_vec(&self) -> Vec<f32> {
         let mut vec = Vec::new();
@@ -157,6 +464,13 @@ impl AudioClip {
         self.0.sample_rate
     }
 
+    /// Channel count of the source this clip was decoded from. Clips are
+    /// always stored as stereo frames.
+    #[inline(always)]
+    pub fn channels(&self) -> u16 {
+        self.0.channels
+    }
+
     #[inline(always)]
     pub fn frame_count(&self) -> usize {
         self.0.frames.len()
@@ -165,4 +479,448 @@ impl AudioClip {
     pub fn length(&self) -> f64 {
         self.frame_count() as f64 / self.sample_rate() as f64
     }
+
+    /// Splits the clip into two mono clips holding its left and right
+    /// channels.
+    pub fn split_channels(&self) -> (AudioClip, AudioClip) {
+        let split = |channel: fn(&Frame) -> f32| {
+            let frames = self
+                .frames()
+                .iter()
+                .map(|it| {
+                    let sample = channel(it);
+                    Frame(sample, sample)
+                })
+                .collect();
+            Self::from_raw_channels(frames, self.sample_rate(), 1)
+        };
+        (split(|it| it.0), split(|it| it.1))
+    }
+
+    /// Builds a stereo clip from two mono clips, the inverse of
+    /// [`AudioClip::split_channels`]. Stereo inputs are downmixed first and
+    /// the shorter clip is padded with silence.
+    pub fn merge_channels(left: &AudioClip, right: &AudioClip) -> Result<AudioClip> {
+        if left.sample_rate() != right.sample_rate() {
+            bail!(
+                "sample rates do not match: {} Hz and {} Hz",
+                left.sample_rate(),
+                right.sample_rate()
+            );
+        }
+        let count = left.frame_count().max(right.frame_count());
+        let get = |clip: &AudioClip, index: usize| clip.frames().get(index).map_or(0., Frame::avg);
+        let frames = (0..count)
+            .map(|index| Frame(get(left, index), get(right, index)))
+            .collect();
+        Ok(Self::from_raw(frames, left.sample_rate()))
+    }
+
+    /// Returns a copy of the clip with linear fades of `fade_in` and
+    /// `fade_out` seconds baked into its ends.
+    pub fn apply_fade(&self, fade_in: f64, fade_out: f64) -> AudioClip {
+        let rate = self.sample_rate() as f64;
+        let fade_in = (fade_in.max(0.) * rate).round() as usize;
+        let fade_out = (fade_out.max(0.) * rate).round() as usize;
+        let count = self.frame_count();
+        let frames = self
+            .frames()
+            .iter()
+            .enumerate()
+            .map(|(index, &frame)| {
+                let mut gain = 1.;
+                if index < fade_in {
+                    gain = (index as f64 / fade_in as f64) as f32;
+                }
+                let remaining = count - 1 - index;
+                if remaining < fade_out {
+                    gain *= (remaining as f64 / fade_out as f64) as f32;
+                }
+                frame * gain
+            })
+            .collect();
+        Self::from_raw_channels(frames, self.sample_rate(), self.channels())
+    }
+
+    /// Compares the clip frame by frame with `other`, e.g. to check that a
+    /// re-encoded asset still decodes to the same audio. Samples differing
+    /// by up to `tolerance` count as equal.
+    ///
+    /// Clips of different sample rates are [`ClipDiff::Incomparable`];
+    /// [`AudioClip::resample`] one of them first to compare them anyway.
+    pub fn compare(&self, other: &AudioClip, tolerance: f32) -> ClipDiff {
+        if self.sample_rate() != other.sample_rate() {
+            return ClipDiff::Incomparable;
+        }
+        let (frames, others) = (self.frames(), other.frames());
+        let mut max_deviation = 0f32;
+        let mut sum = 0.;
+        let mut first_divergence = None;
+        for (index, (a, b)) in frames.iter().zip(others).enumerate() {
+            let deviation = (a.0 - b.0).abs().max((a.1 - b.1).abs());
+            if deviation > tolerance && first_divergence.is_none() {
+                first_divergence = Some(index);
+            }
+            max_deviation = max_deviation.max(deviation);
+            sum += ((a.0 - b.0) as f64).powi(2) + ((a.1 - b.1) as f64).powi(2);
+        }
+        let common = frames.len().min(others.len());
+        if frames.len() != others.len() {
+            first_divergence.get_or_insert(common);
+        }
+        let rms = if common == 0 {
+            0.
+        } else {
+            (sum / (common * 2) as f64).sqrt() as f32
+        };
+        ClipDiff::Compared {
+            length_difference: others.len() as i64 - frames.len() as i64,
+            max_deviation,
+            rms,
+            first_divergence,
+        }
+    }
+
+    /// Converts the clip to another sample rate using cubic interpolation.
+    ///
+    /// Returns a cheap clone of the clip if it already has the target rate.
+    pub fn resample(&self, sample_rate: u32) -> Self {
+        if sample_rate == self.sample_rate() || sample_rate == 0 {
+            return self.clone();
+        }
+        let frames = self.frames();
+        let ratio = self.sample_rate() as f64 / sample_rate as f64;
+        let count = (frames.len() as f64 / ratio).round() as usize;
+        let get =
+            |index: isize| -> Frame { frames[index.clamp(0, frames.len() as isize - 1) as usize] };
+        let mut result = Vec::with_capacity(count);
+        for i in 0..count {
+            let position = i as f64 * ratio;
+            let index = position as isize;
+            let t = (position - index as f64) as f32;
+            let (p0, p1, p2, p3) = (get(index - 1), get(index), get(index + 1), get(index + 2));
+            let catmull_rom = |p0: f32, p1: f32, p2: f32, p3: f32| {
+                p1 + 0.5
+                    * t
+                    * (p2 - p0
+                        + t * (2. * p0 - 5. * p1 + 4. * p2 - p3 + t * (3. * (p1 - p2) + p3 - p0)))
+            };
+            result.push(Frame(
+                catmull_rom(p0.0, p1.0, p2.0, p3.0),
+                catmull_rom(p0.1, p1.1, p2.1, p3.1),
+            ));
+        }
+        Self::from_raw_channels(result, sample_rate, self.channels())
+    }
+}
+
+/// Clips already converted to the stream rate, keyed by their source clip.
+#[derive(Default)]
+pub(crate) struct ResampleCache {
+    entries: Vec<(Weak<ClipInner>, AudioClip)>,
+}
+
+impl ResampleCache {
+    pub(crate) fn get(&mut self, clip: &AudioClip, sample_rate: u32) -> AudioClip {
+        if sample_rate == 0 || clip.sample_rate() == sample_rate {
+            return clip.clone();
+        }
+        self.entries
+            .retain(|(source, _)| source.strong_count() != 0);
+        if let Some((_, resampled)) = self.entries.iter().find(|(source, resampled)| {
+            source.as_ptr() == Arc::as_ptr(&clip.0) && resampled.sample_rate() == sample_rate
+        }) {
+            return resampled.clone();
+        }
+        eprintln!(
+            "resampling clip from {} Hz to {} Hz, consider converting the asset",
+            clip.sample_rate(),
+            sample_rate
+        );
+        let resampled = clip.resample(sample_rate);
+        self.entries
+            .push((Arc::downgrade(&clip.0), resampled.clone()));
+        resampled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 16-bit PCM WAV file holding `data` as its samples.
+    fn wav(channels: u16, sample_rate: u32, data: &[u8]) -> Vec<u8> {
+        let mut wav = Vec::new();
+        wav.extend(b"RIFF");
+        wav.extend((data.len() as u32 + 36).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(channels.to_le_bytes());
+        wav.extend(sample_rate.to_le_bytes());
+        wav.extend((sample_rate * channels as u32 * 2).to_le_bytes());
+        wav.extend((channels * 2).to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend((data.len() as u32).to_le_bytes());
+        wav.extend(data);
+        wav
+    }
+
+    fn crc8(data: &[u8]) -> u8 {
+        data.iter().fold(0, |crc, byte| {
+            (0..8).fold(crc ^ byte, |crc, _| {
+                if crc & 0x80 != 0 {
+                    crc << 1 ^ 0x07
+                } else {
+                    crc << 1
+                }
+            })
+        })
+    }
+
+    fn crc16(data: &[u8]) -> u16 {
+        data.iter().fold(0, |crc, byte| {
+            (0..8).fold(crc ^ (*byte as u16) << 8, |crc, _| {
+                if crc & 0x8000 != 0 {
+                    crc << 1 ^ 0x8005
+                } else {
+                    crc << 1
+                }
+            })
+        })
+    }
+
+    /// A mono 16-bit FLAC file of verbatim frames of 16 samples each,
+    /// `None` writing a frame the decoder rejects.
+    fn flac(sample_rate: u32, frames: &[Option<[i16; 16]>]) -> Vec<u8> {
+        let mut flac = b"fLaC".to_vec();
+        flac.extend([0x80, 0, 0, 34]);
+        flac.extend(16u16.to_be_bytes());
+        flac.extend(16u16.to_be_bytes());
+        flac.extend([0; 6]);
+        let total = frames.len() as u64 * 16;
+        let packed = (sample_rate as u64) << 44 | 15 << 36 | total;
+        flac.extend(packed.to_be_bytes());
+        flac.extend([0; 16]);
+        for (index, samples) in frames.iter().enumerate() {
+            let start = flac.len();
+            flac.extend([0xff, 0xf8, 0x60, 0x08, index as u8, 15]);
+            flac.push(crc8(&flac[start..]));
+            // A verbatim subframe, or one of a reserved type.
+            flac.push(if samples.is_some() { 0b10 } else { 0b100 });
+            for sample in samples.unwrap_or_default() {
+                flac.extend(sample.to_be_bytes());
+            }
+            let crc = crc16(&flac[start..]);
+            flac.extend(crc.to_be_bytes());
+        }
+        flac
+    }
+
+    fn bits(clip: &AudioClip) -> Vec<u32> {
+        clip.to_vec().into_iter().map(f32::to_bits).collect()
+    }
+
+    #[test]
+    fn parallel_decoding_matches_serial() {
+        let data: Vec<u8> = (0..10007 * 2)
+            .flat_map(|i: i32| ((i * 7919 % 65536) as u16).to_le_bytes())
+            .collect();
+        let frames: Vec<_> = (0..37).map(|i| Some([i * 331; 16])).collect();
+        for file in [wav(2, 44100, &data), flac(48000, &frames)] {
+            let serial = AudioClip::new(file.clone()).unwrap();
+            for threads in [1, 2, 3, 8, 64] {
+                let parallel = AudioClip::load_parallel(file.clone(), threads).unwrap();
+                assert_eq!(bits(&parallel), bits(&serial));
+                assert_eq!(parallel.sample_rate(), serial.sample_rate());
+                assert_eq!(parallel.channels(), serial.channels());
+            }
+        }
+    }
+
+    #[test]
+    fn corrupt_head_reports_the_decode_error() {
+        let mut frames: Vec<_> = (0..8).map(|i| Some([i * 100; 16])).collect();
+        frames[0] = None;
+        let file = flac(48000, &frames);
+        let serial = AudioClip::new(file.clone()).err().unwrap();
+        let parallel = AudioClip::load_parallel(file, 4).err().unwrap();
+        assert!(parallel.downcast_ref::<Error>().is_none());
+        assert_eq!(parallel.to_string(), serial.to_string());
+    }
+
+    #[test]
+    fn corrupt_tail_keeps_the_head() {
+        let mut frames: Vec<_> = (0..8).map(|i| Some([i * 100; 16])).collect();
+        frames[5] = None;
+        let file = flac(48000, &frames);
+        let serial = AudioClip::new(file.clone()).unwrap();
+        assert_eq!(serial.frame_count(), 5 * 16);
+        let parallel = AudioClip::load_parallel(file, 4).unwrap();
+        assert_eq!(bits(&parallel), bits(&serial));
+    }
+
+    #[test]
+    fn multichannel_sources_are_downmixed() {
+        // Front left and right, centre, LFE, surround left and right.
+        let levels = [0.5, 0.25, 0.125, 0.75, 0.25, 0.375];
+        let data: Vec<u8> = (0..64)
+            .flat_map(|_| levels)
+            .flat_map(|it: f32| ((it * 32768.) as i16).to_le_bytes())
+            .collect();
+        let clip = AudioClip::new(wav(6, 48000, &data)).unwrap();
+        assert_eq!(clip.channels(), 6);
+        assert_eq!(clip.frame_count(), 64);
+        let gain = DOWNMIX_GAIN;
+        let left = 0.5 + 0.125 * gain + 0.25 * gain;
+        let right = 0.25 + 0.125 * gain + 0.375 * gain;
+        for frame in clip.frames() {
+            assert!((frame.0 - left).abs() < 1e-6 && (frame.1 - right).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn split_channels_round_trips() {
+        let frames: Vec<_> = (0..256)
+            .map(|i| Frame(i as f32 / 256., -(i as f32) / 512.))
+            .collect();
+        let clip = AudioClip::from_raw(frames.clone(), 44100);
+        let (left, right) = clip.split_channels();
+        assert_eq!((left.channels(), right.channels()), (1, 1));
+        assert_eq!(left.sample_rate(), 44100);
+        assert!(left
+            .frames()
+            .iter()
+            .zip(&frames)
+            .all(|(a, b)| a.0 == b.0 && a.1 == b.0));
+        assert!(right
+            .frames()
+            .iter()
+            .zip(&frames)
+            .all(|(a, b)| a.0 == b.1 && a.1 == b.1));
+        let merged = AudioClip::merge_channels(&left, &right).unwrap();
+        assert_eq!(merged.frames(), &frames[..]);
+    }
+
+    #[test]
+    fn merge_channels_puts_each_tone_on_its_channel() {
+        let tone = |frequency: f32, frames: usize| {
+            let samples = (0..frames)
+                .map(|i| {
+                    Frame::from_mono((i as f32 * frequency / 48000. * std::f32::consts::TAU).sin())
+                })
+                .collect();
+            AudioClip::from_raw_channels(samples, 48000, 1)
+        };
+        let (low, high) = (tone(220., 960), tone(880., 480));
+        let merged = AudioClip::merge_channels(&low, &high).unwrap();
+        assert_eq!((merged.channels(), merged.frame_count()), (2, 960));
+        for (index, frame) in merged.frames().iter().enumerate() {
+            assert_eq!(frame.0, low.frames()[index].0);
+            // The shorter clip is padded with silence.
+            assert_eq!(frame.1, high.frames().get(index).map_or(0., |it| it.0));
+        }
+
+        let other_rate = AudioClip::from_raw_channels(high.frames().to_vec(), 44100, 1);
+        assert!(AudioClip::merge_channels(&low, &other_rate).is_err());
+    }
+
+    #[test]
+    fn apply_fade_ramps_only_the_ends() {
+        let clip = AudioClip::from_raw(vec![Frame(0.5, -0.5); 4800], 48000);
+        let faded = clip.apply_fade(0.01, 0.02);
+        let frames = faded.frames();
+        assert_eq!(frames.len(), 4800);
+        assert_eq!(frames[0], Frame(0., 0.));
+        assert_eq!(frames[4799], Frame(0., 0.));
+        assert!((frames[240].0 - 0.25).abs() < 1e-6);
+        assert!((frames[4799 - 480].0 - 0.25).abs() < 1e-6);
+        assert!(frames[480..4799 - 960]
+            .iter()
+            .all(|it| *it == Frame(0.5, -0.5)));
+        assert_eq!(clip.frames()[0], Frame(0.5, -0.5));
+    }
+
+    #[test]
+    fn compare_reports_gain_and_time_shifts() {
+        let clip = crate::test_util::sine(440., 0.5, 0.1);
+        let frames = clip.frame_count();
+        assert!(clip.compare(&clip, 0.).is_identical());
+
+        let scaled = AudioClip::from_raw(
+            clip.frames().iter().map(|it| *it * 0.9).collect(),
+            clip.sample_rate(),
+        );
+        let ClipDiff::Compared {
+            length_difference,
+            max_deviation,
+            rms,
+            first_divergence,
+        } = clip.compare(&scaled, 0.01)
+        else {
+            panic!("clips of the same rate are comparable");
+        };
+        assert_eq!(length_difference, 0);
+        assert!((max_deviation - 0.05).abs() < 1e-4, "{max_deviation}");
+        // The RMS of a sine of amplitude 0.05.
+        assert!((rms - 0.05 / 2f32.sqrt()).abs() < 1e-3, "{rms}");
+        let first = first_divergence.unwrap();
+        assert!(clip.frames()[first].0.abs() * 0.1 > 0.01);
+        assert!(clip.frames()[..first]
+            .iter()
+            .all(|it| it.0.abs() * 0.1 <= 0.01));
+        assert!(clip.compare(&scaled, 0.051).is_identical());
+
+        let mut delayed = vec![Frame::default(); 10];
+        delayed.extend_from_slice(clip.frames());
+        let shifted = AudioClip::from_raw(delayed, clip.sample_rate());
+        let ClipDiff::Compared {
+            length_difference,
+            first_divergence,
+            ..
+        } = clip.compare(&shifted, 0.01)
+        else {
+            panic!("clips of the same rate are comparable");
+        };
+        assert_eq!(length_difference, 10);
+        assert!(first_divergence.unwrap() < 10);
+        // Identical over the shorter clip, still told apart by the length.
+        let truncated = AudioClip::from_raw(clip.frames()[..frames - 5].to_vec(), 48000);
+        assert_eq!(
+            clip.compare(&truncated, 0.),
+            ClipDiff::Compared {
+                length_difference: -5,
+                max_deviation: 0.,
+                rms: 0.,
+                first_divergence: Some(frames - 5),
+            }
+        );
+
+        assert_eq!(
+            clip.compare(&clip.resample(44100), 0.),
+            ClipDiff::Incomparable
+        );
+    }
+
+    #[test]
+    fn frame_accessors_agree() {
+        let frames: Vec<Frame> = (0..1000)
+            .map(|i| Frame(i as f32 / 1000., -(i as f32) / 500.))
+            .collect();
+        let clip = AudioClip::from_raw(frames.clone(), 48000);
+        assert_eq!(clip.samples(), &frames[..]);
+        assert_eq!(clip.samples().len(), clip.frame_count());
+        assert_eq!(clip.samples(), clip.frames());
+        for (i, frame) in clip.samples().iter().enumerate() {
+            assert_eq!(clip.get(i), Some(*frame));
+            // SAFETY: `i` is below the frame count.
+            assert_eq!(unsafe { clip.get_unchecked(i) }, *frame);
+        }
+        assert_eq!(clip.get(clip.frame_count()), None);
+
+        let empty = AudioClip::from_raw(Vec::new(), 48000);
+        assert!(empty.samples().is_empty());
+        assert_eq!(empty.get(0), None);
+    }
 }
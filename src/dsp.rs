@@ -0,0 +1,556 @@
+use crate::{validate, Frame};
+use anyhow::{bail, Result};
+
+#[inline]
+fn one_pole_coefficient(time: f32, sample_rate: u32) -> f32 {
+    if time <= 0. {
+        0.
+    } else {
+        (-1. / (time * sample_rate as f32)).exp()
+    }
+}
+
+/// A parameter moving linearly towards its target over a number of frames.
+pub(crate) struct Ramp {
+    pub(crate) value: f32,
+    target: f32,
+    remaining: i32,
+}
+
+impl Ramp {
+    pub(crate) fn new(value: f32) -> Self {
+        Self {
+            value,
+            target: value,
+            remaining: 0,
+        }
+    }
+
+    pub(crate) fn set(&mut self, target: f32, time: f64, sample_rate: u32) {
+        self.target = target;
+        self.remaining = (time * sample_rate as f64).round() as _;
+        if self.remaining <= 0 {
+            self.value = target;
+            self.remaining = 0;
+        }
+    }
+
+    pub(crate) fn target(&self) -> f32 {
+        self.target
+    }
+
+    /// Whether the value has reached its target.
+    pub(crate) fn settled(&self) -> bool {
+        self.remaining == 0
+    }
+
+    #[inline]
+    pub(crate) fn next(&mut self) -> f32 {
+        if self.remaining > 0 {
+            self.value += (self.target - self.value) / self.remaining as f32;
+            self.remaining -= 1;
+        }
+        self.value
+    }
+}
+
+/// Constant power gains of the left and right channel for `pan` from -1,
+/// full left, to 1, full right. Both are 1 at the center, so that centered
+/// tracks sound as if not panned.
+pub(crate) fn pan_gains(pan: f32) -> (f32, f32) {
+    if pan == 0. {
+        return (1., 1.);
+    }
+    let angle = (pan + 1.) * std::f32::consts::FRAC_PI_4;
+    let (sin, cos) = angle.sin_cos();
+    // `cos` ends up slightly below 0 at full right.
+    (
+        cos.max(0.) * std::f32::consts::SQRT_2,
+        sin.max(0.) * std::f32::consts::SQRT_2,
+    )
+}
+
+/// Scales `amp` by a linear fade `current / total` of the way through,
+/// fading out instead of in when `out` is set.
+#[cfg(not(feature = "fixed_point"))]
+#[inline]
+pub(crate) fn apply_fade(amp: f32, current: i64, total: i64, out: bool) -> f32 {
+    let ratio = (current as f64 / total as f64).clamp(0., 1.) as f32;
+    amp * if out { 1. - ratio } else { ratio }
+}
+
+/// Q15 version of the fade, for targets without fast float arithmetic.
+#[cfg(feature = "fixed_point")]
+#[inline]
+pub(crate) fn apply_fade(amp: f32, current: i64, total: i64, out: bool) -> f32 {
+    let ratio = q15::ratio(current, total).min(q15::ONE);
+    let fade = if out { q15::ONE - ratio } else { ratio };
+    q15::to_f32(q15::mul(q15::from_f32(amp), fade))
+}
+
+/// Q15 fixed-point helpers. Values are stored in an `i32`, so gains above 1
+/// are representable. Every operation rounds to the nearest step instead of
+/// truncating, so short fades do not collapse to zero.
+#[cfg(feature = "fixed_point")]
+mod q15 {
+    pub const ONE: i32 = 1 << 15;
+
+    #[inline]
+    pub fn from_f32(value: f32) -> i32 {
+        (value * ONE as f32).round() as i32
+    }
+
+    #[inline]
+    pub fn to_f32(value: i32) -> f32 {
+        value as f32 / ONE as f32
+    }
+
+    #[inline]
+    pub fn mul(a: i32, b: i32) -> i32 {
+        ((a as i64 * b as i64 + (ONE as i64 >> 1)) >> 15) as i32
+    }
+
+    /// `num / den` in Q15, both having the same sign.
+    #[inline]
+    pub fn ratio(num: i64, den: i64) -> i32 {
+        let (num, den) = (num.unsigned_abs(), den.unsigned_abs().max(1));
+        (((num << 15) + den / 2) / den) as i32
+    }
+}
+
+/// Rolling envelope follower for key signals (ducking, compression).
+///
+/// The envelope rises towards the input level with the `attack` time
+/// constant and falls back with the `release` one, both in seconds.
+#[derive(Debug, Clone)]
+pub struct EnvelopeFollower {
+    attack: f32,
+    release: f32,
+    attack_coef: f32,
+    release_coef: f32,
+    sample_rate: u32,
+    value: f32,
+}
+
+impl EnvelopeFollower {
+    pub fn new(attack: f32, release: f32, sample_rate: u32) -> Self {
+        Self {
+            attack,
+            release,
+            attack_coef: one_pole_coefficient(attack, sample_rate),
+            release_coef: one_pole_coefficient(release, sample_rate),
+            sample_rate,
+            value: 0.,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        if self.sample_rate != sample_rate {
+            *self = Self {
+                value: self.value,
+                ..Self::new(self.attack, self.release, sample_rate)
+            };
+        }
+    }
+
+    #[inline]
+    pub fn process(&mut self, level: f32) -> f32 {
+        let coef = if level > self.value {
+            self.attack_coef
+        } else {
+            self.release_coef
+        };
+        self.value = level + (self.value - level) * coef;
+        self.value
+    }
+
+    #[inline]
+    pub fn process_frame(&mut self, frame: Frame) -> f32 {
+        self.process(frame.magnitude())
+    }
+
+    #[inline(always)]
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+
+    pub fn reset(&mut self) {
+        self.value = 0.;
+    }
+}
+
+/// Longest lookahead of the limiter, in seconds.
+const MAX_LOOKAHEAD: f64 = 0.1;
+
+// The limiter allocates its delay line for this format up front, so that
+// switching to it never allocates on the audio thread.
+const MAX_LIMITER_SAMPLE_RATE: u32 = 192000;
+const MAX_LIMITER_CHANNELS: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct LimiterSettings {
+    /// Linear peak level the output never exceeds, above 0.
+    pub ceiling: f32,
+    /// Seconds the output is delayed by so that the gain can come down
+    /// before a peak instead of on it, at most 0.1. Zero disables
+    /// lookahead.
+    pub lookahead: f32,
+    /// Time constant in seconds of the gain recovering after a peak.
+    pub release: f32,
+}
+impl Default for LimiterSettings {
+    fn default() -> Self {
+        Self {
+            ceiling: 1.,
+            lookahead: 0.,
+            release: 0.05,
+        }
+    }
+}
+
+impl LimiterSettings {
+    pub(crate) fn validate(mut self) -> Result<Self> {
+        self.ceiling = validate("ceiling", self.ceiling as f64, 0., f64::MAX)? as f32;
+        if self.ceiling <= 0. {
+            bail!("limiter ceiling {} must be above 0", self.ceiling);
+        }
+        self.lookahead = validate("lookahead", self.lookahead as f64, 0., MAX_LOOKAHEAD)? as f32;
+        self.release = validate("release", self.release as f64, 0., f64::MAX)? as f32;
+        Ok(self)
+    }
+
+    /// Lookahead in frames at `sample_rate`.
+    fn lookahead_frames(&self, sample_rate: u32) -> usize {
+        (self.lookahead.max(0.) * sample_rate as f32).round() as usize
+    }
+}
+
+/// Brickwall peak limiter applied to the interleaved master output.
+pub(crate) struct Limiter {
+    settings: LimiterSettings,
+    channels: usize,
+    sample_rate: u32,
+    release_coef: f32,
+    gain: f32,
+
+    // Gains needed by the last `lookahead + 1` frames, and the delayed
+    // frames themselves.
+    required: Vec<f32>,
+    required_head: usize,
+    delay: Vec<f32>,
+    delay_head: usize,
+}
+
+impl Limiter {
+    /// Allocates the limiter off the audio thread, see
+    /// [`MAX_LIMITER_SAMPLE_RATE`].
+    pub(crate) fn new(settings: LimiterSettings) -> Self {
+        let lookahead = settings.lookahead_frames(MAX_LIMITER_SAMPLE_RATE);
+        Self {
+            settings,
+            channels: 0,
+            sample_rate: 0,
+            release_coef: 0.,
+            gain: 1.,
+
+            required: Vec::with_capacity(lookahead + 1),
+            required_head: 0,
+            delay: Vec::with_capacity(lookahead * MAX_LIMITER_CHANNELS),
+            delay_head: 0,
+        }
+    }
+
+    fn reset(&mut self, channels: usize, sample_rate: u32) {
+        let lookahead = self.settings.lookahead_frames(sample_rate);
+        self.channels = channels;
+        self.sample_rate = sample_rate;
+        self.release_coef = one_pole_coefficient(self.settings.release, sample_rate);
+        self.gain = 1.;
+        self.required.clear();
+        self.required.resize(lookahead + 1, 1.);
+        self.required_head = 0;
+        self.delay.clear();
+        self.delay.resize(lookahead * channels, 0.);
+        self.delay_head = 0;
+    }
+
+    pub(crate) fn process(&mut self, data: &mut [f32], channels: usize, sample_rate: u32) {
+        if self.channels != channels || self.sample_rate != sample_rate {
+            self.reset(channels, sample_rate);
+        }
+        let ceiling = self.settings.ceiling;
+        for frame in data.chunks_exact_mut(channels) {
+            let peak = frame.iter().fold(0f32, |max, it| max.max(it.abs()));
+            self.required[self.required_head] = if peak > ceiling { ceiling / peak } else { 1. };
+            self.required_head = (self.required_head + 1) % self.required.len();
+            let target = self.required.iter().copied().fold(1., f32::min);
+            self.gain = (1. + (self.gain - 1.) * self.release_coef).min(target);
+
+            let gain = self.gain;
+            if self.delay.is_empty() {
+                frame
+                    .iter_mut()
+                    .for_each(|it| *it = (*it * gain).clamp(-ceiling, ceiling));
+            } else {
+                let delayed = &mut self.delay[self.delay_head..self.delay_head + channels];
+                for (output, delayed) in frame.iter_mut().zip(delayed) {
+                    let input = *output;
+                    *output = (*delayed * gain).clamp(-ceiling, ceiling);
+                    *delayed = input;
+                }
+                self.delay_head = (self.delay_head + channels) % self.delay.len();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterKind {
+    LowPass,
+    HighPass,
+    BandPass,
+}
+
+#[derive(Debug, Clone)]
+pub struct FilterSettings {
+    pub kind: FilterKind,
+    /// Cutoff (or center) frequency in Hz.
+    pub cutoff: f32,
+    pub q: f32,
+}
+impl Default for FilterSettings {
+    fn default() -> Self {
+        Self {
+            kind: FilterKind::LowPass,
+            cutoff: 1000.,
+            q: std::f32::consts::FRAC_1_SQRT_2,
+        }
+    }
+}
+
+/// Second-order IIR filter with coefficients from the RBJ audio EQ cookbook,
+/// keeping separate state for each channel of interleaved data.
+pub(crate) struct Biquad {
+    settings: FilterSettings,
+    sample_rate: u32,
+    // b0, b1, b2, a1, a2, normalized by a0.
+    coefs: [f32; 5],
+    state: Vec<[f32; 2]>,
+}
+
+impl Biquad {
+    pub(crate) fn new(settings: FilterSettings) -> Self {
+        Self {
+            settings,
+            sample_rate: 0,
+            coefs: [1., 0., 0., 0., 0.],
+            state: Vec::new(),
+        }
+    }
+
+    fn update_coefs(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        let nyquist = sample_rate as f32 / 2.;
+        let cutoff = self.settings.cutoff.clamp(1., nyquist * 0.99);
+        let w0 = std::f32::consts::TAU * cutoff / sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2. * self.settings.q.max(0.01));
+        let (b0, b1, b2) = match self.settings.kind {
+            FilterKind::LowPass => ((1. - cos) / 2., 1. - cos, (1. - cos) / 2.),
+            FilterKind::HighPass => ((1. + cos) / 2., -1. - cos, (1. + cos) / 2.),
+            FilterKind::BandPass => (alpha, 0., -alpha),
+        };
+        let a0 = 1. + alpha;
+        self.coefs = [b0 / a0, b1 / a0, b2 / a0, -2. * cos / a0, (1. - alpha) / a0];
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.state.fill([0.; 2]);
+    }
+
+    pub(crate) fn process(&mut self, data: &mut [f32], channels: usize, sample_rate: u32) {
+        if self.sample_rate != sample_rate {
+            self.update_coefs(sample_rate);
+        }
+        if self.state.len() != channels {
+            self.state = vec![[0.; 2]; channels];
+        }
+        let [b0, b1, b2, a1, a2] = self.coefs;
+        for frame in data.chunks_exact_mut(channels) {
+            for (sample, state) in frame.iter_mut().zip(&mut self.state) {
+                let input = *sample;
+                let output = b0 * input + state[0];
+                state[0] = b1 * input - a1 * output + state[1];
+                state[1] = b2 * input - a2 * output;
+                *sample = output;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::offline::OfflineBackend,
+        test_util::{manager, manager_with, peak, render, settings, sine, BLOCK_SIZE, SAMPLE_RATE},
+        MusicParams, PlaySfxParams,
+    };
+
+    #[test]
+    fn limited_mix_stays_below_full_scale() {
+        for lookahead in [0., 0.005] {
+            let (mut manager, mut out) = manager_with(
+                crate::AudioManagerSettings {
+                    limiter: Some(LimiterSettings {
+                        lookahead,
+                        ..LimiterSettings::default()
+                    }),
+                    ..settings()
+                },
+                Default::default(),
+            );
+            let mut sfx = manager.create_sfx(sine(440., 0.9, 0.5), None).unwrap();
+            for _ in 0..4 {
+                sfx.play(PlaySfxParams::default()).unwrap();
+            }
+            let data = render(&mut out, SAMPLE_RATE as usize / 2);
+            assert!(peak(&data) <= 1., "{lookahead}: {}", peak(&data));
+            assert!(peak(&data) > 0.9);
+        }
+    }
+
+    #[test]
+    fn limiter_settings_are_validated() {
+        for ceiling in [0., -1., f32::NAN, f32::INFINITY] {
+            let limiter = LimiterSettings {
+                ceiling,
+                ..LimiterSettings::default()
+            };
+            assert!(limiter.validate().is_err(), "{ceiling}");
+        }
+        for (lookahead, release) in [(f32::NAN, 0.05), (0., f32::INFINITY)] {
+            let limiter = LimiterSettings {
+                lookahead,
+                release,
+                ..LimiterSettings::default()
+            };
+            assert!(limiter.validate().is_err());
+        }
+        let limiter = LimiterSettings {
+            lookahead: 10.,
+            ..LimiterSettings::default()
+        };
+        assert_eq!(limiter.validate().unwrap().lookahead, MAX_LOOKAHEAD as f32);
+
+        let (backend, _out) = OfflineBackend::new(Default::default());
+        let manager = crate::AudioManagerSettings {
+            limiter: Some(LimiterSettings {
+                ceiling: 0.,
+                ..LimiterSettings::default()
+            }),
+            ..settings()
+        };
+        assert!(crate::AudioManager::with_settings(backend, manager).is_err());
+    }
+
+    #[test]
+    fn limiter_switches_format_within_its_allocation() {
+        let mut limiter = Limiter::new(LimiterSettings {
+            lookahead: 0.01,
+            ..LimiterSettings::default()
+        });
+        let (required, delay) = (limiter.required.as_ptr(), limiter.delay.as_ptr());
+        let mut data = vec![2.; 1024 * MAX_LIMITER_CHANNELS];
+        for (channels, sample_rate) in [(2, 48000), (1, 44100), (8, MAX_LIMITER_SAMPLE_RATE)] {
+            limiter.process(&mut data, channels, sample_rate);
+            assert_eq!(limiter.required.as_ptr(), required);
+            assert_eq!(limiter.delay.as_ptr(), delay);
+        }
+        assert!(peak(&data) <= 1.);
+    }
+
+    #[test]
+    fn envelope_follows_key_levels() {
+        let key = Frame(0.5, -0.25);
+        assert_eq!(key.magnitude(), 0.75);
+        let mut follower = EnvelopeFollower::new(0.01, 0.1, SAMPLE_RATE);
+        for _ in 0..SAMPLE_RATE / 100 {
+            follower.process_frame(key);
+        }
+        let attacked = follower.value();
+        assert!((attacked - 0.75 * (1. - (-1f32).exp())).abs() < 1e-3);
+
+        follower.set_sample_rate(SAMPLE_RATE / 2);
+        assert_eq!(follower.value(), attacked);
+        for _ in 0..SAMPLE_RATE / 20 {
+            follower.process_frame(Frame::default());
+        }
+        assert!((follower.value() - attacked * (-1f32).exp()).abs() < 1e-3);
+
+        let mut instant = EnvelopeFollower::new(0., 0., SAMPLE_RATE);
+        assert_eq!(instant.process(0.5), 0.5);
+        assert_eq!(instant.process(0.), 0.);
+    }
+
+    #[cfg(feature = "fixed_point")]
+    #[test]
+    fn q15_fade_matches_the_float_fade() {
+        for (amp, total) in [(1., 48000), (0.25, 480), (-0.5, 7), (1.5, 12000)] {
+            let fade: Vec<f32> = (0..=total)
+                .map(|i| apply_fade(amp, i, total, false))
+                .collect();
+            for (i, value) in fade.iter().enumerate() {
+                let float = amp * i as f32 / total as f32;
+                assert!((value - float).abs() <= 2. / 32768., "{amp} {total} {i}");
+            }
+            assert!(fade
+                .windows(2)
+                .all(|it| (it[1] - it[0]) * amp.signum() >= 0.));
+            // Short fades of quiet tracks still move on the first step.
+            assert_ne!(fade[1], 0.);
+            assert_eq!(apply_fade(amp, total, total, true), 0.);
+        }
+    }
+
+    #[test]
+    fn master_low_pass_muffles_every_source() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(sine(8000., 0.25, 2.), MusicParams::default())
+            .unwrap();
+        let mut sfx = manager.create_sfx(sine(6000., 0.25, 2.), None).unwrap();
+        music.play().unwrap();
+        sfx.play(PlaySfxParams::default()).unwrap();
+        let open = peak(&render(&mut out, BLOCK_SIZE * 8));
+        assert!(open > 0.4);
+
+        manager
+            .set_master_filter(Some(FilterSettings {
+                kind: FilterKind::LowPass,
+                cutoff: 500.,
+                ..FilterSettings::default()
+            }))
+            .unwrap();
+        render(&mut out, BLOCK_SIZE * 4);
+        let muffled = peak(&render(&mut out, BLOCK_SIZE * 8));
+        assert!(muffled < open / 20., "{muffled}");
+
+        manager.set_master_filter(None).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert!((peak(&render(&mut out, BLOCK_SIZE * 8)) - open).abs() < 0.01);
+    }
+
+    #[test]
+    fn pan_gains_keep_their_power() {
+        for step in -20..=20 {
+            let pan = step as f32 / 20.;
+            let (left, right) = pan_gains(pan);
+            assert!((left * left + right * right - 2.).abs() < 1e-5, "{pan}");
+            assert!(left >= 0. && right >= 0., "{pan}");
+        }
+        assert_eq!(pan_gains(0.), (1., 1.));
+        assert_eq!(pan_gains(-1.), (std::f32::consts::SQRT_2, 0.));
+        assert_eq!(pan_gains(1.), (0., std::f32::consts::SQRT_2));
+    }
+}
@@ -0,0 +1,40 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use thiserror::Error;
+
+/// Typed errors of this crate. They are returned wrapped in
+/// [`anyhow::Error`] and can be recovered with `downcast_ref`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum Error {
+    /// The [`AudioManager`](crate::AudioManager) owning this handle has been
+    /// shut down or dropped.
+    #[error("audio manager has been shut down")]
+    Disconnected,
+    /// A parameter was NaN or infinite.
+    #[error("`{0}` must be finite")]
+    NonFinite(&'static str),
+    /// Decoding produced no audio at all.
+    #[error("clip has no frames")]
+    EmptyClip,
+}
+
+#[inline]
+pub(crate) fn check_connected(connected: &AtomicBool) -> Result<(), Error> {
+    if connected.load(Ordering::Acquire) {
+        Ok(())
+    } else {
+        Err(Error::Disconnected)
+    }
+}
+
+/// Rejects non-finite values and clamps the others into `min..=max`,
+/// logging the clamp in debug builds.
+pub(crate) fn validate(name: &'static str, value: f64, min: f64, max: f64) -> Result<f64, Error> {
+    if !value.is_finite() {
+        return Err(Error::NonFinite(name));
+    }
+    let clamped = value.clamp(min, max);
+    if cfg!(debug_assertions) && clamped != value {
+        eprintln!("{name} {value} is out of range, clamped to {clamped}");
+    }
+    Ok(clamped)
+}
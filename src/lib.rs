@@ -3,34 +3,148 @@ pub mod backend;
 use atomic_float::AtomicF64;
 pub use backend::Backend;
 
+pub mod android_glue;
+
+mod calibration;
+pub use calibration::{Calibration, CalibrationResult};
+
 mod clip;
-pub use clip::AudioClip;
+use clip::ResampleCache;
+pub use clip::{AudioClip, ClipDiff};
+
+mod dsp;
+use dsp::Limiter;
+pub use dsp::{EnvelopeFollower, FilterKind, FilterSettings, LimiterSettings};
+
+mod error;
+pub use error::Error;
+use error::{check_connected, validate};
 
 mod mixer;
+pub use mixer::{AudioEvent, DirectHandle, Health, OverloadEvent, OverloadPolicy};
+
+mod recorder;
+use recorder::FileWriter;
+pub use recorder::{FileRecording, OutputRecorder};
+
+mod rng;
+use rng::Rng;
+
+pub mod rt;
+
+mod snapshot;
+use snapshot::Labeled;
+pub use snapshot::{RendererKind, RendererSnapshot};
+
+mod tempo;
+pub use tempo::TempoMap;
+
+mod volume;
+pub use volume::{amplitude_to_db, db_to_amplitude, VolumeCurve, SILENCE_DB};
+
+#[cfg(test)]
+mod test_util;
 
 mod renderer;
-pub use renderer::{Music, MusicParams, PlaySfxParams, Renderer, Sfx};
+use renderer::render_isolated;
+#[allow(deprecated)]
+pub use renderer::LegacyRenderer;
+pub use renderer::{
+    AppliedCommand, BusHandle, CommandOverflow, GainStages, GroupHandle, Humanize, LoopMode, Music,
+    MusicParams, MusicStats, PlaySfxParams, RenderContext, Renderer, ScheduledCommand,
+    ScheduledPlay, Sfx, SfxInstance, SyncedMusic,
+};
 
-use crate::{backend::BackendSetup, mixer::MixerCommand};
-use anyhow::{anyhow, Context, Result};
-use ringbuf::{HeapProducer, HeapRb};
+use crate::{
+    backend::BackendSetup,
+    mixer::{MixerCommand, OutputTap, StreamInfo},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
 use std::{
-    ops::{Add, Mul},
+    ops::{Add, AddAssign, Mul, MulAssign, Neg, Sub},
+    path::Path,
     sync::{
-        atomic::Ordering,
+        atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::Duration,
 };
 
 fn buffer_is_full<E>(_: E) -> anyhow::Error {
     anyhow!("buffer is full")
 }
 
-#[derive(Clone, Copy, Default)]
+/// A stereo sample, left channel first.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
 pub struct Frame(pub f32, pub f32);
 impl Frame {
+    /// A frame with `sample` on both channels.
+    #[inline]
+    pub fn from_mono(sample: f32) -> Self {
+        Self(sample, sample)
+    }
+
+    #[inline(always)]
+    pub fn left(&self) -> f32 {
+        self.0
+    }
+
+    #[inline(always)]
+    pub fn right(&self) -> f32 {
+        self.1
+    }
+
+    #[inline]
+    pub fn gain(&self, gain: f32) -> Self {
+        *self * gain
+    }
+
+    /// Linear interpolation from `a` at `t = 0` to `b` at `t = 1`.
+    #[inline]
+    pub fn lerp(a: Self, b: Self, t: f32) -> Self {
+        a.interpolate(&b, t)
+    }
+
+    /// Clamps both channels to full scale.
+    #[inline]
+    pub fn clamp(&self) -> Self {
+        Self(self.0.clamp(-1., 1.), self.1.clamp(-1., 1.))
+    }
+
+    /// Writes `frames` as interleaved 16-bit stereo, clamping to full
+    /// scale. Stops at the end of the shorter of the two.
+    pub fn write_interleaved(frames: &[Frame], out: &mut [i16]) {
+        let convert = |x: f32| (x * 32768.).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        for (frame, out) in frames.iter().zip(out.chunks_exact_mut(2)) {
+            out[0] = convert(frame.0);
+            out[1] = convert(frame.1);
+        }
+    }
+
+    /// Reads interleaved 16-bit stereo into `out`, the inverse of
+    /// [`Frame::write_interleaved`]. Stops at the end of the shorter of the
+    /// two.
+    pub fn read_interleaved(data: &[i16], out: &mut [Frame]) {
+        for (data, frame) in data.chunks_exact(2).zip(out.iter_mut()) {
+            *frame = Frame(data[0] as f32 / 32768., data[1] as f32 / 32768.);
+        }
+    }
+
+    /// Mono downmix, `(l + r) * 0.5` computed in `f64` so that correlated
+    /// full-scale channels can not overflow.
     pub fn avg(&self) -> f32 {
-        (self.0 + self.1) / 2.
+        ((self.0 as f64 + self.1 as f64) * 0.5) as f32
+    }
+
+    /// Sum of the absolute values of both channels, used as the level of a
+    /// key signal.
+    pub fn magnitude(&self) -> f32 {
+        self.0.abs() + self.1.abs()
+    }
+
+    pub fn is_finite(&self) -> bool {
+        self.0.is_finite() && self.1.is_finite()
     }
 
     pub fn interpolate(&self, other: &Self, f: f32) -> Self {
@@ -47,6 +161,25 @@ impl Add for Frame {
         Self(self.0 + rhs.0, self.1 + rhs.1)
     }
 }
+impl AddAssign for Frame {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+impl Sub for Frame {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Self(self.0 - rhs.0, self.1 - rhs.1)
+    }
+}
+impl Neg for Frame {
+    type Output = Self;
+
+    fn neg(self) -> Self::Output {
+        Self(-self.0, -self.1)
+    }
+}
 impl Mul<f32> for Frame {
     type Output = Self;
 
@@ -54,6 +187,21 @@ impl Mul<f32> for Frame {
         Self(self.0 * rhs, self.1 * rhs)
     }
 }
+impl MulAssign<f32> for Frame {
+    fn mul_assign(&mut self, rhs: f32) {
+        *self = *self * rhs;
+    }
+}
+impl From<(f32, f32)> for Frame {
+    fn from((left, right): (f32, f32)) -> Self {
+        Self(left, right)
+    }
+}
+impl From<Frame> for (f32, f32) {
+    fn from(frame: Frame) -> Self {
+        (frame.0, frame.1)
+    }
+}
 
 const LATENCY_RECORD_NUM: usize = 640;
 
@@ -97,10 +245,90 @@ impl LatencyRecorder {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct AudioManagerSettings {
+    /// Convert clips whose sample rate differs from the output stream when
+    /// creating [`Music`] or [`Sfx`] from them.
+    pub auto_resample: bool,
+    /// Boost mono output by 3 dB so that content downmixed from stereo
+    /// keeps roughly the same loudness as on stereo devices.
+    pub mono_compensation: bool,
+    /// Seed for every random choice made by the engine, so that the same
+    /// commands render the same output with [`backend::offline`].
+    pub rng_seed: u64,
+    /// Limiter applied to the master output, after the master gain. `None`
+    /// lets the output exceed full scale.
+    pub limiter: Option<LimiterSettings>,
+    /// Preferred sample rate of the output stream, if the backend and the
+    /// device support it.
+    pub sample_rate: Option<u32>,
+    /// Preferred buffer size in frames of the output stream, overriding the
+    /// one in the backend settings.
+    pub buffer_size: Option<u32>,
+    /// Lowers the rendering quality while callbacks come close to missing
+    /// their deadline. `None` never degrades.
+    pub overload: Option<OverloadPolicy>,
+    /// Frames the mixer renders at a time, whatever the size of the device
+    /// callbacks, e.g. 128. Renderers, effects and scheduled commands then
+    /// always see blocks of this size, at the cost of up to one quantum of
+    /// added latency. `None` renders each callback in one block.
+    pub quantum: Option<usize>,
+    /// Seconds over which the output ramps in from silence whenever the
+    /// stream starts or is rebuilt, so that renderers resuming mid-sound do
+    /// not click. Filters are cleared at the same time. 0 disables the ramp.
+    pub soft_start: f64,
+}
+impl AudioManagerSettings {
+    fn validate(mut self) -> Result<Self> {
+        if let Some(limiter) = self.limiter.take() {
+            self.limiter = Some(limiter.validate().context("limiter")?);
+        }
+        self.soft_start = validate("soft start", self.soft_start, 0., f64::MAX)?;
+        Ok(self)
+    }
+}
+impl Default for AudioManagerSettings {
+    fn default() -> Self {
+        Self {
+            auto_resample: true,
+            mono_compensation: false,
+            rng_seed: 0,
+            limiter: Some(LimiterSettings::default()),
+            sample_rate: None,
+            buffer_size: None,
+            overload: None,
+            quantum: None,
+            soft_start: 0.02,
+        }
+    }
+}
+
+/// Fade applied to the master output when an [`AudioManager`] is dropped.
+const DROP_FADE: f32 = 0.05;
+
+/// Longest fade [`AudioManager::shutdown`] waits for, in seconds.
+const MAX_SHUTDOWN_FADE: f64 = 5.;
+
+/// A stream without callbacks for this long is not counted as running.
+const STALL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Length of the tone played by [`AudioManager::self_test`].
+const SELF_TEST_TONE: f64 = 0.1;
+
 pub struct AudioManager {
     backend: Box<dyn Backend>,
+    connected: Arc<AtomicBool>,
+    settings: AudioManagerSettings,
     latency: Arc<AtomicF64>,
+    stream: Arc<StreamInfo>,
     prod: HeapProducer<MixerCommand>,
+    resample_cache: ResampleCache,
+    overload_events: HeapConsumer<OverloadEvent>,
+    audio_events: HeapConsumer<AudioEvent>,
+    labeled: Vec<Labeled>,
+    /// Writers of the file recordings, by bus id or `None` for the output.
+    file_recordings: Vec<(Option<u64>, FileWriter)>,
+    rng: Rng,
 }
 
 impl AudioManager {
@@ -108,35 +336,435 @@ impl AudioManager {
         Self::new_box(Box::new(backend))
     }
 
-    pub fn new_box(mut backend: Box<dyn Backend>) -> Result<Self> {
-        let (prod, cons) = HeapRb::new(16).split();
+    pub fn with_settings(
+        backend: impl Backend + 'static,
+        settings: AudioManagerSettings,
+    ) -> Result<Self> {
+        Self::new_box_with_settings(Box::new(backend), settings)
+    }
+
+    pub fn new_box(backend: Box<dyn Backend>) -> Result<Self> {
+        Self::new_box_with_settings(backend, AudioManagerSettings::default())
+    }
+
+    pub fn new_box_with_settings(
+        mut backend: Box<dyn Backend>,
+        settings: AudioManagerSettings,
+    ) -> Result<Self> {
+        let settings = settings.validate().context("create audio manager")?;
+        let (mut prod, cons) = HeapRb::new(16).split();
+        let (events_prod, overload_events) = HeapRb::new(16).split();
+        prod.push(MixerCommand::SetOverloadEvents(events_prod))
+            .map_err(buffer_is_full)?;
+        let (events_prod, audio_events) = HeapRb::new(16).split();
+        prod.push(MixerCommand::SetAudioEvents(events_prod))
+            .map_err(buffer_is_full)?;
         let latency: Arc<AtomicF64> = Arc::default();
         let latency_rec = LatencyRecorder::new(Arc::clone(&latency));
+        let stream: Arc<StreamInfo> = Arc::default();
         backend.setup(BackendSetup {
             mixer_cons: cons,
             latency_rec,
+            stream: Arc::clone(&stream),
+            settings: settings.clone(),
         })?;
         backend.start()?;
         Ok(Self {
             backend,
+            connected: Arc::new(AtomicBool::new(true)),
             latency,
+            stream,
             prod,
+            resample_cache: ResampleCache::default(),
+            overload_events,
+            audio_events,
+            labeled: Vec::new(),
+            file_recordings: Vec::new(),
+            rng: Rng::new(settings.rng_seed),
+            settings,
         })
     }
 
+    /// Frames rendered by the mixer so far, the clock of
+    /// [`RenderContext::sample_time`]. Divide by [`AudioManager::sample_rate`]
+    /// for the seconds used by [`Sfx::schedule_batch`].
+    pub fn sample_time(&self) -> u64 {
+        self.stream.sample_time.load(Ordering::Relaxed)
+    }
+
+    /// Earliest [`sample time`](AudioManager::sample_time) any renderer may
+    /// produce audio on as of the latest block, `u64::MAX` if nothing plays
+    /// until a command is sent, or `None` while audio may be playing. A
+    /// backend can use it to suspend the output until then.
+    ///
+    /// Commands sent since the latest block are not accounted for.
+    pub fn next_activity(&self) -> Option<u64> {
+        match self.stream.next_activity.load(Ordering::Relaxed) {
+            0 => None,
+            at => Some(at),
+        }
+    }
+
+    /// Sample rate of the output stream, or 0 if it has not started yet.
+    pub fn sample_rate(&self) -> u32 {
+        self.stream.sample_rate.load(Ordering::Relaxed)
+    }
+
+    /// Whether the mixer currently renders at lower quality, see
+    /// [`AudioManagerSettings::overload`].
+    pub fn is_degraded(&self) -> bool {
+        self.stream.degraded.load(Ordering::Relaxed)
+    }
+
+    /// Overload transitions since the last call, oldest first. At most 16
+    /// are kept.
+    pub fn drain_overload_events(&mut self) -> Vec<OverloadEvent> {
+        self.overload_events.pop_iter().collect()
+    }
+
+    /// Changes of the output stream since the last call, oldest first, at
+    /// most 16 of them, followed by the labeled handles that finished since
+    /// the last call, in creation order.
+    pub fn drain_audio_events(&mut self) -> Vec<AudioEvent> {
+        let mut events: Vec<_> = self.audio_events.pop_iter().collect();
+        self.labeled.retain_mut(|it| match it.poll_finished() {
+            Some(finished) => {
+                if finished {
+                    events.push(AudioEvent::Finished {
+                        label: it.label.clone(),
+                        kind: it.kind,
+                    });
+                }
+                true
+            }
+            None => false,
+        });
+        events
+    }
+
+    /// Cheap snapshot of the output state, e.g. to tell apart a stopped
+    /// stream, a missing device and silence on a pause screen.
+    pub fn health(&self) -> Health {
+        let age = self.stream.last_callback_age();
+        Health {
+            stream_running: self.connected.load(Ordering::Relaxed)
+                && self.backend.is_running()
+                && age.is_some_and(|it| it < STALL_TIMEOUT),
+            last_callback_age: age.unwrap_or(Duration::MAX),
+            device_name: self.backend.device_name(),
+            underruns_recent: self.stream.underruns_recent.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Plays a quiet 100 ms tone with [`AudioManager::play_direct`] and
+    /// blocks until the output has consumed it, returning `false` if it has
+    /// not within half a second after it should have ended.
+    pub fn self_test(&mut self) -> Result<bool> {
+        let sample_rate = match self.sample_rate() {
+            0 => 48000,
+            rate => rate,
+        };
+        let frames = (0..(SELF_TEST_TONE * sample_rate as f64) as usize)
+            .map(|index| {
+                let phase = index as f64 / sample_rate as f64 * 440. * std::f64::consts::TAU;
+                Frame::from_mono(phase.sin() as f32 * 0.1)
+            })
+            .collect();
+        let handle = self
+            .play_direct(AudioClip::from_raw(frames, sample_rate), 1.)
+            .context("self test")?;
+        let deadline =
+            std::time::Instant::now() + Duration::from_secs_f64(SELF_TEST_TONE) + STALL_TIMEOUT;
+        while !handle.finished() {
+            if std::time::Instant::now() >= deadline {
+                handle.cancel();
+                return Ok(false);
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        Ok(true)
+    }
+
+    /// Channel count of the output stream.
+    pub fn channels(&self) -> u16 {
+        self.stream.channels.load(Ordering::Relaxed)
+    }
+
+    /// Routes the left and right channels of the mix to the given output
+    /// channels, e.g. `[1, 0]` swaps them. Passing `None` restores the
+    /// default layout. Ignored for mono output.
+    ///
+    /// The map is checked against the running stream. Set while no stream
+    /// runs, it is kept as is: whenever the output lacks a channel it names,
+    /// e.g. after a device change, the default layout plays instead.
+    pub fn set_channel_map(&mut self, map: Option<[usize; 2]>) -> Result<()> {
+        check_connected(&self.connected)?;
+        if let Some(map) = map.filter(|_| self.backend.is_running()) {
+            let channels = self.channels() as usize;
+            if channels > 1 {
+                if let Some(channel) = map.iter().find(|&&it| it >= channels) {
+                    bail!("channel {channel} is out of range for {channels} output channels");
+                }
+            }
+        }
+        self.prod
+            .push(MixerCommand::SetChannelMap(map))
+            .map_err(buffer_is_full)
+            .context("set channel map")
+    }
+
+    fn prepare_clip(&mut self, clip: AudioClip) -> AudioClip {
+        if self.settings.auto_resample {
+            let sample_rate = self.sample_rate();
+            self.resample_cache.get(&clip, sample_rate)
+        } else {
+            clip
+        }
+    }
+
     pub fn create_sfx(&mut self, clip: AudioClip, buffer_size: Option<usize>) -> Result<Sfx> {
-        let (sfx, sfx_renderer) = Sfx::new(clip, buffer_size);
+        check_connected(&self.connected)?;
+        let clip = self.prepare_clip(clip);
+        let (sfx, sfx_renderer) = Sfx::new(
+            clip,
+            buffer_size,
+            self.rng.fork(),
+            Arc::clone(&self.connected),
+            self.settings
+                .auto_resample
+                .then(|| Arc::clone(&self.stream)),
+        );
         self.add_renderer(sfx_renderer)?;
         Ok(sfx)
     }
 
+    /// Like [`AudioManager::create_sfx`], listing the handle under `label`
+    /// in [`AudioManager::debug_snapshot`] while it is alive and reporting
+    /// [`AudioEvent::Finished`] when it finishes. Labels need not be unique.
+    pub fn create_sfx_labeled(
+        &mut self,
+        clip: AudioClip,
+        buffer_size: Option<usize>,
+        label: impl Into<String>,
+    ) -> Result<Sfx> {
+        let sfx = self.create_sfx(clip, buffer_size)?;
+        self.labeled.push(Labeled {
+            label: label.into(),
+            kind: RendererKind::Sfx,
+            probe: sfx.probe(),
+            finished: true,
+        });
+        Ok(sfx)
+    }
+
+    /// Like [`AudioManager::create_music`], listing the handle under `label`
+    /// in [`AudioManager::debug_snapshot`] while it is alive.
+    pub fn create_music_labeled(
+        &mut self,
+        clip: AudioClip,
+        settings: MusicParams,
+        label: impl Into<String>,
+    ) -> Result<Music> {
+        let music = self.create_music(clip, settings)?;
+        self.labeled.push(Labeled {
+            label: label.into(),
+            kind: RendererKind::Music,
+            probe: music.probe(),
+            finished: false,
+        });
+        Ok(music)
+    }
+
+    /// State of every labeled handle still alive, in creation order.
+    pub fn debug_snapshot(&mut self) -> Vec<RendererSnapshot> {
+        let mut snapshot = Vec::with_capacity(self.labeled.len());
+        self.labeled.retain(|it| match it.snapshot() {
+            Some(it) => {
+                snapshot.push(it);
+                true
+            }
+            None => false,
+        });
+        snapshot
+    }
+
     pub fn create_music(&mut self, clip: AudioClip, settings: MusicParams) -> Result<Music> {
-        let (music, music_renderer) = Music::new(clip, settings);
+        check_connected(&self.connected)?;
+        let settings = settings.validate().context("create music")?;
+        settings
+            .check_loop_region(clip.length())
+            .context("create music")?;
+        let clip = self.prepare_clip(clip);
+        let (music, music_renderer) = Music::new(
+            clip,
+            settings,
+            Arc::clone(&self.connected),
+            Arc::clone(&self.latency),
+        );
         self.add_renderer(music_renderer)?;
         Ok(music)
     }
 
+    /// Lowers `target` by `depth_db` decibels while the play head of
+    /// `trigger` is between `start` and `end` seconds of its clip, ramping
+    /// over `ramp` seconds at both ends, e.g. to duck music under a dialogue
+    /// track.
+    ///
+    /// The audio thread follows the trigger frame by frame, so the duck
+    /// starts and ends on time whatever the game thread does, and seeking
+    /// the trigger into or out of the region applies or releases it. Tracks
+    /// render in the order they were created, so a target created before its
+    /// trigger only sees a seek, pause or rate change of the trigger a block
+    /// after it is applied. At most 8 ducks can be registered on a track; see
+    /// [`Music::clear_ducks`].
+    pub fn schedule_duck(
+        &mut self,
+        trigger: &Music,
+        target: &Music,
+        start: f64,
+        end: f64,
+        depth_db: f32,
+        ramp: f64,
+    ) -> Result<()> {
+        check_connected(&self.connected)?;
+        let start = validate("start", start, 0., f64::MAX).context("schedule duck")?;
+        let end = validate("end", end, 0., f64::MAX).context("schedule duck")?;
+        let depth_db = validate("depth", depth_db as f64, 0., f64::MAX).context("schedule duck")?;
+        let ramp = validate("ramp", ramp, 0., f64::MAX).context("schedule duck")?;
+        if end <= start {
+            bail!("duck end {end} must be after its start {start}");
+        }
+        let gain = db_to_amplitude(-depth_db as f32);
+        target
+            .add_duck(trigger, start, end, gain, ramp)
+            .context("schedule duck")
+    }
+
+    /// Creates a renderer playing `clips` as stems locked to a single play
+    /// head. The clips must share a sample rate; shorter stems are silent
+    /// past their end.
+    ///
+    /// Of `settings`, only the amplifier, playback rate, loop mix time and
+    /// command buffer size are used.
+    pub fn create_synced_music(
+        &mut self,
+        clips: Vec<AudioClip>,
+        settings: MusicParams,
+    ) -> Result<SyncedMusic> {
+        check_connected(&self.connected)?;
+        let settings = settings.validate().context("create synced music")?;
+        let clips = clips.into_iter().map(|it| self.prepare_clip(it)).collect();
+        let (music, renderer) = SyncedMusic::new(clips, settings, Arc::clone(&self.connected))
+            .context("create synced music")?;
+        self.add_renderer(renderer)?;
+        Ok(music)
+    }
+
+    /// Plays `clip` once straight into the master output, bypassing buses,
+    /// the master fade and the master filter. It is still limited. Keeps
+    /// playing while sound effects are paused with
+    /// [`AudioManager::pause_sfx`] and while the host holds the output, see
+    /// [`AudioManager::host_paused`].
+    ///
+    /// Meant for previews such as a volume test sound that must be heard
+    /// unaltered whatever the state of the rest of the mix.
+    pub fn play_direct(&mut self, clip: AudioClip, amplifier: f32) -> Result<DirectHandle> {
+        check_connected(&self.connected)?;
+        let amplifier = validate("amplifier", amplifier as f64, f64::MIN, f64::MAX)
+            .context("play direct")? as f32;
+        let clip = self.prepare_clip(clip);
+        let (handle, voice) = DirectHandle::new(clip, amplifier);
+        self.prod
+            .push(MixerCommand::PlayDirect(voice))
+            .map_err(buffer_is_full)
+            .context("play direct")?;
+        Ok(handle)
+    }
+
+    /// Starts playing `clip` as a click at `bpm` beats per minute, timed on
+    /// the mixer clock, for the player to tap along with.
+    ///
+    /// Offsets are measured to the nearest click, so they must stay within
+    /// half a beat: 120 bpm allows up to 250 ms.
+    pub fn start_calibration(&mut self, clip: AudioClip, bpm: f64) -> Result<Calibration> {
+        check_connected(&self.connected)?;
+        let bpm = validate("bpm", bpm, f64::MIN_POSITIVE, f64::MAX).context("start calibration")?;
+        let clip = self.prepare_clip(clip);
+        let (calibration, renderer) = Calibration::new(clip, bpm);
+        self.add_renderer(renderer)?;
+        Ok(calibration)
+    }
+
+    /// Creates a group controlling `members` together, see [`GroupHandle`].
+    pub fn create_group(&mut self, members: &[&Music]) -> Result<GroupHandle> {
+        check_connected(&self.connected)?;
+        let (group, release) = GroupHandle::new(members, Arc::clone(&self.connected));
+        self.prod
+            .push(MixerCommand::AddGroup(release))
+            .map_err(buffer_is_full)
+            .context("create group")?;
+        Ok(group)
+    }
+
+    /// Creates a bus whose renderers are mixed, filtered and attenuated
+    /// together before reaching the master output.
+    pub fn create_bus(&mut self) -> Result<BusHandle> {
+        check_connected(&self.connected)?;
+        let (bus, bus_renderer) = BusHandle::new(
+            Arc::clone(&self.connected),
+            mixer::max_block_frames(&self.settings),
+        );
+        self.add_renderer(bus_renderer)?;
+        Ok(bus)
+    }
+
+    /// Like [`AudioManager::create_sfx`], but plays through `bus`.
+    pub fn create_sfx_in(
+        &mut self,
+        bus: &mut BusHandle,
+        clip: AudioClip,
+        buffer_size: Option<usize>,
+    ) -> Result<Sfx> {
+        check_connected(&self.connected)?;
+        let clip = self.prepare_clip(clip);
+        let (sfx, sfx_renderer) = Sfx::new(
+            clip,
+            buffer_size,
+            self.rng.fork(),
+            Arc::clone(&self.connected),
+            self.settings
+                .auto_resample
+                .then(|| Arc::clone(&self.stream)),
+        );
+        bus.add_renderer(sfx_renderer)?;
+        Ok(sfx)
+    }
+
+    /// Like [`AudioManager::create_music`], but plays through `bus`.
+    pub fn create_music_in(
+        &mut self,
+        bus: &mut BusHandle,
+        clip: AudioClip,
+        settings: MusicParams,
+    ) -> Result<Music> {
+        check_connected(&self.connected)?;
+        let settings = settings.validate().context("create music")?;
+        settings
+            .check_loop_region(clip.length())
+            .context("create music")?;
+        let clip = self.prepare_clip(clip);
+        let (music, music_renderer) = Music::new(
+            clip,
+            settings,
+            Arc::clone(&self.connected),
+            Arc::clone(&self.latency),
+        );
+        bus.add_renderer(music_renderer)?;
+        Ok(music)
+    }
+
     pub fn add_renderer(&mut self, renderer: impl Renderer + 'static) -> Result<()> {
+        check_connected(&self.connected)?;
         self.prod
             .push(MixerCommand::AddRenderer(Box::new(renderer)))
             .map_err(buffer_is_full)
@@ -144,6 +772,215 @@ impl AudioManager {
         Ok(())
     }
 
+    /// Fades out every playing sound effect over `fade` seconds and cancels
+    /// the delayed ones.
+    pub fn stop_all_sfx(&mut self, fade: f32) -> Result<()> {
+        check_connected(&self.connected)?;
+        let fade = validate("fade", fade as f64, 0., f64::MAX).context("stop all sfx")? as f32;
+        self.prod
+            .push(MixerCommand::StopAllSfx(fade))
+            .map_err(buffer_is_full)
+            .context("stop all sfx")
+    }
+
+    /// Holds every sound effect instance, including the delayed ones and
+    /// those started meanwhile, until [`AudioManager::resume_sfx`]. Music
+    /// and [`AudioManager::play_direct`] keep playing. Plays of a batch
+    /// falling due meanwhile are skipped.
+    pub fn pause_sfx(&mut self) -> Result<()> {
+        check_connected(&self.connected)?;
+        self.prod
+            .push(MixerCommand::SetSfxPaused(true))
+            .map_err(buffer_is_full)
+            .context("pause sfx")
+    }
+
+    pub fn resume_sfx(&mut self) -> Result<()> {
+        check_connected(&self.connected)?;
+        self.prod
+            .push(MixerCommand::SetSfxPaused(false))
+            .map_err(buffer_is_full)
+            .context("resume sfx")
+    }
+
+    /// Ramps the master volume, applied after every renderer and before the
+    /// limiter, to `volume` over `ramp` seconds.
+    pub fn set_master_volume(&mut self, volume: f32, ramp: f64) -> Result<()> {
+        check_connected(&self.connected)?;
+        let volume =
+            validate("volume", volume as f64, 0., f64::MAX).context("set master volume")? as f32;
+        let ramp = validate("ramp", ramp, 0., f64::MAX).context("set master volume")?;
+        self.prod
+            .push(MixerCommand::SetMasterVolume(volume, ramp))
+            .map_err(buffer_is_full)
+            .context("set master volume")
+    }
+
+    /// Like [`AudioManager::set_master_volume`], in decibels, see
+    /// [`db_to_amplitude`].
+    pub fn set_master_volume_db(&mut self, db: f32, ramp: f64) -> Result<()> {
+        let db = validate("volume", db as f64, f64::MIN, f64::MAX).context("set master volume")?;
+        self.set_master_volume(db_to_amplitude(db as f32), ramp)
+    }
+
+    /// Starts copying the final output into a ring buffer of `capacity`
+    /// samples, replacing any previous recorder.
+    pub fn record_output(&mut self, capacity: usize) -> Result<OutputRecorder> {
+        check_connected(&self.connected)?;
+        let (recorder, sink) = OutputRecorder::new(capacity, self.channels(), self.sample_rate());
+        self.prod
+            .push(MixerCommand::SetRecorder(Some(sink)))
+            .map_err(buffer_is_full)
+            .context("record output")?;
+        self.replace_file_recording(None, None);
+        Ok(recorder)
+    }
+
+    /// Like [`AudioManager::record_output`], writing the output to a 16-bit
+    /// WAV file at `path` instead, see [`FileRecording`].
+    pub fn start_recording(&mut self, path: impl AsRef<Path>) -> Result<FileRecording> {
+        check_connected(&self.connected)?;
+        let (channels, sample_rate) = (self.channels(), self.sample_rate());
+        let (recording, writer) = FileWriter::start(path.as_ref(), channels, sample_rate, |sink| {
+            self.prod
+                .push(MixerCommand::SetRecorder(Some(sink)))
+                .map_err(buffer_is_full)
+        })
+        .context("start recording")?;
+        self.replace_file_recording(None, Some(writer));
+        Ok(recording)
+    }
+
+    /// Stops recording the output, completing its file if it is recorded
+    /// to one.
+    pub fn stop_recording(&mut self) -> Result<()> {
+        self.prod
+            .push(MixerCommand::SetRecorder(None))
+            .map_err(buffer_is_full)
+            .context("stop recording")?;
+        self.finish_file_recording(None)
+    }
+
+    /// Like [`AudioManager::record_output`], copying only what `bus`
+    /// contributes to the output, after its gain and filter and before the
+    /// master gain. Replaces any previous recorder of the bus; recording the
+    /// output and other buses is unaffected.
+    pub fn record_bus(&mut self, bus: &mut BusHandle, capacity: usize) -> Result<OutputRecorder> {
+        let (recorder, sink) = OutputRecorder::new(capacity, self.channels(), self.sample_rate());
+        bus.set_recorder(Some(sink)).context("record bus")?;
+        self.replace_file_recording(Some(bus.id()), None);
+        Ok(recorder)
+    }
+
+    /// Like [`AudioManager::record_bus`], writing to a file like
+    /// [`AudioManager::start_recording`].
+    pub fn start_recording_bus(
+        &mut self,
+        bus: &mut BusHandle,
+        path: impl AsRef<Path>,
+    ) -> Result<FileRecording> {
+        check_connected(&self.connected)?;
+        let (recording, writer) =
+            FileWriter::start(path.as_ref(), self.channels(), self.sample_rate(), |sink| {
+                bus.set_recorder(Some(sink))
+            })
+            .context("start recording bus")?;
+        self.replace_file_recording(Some(bus.id()), Some(writer));
+        Ok(recording)
+    }
+
+    /// Stops recording `bus`, completing its file if it is recorded to one.
+    pub fn stop_recording_bus(&mut self, bus: &mut BusHandle) -> Result<()> {
+        bus.set_recorder(None).context("stop recording bus")?;
+        self.finish_file_recording(Some(bus.id()))
+    }
+
+    /// Replaces the file recording of a bus, or of the output for `None`,
+    /// once its recorder has been replaced.
+    fn replace_file_recording(&mut self, target: Option<u64>, writer: Option<FileWriter>) {
+        if let Err(err) = self.finish_file_recording(target) {
+            eprintln!("failed to finish recording: {err:?}");
+        }
+        if let Some(writer) = writer {
+            self.file_recordings.push((target, writer));
+        }
+    }
+
+    fn finish_file_recording(&mut self, target: Option<u64>) -> Result<()> {
+        match self.file_recordings.iter().position(|it| it.0 == target) {
+            Some(index) => self.file_recordings.swap_remove(index).1.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Calls `tap` with every block of the final output, after the master
+    /// gain, replacing any previous tap. Mono output is passed with both
+    /// channels equal.
+    ///
+    /// The tap runs on the audio thread: it must not block, allocate or
+    /// otherwise take long, or the output will glitch.
+    pub fn set_output_tap(&mut self, tap: impl FnMut(&[Frame]) + Send + 'static) -> Result<()> {
+        check_connected(&self.connected)?;
+        self.prod
+            .push(MixerCommand::SetOutputTap(Some(OutputTap::new(
+                tap,
+                mixer::max_block_frames(&self.settings),
+            ))))
+            .map_err(buffer_is_full)
+            .context("set output tap")
+    }
+
+    pub fn clear_output_tap(&mut self) -> Result<()> {
+        self.prod
+            .push(MixerCommand::SetOutputTap(None))
+            .map_err(buffer_is_full)
+            .context("clear output tap")
+    }
+
+    /// Filters the summed output of every source, e.g. to muffle the game
+    /// behind a menu. Passing `None` removes the filter.
+    pub fn set_master_filter(&mut self, filter: Option<FilterSettings>) -> Result<()> {
+        check_connected(&self.connected)?;
+        if let Some(filter) = &filter {
+            validate("cutoff", filter.cutoff as f64, 0., f64::MAX).context("set master filter")?;
+            validate("q", filter.q as f64, 0., f64::MAX).context("set master filter")?;
+        }
+        self.prod
+            .push(MixerCommand::SetMasterFilter(filter))
+            .map_err(buffer_is_full)
+            .context("set master filter")
+    }
+
+    /// Makes the functions of [`android_glue`] forward to this manager,
+    /// replacing any manager that installed them before. While another app
+    /// may play over this one, the output is lowered by `duck_db` decibels.
+    pub fn install_android_hooks(&mut self, duck_db: f32) -> Result<()> {
+        let duck_db = validate("duck depth", duck_db as f64, 0., f64::MAX)
+            .context("install android hooks")?;
+        android_glue::install(&self.stream, db_to_amplitude(-duck_db as f32));
+        Ok(())
+    }
+
+    /// Whether the output is held by the host, i.e. after losing the audio
+    /// focus or the output becoming noisy. Renderers do not advance while
+    /// it is.
+    pub fn host_paused(&self) -> bool {
+        self.stream.host.paused()
+    }
+
+    /// Releases the hold of the output becoming noisy, see
+    /// [`android_glue`], e.g. once the player resumes the game.
+    pub fn resume_after_noisy(&mut self) {
+        self.stream.host.noisy.store(false, Ordering::Relaxed);
+    }
+
+    /// Burst size reported through [`android_glue`], e.g. to use as [`AudioManagerSettings::quantum`] on
+    /// [`AudioManager::reconfigure`].
+    pub fn frames_per_burst(&self) -> Option<u32> {
+        let frames = self.stream.host.frames_per_burst.load(Ordering::Relaxed);
+        (frames != 0).then_some(frames)
+    }
+
     pub fn estimate_latency(&self) -> f64 {
         self.latency.load(Ordering::SeqCst)
     }
@@ -155,6 +992,7 @@ impl AudioManager {
 
     #[inline(always)]
     pub fn start(&mut self) -> Result<()> {
+        check_connected(&self.connected)?;
         self.backend.start()
     }
 
@@ -165,4 +1003,726 @@ impl AudioManager {
             Ok(())
         }
     }
+
+    /// Applies new settings without recreating any handle: the output
+    /// stream is rebuilt and renderers carry on from their positions at the
+    /// new sample rate. Returns the achieved sample rate and channel count.
+    ///
+    /// Clips created before keep their conversion, only new clips are
+    /// resampled to the new rate. If the stream can not be rebuilt, the
+    /// previous one keeps running with the previous settings.
+    pub fn reconfigure(&mut self, settings: AudioManagerSettings) -> Result<(u32, u16)> {
+        check_connected(&self.connected)?;
+        let settings = settings.validate().context("reconfigure")?;
+        self.backend
+            .reconfigure(&settings)
+            .context("reconfigure output stream")?;
+        self.prod
+            .push(MixerCommand::Configure(Box::new((
+                settings.clone(),
+                settings.limiter.clone().map(Limiter::new),
+            ))))
+            .map_err(buffer_is_full)
+            .context("reconfigure mixer")?;
+        self.settings = settings;
+        Ok((self.sample_rate(), self.channels()))
+    }
+
+    /// Stops the output stream, optionally fading the master output out over
+    /// `fade` seconds first, at most 5 seconds.
+    ///
+    /// Every handle created by this manager is disconnected and further
+    /// commands on them return [`Error::Disconnected`]. Calling this again is
+    /// a no-op.
+    pub fn shutdown(&mut self, fade: Option<f32>) -> Result<()> {
+        let fade = fade
+            .map(|it| validate("fade", it as f64, 0., MAX_SHUTDOWN_FADE))
+            .transpose()
+            .context("shut down")?;
+        if !self.connected.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        if let Some(fade) = fade.filter(|it| *it > 0.) {
+            if self
+                .prod
+                .push(MixerCommand::FadeOutMaster(fade as f32))
+                .is_ok()
+            {
+                std::thread::sleep(Duration::from_secs_f64(fade));
+            }
+        }
+        let stopped = self.backend.stop();
+        for (_, writer) in self.file_recordings.drain(..) {
+            if let Err(err) = writer.finish() {
+                eprintln!("failed to finish recording: {err:?}");
+            }
+        }
+        stopped
+    }
+}
+
+impl Drop for AudioManager {
+    fn drop(&mut self) {
+        android_glue::uninstall(&self.stream);
+        if let Err(err) = self.shutdown(Some(DROP_FADE)) {
+            eprintln!("failed to shut down audio: {err:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::offline::{OfflineBackend, OfflineSettings},
+        test_util::{
+            constant, manager, manager_with, render, settings, sine, BLOCK_SIZE, SAMPLE_RATE,
+        },
+    };
+
+    fn disconnected(result: Result<impl Sized>) -> bool {
+        result
+            .err()
+            .and_then(|it| it.downcast_ref::<Error>().copied())
+            == Some(Error::Disconnected)
+    }
+
+    #[test]
+    fn handles_disconnect_on_shutdown() {
+        let (mut manager, _out) = manager();
+        let clip = constant(0.5, SAMPLE_RATE as usize);
+        let music = manager
+            .create_music(clip.clone(), MusicParams::default())
+            .unwrap();
+        let mut sfx = manager.create_sfx(clip.clone(), None).unwrap();
+        let instance = sfx.play(PlaySfxParams::default()).unwrap();
+        let mut bus = manager.create_bus().unwrap();
+        let mut group = manager.create_group(&[&music]).unwrap();
+        manager.shutdown(Some(0.001)).unwrap();
+
+        assert!(disconnected(music.play()));
+        assert!(disconnected(music.seek_to(0.5)));
+        assert!(disconnected(music.fade_out(0.1)));
+        assert!(disconnected(sfx.play(PlaySfxParams::default())));
+        assert!(disconnected(sfx.stop(instance)));
+        assert!(disconnected(bus.set_gain(0.5, 0.)));
+        assert!(disconnected(group.pause()));
+        assert!(disconnected(manager.create_sfx(clip.clone(), None)));
+        assert!(disconnected(
+            manager.create_music(clip, MusicParams::default())
+        ));
+        assert!(disconnected(manager.set_master_volume(0.5, 0.)));
+        // Shutting down again is a no-op.
+        manager.shutdown(None).unwrap();
+    }
+
+    #[test]
+    fn shutdown_rejects_invalid_fades() {
+        let (mut manager, _out) = manager();
+        let music = manager
+            .create_music(constant(0.5, 64), MusicParams::default())
+            .unwrap();
+        for fade in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let err = manager.shutdown(Some(fade)).unwrap_err();
+            assert_eq!(err.downcast_ref::<Error>(), Some(&Error::NonFinite("fade")));
+        }
+        // Still connected after a rejected call.
+        music.play().unwrap();
+        manager.shutdown(Some(-1.)).unwrap();
+        assert!(disconnected(music.play()));
+    }
+
+    #[test]
+    fn swap_map_exchanges_left_and_right() {
+        let (mut manager, mut out) = manager();
+        let clip = AudioClip::from_raw(vec![Frame(0.25, 0.5); SAMPLE_RATE as usize], SAMPLE_RATE);
+        let music = manager.create_music(clip, MusicParams::default()).unwrap();
+        music.play().unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        assert_eq!(&data[data.len() - 2..], [0.25, 0.5]);
+        manager.set_channel_map(Some([1, 0])).unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        assert_eq!(&data[data.len() - 2..], [0.5, 0.25]);
+        assert!(manager.set_channel_map(Some([0, 2])).is_err());
+        manager.set_channel_map(None).unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        assert_eq!(&data[data.len() - 2..], [0.25, 0.5]);
+    }
+
+    /// An offline backend reporting no running stream until started again.
+    struct Stopped(OfflineBackend, bool);
+    impl Backend for Stopped {
+        fn setup(&mut self, setup: BackendSetup) -> Result<()> {
+            self.0.setup(setup)
+        }
+
+        fn start(&mut self) -> Result<()> {
+            self.1 = true;
+            self.0.start()
+        }
+
+        fn stop(&mut self) -> Result<()> {
+            self.1 = false;
+            self.0.stop()
+        }
+
+        fn consume_broken(&self) -> bool {
+            false
+        }
+
+        fn is_running(&self) -> bool {
+            self.1
+        }
+    }
+
+    #[test]
+    fn channel_map_set_while_stopped_applies_later() {
+        let (backend, mut out) = OfflineBackend::new(OfflineSettings {
+            block_size: BLOCK_SIZE,
+            ..OfflineSettings::default()
+        });
+        let mut manager = AudioManager::with_settings(Stopped(backend, false), settings()).unwrap();
+        manager.backend.stop().unwrap();
+        let music = manager
+            .create_music(constant(0.5, SAMPLE_RATE as usize), MusicParams::default())
+            .unwrap();
+        music.play().unwrap();
+        // Kept though the stream has two channels for now, which play as
+        // usual meanwhile.
+        manager.set_channel_map(Some([2, 3])).unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        assert_eq!(&data[data.len() - 2..], [0.5, 0.5]);
+
+        out.set_channels(4).unwrap();
+        let mut data = vec![0.; BLOCK_SIZE * 4];
+        out.render(&mut data);
+        assert_eq!(&data[data.len() - 4..], [0., 0., 0.5, 0.5]);
+    }
+
+    #[test]
+    fn snapshot_finds_labeled_tracks_by_name() {
+        let (mut manager, mut out) = manager();
+        let clip = constant(0.1, SAMPLE_RATE as usize);
+        let mut tracks = Vec::new();
+        for label in ["menu", "battle", "ambience"] {
+            tracks.push(
+                manager
+                    .create_music_labeled(clip.clone(), MusicParams::default(), label)
+                    .unwrap(),
+            );
+        }
+        tracks[1].play().unwrap();
+        render(&mut out, BLOCK_SIZE);
+        let snapshot = manager.debug_snapshot();
+        assert_eq!(snapshot.len(), 3);
+        let find = |label: &str| snapshot.iter().find(|it| it.label == label).unwrap();
+        assert!(["menu", "ambience"]
+            .into_iter()
+            .all(|it| find(it).kind == RendererKind::Music && !find(it).playing));
+        assert!(find("battle").playing);
+
+        drop(tracks.remove(0));
+        let labels: Vec<_> = manager
+            .debug_snapshot()
+            .into_iter()
+            .map(|it| it.label)
+            .collect();
+        assert_eq!(labels, ["battle", "ambience"]);
+    }
+
+    #[test]
+    fn finished_events_carry_labels() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music_labeled(constant(0.1, BLOCK_SIZE), MusicParams::default(), "jingle")
+            .unwrap();
+        let mut sfx = manager
+            .create_sfx_labeled(constant(0.1, BLOCK_SIZE * 4), None, "click")
+            .unwrap();
+        music.play().unwrap();
+        sfx.play(PlaySfxParams::default()).unwrap();
+        assert_eq!(manager.drain_audio_events(), []);
+
+        render(&mut out, BLOCK_SIZE * 2);
+        assert_eq!(
+            manager.drain_audio_events(),
+            [AudioEvent::Finished {
+                label: "jingle".into(),
+                kind: RendererKind::Music,
+            }]
+        );
+        render(&mut out, BLOCK_SIZE * 4);
+        assert_eq!(
+            manager.drain_audio_events(),
+            [AudioEvent::Finished {
+                label: "click".into(),
+                kind: RendererKind::Sfx,
+            }]
+        );
+        render(&mut out, BLOCK_SIZE * 2);
+        assert_eq!(manager.drain_audio_events(), []);
+    }
+
+    #[test]
+    fn paused_sfx_hold_while_direct_voices_play() {
+        let (mut manager, mut out) = manager();
+        let mut sfx = manager
+            .create_sfx(constant(0.25, BLOCK_SIZE * 2), None)
+            .unwrap();
+        let music = manager
+            .create_music(
+                constant(0.125, SAMPLE_RATE as usize),
+                MusicParams::default(),
+            )
+            .unwrap();
+        music.play().unwrap();
+        manager.pause_sfx().unwrap();
+        let instance = sfx.play(PlaySfxParams::default()).unwrap();
+        let direct = manager.play_direct(constant(0.5, BLOCK_SIZE), 1.).unwrap();
+
+        let data = render(&mut out, BLOCK_SIZE * 2);
+        assert!(data[..BLOCK_SIZE * 2].iter().all(|it| *it == 0.625));
+        assert!(data[BLOCK_SIZE * 2..].iter().all(|it| *it == 0.125));
+        assert!(direct.finished() && sfx.is_playing(instance));
+
+        manager.resume_sfx().unwrap();
+        let data = render(&mut out, BLOCK_SIZE * 3);
+        assert!(data[..BLOCK_SIZE * 4].iter().all(|it| *it == 0.375));
+        assert!(data[BLOCK_SIZE * 4..].iter().all(|it| *it == 0.125));
+        assert!(!sfx.is_playing(instance));
+    }
+
+    #[test]
+    fn direct_voices_play_while_the_host_holds_the_output() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(
+                constant(0.125, SAMPLE_RATE as usize),
+                MusicParams::default(),
+            )
+            .unwrap();
+        music.play().unwrap();
+        render(&mut out, BLOCK_SIZE);
+        manager
+            .stream
+            .host
+            .focus_lost
+            .store(true, Ordering::Relaxed);
+        let position = music.position();
+        manager
+            .play_direct(constant(0.5, BLOCK_SIZE * 2), 1.)
+            .unwrap();
+        let data = render(&mut out, BLOCK_SIZE * 3);
+        assert!(data[..BLOCK_SIZE * 4].iter().all(|it| *it == 0.5));
+        assert!(data[BLOCK_SIZE * 4..].iter().all(|it| *it == 0.));
+        assert_eq!(music.position(), position);
+    }
+
+    #[test]
+    fn mono_downmix_keeps_the_stereo_level() {
+        fn rms(data: &[f32]) -> f32 {
+            (data.iter().map(|it| it * it).sum::<f32>() / data.len() as f32).sqrt()
+        }
+        // Music and sfx of the same clip, rendered to `channels` channels.
+        let rendered = |channels: u16, mono_compensation: bool| {
+            let (mut manager, mut out) = manager_with(
+                AudioManagerSettings {
+                    mono_compensation,
+                    ..settings()
+                },
+                OfflineSettings {
+                    sample_rate: SAMPLE_RATE,
+                    channels,
+                    block_size: BLOCK_SIZE,
+                },
+            );
+            let mut data = vec![0.; BLOCK_SIZE * 8 * channels as usize];
+            // Ends with the first render.
+            let clip = sine(440., 0.25, (BLOCK_SIZE * 8) as f64 / SAMPLE_RATE as f64);
+            let music = manager
+                .create_music(clip.clone(), MusicParams::default())
+                .unwrap();
+            music.play().unwrap();
+            out.render(&mut data);
+            let music_level = rms(&data);
+            let mut sfx = manager.create_sfx(clip, None).unwrap();
+            sfx.play(PlaySfxParams::default()).unwrap();
+            out.render(&mut data);
+            (music_level, rms(&data))
+        };
+        let stereo = rendered(2, false);
+        let mono = rendered(1, false);
+        assert!((mono.0 - stereo.0).abs() < 1e-4 && (mono.1 - stereo.1).abs() < 1e-4);
+        let compensated = rendered(1, true);
+        let boost = std::f32::consts::SQRT_2;
+        assert!((compensated.0 - stereo.0 * boost).abs() < 1e-4);
+        assert!((compensated.1 - stereo.1 * boost).abs() < 1e-4);
+    }
+
+    #[test]
+    fn seeded_sessions_render_the_same_output() {
+        // Ten seconds of music with a humanized sound effect every quarter.
+        let session = |rng_seed: u64| {
+            let (mut manager, mut out) = manager_with(
+                AudioManagerSettings {
+                    rng_seed,
+                    ..settings()
+                },
+                OfflineSettings {
+                    sample_rate: SAMPLE_RATE,
+                    channels: 2,
+                    block_size: 480,
+                },
+            );
+            let music = manager
+                .create_music(
+                    sine(220., 0.3, 4.),
+                    MusicParams {
+                        loop_mix_time: 0.5,
+                        ..MusicParams::default()
+                    },
+                )
+                .unwrap();
+            let mut sfx = manager.create_sfx(sine(1000., 0.2, 0.1), None).unwrap();
+            let params = PlaySfxParams {
+                humanize: Humanize {
+                    amp_jitter: 0.5,
+                    time_jitter: 0.05,
+                },
+                ..PlaySfxParams::default()
+            };
+            music.play().unwrap();
+            let mut output = Vec::new();
+            for _ in 0..40 {
+                sfx.play(params.clone()).unwrap();
+                let mut data = vec![0.; SAMPLE_RATE as usize / 4 * 2];
+                out.render(&mut data);
+                output.extend(data.into_iter().map(f32::to_bits));
+            }
+            output
+        };
+        assert!(session(7) == session(7));
+        assert!(session(7) != session(8));
+    }
+
+    #[test]
+    fn frame_utilities() {
+        let frame = Frame::from((0.5, -0.25));
+        assert_eq!((frame.left(), frame.right()), (0.5, -0.25));
+        assert_eq!(<(f32, f32)>::from(frame), (0.5, -0.25));
+        assert_eq!(Frame::from_mono(0.5), Frame(0.5, 0.5));
+        assert_eq!(frame.gain(2.), Frame(1., -0.5));
+        assert_eq!(frame + frame - Frame(1., 0.), Frame(0., -0.5));
+        assert_eq!(-frame, Frame(-0.5, 0.25));
+        assert_eq!(Frame::lerp(frame, Frame(1., 0.25), 0.5), Frame(0.75, 0.));
+        assert_eq!(Frame(1.5, -2.).clamp(), Frame(1., -1.));
+        assert_eq!(frame.avg(), 0.125);
+        assert_eq!(Frame(1., 1.).avg(), 1.);
+        assert!(!Frame(f32::NAN, 0.).is_finite());
+
+        let frames = [Frame(0.5, -0.5), Frame(2., -2.), Frame(0., 1. / 32768.)];
+        let mut data = [0; 8];
+        Frame::write_interleaved(&frames, &mut data);
+        assert_eq!(data, [16384, -16384, i16::MAX, i16::MIN, 0, 1, 0, 0]);
+        let mut read = [Frame::default(); 4];
+        Frame::read_interleaved(&data[..6], &mut read);
+        assert_eq!(read[0], frames[0]);
+        assert_eq!(read[1], Frame(i16::MAX as f32 / 32768., -1.));
+        assert_eq!(read[2], frames[2]);
+        assert_eq!(read[3], Frame::default());
+    }
+
+    #[test]
+    fn health_follows_the_callbacks() {
+        let (manager, mut out) = manager();
+        let health = manager.health();
+        assert!(!health.stream_running);
+        assert_eq!(health.last_callback_age, Duration::MAX);
+        assert_eq!(health.device_name, None);
+
+        render(&mut out, BLOCK_SIZE);
+        let health = manager.health();
+        assert!(health.stream_running);
+        assert!(health.last_callback_age < STALL_TIMEOUT);
+        assert_eq!(health.underruns_recent, 0);
+    }
+
+    #[test]
+    fn self_test_reports_whether_the_tone_was_consumed() {
+        let (mut manager, out) = manager();
+        assert!(!manager.self_test().unwrap());
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let render_thread = std::thread::spawn({
+            let stop = Arc::clone(&stop);
+            let mut out = out;
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    render(&mut out, BLOCK_SIZE);
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            }
+        });
+        assert!(manager.self_test().unwrap());
+        stop.store(true, Ordering::Relaxed);
+        render_thread.join().unwrap();
+    }
+
+    #[test]
+    fn next_activity_predicts_the_first_audible_frame() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(constant(0.5, SAMPLE_RATE as usize), MusicParams::default())
+            .unwrap();
+        let mut sfx = manager.create_sfx(constant(0.5, BLOCK_SIZE), None).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert_eq!(manager.next_activity(), Some(u64::MAX));
+
+        sfx.play(PlaySfxParams {
+            delay: 0.5,
+            ..PlaySfxParams::default()
+        })
+        .unwrap();
+        assert_eq!(out.next_activity(), None);
+        assert_eq!(manager.next_activity(), Some(u64::MAX));
+        render(&mut out, BLOCK_SIZE);
+        let at = manager.next_activity().unwrap();
+        assert_eq!(out.next_activity(), Some(at));
+        assert_eq!(at, BLOCK_SIZE as u64 + SAMPLE_RATE as u64 / 2);
+        // Silent up to that very frame.
+        let silent = (at - manager.sample_time()) as usize;
+        let data = render(&mut out, silent + BLOCK_SIZE);
+        assert_eq!(data.iter().position(|it| *it != 0.), Some(silent * 2));
+        assert_eq!(manager.next_activity(), Some(u64::MAX));
+        music.play().unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert_eq!(manager.next_activity(), None);
+        music.pause().unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert_eq!(manager.next_activity(), Some(u64::MAX));
+    }
+
+    #[test]
+    fn renderers_carry_on_across_layout_changes() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(sine(440., 0.5, 1.), MusicParams::default())
+            .unwrap();
+        music.play().unwrap();
+        let mut recorder = manager.record_output(BLOCK_SIZE * 16).unwrap();
+        let expected = |frame: usize| {
+            let t = frame as f32 / SAMPLE_RATE as f32;
+            (t * 440. * std::f32::consts::TAU).sin() * 0.5
+        };
+        let stereo = render(&mut out, BLOCK_SIZE);
+        assert!((stereo[stereo.len() - 2] - expected(BLOCK_SIZE - 1)).abs() < 1e-4);
+
+        out.set_channels(1).unwrap();
+        assert_eq!(
+            manager.drain_audio_events(),
+            [AudioEvent::ChannelLayoutChanged {
+                previous: 2,
+                channels: 1
+            }]
+        );
+        let mut mono = vec![0.; BLOCK_SIZE];
+        out.render(&mut mono);
+        for (i, sample) in mono.iter().enumerate() {
+            assert!((sample - expected(BLOCK_SIZE + i)).abs() < 1e-4);
+        }
+        assert_eq!(manager.channels(), 1);
+
+        out.set_channels(2).unwrap();
+        let stereo = render(&mut out, BLOCK_SIZE);
+        assert!((stereo[0] - expected(BLOCK_SIZE * 2)).abs() < 1e-4);
+        assert_eq!(stereo[0], stereo[1]);
+        assert_eq!(manager.drain_audio_events().len(), 1);
+
+        // The recording keeps its layout, with the mono block spread.
+        assert_eq!(recorder.channels(), 2);
+        let mut samples = vec![0; BLOCK_SIZE * 6];
+        assert_eq!(recorder.read(&mut samples), BLOCK_SIZE * 6);
+        let mono: Vec<_> = samples[BLOCK_SIZE * 2..BLOCK_SIZE * 4].chunks(2).collect();
+        assert!(mono.iter().all(|it| it[0] == it[1]));
+        assert!(mono.iter().any(|it| it[0] != 0));
+    }
+
+    #[test]
+    fn output_ramps_in_after_a_rebuild() {
+        let offline = OfflineSettings {
+            sample_rate: SAMPLE_RATE,
+            channels: 2,
+            block_size: BLOCK_SIZE,
+        };
+        let with_soft_start = |soft_start: f64| AudioManagerSettings {
+            soft_start,
+            ..settings()
+        };
+        let ramp = SAMPLE_RATE as usize / 50;
+        let (mut manager, mut out) = manager_with(with_soft_start(0.02), offline.clone());
+        let music = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize * 4),
+                MusicParams::default(),
+            )
+            .unwrap();
+        music.play().unwrap();
+        for _ in 0..2 {
+            let left: Vec<_> = render(&mut out, BLOCK_SIZE * 4)
+                .into_iter()
+                .step_by(2)
+                .collect();
+            assert!(left[0] < 0.001);
+            assert!(left[..ramp].windows(2).all(|it| it[1] > it[0]));
+            assert!((left[ramp / 2] - 0.25).abs() < 0.01);
+            assert!(left[ramp..].iter().all(|it| *it == 0.5));
+            manager.reconfigure(with_soft_start(0.02)).unwrap();
+        }
+
+        // Without the ramp, the low pass still restarts from silence.
+        let (mut manager, mut out) = manager_with(with_soft_start(0.), offline);
+        let music = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize * 4),
+                MusicParams::default(),
+            )
+            .unwrap();
+        music.play().unwrap();
+        music.set_low_pass(0.9).unwrap();
+        render(&mut out, BLOCK_SIZE * 8);
+        assert!((render(&mut out, BLOCK_SIZE)[0] - 0.5).abs() < 1e-3);
+        manager.reconfigure(with_soft_start(0.)).unwrap();
+        assert!(render(&mut out, BLOCK_SIZE)[0] < 0.1);
+    }
+
+    #[test]
+    fn ducks_follow_the_play_head_of_their_trigger() {
+        let (mut manager, mut out) = manager();
+        // Silent, only its play head matters. Created first, so that the
+        // target follows its seeks on the same block.
+        let trigger = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize * 4),
+                MusicParams {
+                    amplifier: 0.,
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        let target = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize * 4),
+                MusicParams::default(),
+            )
+            .unwrap();
+        let left = |data: Vec<f32>| data.into_iter().step_by(2).collect::<Vec<_>>();
+        let gain = db_to_amplitude(-6.);
+        manager
+            .schedule_duck(&trigger, &target, 0.5, 1., 6., 0.)
+            .unwrap();
+        target.play().unwrap();
+        trigger.play().unwrap();
+
+        // Ducked from the frame the trigger reaches the start to the frame
+        // it reaches the end.
+        let data = left(render(&mut out, BLOCK_SIZE * 150));
+        let half = SAMPLE_RATE as usize / 2;
+        assert!(data[..half].iter().all(|it| *it == 0.5));
+        assert!(data[half..half * 2]
+            .iter()
+            .all(|it| (it - 0.5 * gain).abs() < 1e-6));
+        assert!(data[half * 2..].iter().all(|it| *it == 0.5));
+
+        // Seeking the trigger into the region applies the duck on the next
+        // block, and out of it releases it.
+        trigger.seek_to(0.75).unwrap();
+        let data = left(render(&mut out, BLOCK_SIZE));
+        assert!(data.iter().all(|it| (it - 0.5 * gain).abs() < 1e-6));
+        trigger.seek_to(2.).unwrap();
+        let data = left(render(&mut out, BLOCK_SIZE));
+        assert!(data.iter().all(|it| *it == 0.5));
+
+        // With a ramp, both ends take `ramp` seconds.
+        target.clear_ducks().unwrap();
+        manager
+            .schedule_duck(&trigger, &target, 0.5, 1., 6., 0.005)
+            .unwrap();
+        render(&mut out, BLOCK_SIZE);
+        let ramp = SAMPLE_RATE as usize / 200;
+        for (position, from, to) in [(0.5, 0.5, 0.5 * gain), (2., 0.5 * gain, 0.5)] {
+            trigger.seek_to(position).unwrap();
+            let data = left(render(&mut out, BLOCK_SIZE));
+            assert!((data[0] - from).abs() < 0.01, "{}", data[0]);
+            for pair in data[..ramp].windows(2) {
+                assert!((pair[1] - pair[0]) * (to - from) > 0.);
+            }
+            assert!(data[ramp..].iter().all(|it| (it - to).abs() < 1e-6));
+        }
+    }
+
+    #[test]
+    fn ducks_created_before_their_trigger_follow_seeks_a_block_late() {
+        let (mut manager, mut out) = manager();
+        let target = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize * 4),
+                MusicParams::default(),
+            )
+            .unwrap();
+        let trigger = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize * 4),
+                MusicParams {
+                    amplifier: 0.,
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        let gain = db_to_amplitude(-6.);
+        manager
+            .schedule_duck(&trigger, &target, 0.5, 1., 6., 0.)
+            .unwrap();
+        target.play().unwrap();
+        trigger.play().unwrap();
+        render(&mut out, BLOCK_SIZE);
+
+        trigger.seek_to(0.75).unwrap();
+        assert!(render(&mut out, BLOCK_SIZE).iter().all(|it| *it == 0.5));
+        let data = render(&mut out, BLOCK_SIZE);
+        assert!(data.iter().all(|it| (it - 0.5 * gain).abs() < 1e-6));
+        trigger.seek_to(2.).unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        assert!(data.iter().all(|it| (it - 0.5 * gain).abs() < 1e-6));
+        assert!(render(&mut out, BLOCK_SIZE).iter().all(|it| *it == 0.5));
+    }
+
+    #[test]
+    fn empty_clips_end_right_away() {
+        let (mut manager, mut out) = manager();
+        let empty = AudioClip::from_raw(Vec::new(), SAMPLE_RATE);
+        assert_eq!(empty.length(), 0.);
+        let music = manager
+            .create_music(empty.clone(), MusicParams::default())
+            .unwrap();
+        let looping = manager
+            .create_music(
+                empty.clone(),
+                MusicParams {
+                    loop_mix_time: 0.,
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        let mut sfx = manager.create_sfx(empty, None).unwrap();
+        music.play().unwrap();
+        looping.play().unwrap();
+        let instance = sfx.play(PlaySfxParams::default()).unwrap();
+        assert!(render(&mut out, BLOCK_SIZE * 2).iter().all(|it| *it == 0.));
+        assert!(music.paused());
+        assert!(looping.paused());
+        assert_eq!(music.position(), 0.);
+        assert!(!sfx.is_playing(instance));
+        // Only a command can wake anything up.
+        assert_eq!(out.next_activity(), Some(u64::MAX));
+    }
 }
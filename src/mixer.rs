@@ -1,51 +1,986 @@
-use ringbuf::HeapConsumer;
-use crate::Renderer;
+use crate::{
+    android_glue::HostState,
+    dsp::{Biquad, FilterSettings, Limiter, Ramp},
+    recorder::RecorderSink,
+    render_isolated,
+    renderer::GroupRelease,
+    AudioClip, AudioManagerSettings, Frame, RenderContext, Renderer, RendererKind,
+};
+use ringbuf::{HeapConsumer, HeapProducer};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Seconds of audio over which underruns are counted as recent.
+const UNDERRUN_WINDOW: u64 = 10;
+
+/// Gaps between callbacks longer than this are taken as the stream being
+/// stopped rather than as underruns.
+const MAX_UNDERRUN_GAP: Duration = Duration::from_secs(1);
+
+/// Seconds the output takes to duck under another app or to recover.
+const HOST_DUCK_RAMP: f64 = 0.1;
+
+/// Frames per block the mixer and buses make room for up front, unless the
+/// settings ask for more. Longer blocks still play, at the cost of an
+/// allocation on the audio thread.
+const MAX_BLOCK_FRAMES: usize = 8192;
+
+/// Most frames a block is expected to have under `settings`, i.e. the
+/// quantum if set.
+pub(crate) fn max_block_frames(settings: &AudioManagerSettings) -> usize {
+    match settings.quantum.filter(|it| *it != 0) {
+        Some(quantum) => quantum,
+        None => settings
+            .buffer_size
+            .map_or(0, |it| it as usize)
+            .max(MAX_BLOCK_FRAMES),
+    }
+}
+
+/// Output stream format as last reported by the backend.
+pub(crate) struct StreamInfo {
+    pub(crate) sample_rate: AtomicU32,
+    pub(crate) channels: AtomicU16,
+    pub(crate) degraded: AtomicBool,
+    /// Reference for `last_callback`.
+    pub(crate) epoch: Instant,
+    /// Nanoseconds from `epoch` to the start of the latest callback, or
+    /// `u64::MAX` before the first one.
+    pub(crate) last_callback: AtomicU64,
+    pub(crate) underruns_recent: AtomicU32,
+    /// Frames rendered by the mixer so far.
+    pub(crate) sample_time: AtomicU64,
+    /// [`Mixer::next_activity`] after the latest block, 0 while audible.
+    pub(crate) next_activity: AtomicU64,
+    pub(crate) host: HostState,
+}
+impl Default for StreamInfo {
+    fn default() -> Self {
+        Self {
+            sample_rate: AtomicU32::default(),
+            channels: AtomicU16::default(),
+            degraded: AtomicBool::default(),
+            epoch: Instant::now(),
+            last_callback: AtomicU64::new(u64::MAX),
+            underruns_recent: AtomicU32::default(),
+            sample_time: AtomicU64::default(),
+            next_activity: AtomicU64::default(),
+            host: HostState::default(),
+        }
+    }
+}
+impl StreamInfo {
+    /// Time since the latest callback started, if any.
+    pub(crate) fn last_callback_age(&self) -> Option<Duration> {
+        let nanos = self.last_callback.load(Ordering::Relaxed);
+        (nanos != u64::MAX).then(|| {
+            self.epoch
+                .elapsed()
+                .saturating_sub(Duration::from_nanos(nanos))
+        })
+    }
+}
+
+/// Snapshot of the state of the output, see
+/// [`AudioManager::health`](crate::AudioManager::health).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Health {
+    /// Whether the backend has a stream and it called back recently.
+    pub stream_running: bool,
+    /// Time since the latest callback started, [`Duration::MAX`] if there
+    /// was none yet.
+    pub last_callback_age: Duration,
+    pub device_name: Option<String>,
+    /// Callbacks that came late over the last 10 seconds of audio, i.e.
+    /// more than twice the duration of the previous block after it.
+    pub underruns_recent: u32,
+}
+
+/// When the mixer should shed work, measured against the duration of the
+/// audio rendered by each callback.
+///
+/// While degraded, renderers drop interpolation, sound effects only keep
+/// their loudest voices and the master and bus filters are bypassed. The
+/// limiter stays on.
+#[derive(Debug, Clone)]
+pub struct OverloadPolicy {
+    /// Fraction of the callback budget above which a callback counts as
+    /// overloaded.
+    pub threshold: f32,
+    /// Consecutive overloaded callbacks before degrading.
+    pub trigger_callbacks: u32,
+    /// Fraction of the budget below which a degraded callback counts as
+    /// having headroom.
+    pub restore_threshold: f32,
+    /// Consecutive callbacks with headroom before restoring full quality.
+    pub restore_callbacks: u32,
+}
+impl Default for OverloadPolicy {
+    fn default() -> Self {
+        Self {
+            threshold: 0.8,
+            trigger_callbacks: 4,
+            restore_threshold: 0.4,
+            restore_callbacks: 64,
+        }
+    }
+}
+
+/// A transition of the mixer under its [`OverloadPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverloadEvent {
+    Degraded,
+    Restored,
+}
+
+/// A change of the output stream, see
+/// [`AudioManager::drain_audio_events`](crate::AudioManager::drain_audio_events).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AudioEvent {
+    /// The output stream now has `channels` channels instead of `previous`.
+    /// Renderers carry on in the new layout without losing their state.
+    ChannelLayoutChanged { previous: u16, channels: u16 },
+    /// A labeled handle finished: music stopped at the end of its clip, see
+    /// [`Music::finished`](crate::Music::finished), or every instance of a
+    /// sound effect stopped playing. Found when draining the events, so
+    /// it is reported at most once between two calls.
+    Finished { label: String, kind: RendererKind },
+}
+
+/// Callback receiving the final output, see
+/// [`AudioManager::set_output_tap`](crate::AudioManager::set_output_tap).
+pub(crate) struct OutputTap {
+    tap: Box<TapFn>,
+    /// Frames handed to the tap, allocated along with it.
+    frames: Vec<Frame>,
+}
+type TapFn = dyn FnMut(&[Frame]) + Send;
+
+impl OutputTap {
+    /// `max_frames` is the longest block the tap is fed without allocating.
+    pub(crate) fn new(tap: impl FnMut(&[Frame]) + Send + 'static, max_frames: usize) -> Self {
+        Self {
+            tap: Box::new(tap),
+            frames: Vec::with_capacity(max_frames),
+        }
+    }
+}
+
+// SAFETY: the tap is only ever called through `&mut Mixer`, i.e. from one
+// thread at a time.
+unsafe impl Sync for OutputTap {}
+
+/// A one-shot mixed straight into the master, see
+/// [`AudioManager::play_direct`](crate::AudioManager::play_direct).
+pub(crate) struct DirectVoice {
+    clip: AudioClip,
+    amplifier: f32,
+    /// Read position in frames of the clip, so that a clip at the output
+    /// rate is played for exactly its length.
+    index: f64,
+    stopped: Arc<AtomicBool>,
+}
+
+/// Handle to a clip played with
+/// [`AudioManager::play_direct`](crate::AudioManager::play_direct).
+pub struct DirectHandle {
+    stopped: Arc<AtomicBool>,
+}
+
+impl DirectHandle {
+    pub(crate) fn new(clip: AudioClip, amplifier: f32) -> (DirectHandle, DirectVoice) {
+        let stopped = Arc::new(AtomicBool::new(false));
+        (
+            Self {
+                stopped: Arc::clone(&stopped),
+            },
+            DirectVoice {
+                clip,
+                amplifier,
+                index: 0.,
+                stopped,
+            },
+        )
+    }
+
+    /// Stops the clip on the next block.
+    pub fn cancel(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the clip has finished playing or been cancelled.
+    pub fn finished(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
 
 pub(crate) enum MixerCommand {
     AddRenderer(Box<dyn Renderer>),
+    SetChannelMap(Option<[usize; 2]>),
+    FadeOutMaster(f32),
+    SetMasterVolume(f32, f64),
+    SetRecorder(Option<RecorderSink>),
+    StopAllSfx(f32),
+    SetSfxPaused(bool),
+    SetOutputTap(Option<OutputTap>),
+    /// New settings, with the limiter they describe allocated beforehand.
+    Configure(Box<(AudioManagerSettings, Option<Limiter>)>),
+    SetMasterFilter(Option<FilterSettings>),
+    AddGroup(Arc<GroupRelease>),
+    PlayDirect(DirectVoice),
+    SetOverloadEvents(HeapProducer<OverloadEvent>),
+    SetAudioEvents(HeapProducer<AudioEvent>),
 }
 pub(crate) struct Mixer {
     pub(crate) sample_rate: u32,
+    channels: u16,
+    sample_time: u64,
+    stream: Arc<StreamInfo>,
 
     renderers: Vec<Box<dyn Renderer>>,
     cons: HeapConsumer<MixerCommand>,
+    groups: Vec<Arc<GroupRelease>>,
+    direct: Vec<DirectVoice>,
+    /// Applied to renderers added later.
+    sfx_paused: bool,
+
+    channel_map: Option<[usize; 2]>,
+    /// Stereo mix routed through the channel map.
+    scratch: Vec<f32>,
+    /// See [`max_block_frames`].
+    max_frames: usize,
+
+    master_gain: f32,
+    master_fade: f32,
+    master_volume: Ramp,
+    host_duck: Ramp,
+    soft_start: Ramp,
+    soft_start_time: f64,
+    soft_start_pending: bool,
+    mono_gain: f32,
+    filter: Option<Biquad>,
+    limiter: Option<Limiter>,
+
+    recorder: Option<RecorderSink>,
+    tap: Option<OutputTap>,
+
+    overload: Option<OverloadPolicy>,
+    overload_streak: u32,
+    degraded: bool,
+    overload_events: Option<HeapProducer<OverloadEvent>>,
+    audio_events: Option<HeapProducer<AudioEvent>>,
+
+    quantum: Option<usize>,
+    /// Output of the last quantum, interleaved, and how much of it has been
+    /// handed out already.
+    quantum_block: Vec<f32>,
+    quantum_offset: usize,
+
+    /// Count late callbacks as underruns. Off for backends not driven by a
+    /// device clock.
+    pub(crate) watch_underruns: bool,
+    /// Start and length in seconds of the previous callback.
+    last_callback: Option<(Instant, f64)>,
+    /// Underruns in the current and previous windows.
+    underruns: [u32; 2],
+    window_frames: u64,
+}
+
+/// Makes room for `len` elements in `buffer`, so that resizing it up to
+/// that on the audio thread does not allocate.
+fn reserve<T>(buffer: &mut Vec<T>, len: usize) {
+    buffer.reserve(len.saturating_sub(buffer.len()));
 }
 
 impl Mixer {
-    pub(crate) fn new(sample_rate: u32, cons: HeapConsumer<MixerCommand>) -> Self {
-        Self {
+    pub(crate) fn new(
+        sample_rate: u32,
+        stream: Arc<StreamInfo>,
+        cons: HeapConsumer<MixerCommand>,
+        settings: &AudioManagerSettings,
+    ) -> Self {
+        stream.sample_rate.store(sample_rate, Ordering::Relaxed);
+        stream.channels.store(2, Ordering::Relaxed);
+        let mut mixer = Self {
             sample_rate,
+            channels: 2,
+            sample_time: 0,
+            stream,
 
             renderers: Vec::new(),
             cons,
+            groups: Vec::new(),
+            direct: Vec::new(),
+            sfx_paused: false,
+
+            channel_map: None,
+            scratch: Vec::new(),
+            max_frames: 0,
+
+            master_gain: 1.,
+            master_fade: 0.,
+            master_volume: Ramp::new(1.),
+            host_duck: Ramp::new(1.),
+            soft_start: Ramp::new(1.),
+            soft_start_time: 0.,
+            soft_start_pending: false,
+            mono_gain: 1.,
+            filter: None,
+            limiter: None,
+
+            recorder: None,
+            tap: None,
+
+            overload: None,
+            overload_streak: 0,
+            degraded: false,
+            overload_events: None,
+            audio_events: None,
+
+            quantum: None,
+            quantum_block: Vec::new(),
+            quantum_offset: 0,
+
+            watch_underruns: true,
+            last_callback: None,
+            underruns: [0; 2],
+            window_frames: 0,
+        };
+        mixer.configure(settings, settings.limiter.clone().map(Limiter::new));
+        mixer
+    }
+
+    fn configure(&mut self, settings: &AudioManagerSettings, limiter: Option<Limiter>) {
+        // Buffers grow to this on the next format change, as this may run on
+        // the audio thread.
+        self.max_frames = max_block_frames(settings);
+        self.mono_gain = if settings.mono_compensation {
+            std::f32::consts::SQRT_2
+        } else {
+            1.
+        };
+        self.limiter = limiter;
+        self.soft_start_time = settings.soft_start.max(0.);
+        self.overload = settings.overload.clone();
+        self.overload_streak = 0;
+        // Picked up at the end of the current quantum, see `render_quanta`.
+        self.quantum = settings.quantum.filter(|it| *it != 0);
+        if self.overload.is_none() {
+            self.set_degraded(false);
+        }
+    }
+
+    /// Clears the filters of every renderer and ramps the output in from
+    /// silence on the next block, as the stream has just been (re)built.
+    /// Backends call this after [`Mixer::set_format`] and before the first
+    /// block.
+    pub(crate) fn soft_start(&mut self) {
+        for renderer in &mut self.renderers {
+            renderer.reset_filters();
+        }
+        if let Some(filter) = &mut self.filter {
+            filter.reset();
+        }
+        self.soft_start_pending = true;
+    }
+
+    fn set_degraded(&mut self, degraded: bool) {
+        if self.degraded == degraded {
+            return;
+        }
+        self.degraded = degraded;
+        self.stream.degraded.store(degraded, Ordering::Relaxed);
+        if let Some(events) = &mut self.overload_events {
+            let _ = events.push(if degraded {
+                OverloadEvent::Degraded
+            } else {
+                OverloadEvent::Restored
+            });
+        }
+    }
+
+    /// Updates the overload state after a callback that took `elapsed`
+    /// seconds to render `frames` frames.
+    fn track_overload(&mut self, elapsed: f64, frames: usize) {
+        let Some(policy) = &self.overload else {
+            return;
+        };
+        if frames == 0 || self.sample_rate == 0 {
+            return;
+        }
+        let load = elapsed * self.sample_rate as f64 / frames as f64;
+        let (counts, needed) = if self.degraded {
+            (
+                load < policy.restore_threshold as f64,
+                policy.restore_callbacks,
+            )
+        } else {
+            (load > policy.threshold as f64, policy.trigger_callbacks)
+        };
+        if !counts {
+            self.overload_streak = 0;
+            return;
+        }
+        self.overload_streak += 1;
+        if self.overload_streak >= needed.max(1) {
+            self.overload_streak = 0;
+            self.set_degraded(!self.degraded);
+        }
+    }
+
+    /// Records a callback starting at `now` to render `frames` frames.
+    fn track_callback(&mut self, now: Instant, frames: usize) {
+        let nanos = now.saturating_duration_since(self.stream.epoch).as_nanos();
+        self.stream
+            .last_callback
+            .store(nanos.min(u64::MAX as u128 - 1) as u64, Ordering::Relaxed);
+        if !self.watch_underruns || self.sample_rate == 0 {
+            return;
+        }
+        if let Some((last, length)) = self.last_callback {
+            let gap = now.saturating_duration_since(last);
+            if gap > Duration::from_secs_f64(length * 2.) && gap < MAX_UNDERRUN_GAP {
+                self.underruns[0] += 1;
+            }
+        }
+        self.last_callback = Some((now, frames as f64 / self.sample_rate as f64));
+        self.window_frames += frames as u64;
+        if self.window_frames >= UNDERRUN_WINDOW * self.sample_rate as u64 {
+            self.window_frames = 0;
+            self.underruns = [0, self.underruns[0]];
+        }
+        self.stream
+            .underruns_recent
+            .store(self.underruns[0] + self.underruns[1], Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_format(&mut self, sample_rate: u32, channels: u16) {
+        if self.sample_rate != sample_rate {
+            self.sample_rate = sample_rate;
+            self.stream
+                .sample_rate
+                .store(sample_rate, Ordering::Relaxed);
+        }
+        if self.channels != channels {
+            let previous = std::mem::replace(&mut self.channels, channels);
+            self.stream.channels.store(channels, Ordering::Relaxed);
+            // Rendered in the previous layout.
+            self.quantum_block.clear();
+            self.quantum_offset = 0;
+            if let Some(events) = &mut self.audio_events {
+                let _ = events.push(AudioEvent::ChannelLayoutChanged { previous, channels });
+            }
         }
+        reserve(
+            &mut self.quantum_block,
+            self.quantum.unwrap_or(0) * channels as usize,
+        );
+        reserve(&mut self.scratch, self.max_frames * 2);
     }
 
     fn consume_commands(&mut self) {
-        for cmd in self.cons.pop_iter() {
+        while let Some(cmd) = self.cons.pop() {
             match cmd {
-                MixerCommand::AddRenderer(renderer) => self.renderers.push(renderer),
+                MixerCommand::AddRenderer(mut renderer) => {
+                    if self.sfx_paused {
+                        renderer.set_sfx_paused(true);
+                    }
+                    self.renderers.push(renderer);
+                }
+                MixerCommand::SetChannelMap(map) => self.channel_map = map,
+                MixerCommand::FadeOutMaster(time) => {
+                    self.master_fade = -self.master_gain / (time * self.sample_rate as f32);
+                }
+                MixerCommand::SetMasterVolume(volume, ramp) => {
+                    self.master_volume.set(volume, ramp, self.sample_rate)
+                }
+                MixerCommand::SetRecorder(recorder) => self.recorder = recorder,
+                MixerCommand::StopAllSfx(fade) => {
+                    for renderer in &mut self.renderers {
+                        renderer.stop_all_sfx(fade);
+                    }
+                }
+                MixerCommand::SetSfxPaused(paused) => {
+                    self.sfx_paused = paused;
+                    for renderer in &mut self.renderers {
+                        renderer.set_sfx_paused(paused);
+                    }
+                }
+                MixerCommand::SetOutputTap(tap) => self.tap = tap,
+                MixerCommand::Configure(config) => {
+                    let (settings, limiter) = *config;
+                    self.configure(&settings, limiter);
+                }
+                MixerCommand::SetMasterFilter(filter) => self.filter = filter.map(Biquad::new),
+                MixerCommand::AddGroup(group) => self.groups.push(group),
+                MixerCommand::PlayDirect(voice) => self.direct.push(voice),
+                MixerCommand::SetOverloadEvents(events) => self.overload_events = Some(events),
+                MixerCommand::SetAudioEvents(events) => self.audio_events = Some(events),
             }
         }
+        // Released here rather than by the handles, so that every member
+        // sees a release on the same block. A group only referenced by the
+        // mixer has no pending commands left.
+        self.groups.retain(|group| {
+            let requested = group.requested.load(Ordering::SeqCst);
+            group.released.store(requested, Ordering::SeqCst);
+            Arc::strong_count(group) > 1
+        });
+    }
+
+    fn apply_master(&mut self, data: &mut [f32], channels: usize) {
+        let settled =
+            self.master_volume.settled() && self.host_duck.settled() && self.soft_start.settled();
+        if self.master_fade == 0. && settled {
+            let gain = self.master_gain * self.master_volume.value * self.host_duck.value;
+            if gain != 1. {
+                data.iter_mut().for_each(|it| *it *= gain);
+            }
+            return;
+        }
+        for frame in data.chunks_exact_mut(channels) {
+            self.master_gain = (self.master_gain + self.master_fade).max(0.);
+            let gain = self.master_gain
+                * self.master_volume.next()
+                * self.host_duck.next()
+                * self.soft_start.next();
+            frame.iter_mut().for_each(|it| *it *= gain);
+        }
+    }
+
+    /// Renders interleaved data with the channel count last passed to
+    /// [`Mixer::set_format`], routing the stereo mix through the channel map.
+    ///
+    /// With a quantum set, the output is rendered in quanta, carrying what
+    /// is left of the last one over to the next call.
+    pub fn render(&mut self, data: &mut [f32]) {
+        crate::rt::checked("mixer", || self.render_checked(data));
+    }
+
+    fn render_checked(&mut self, data: &mut [f32]) {
+        let start = Instant::now();
+        let frames = data.len() / self.channels.max(1) as usize;
+        self.track_callback(start, frames);
+        if std::mem::take(&mut self.soft_start_pending) {
+            // A reconfiguration is pushed after restarting the stream, and
+            // its ramp time must apply to this restart.
+            self.consume_commands();
+            let time = self.soft_start_time;
+            self.soft_start = Ramp::new(if time > 0. { 0. } else { 1. });
+            self.soft_start.set(1., time, self.sample_rate);
+        }
+        if self.stream.host.paused() {
+            // Keep taking commands so that their buffer does not fill up
+            // while the host holds the output. Direct one-shots are meant
+            // to be heard whatever the rest of the mix does.
+            self.consume_commands();
+            data.fill(0.);
+            self.mix_direct(data);
+            if let Some(limiter) = &mut self.limiter {
+                limiter.process(data, self.channels as usize, self.sample_rate);
+            }
+            return;
+        }
+        let duck = self.stream.host.duck.load(Ordering::Relaxed);
+        if duck != self.host_duck.target() {
+            self.host_duck.set(duck, HOST_DUCK_RAMP, self.sample_rate);
+        }
+        if self.quantum.is_some() || self.quantum_offset != self.quantum_block.len() {
+            self.render_quanta(data);
+        } else {
+            self.render_block(data);
+        }
+        self.stream
+            .next_activity
+            .store(self.next_activity().unwrap_or_default(), Ordering::Relaxed);
+        self.stream
+            .sample_time
+            .store(self.sample_time, Ordering::Relaxed);
+        if self.overload.is_some() {
+            self.track_overload(start.elapsed().as_secs_f64(), frames);
+        }
+    }
+
+    /// Fills `data` from the quantum buffer, rendering a new quantum whenever
+    /// it runs out. A quantum changed while rendering one takes effect once
+    /// the rest of it has been handed out.
+    fn render_quanta(&mut self, mut data: &mut [f32]) {
+        let mut block = std::mem::take(&mut self.quantum_block);
+        while !data.is_empty() {
+            if self.quantum_offset == block.len() {
+                let len = self.quantum.unwrap_or(0) * self.channels as usize;
+                if len == 0 {
+                    block.clear();
+                    self.quantum_offset = 0;
+                    self.render_block(data);
+                    break;
+                }
+                // Only allocates if the quantum grew since the last format
+                // change.
+                block.resize(len, 0.);
+                self.render_block(&mut block);
+                self.quantum_offset = 0;
+            }
+            let len = data.len().min(block.len() - self.quantum_offset);
+            data[..len].copy_from_slice(&block[self.quantum_offset..self.quantum_offset + len]);
+            self.quantum_offset += len;
+            data = &mut data[len..];
+        }
+        self.quantum_block = block;
+    }
+
+    /// Earliest frame any renderer may be audible on, see
+    /// [`Renderer::next_activity`], or `None` if the next block may be.
+    pub(crate) fn next_activity(&self) -> Option<u64> {
+        if !self.cons.is_empty() || !self.direct.is_empty() {
+            return None;
+        }
+        let now = self.sample_time;
+        self.renderers
+            .iter()
+            .try_fold(u64::MAX, |at, it| Some(at.min(it.next_activity(now)?)))
+    }
+
+    fn render_block(&mut self, data: &mut [f32]) {
+        self.render_output(data);
+        if let Some(filter) = &mut self.filter {
+            if !self.degraded {
+                filter.process(data, self.channels as usize, self.sample_rate);
+            }
+        }
+        self.mix_direct(data);
+        if let Some(limiter) = &mut self.limiter {
+            limiter.process(data, self.channels as usize, self.sample_rate);
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.write(data, self.channels);
+        }
+        let [left, right] = self.output_map();
+        if let Some(tap) = &mut self.tap {
+            let channels = self.channels as usize;
+            tap.frames.clear();
+            tap.frames.extend(
+                data.chunks_exact(channels)
+                    .map(|frame| Frame(frame[left], frame[right])),
+            );
+            crate::rt::checked("output tap", || (tap.tap)(&tap.frames));
+        }
+    }
+
+    /// Output channels receiving the left and right side of the mix.
+    fn output_map(&self) -> [usize; 2] {
+        let channels = self.channels as usize;
+        match self.channel_map {
+            _ if channels == 1 => [0, 0],
+            Some(map) if map.iter().all(|&it| it < channels) => map,
+            _ => [0, 1],
+        }
+    }
+
+    /// Mixes the direct one-shots into the final output, past the master
+    /// gain and filter.
+    fn mix_direct(&mut self, data: &mut [f32]) {
+        if self.direct.is_empty() {
+            return;
+        }
+        let channels = self.channels as usize;
+        let [left, right] = self.output_map();
+        let sample_rate = self.sample_rate as f64;
+        self.direct.retain_mut(|voice| {
+            if voice.stopped.load(Ordering::SeqCst) {
+                return false;
+            }
+            let step = voice.clip.sample_rate() as f64 / sample_rate;
+            for output in data.chunks_exact_mut(channels) {
+                let Some(frame) = voice.clip.sample_index(voice.index) else {
+                    voice.stopped.store(true, Ordering::SeqCst);
+                    return false;
+                };
+                let frame = frame * voice.amplifier;
+                if channels == 1 {
+                    output[0] += frame.avg();
+                } else {
+                    output[left] += frame.0;
+                    output[right] += frame.1;
+                }
+                voice.index += step;
+            }
+            true
+        });
+    }
+
+    fn render_output(&mut self, data: &mut [f32]) {
+        // Before routing, so that a new channel map applies to this block.
+        self.consume_commands();
+        let channels = self.channels as usize;
+        let map = match (channels, self.channel_map) {
+            (1, _) => return self.render_mono(data),
+            (2, None) => return self.render_stereo(data),
+            (_, Some(map)) if map.iter().all(|&it| it < channels) => map,
+            _ => [0, 1],
+        };
+        let mut scratch = std::mem::take(&mut self.scratch);
+        // Within the room made by `set_format` unless the block is longer.
+        scratch.resize(data.len() / channels * 2, 0.);
+        self.render_stereo(&mut scratch);
+        data.fill(0.);
+        for (output, frame) in data.chunks_exact_mut(channels).zip(scratch.chunks_exact(2)) {
+            output[map[0]] += frame[0];
+            output[map[1]] += frame[1];
+        }
+        self.scratch = scratch;
+    }
+
+    fn context(&self, channels: u16) -> RenderContext {
+        RenderContext {
+            sample_rate: self.sample_rate,
+            sample_time: self.sample_time,
+            channels,
+            degraded: self.degraded,
+            output_gain: self.master_gain
+                * self.master_volume.value
+                * self.host_duck.value
+                * self.soft_start.value,
+        }
     }
 
     pub fn render_mono(&mut self, data: &mut [f32]) {
         self.consume_commands();
         data.fill(0.);
 
-        self.renderers.retain_mut(|renderer| {
-            renderer.render_mono(self.sample_rate, data);
-            renderer.alive()
-        });
+        let ctx = self.context(1);
+        self.renderers
+            .retain_mut(|renderer| render_isolated(renderer, &ctx, data));
+        self.sample_time += data.len() as u64;
+        if self.mono_gain != 1. {
+            data.iter_mut().for_each(|it| *it *= self.mono_gain);
+        }
+        self.apply_master(data, 1);
     }
 
     pub fn render_stereo(&mut self, data: &mut [f32]) {
         self.consume_commands();
         data.fill(0.);
 
-        self.renderers.retain_mut(|renderer| {
-            renderer.render_stereo(self.sample_rate, data);
-            renderer.alive()
-        });
+        let ctx = self.context(2);
+        self.renderers
+            .retain_mut(|renderer| render_isolated(renderer, &ctx, data));
+        self.sample_time += data.len() as u64 / 2;
+        self.apply_master(data, 2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::offline::OfflineSettings,
+        test_util::{manager_with, render, settings, sine, BLOCK_SIZE, SAMPLE_RATE},
+        Music, MusicParams,
+    };
+    use ringbuf::HeapRb;
+
+    /// A started stereo mixer playing a sine, with its command queue.
+    fn mixer(quantum: Option<usize>) -> (Mixer, HeapProducer<MixerCommand>, Music) {
+        let (mut prod, cons) = HeapRb::new(16).split();
+        let settings = AudioManagerSettings {
+            quantum,
+            ..settings()
+        };
+        let mut mixer = Mixer::new(0, Arc::default(), cons, &settings);
+        mixer.set_format(SAMPLE_RATE, 2);
+        let (music, renderer) = Music::new(
+            sine(440., 0.5, 1.),
+            MusicParams::default(),
+            Arc::new(AtomicBool::new(true)),
+            Arc::default(),
+        );
+        music.play().unwrap();
+        assert!(prod
+            .push(MixerCommand::AddRenderer(Box::new(renderer)))
+            .is_ok());
+        (mixer, prod, music)
     }
-}
\ No newline at end of file
+
+    /// Renders `frames` frames through callbacks cycling through `sizes`.
+    fn render_in(mixer: &mut Mixer, frames: usize, sizes: &[usize]) -> Vec<f32> {
+        let mut data = vec![0.; frames * 2];
+        let mut rest = &mut data[..];
+        for &size in sizes.iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let len = rest.len().min(size * 2);
+            let (chunk, next) = rest.split_at_mut(len);
+            mixer.render(chunk);
+            rest = next;
+        }
+        data
+    }
+
+    #[test]
+    fn quanta_do_not_depend_on_callback_sizes() {
+        let frames = SAMPLE_RATE as usize / 4;
+        let (mut reference, _prod, _music) = mixer(Some(128));
+        let reference = render_in(&mut reference, frames, &[frames]);
+        assert!(reference.iter().any(|it| *it != 0.));
+        for sizes in [&[333, 1024, 17][..], &[333], &[1024], &[17]] {
+            let (mut mixer, _prod, _music) = mixer(Some(128));
+            assert!(
+                render_in(&mut mixer, frames, sizes) == reference,
+                "{sizes:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn tap_frames_are_allocated_with_the_tap() {
+        let (mut mixer, mut prod, _music) = mixer(None);
+        let tapped = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&tapped);
+        let tap = OutputTap::new(
+            move |frames: &[Frame]| {
+                counter.fetch_add(frames.len() as u64, Ordering::Relaxed);
+            },
+            1024,
+        );
+        let frames = tap.frames.as_ptr();
+        assert!(prod.push(MixerCommand::SetOutputTap(Some(tap))).is_ok());
+        render_in(&mut mixer, 4096, &[1024, 333, 17]);
+        assert_eq!(mixer.tap.as_ref().unwrap().frames.as_ptr(), frames);
+        assert_eq!(tapped.load(Ordering::Relaxed), 4096);
+    }
+
+    #[test]
+    fn mapped_output_renders_within_its_allocation() {
+        let (mut mixer, mut prod, _music) = mixer(None);
+        mixer.set_format(SAMPLE_RATE, 4);
+        assert!(prod.push(MixerCommand::SetChannelMap(Some([3, 2]))).is_ok());
+        let scratch = mixer.scratch.as_ptr();
+        let mut data = vec![0.; 1024 * 4];
+        for frames in [1024, 333, 17] {
+            mixer.render(&mut data[..frames * 4]);
+            assert_eq!(mixer.scratch.as_ptr(), scratch);
+        }
+        assert!(data[..17 * 4]
+            .chunks_exact(4)
+            .all(|it| it[0] == 0. && it[1] == 0. && it[2] == it[3]));
+    }
+
+    #[test]
+    fn reconfigured_quantum_takes_effect() {
+        let (mut mixer, mut prod, _music) = mixer(Some(128));
+        render_in(&mut mixer, 1000, &[333, 17]);
+        let settings = AudioManagerSettings {
+            quantum: Some(64),
+            ..settings()
+        };
+        assert!(prod
+            .push(MixerCommand::Configure(Box::new((settings.clone(), None))))
+            .is_ok());
+        render_in(&mut mixer, 1000, &[333, 17]);
+        assert_eq!(mixer.quantum_block.len(), 64 * 2);
+
+        let settings = AudioManagerSettings {
+            quantum: None,
+            ..settings
+        };
+        assert!(prod
+            .push(MixerCommand::Configure(Box::new((settings, None))))
+            .is_ok());
+        render_in(&mut mixer, 1000, &[333, 17]);
+        assert!(mixer.quantum_block.is_empty());
+        let sample_time = mixer.sample_time;
+        render_in(&mut mixer, 17, &[17]);
+        assert_eq!(mixer.sample_time, sample_time + 17);
+    }
+
+    /// Sleeps for longer than a block lasts while `slow` is set, recording
+    /// whether it was asked to degrade.
+    struct Slow {
+        slow: Arc<AtomicBool>,
+        degraded: Arc<AtomicBool>,
+    }
+    impl Renderer for Slow {
+        fn alive(&self) -> bool {
+            true
+        }
+
+        fn render_mono(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+            self.render_stereo(ctx, data)
+        }
+
+        fn render_stereo(&mut self, ctx: &RenderContext, _data: &mut [f32]) {
+            self.degraded.store(ctx.degraded, Ordering::SeqCst);
+            if self.slow.load(Ordering::SeqCst) {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+        }
+    }
+
+    #[test]
+    fn slow_callbacks_degrade_until_they_recover() {
+        let (mut manager, mut out) = manager_with(
+            AudioManagerSettings {
+                overload: Some(OverloadPolicy {
+                    trigger_callbacks: 2,
+                    restore_callbacks: 2,
+                    ..OverloadPolicy::default()
+                }),
+                ..settings()
+            },
+            OfflineSettings {
+                sample_rate: SAMPLE_RATE,
+                channels: 2,
+                block_size: BLOCK_SIZE,
+            },
+        );
+        let slow = Arc::new(AtomicBool::new(true));
+        let degraded = Arc::new(AtomicBool::new(false));
+        manager
+            .add_renderer(Slow {
+                slow: Arc::clone(&slow),
+                degraded: Arc::clone(&degraded),
+            })
+            .unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert!(!manager.is_degraded());
+        render(&mut out, BLOCK_SIZE * 2);
+        assert!(manager.is_degraded());
+        render(&mut out, BLOCK_SIZE);
+        assert!(degraded.load(Ordering::SeqCst));
+        assert_eq!(manager.drain_overload_events(), [OverloadEvent::Degraded]);
+
+        slow.store(false, Ordering::SeqCst);
+        render(&mut out, BLOCK_SIZE * 3);
+        assert!(!manager.is_degraded());
+        render(&mut out, BLOCK_SIZE);
+        assert!(!degraded.load(Ordering::SeqCst));
+        assert_eq!(manager.drain_overload_events(), [OverloadEvent::Restored]);
+    }
+
+    #[test]
+    fn late_callbacks_count_as_underruns() {
+        let (mut mixer, _prod, _music) = mixer(None);
+        render_in(&mut mixer, 4096, &[512]);
+        assert_eq!(mixer.stream.underruns_recent.load(Ordering::Relaxed), 0);
+        assert!(mixer.stream.last_callback_age().unwrap() < Duration::from_millis(100));
+
+        // A 512 frame block lasts about 11 ms.
+        std::thread::sleep(Duration::from_millis(50));
+        render_in(&mut mixer, 512, &[512]);
+        render_in(&mut mixer, 4096, &[512]);
+        assert_eq!(mixer.stream.underruns_recent.load(Ordering::Relaxed), 1);
+
+        // Gaps this long are the stream being stopped, not underruns.
+        std::thread::sleep(MAX_UNDERRUN_GAP + Duration::from_millis(50));
+        render_in(&mut mixer, 512, &[512]);
+        assert_eq!(mixer.stream.underruns_recent.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,357 @@
+use anyhow::{anyhow, Context, Result};
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+/// How often the writer thread of a file recording drains its ring buffer.
+const WRITE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Audio thread side of an [`OutputRecorder`].
+pub(crate) struct RecorderSink {
+    prod: HeapProducer<i16>,
+    dropped: Arc<AtomicUsize>,
+    /// Layout of the recording, kept if the output layout changes.
+    channels: u16,
+}
+
+impl RecorderSink {
+    /// Copies the block of `channels` interleaved channels into the ring
+    /// buffer without blocking, counting the samples that did not fit. A
+    /// block in another layout than the recording is downmixed to mono or
+    /// spread over the channels of the recording.
+    pub(crate) fn write(&mut self, data: &[f32], channels: u16) {
+        let to_i16 = |it: f32| (it.clamp(-1., 1.) * i16::MAX as f32) as i16;
+        let (len, pushed) = if channels == self.channels {
+            (
+                data.len(),
+                self.prod.push_iter(&mut data.iter().copied().map(to_i16)),
+            )
+        } else {
+            let (from, to) = (channels.max(1) as usize, self.channels as usize);
+            let samples = data.chunks_exact(from).flat_map(|frame| {
+                (0..to).map(move |i| {
+                    if to == 1 {
+                        frame.iter().sum::<f32>() / from as f32
+                    } else {
+                        frame[i.min(from - 1)]
+                    }
+                })
+            });
+            (
+                data.len() / from * to,
+                self.prod.push_iter(&mut samples.map(to_i16)),
+            )
+        };
+        if pushed < len {
+            self.dropped.fetch_add(len - pushed, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Receives a copy of the final output or of a bus, see
+/// [`AudioManager::record_bus`](crate::AudioManager::record_bus), as
+/// interleaved `i16` samples with the channel layout of the output stream
+/// when recording started.
+///
+/// Samples are buffered in a bounded ring; if it is not drained fast enough,
+/// new samples are dropped and counted by [`OutputRecorder::dropped`].
+pub struct OutputRecorder {
+    cons: HeapConsumer<i16>,
+    dropped: Arc<AtomicUsize>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl OutputRecorder {
+    pub(crate) fn new(capacity: usize, channels: u16, sample_rate: u32) -> (Self, RecorderSink) {
+        let (prod, cons) = HeapRb::new(capacity).split();
+        let dropped: Arc<AtomicUsize> = Arc::default();
+        (
+            Self {
+                cons,
+                dropped: Arc::clone(&dropped),
+                channels,
+                sample_rate,
+            },
+            RecorderSink {
+                prod,
+                dropped,
+                channels,
+            },
+        )
+    }
+
+    /// Moves buffered samples into `buf`, returning how many were written.
+    pub fn read(&mut self, buf: &mut [i16]) -> usize {
+        self.cons.pop_slice(buf)
+    }
+
+    #[inline(always)]
+    pub fn available(&self) -> usize {
+        self.cons.len()
+    }
+
+    /// Number of samples dropped because the ring buffer was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    #[inline(always)]
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    #[inline(always)]
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+/// A recording written to a WAV file, see
+/// [`AudioManager::start_recording`](crate::AudioManager::start_recording).
+///
+/// The file is written by a thread of its own, draining a ring buffer of
+/// one second; samples that do not fit are dropped and counted by
+/// [`FileRecording::dropped`]. The file is complete once the recording is
+/// stopped or replaced, or the manager is shut down.
+#[derive(Debug, Clone)]
+pub struct FileRecording {
+    path: PathBuf,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl FileRecording {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of samples dropped because the writer fell behind.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Thread writing the samples of an [`OutputRecorder`] to a file.
+pub(crate) struct FileWriter {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl FileWriter {
+    /// Creates the file at `path` and starts writing into it what the sink
+    /// handed to `install` receives, stopping again if that fails.
+    pub(crate) fn start(
+        path: &Path,
+        channels: u16,
+        sample_rate: u32,
+        install: impl FnOnce(RecorderSink) -> Result<()>,
+    ) -> Result<(FileRecording, Self)> {
+        let (recorder, sink) = OutputRecorder::new(
+            sample_rate as usize * channels as usize,
+            channels,
+            sample_rate,
+        );
+        let file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+        let recording = FileRecording {
+            path: path.to_owned(),
+            dropped: Arc::clone(&recorder.dropped),
+        };
+        let stop: Arc<AtomicBool> = Arc::default();
+        let thread = std::thread::Builder::new()
+            .name("sasa-recorder".to_owned())
+            .spawn({
+                let stop = Arc::clone(&stop);
+                move || write_wav(BufWriter::new(file), recorder, &stop)
+            })
+            .context("spawn recording thread")?;
+        let writer = Self { stop, thread };
+        if let Err(err) = install(sink) {
+            let _ = writer.finish();
+            return Err(err);
+        }
+        Ok((recording, writer))
+    }
+
+    /// Writes what is left in the ring buffer, completes the file and joins
+    /// the thread.
+    pub(crate) fn finish(self) -> Result<()> {
+        self.stop.store(true, Ordering::Release);
+        self.thread
+            .join()
+            .map_err(|_| anyhow!("recording thread panicked"))?
+            .context("write recording")
+    }
+}
+
+fn write_wav(
+    mut file: BufWriter<File>,
+    mut recorder: OutputRecorder,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let (channels, sample_rate) = (recorder.channels(), recorder.sample_rate());
+    write_wav_header(&mut file, channels, sample_rate, 0)?;
+    let mut buf = vec![0; 4096];
+    let mut samples = 0usize;
+    loop {
+        // Checked before draining, so that everything received before
+        // stopping is written.
+        let stopping = stop.load(Ordering::Acquire);
+        loop {
+            let len = recorder.read(&mut buf);
+            if len == 0 {
+                break;
+            }
+            for sample in &buf[..len] {
+                file.write_all(&sample.to_le_bytes())?;
+            }
+            samples += len;
+        }
+        if stopping {
+            break;
+        }
+        std::thread::sleep(WRITE_INTERVAL);
+    }
+    file.seek(SeekFrom::Start(0))?;
+    let data_len = u32::try_from(samples * 2).unwrap_or(u32::MAX);
+    write_wav_header(&mut file, channels, sample_rate, data_len)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Header of a 16-bit PCM WAV file holding `data_len` bytes of samples.
+fn write_wav_header(
+    file: &mut impl Write,
+    channels: u16,
+    sample_rate: u32,
+    data_len: u32,
+) -> std::io::Result<()> {
+    let block_align = channels * 2;
+    file.write_all(b"RIFF")?;
+    file.write_all(&data_len.saturating_add(36).to_le_bytes())?;
+    file.write_all(b"WAVEfmt ")?;
+    file.write_all(&16u32.to_le_bytes())?;
+    file.write_all(&1u16.to_le_bytes())?;
+    file.write_all(&channels.to_le_bytes())?;
+    file.write_all(&sample_rate.to_le_bytes())?;
+    file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    file.write_all(&block_align.to_le_bytes())?;
+    file.write_all(&16u16.to_le_bytes())?;
+    file.write_all(b"data")?;
+    file.write_all(&data_len.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{constant, manager, render, sine, BLOCK_SIZE, SAMPLE_RATE},
+        MusicParams,
+    };
+
+    /// Channels, sample rate and samples of a WAV file written by a
+    /// [`FileWriter`].
+    fn read_wav(path: &Path) -> (u16, u32, Vec<i16>) {
+        let bytes = std::fs::read(path).unwrap();
+        let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(u32_at(4) as usize, bytes.len() - 8);
+        assert_eq!(u32_at(40) as usize, bytes.len() - 44);
+        let samples = bytes[44..]
+            .chunks_exact(2)
+            .map(|it| i16::from_le_bytes([it[0], it[1]]))
+            .collect();
+        (u16_at(22), u32_at(24), samples)
+    }
+
+    #[test]
+    fn records_output_and_bus_to_separate_files() {
+        let dir = std::env::temp_dir();
+        let output_path = dir.join(format!("sasa-output-{}.wav", std::process::id()));
+        let bus_path = dir.join(format!("sasa-bus-{}.wav", std::process::id()));
+
+        let (mut manager, mut out) = manager();
+        let mut bus = manager.create_bus().unwrap();
+        let clip = constant(0.25, SAMPLE_RATE as usize);
+        let voice = manager
+            .create_music_in(&mut bus, clip.clone(), MusicParams::default())
+            .unwrap();
+        let music = manager
+            .create_music(constant(0.5, SAMPLE_RATE as usize), MusicParams::default())
+            .unwrap();
+        voice.play().unwrap();
+        music.play().unwrap();
+
+        let output = manager.start_recording(&output_path).unwrap();
+        let recorded_bus = manager.start_recording_bus(&mut bus, &bus_path).unwrap();
+        render(&mut out, BLOCK_SIZE * 4);
+        manager.stop_recording_bus(&mut bus).unwrap();
+        render(&mut out, BLOCK_SIZE * 2);
+        manager.stop_recording().unwrap();
+        assert_eq!((output.dropped(), recorded_bus.dropped()), (0, 0));
+
+        let to_i16 = |it: f32| (it * i16::MAX as f32) as i16;
+        let (channels, sample_rate, samples) = read_wav(&output_path);
+        assert_eq!((channels, sample_rate), (2, SAMPLE_RATE));
+        assert_eq!(samples.len(), BLOCK_SIZE * 6 * 2);
+        assert!(samples.iter().all(|it| *it == to_i16(0.75)));
+        let (channels, _, samples) = read_wav(&bus_path);
+        assert_eq!(channels, 2);
+        assert_eq!(samples.len(), BLOCK_SIZE * 4 * 2);
+        assert!(samples.iter().all(|it| *it == to_i16(0.25)));
+
+        std::fs::remove_file(output_path).unwrap();
+        std::fs::remove_file(bus_path).unwrap();
+    }
+
+    #[test]
+    fn shutdown_completes_file_recordings() {
+        let path = std::env::temp_dir().join(format!("sasa-shutdown-{}.wav", std::process::id()));
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(constant(0.5, SAMPLE_RATE as usize), MusicParams::default())
+            .unwrap();
+        music.play().unwrap();
+        manager.start_recording(&path).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        manager.shutdown(None).unwrap();
+        assert_eq!(read_wav(&path).2.len(), BLOCK_SIZE * 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn ring_receives_the_output_samples() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(sine(440., 0.5, 1.), MusicParams::default())
+            .unwrap();
+        music.play().unwrap();
+        let mut recorder = manager.record_output(BLOCK_SIZE * 2 * 2).unwrap();
+        assert_eq!(
+            (recorder.channels(), recorder.sample_rate()),
+            (2, SAMPLE_RATE)
+        );
+        let data = render(&mut out, BLOCK_SIZE * 2);
+        let mut recorded = vec![0; data.len()];
+        assert_eq!(recorder.read(&mut recorded), data.len());
+        let expected: Vec<i16> = data
+            .iter()
+            .map(|it| (it.clamp(-1., 1.) * i16::MAX as f32) as i16)
+            .collect();
+        assert_eq!(recorded, expected);
+
+        // Samples that do not fit are dropped instead of blocking.
+        render(&mut out, BLOCK_SIZE * 3);
+        assert_eq!(recorder.available(), BLOCK_SIZE * 2 * 2);
+        assert_eq!(recorder.dropped(), BLOCK_SIZE * 2);
+    }
+}
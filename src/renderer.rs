@@ -1,11 +1,356 @@
+mod bus;
+pub use bus::BusHandle;
+
+mod group;
+pub use group::GroupHandle;
+pub(crate) use group::GroupRelease;
+
 mod music;
-pub use music::{Music, MusicParams};
+pub use music::{
+    AppliedCommand, CommandOverflow, GainStages, LoopMode, Music, MusicParams, MusicStats,
+    ScheduledCommand,
+};
+
+mod synced;
+pub use synced::SyncedMusic;
 
 mod sfx;
-pub use sfx::{Sfx, PlaySfxParams};
+pub use sfx::{Humanize, PlaySfxParams, ScheduledPlay, Sfx, SfxInstance};
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// Information about the block being rendered.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RenderContext {
+    pub sample_rate: u32,
+    /// Frames rendered by the mixer before this block.
+    pub sample_time: u64,
+    /// Channels of the interleaved data passed to the renderer, 1 for
+    /// [`Renderer::render_mono`] and 2 for [`Renderer::render_stereo`].
+    pub channels: u16,
+    /// Whether the mixer is shedding work to keep up with the output, see
+    /// [`OverloadPolicy`](crate::OverloadPolicy). Renderers should use their
+    /// cheapest processing meanwhile.
+    pub degraded: bool,
+    /// Gain applied to the renderer's output by the buses it is in and the
+    /// master volume, as of the start of the block.
+    pub output_gain: f32,
+}
 
 pub trait Renderer: Send + Sync {
+    fn alive(&self) -> bool;
+    fn render_mono(&mut self, ctx: &RenderContext, data: &mut [f32]);
+    fn render_stereo(&mut self, ctx: &RenderContext, data: &mut [f32]);
+
+    /// Fades out every sound effect instance over `fade` seconds, see
+    /// [`AudioManager::stop_all_sfx`](crate::AudioManager::stop_all_sfx).
+    fn stop_all_sfx(&mut self, _fade: f32) {}
+
+    /// Holds or resumes every sound effect instance, see
+    /// [`AudioManager::pause_sfx`](crate::AudioManager::pause_sfx).
+    fn set_sfx_paused(&mut self, _paused: bool) {}
+
+    /// Earliest sample time from `now` on at which the renderer may produce
+    /// audio again, `u64::MAX` if only a command can wake it. Pending
+    /// commands count as activity.
+    ///
+    /// The default, `None`, means the renderer may be audible in the block
+    /// starting at `now`, or can not tell, and is asked again next block.
+    ///
+    /// Renderers returning `u64::MAX` are not rendered at all until that
+    /// changes, so they must not rely on being called every block. As
+    /// commands count, a push is picked up on the next block.
+    fn next_activity(&self, _now: u64) -> Option<u64> {
+        None
+    }
+
+    /// Clears filter state carried over from the previous output stream,
+    /// called before the first block of a new one, see
+    /// [`AudioManagerSettings::soft_start`](crate::AudioManagerSettings::soft_start).
+    fn reset_filters(&mut self) {}
+}
+
+/// The previous form of [`Renderer`], which only received the sample rate.
+///
+/// Implementors of this trait keep working through a blanket implementation
+/// of [`Renderer`]. To upgrade, implement [`Renderer`] instead and read the
+/// sample rate from [`RenderContext::sample_rate`].
+#[deprecated(note = "implement `Renderer`, which receives a `RenderContext`")]
+pub trait LegacyRenderer: Send + Sync {
     fn alive(&self) -> bool;
     fn render_mono(&mut self, sample_rate: u32, data: &mut [f32]);
     fn render_stereo(&mut self, sample_rate: u32, data: &mut [f32]);
 }
+
+#[allow(deprecated)]
+impl<T: LegacyRenderer> Renderer for T {
+    fn alive(&self) -> bool {
+        LegacyRenderer::alive(self)
+    }
+
+    fn render_mono(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+        LegacyRenderer::render_mono(self, ctx.sample_rate, data)
+    }
+
+    fn render_stereo(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+        LegacyRenderer::render_stereo(self, ctx.sample_rate, data)
+    }
+}
+
+/// Renders `renderer` and returns whether it should be kept. A renderer
+/// that panics is logged and reported as dead, so that it can not take down
+/// the rest of the output. Renderers idle until a command are skipped, see
+/// [`Renderer::next_activity`]. Allocations are logged with the `rt-checks`
+/// feature, see [`crate::rt`].
+pub(crate) fn render_isolated(
+    renderer: &mut Box<dyn Renderer>,
+    ctx: &RenderContext,
+    data: &mut [f32],
+) -> bool {
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        crate::rt::checked("renderer", || {
+            if renderer.next_activity(ctx.sample_time) != Some(u64::MAX) {
+                if ctx.channels == 2 {
+                    renderer.render_stereo(ctx, data);
+                } else {
+                    renderer.render_mono(ctx, data);
+                }
+            }
+            renderer.alive()
+        })
+    }));
+    result.unwrap_or_else(|_| {
+        eprintln!("renderer panicked, removing it");
+        false
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{constant, manager, peak, render, BLOCK_SIZE, SAMPLE_RATE},
+        MusicParams,
+    };
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    /// Panics on its second block, counting the blocks it was asked for.
+    struct Faulty(Arc<AtomicUsize>);
+    impl Renderer for Faulty {
+        fn alive(&self) -> bool {
+            true
+        }
+
+        fn render_mono(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+            self.render_stereo(ctx, data)
+        }
+
+        fn render_stereo(&mut self, _ctx: &RenderContext, _data: &mut [f32]) {
+            if self.0.fetch_add(1, Ordering::SeqCst) == 1 {
+                panic!("faulty renderer");
+            }
+        }
+    }
+
+    #[test]
+    fn panicking_renderers_are_removed() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(constant(0.5, SAMPLE_RATE as usize), MusicParams::default())
+            .unwrap();
+        music.play().unwrap();
+        let blocks = Arc::new(AtomicUsize::new(0));
+        manager.add_renderer(Faulty(Arc::clone(&blocks))).unwrap();
+        for _ in 0..4 {
+            let data = render(&mut out, BLOCK_SIZE);
+            assert!(data.iter().all(|it| *it == 0.5));
+        }
+        assert_eq!(blocks.load(Ordering::SeqCst), 2);
+        assert_eq!(Arc::strong_count(&blocks), 1);
+    }
+
+    /// Records the start and length of every block it renders.
+    struct Clocked(Arc<Mutex<Vec<(u64, usize)>>>);
+    impl Renderer for Clocked {
+        fn alive(&self) -> bool {
+            true
+        }
+
+        fn render_mono(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+            self.0.lock().unwrap().push((ctx.sample_time, data.len()));
+        }
+
+        fn render_stereo(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((ctx.sample_time, data.len() / 2));
+        }
+    }
+
+    /// Writes the sample rate it is given.
+    struct Legacy;
+    #[allow(deprecated)]
+    impl LegacyRenderer for Legacy {
+        fn alive(&self) -> bool {
+            true
+        }
+
+        fn render_mono(&mut self, sample_rate: u32, data: &mut [f32]) {
+            data.fill(sample_rate as f32);
+        }
+
+        fn render_stereo(&mut self, sample_rate: u32, data: &mut [f32]) {
+            data.fill(sample_rate as f32);
+        }
+    }
+
+    #[test]
+    fn sample_time_is_continuous_across_blocks() {
+        let (mut manager, mut out) = manager();
+        let blocks = Arc::default();
+        manager.add_renderer(Clocked(Arc::clone(&blocks))).unwrap();
+        render(&mut out, BLOCK_SIZE * 8);
+        let blocks = blocks.lock().unwrap();
+        assert_eq!(blocks.len(), 8);
+        assert!(blocks
+            .windows(2)
+            .all(|it| it[1].0 == it[0].0 + it[0].1 as u64));
+    }
+
+    #[test]
+    fn legacy_renderers_receive_the_sample_rate() {
+        let (mut manager, mut out) = manager();
+        manager.add_renderer(Legacy).unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        assert!(data.iter().all(|it| *it == SAMPLE_RATE as f32));
+    }
+
+    /// Counts its blocks, reporting itself idle while `idle` is set.
+    struct Idle {
+        idle: Arc<AtomicBool>,
+        blocks: Arc<AtomicUsize>,
+    }
+    impl Renderer for Idle {
+        fn alive(&self) -> bool {
+            true
+        }
+
+        fn next_activity(&self, _now: u64) -> Option<u64> {
+            self.idle.load(Ordering::SeqCst).then_some(u64::MAX)
+        }
+
+        fn render_mono(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+            self.render_stereo(ctx, data)
+        }
+
+        fn render_stereo(&mut self, _ctx: &RenderContext, _data: &mut [f32]) {
+            self.blocks.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn idle_renderers_are_skipped() {
+        let (mut manager, mut out) = manager();
+        let idle = Arc::new(AtomicBool::new(true));
+        let blocks = Arc::new(AtomicUsize::new(0));
+        manager
+            .add_renderer(Idle {
+                idle: Arc::clone(&idle),
+                blocks: Arc::clone(&blocks),
+            })
+            .unwrap();
+        render(&mut out, BLOCK_SIZE * 4);
+        assert_eq!(blocks.load(Ordering::SeqCst), 0);
+        idle.store(false, Ordering::SeqCst);
+        render(&mut out, BLOCK_SIZE * 4);
+        assert_eq!(blocks.load(Ordering::SeqCst), 4);
+        idle.store(true, Ordering::SeqCst);
+        render(&mut out, BLOCK_SIZE * 4);
+        assert_eq!(blocks.load(Ordering::SeqCst), 4);
+        // Still alive, so kept.
+        assert_eq!(Arc::strong_count(&blocks), 2);
+    }
+
+    #[test]
+    fn pushes_to_idle_tracks_are_never_missed() {
+        let (mut manager, mut out) = manager();
+        let mut tracks = Vec::new();
+        for _ in 0..200 {
+            let clip = constant(0.5, SAMPLE_RATE as usize);
+            tracks.push(manager.create_music(clip, MusicParams::default()).unwrap());
+            // Lets the mixer take the renderers off its queue.
+            render(&mut out, BLOCK_SIZE);
+        }
+        for (i, music) in tracks.iter().enumerate() {
+            music.play().unwrap();
+            let data = render(&mut out, BLOCK_SIZE);
+            assert!(data.iter().all(|it| *it == 0.5), "track {i}");
+            music.pause().unwrap();
+            assert_eq!(peak(&render(&mut out, BLOCK_SIZE)), 0., "track {i}");
+            let played = BLOCK_SIZE as f64 / SAMPLE_RATE as f64;
+            assert!((music.position() - played).abs() < 1e-9, "track {i}");
+        }
+    }
+
+    /// Forwards to a renderer, but is always rendered whatever it reports,
+    /// as every renderer was before idle ones were skipped.
+    struct Always<R>(R);
+    impl<R: Renderer> Renderer for Always<R> {
+        fn alive(&self) -> bool {
+            self.0.alive()
+        }
+
+        fn render_mono(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+            self.0.render_mono(ctx, data)
+        }
+
+        fn render_stereo(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+            self.0.render_stereo(ctx, data)
+        }
+    }
+
+    #[test]
+    #[ignore = "timing, run with `--ignored --nocapture` in release"]
+    fn skipping_paused_tracks_is_cheaper() {
+        let time = |skip: bool| {
+            let (mut manager, mut out) = manager();
+            let mut tracks = Vec::new();
+            for _ in 0..200 {
+                let clip = constant(0.5, SAMPLE_RATE as usize);
+                if skip {
+                    tracks.push(manager.create_music(clip, MusicParams::default()).unwrap());
+                } else {
+                    let (music, renderer) = Music::new(
+                        clip,
+                        MusicParams::default(),
+                        Arc::clone(&manager.connected),
+                        Arc::clone(&manager.latency),
+                    );
+                    manager.add_renderer(Always(renderer)).unwrap();
+                    tracks.push(music);
+                }
+                render(&mut out, BLOCK_SIZE);
+            }
+            // Best of a few runs, to leave out other load on the machine.
+            (0..5)
+                .map(|_| {
+                    let start = std::time::Instant::now();
+                    render(&mut out, BLOCK_SIZE * 1000);
+                    start.elapsed()
+                })
+                .min()
+                .unwrap()
+        };
+        let skipped = time(true);
+        let rendered = time(false);
+        println!(
+            "200 paused tracks, 1000 blocks: skipped in {skipped:?}, rendered in {rendered:?}"
+        );
+        assert!(skipped < rendered);
+    }
+}
@@ -0,0 +1,264 @@
+use crate::{
+    buffer_is_full, check_connected, db_to_amplitude, dsp::Ramp, recorder::RecorderSink,
+    render_isolated, validate, Frame, RenderContext, Renderer,
+};
+use anyhow::{Context, Result};
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Weak,
+};
+
+/// Identifies a [`BusHandle`], e.g. to find its file recording.
+static NEXT_BUS: AtomicU64 = AtomicU64::new(0);
+
+enum BusCommand {
+    AddRenderer(Box<dyn Renderer>),
+    SetGain(f32, f64),
+    SetLowPass(f32, f64),
+    SetRecorder(Option<RecorderSink>),
+}
+
+pub(crate) struct BusRenderer {
+    arc: Weak<()>,
+    cons: HeapConsumer<BusCommand>,
+    renderers: Vec<Box<dyn Renderer>>,
+    scratch: Vec<f32>,
+
+    gain: Ramp,
+    low_pass: Ramp,
+    last_output: Frame,
+    recorder: Option<RecorderSink>,
+    /// Applied to renderers added later.
+    sfx_paused: bool,
+}
+
+impl BusRenderer {
+    fn prepare(&mut self, sample_rate: u32) {
+        while let Some(cmd) = self.cons.pop() {
+            match cmd {
+                BusCommand::AddRenderer(mut renderer) => {
+                    if self.sfx_paused {
+                        renderer.set_sfx_paused(true);
+                    }
+                    self.renderers.push(renderer);
+                }
+                BusCommand::SetGain(gain, time) => self.gain.set(gain, time, sample_rate),
+                BusCommand::SetLowPass(low_pass, time) => {
+                    self.low_pass.set(low_pass, time, sample_rate)
+                }
+                BusCommand::SetRecorder(recorder) => self.recorder = recorder,
+            }
+        }
+    }
+
+    /// Renders every renderer of the bus into the scratch buffer, then mixes
+    /// it into `data` through the bus gain and low-pass filter, leaving the
+    /// contribution of the bus in the scratch buffer for the recorder.
+    fn render(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+        self.prepare(ctx.sample_rate);
+        if !self.last_output.is_finite() {
+            self.last_output = Frame::default();
+        }
+        self.scratch.clear();
+        self.scratch.resize(data.len(), 0.);
+        let inner = RenderContext {
+            output_gain: ctx.output_gain * self.gain.value,
+            ..ctx.clone()
+        };
+        let scratch = &mut self.scratch;
+        self.renderers
+            .retain_mut(|renderer| render_isolated(renderer, &inner, scratch));
+        let stereo = ctx.channels == 2;
+        let channels = ctx.channels as usize;
+        for (output, input) in data
+            .chunks_exact_mut(channels)
+            .zip(self.scratch.chunks_exact_mut(channels))
+        {
+            let gain = self.gain.next();
+            let low_pass = if ctx.degraded {
+                0.
+            } else {
+                self.low_pass.next()
+            };
+            let frame = Frame(input[0], input[channels - 1]);
+            self.last_output = self.last_output * low_pass + frame * (1. - low_pass);
+            input[0] = self.last_output.0 * gain;
+            output[0] += input[0];
+            if stereo {
+                input[1] = self.last_output.1 * gain;
+                output[1] += input[1];
+            }
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.write(&self.scratch, ctx.channels);
+        }
+    }
+}
+
+impl Renderer for BusRenderer {
+    fn alive(&self) -> bool {
+        !self.renderers.is_empty() || !self.cons.is_empty() || self.arc.strong_count() != 0
+    }
+
+    fn stop_all_sfx(&mut self, fade: f32) {
+        for renderer in &mut self.renderers {
+            renderer.stop_all_sfx(fade);
+        }
+    }
+
+    fn set_sfx_paused(&mut self, paused: bool) {
+        self.sfx_paused = paused;
+        for renderer in &mut self.renderers {
+            renderer.set_sfx_paused(paused);
+        }
+    }
+
+    fn reset_filters(&mut self) {
+        self.last_output = Frame::default();
+        for renderer in &mut self.renderers {
+            renderer.reset_filters();
+        }
+    }
+
+    /// The earliest activity of the renderers of the bus, once the tail of
+    /// the low-pass filter has died down and the ramps are done.
+    fn next_activity(&self, now: u64) -> Option<u64> {
+        let ringing = self.last_output.0.abs().max(self.last_output.1.abs()) > f32::EPSILON;
+        let ramping = !self.gain.settled() || !self.low_pass.settled();
+        if ringing || ramping || !self.cons.is_empty() {
+            return None;
+        }
+        self.renderers
+            .iter()
+            .try_fold(u64::MAX, |at, it| Some(at.min(it.next_activity(now)?)))
+    }
+
+    fn render_mono(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+        self.render(ctx, data);
+    }
+
+    fn render_stereo(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+        self.render(ctx, data);
+    }
+}
+
+/// Handle to a group of renderers that are summed, filtered and attenuated
+/// together before reaching the master output.
+///
+/// The bus keeps playing its renderers after the handle is dropped, but can
+/// no longer be controlled.
+pub struct BusHandle {
+    _arc: Arc<()>,
+    id: u64,
+    prod: HeapProducer<BusCommand>,
+    connected: Arc<AtomicBool>,
+}
+
+impl BusHandle {
+    /// `max_frames` is the longest block the bus is ready to render without
+    /// allocating.
+    pub(crate) fn new(connected: Arc<AtomicBool>, max_frames: usize) -> (BusHandle, BusRenderer) {
+        let (prod, cons) = HeapRb::new(16).split();
+        let arc = Arc::new(());
+        let renderer = BusRenderer {
+            arc: Arc::downgrade(&arc),
+            cons,
+            renderers: Vec::new(),
+            scratch: Vec::with_capacity(max_frames * 2),
+
+            gain: Ramp::new(1.),
+            low_pass: Ramp::new(0.),
+            last_output: Frame(0., 0.),
+            recorder: None,
+            sfx_paused: false,
+        };
+        (
+            Self {
+                _arc: arc,
+                id: NEXT_BUS.fetch_add(1, Ordering::Relaxed),
+                prod,
+                connected,
+            },
+            renderer,
+        )
+    }
+
+    pub fn add_renderer(&mut self, renderer: impl Renderer + 'static) -> Result<()> {
+        check_connected(&self.connected)?;
+        self.prod
+            .push(BusCommand::AddRenderer(Box::new(renderer)))
+            .map_err(buffer_is_full)
+            .context("add renderer to bus")
+    }
+
+    /// Ramps the gain of the bus to `gain` over `ramp` seconds.
+    pub fn set_gain(&mut self, gain: f32, ramp: f64) -> Result<()> {
+        check_connected(&self.connected)?;
+        let gain = validate("gain", gain as f64, f64::MIN, f64::MAX).context("set bus gain")?;
+        let ramp = validate("ramp", ramp, 0., f64::MAX).context("set bus gain")?;
+        self.prod
+            .push(BusCommand::SetGain(gain as f32, ramp))
+            .map_err(buffer_is_full)
+            .context("set bus gain")
+    }
+
+    /// Like [`BusHandle::set_gain`], in decibels, see [`db_to_amplitude`].
+    pub fn set_gain_db(&mut self, db: f32, ramp: f64) -> Result<()> {
+        let db = validate("gain", db as f64, f64::MIN, f64::MAX).context("set bus gain")?;
+        self.set_gain(db_to_amplitude(db as f32), ramp)
+    }
+
+    /// Ramps the low pass coefficient of the bus to `low_pass` over `ramp`
+    /// seconds. A coefficient of 0 leaves the signal unfiltered.
+    pub fn set_low_pass(&mut self, low_pass: f32, ramp: f64) -> Result<()> {
+        check_connected(&self.connected)?;
+        let low_pass = validate("low pass", low_pass as f64, 0., 1.).context("set bus low pass")?;
+        let ramp = validate("ramp", ramp, 0., f64::MAX).context("set bus low pass")?;
+        self.prod
+            .push(BusCommand::SetLowPass(low_pass as f32, ramp))
+            .map_err(buffer_is_full)
+            .context("set bus low pass")
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn set_recorder(&mut self, recorder: Option<RecorderSink>) -> Result<()> {
+        check_connected(&self.connected)?;
+        self.prod
+            .push(BusCommand::SetRecorder(recorder))
+            .map_err(buffer_is_full)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::SAMPLE_RATE;
+
+    #[test]
+    fn scratch_is_allocated_up_front() {
+        let (_bus, mut renderer) = BusHandle::new(Arc::new(AtomicBool::new(true)), 1024);
+        let scratch = renderer.scratch.as_ptr();
+        let mut data = vec![0.; 1024 * 2];
+        for (frames, channels) in [(1024, 2), (333, 2), (17, 2), (1024, 1), (1, 1)] {
+            let ctx = RenderContext {
+                sample_rate: SAMPLE_RATE,
+                sample_time: 0,
+                channels,
+                degraded: false,
+                output_gain: 1.,
+            };
+            let data = &mut data[..frames * channels as usize];
+            if channels == 2 {
+                renderer.render_stereo(&ctx, data);
+            } else {
+                renderer.render_mono(&ctx, data);
+            }
+            assert_eq!(renderer.scratch.as_ptr(), scratch);
+            assert_eq!(renderer.scratch.len(), data.len());
+        }
+    }
+}
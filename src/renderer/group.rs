@@ -0,0 +1,129 @@
+use super::music::{GroupCommand, MusicCommand, MusicLink};
+use crate::{check_connected, validate, Music};
+use anyhow::{Context, Result};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+
+/// Release point shared by a group, its members' pending commands and the
+/// mixer.
+///
+/// The handle bumps `requested` once a command has been pushed to every
+/// member. The mixer copies it to `released` before rendering a block, so
+/// that all members see the release on the same block.
+#[derive(Default)]
+pub(crate) struct GroupRelease {
+    pub(crate) requested: AtomicU64,
+    pub(crate) released: AtomicU64,
+}
+
+/// Handle to a set of [`Music`] tracks controlled together.
+///
+/// Every operation takes effect on all members on the same audio block.
+/// Members that have been dropped are skipped silently.
+pub struct GroupHandle {
+    release: Arc<GroupRelease>,
+    seq: u64,
+    members: Vec<MusicLink>,
+    connected: Arc<AtomicBool>,
+}
+
+impl GroupHandle {
+    pub(crate) fn new(
+        members: &[&Music],
+        connected: Arc<AtomicBool>,
+    ) -> (GroupHandle, Arc<GroupRelease>) {
+        let release = Arc::<GroupRelease>::default();
+        (
+            Self {
+                release: Arc::clone(&release),
+                seq: 0,
+                members: members.iter().map(|it| it.link()).collect(),
+                connected,
+            },
+            release,
+        )
+    }
+
+    pub fn add(&mut self, music: &Music) {
+        if !self.contains(music) {
+            self.members.push(music.link());
+        }
+    }
+
+    pub fn remove(&mut self, music: &Music) {
+        self.members.retain(|it| !it.links(music));
+    }
+
+    pub fn contains(&self, music: &Music) -> bool {
+        self.members.iter().any(|it| it.links(music))
+    }
+
+    /// Number of members, including dropped ones not yet skipped by an
+    /// operation.
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Pushes `cmd` to every member, then releases it. If some members'
+    /// buffers are full, the others still apply the command and the first
+    /// error is returned.
+    fn push(&mut self, cmd: GroupCommand) -> Result<()> {
+        check_connected(&self.connected)?;
+        self.seq += 1;
+        let mut result = Ok(());
+        self.members.retain(|member| {
+            let cmd = MusicCommand::Group(Arc::clone(&self.release), self.seq, cmd);
+            match member.push(cmd) {
+                None => false,
+                Some(Err(err)) => {
+                    if result.is_ok() {
+                        result = Err(err);
+                    }
+                    true
+                }
+                Some(Ok(())) => true,
+            }
+        });
+        self.release.requested.store(self.seq, Ordering::SeqCst);
+        result
+    }
+
+    pub fn set_amplifier(&mut self, amp: f32) -> Result<()> {
+        let amp =
+            validate("amplifier", amp as f64, f64::MIN, f64::MAX).context("set group amplifier")?;
+        self.push(GroupCommand::SetAmplifier(amp as f32))
+            .context("set group amplifier")
+    }
+
+    pub fn fade_in(&mut self, time: f64) -> Result<()> {
+        let time = validate("time", time, 0., f64::MAX).context("fade in group")?;
+        self.push(GroupCommand::FadeIn(time))
+            .context("fade in group")
+    }
+
+    pub fn fade_out(&mut self, time: f64) -> Result<()> {
+        let time = validate("time", time, 0., f64::MAX).context("fade out group")?;
+        self.push(GroupCommand::FadeOut(time))
+            .context("fade out group")
+    }
+
+    pub fn pause(&mut self) -> Result<()> {
+        self.push(GroupCommand::Pause).context("pause group")
+    }
+
+    pub fn resume(&mut self) -> Result<()> {
+        self.push(GroupCommand::Resume).context("resume group")
+    }
+
+    pub fn seek_to(&mut self, position: f64) -> Result<()> {
+        let position = validate("position", position, 0., f64::MAX).context("seek group")?;
+        self.push(GroupCommand::SeekTo(position))
+            .context("seek group")
+    }
+}
@@ -1,309 +1,3777 @@
-use crate::{buffer_is_full, AudioClip, Frame, Renderer};
-use anyhow::{Context, Result};
-use atomic_float::AtomicF64;
+use super::GroupRelease;
+use crate::{
+    buffer_is_full, check_connected,
+    dsp::{apply_fade, pan_gains},
+    snapshot::{Probe, Probed},
+    validate, AudioClip, Frame, RenderContext, Renderer, TempoMap,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use atomic_float::{AtomicF32, AtomicF64};
 use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Weak,
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
 };
 
+/// Capacity of the dedicated lane for transport commands.
+const TRANSPORT_LANE_SIZE: usize = 4;
+
+const OVERFLOW_NONE: u64 = 0;
+const OVERFLOW_PAUSE: u64 = 1;
+const OVERFLOW_RESUME: u64 = 2;
+const OVERFLOW_STOP: u64 = 3;
+
+const SETTER_SLOTS: usize = 4;
+
+/// Maximum number of commands waiting in the schedule of a track.
+const MAX_SCHEDULED: u32 = 32;
+
+/// Maximum number of ducks registered on a track.
+const MAX_DUCKS: u32 = 8;
+
+/// Seconds within which a play head is taken to have reached a point.
+const POSITION_EPSILON: f64 = 1e-9;
+
+/// Length in seconds of the grains used by [`MusicParams::preserve_pitch`].
+const STRETCH_GRAIN: f64 = 0.04;
+
+/// What happens when playback reaches the end of the clip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopMode {
+    /// Stop at the end of the clip.
+    Off,
+    /// Wrap back to the start, mixing the tail with the head over
+    /// [`MusicParams::loop_mix_time`].
+    Forward,
+    /// Reverse direction at either end of the clip.
+    PingPong,
+}
+
+/// What a setter does when the command buffer of its track is full, see
+/// [`MusicParams::command_overflow`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CommandOverflow {
+    /// Return an error, leaving the value unchanged.
+    #[default]
+    Fail,
+    /// Replace the value of the amplifier, pan, playback rate or low pass
+    /// not applied yet, so that only the latest one is applied. Other
+    /// commands still fail.
+    Coalesce,
+}
+
 #[derive(Debug, Clone)]
 pub struct MusicParams {
+    /// Seconds of the clip over which the tail is mixed with the head when
+    /// looping. Negative values disable looping.
+    ///
+    /// Like every position of the track, this is measured in the source
+    /// clip, so the wrap happens at the same sample of the clip whatever
+    /// the playback rate.
     pub loop_mix_time: f64,
+    /// Length in seconds of a fade applied before the end of the clip when
+    /// it is not looping. Zero disables it.
+    pub auto_fade_out: f64,
+    /// Linear gain. Negative values invert the phase; use
+    /// [`Music::set_muted`] to silence the track.
     pub amplifier: f32,
+    /// Balance from -1, full left, to 1, full right, see [`Music::set_pan`].
+    pub pan: f32,
+    /// Times the clip is played in [`LoopMode::Forward`] before playing to
+    /// its end like [`LoopMode::Off`], without mixing the tail over the head
+    /// on the last pass. `None` or `Some(0)` loops forever. See
+    /// [`Music::set_loop_count`].
+    pub loop_count: Option<u32>,
+    /// Seconds of the clip [`LoopMode::Forward`] wraps back to, with the
+    /// head mixed over the tail from there. See [`Music::set_loop_region`].
+    pub loop_start: f64,
+    /// Seconds of the clip at which [`LoopMode::Forward`] wraps around, the
+    /// end of the clip if `None`. Creating a track fails unless
+    /// `loop_start < loop_end <= length`.
+    pub loop_end: Option<f64>,
+    /// Seconds of the clip played per second, see
+    /// [`Music::set_playback_rate`].
     pub playback_rate: f64,
     pub command_buffer_size: usize,
+    /// What setters do once `command_buffer_size` commands are pending.
+    /// Transport commands, i.e. play, pause, stop and seeks, never fail and
+    /// only the latest of them is kept on overflow.
+    pub command_overflow: CommandOverflow,
+    /// Change the tempo without changing the pitch when playing at a rate
+    /// other than 1, using overlap-add of short grains. Meant for rates
+    /// between 0.5 and 1.5 and costs noticeably more CPU.
+    ///
+    /// Positions are still reported in seconds of the clip.
+    pub preserve_pitch: bool,
+    /// Keep playing silently when a fade out completes instead of pausing,
+    /// so that the position stays in step with other tracks. A later fade
+    /// in or resume makes the track audible again.
+    ///
+    /// A silent track costs as much CPU as an audible one.
+    pub keep_time_when_silent: bool,
 }
 impl Default for MusicParams {
     fn default() -> Self {
         Self {
             loop_mix_time: -1.,
+            auto_fade_out: 0.,
             amplifier: 1.,
+            pan: 0.,
+            loop_count: None,
+            loop_start: 0.,
+            loop_end: None,
             playback_rate: 1.,
             command_buffer_size: 16,
+            command_overflow: CommandOverflow::Fail,
+            preserve_pitch: false,
+            keep_time_when_silent: false,
+        }
+    }
+}
+impl MusicParams {
+    pub(crate) fn validate(mut self) -> Result<Self> {
+        self.loop_mix_time = validate("loop mix time", self.loop_mix_time, f64::MIN, f64::MAX)?;
+        self.auto_fade_out = validate("auto fade out", self.auto_fade_out, 0., f64::MAX)?;
+        self.amplifier = validate("amplifier", self.amplifier as f64, f64::MIN, f64::MAX)? as f32;
+        self.pan = validate("pan", self.pan as f64, -1., 1.)? as f32;
+        self.loop_start = validate("loop start", self.loop_start, 0., f64::MAX)?;
+        if let Some(end) = self.loop_end {
+            self.loop_end = Some(validate("loop end", end, 0., f64::MAX)?);
+        }
+        self.playback_rate = validate("playback rate", self.playback_rate, 0., f64::MAX)?;
+        Ok(self)
+    }
+
+    /// Checks that the loop region is within a clip of `length` seconds.
+    pub(crate) fn check_loop_region(&self, length: f64) -> Result<()> {
+        if self.loop_start == 0. && self.loop_end.is_none() {
+            return Ok(());
         }
+        let end = self.loop_end.unwrap_or(length);
+        if self.loop_start >= end || end > length {
+            bail!(
+                "loop region {}..{end} is not within the clip of {length} seconds",
+                self.loop_start
+            );
+        }
+        Ok(())
     }
 }
 
+/// Start, end and mix time of the loop region `start..end` of a clip of
+/// `length` seconds, over the whole clip if it is not within it.
+fn loop_region(settings: &MusicParams, length: f64, frame_time: f64) -> (f64, f64, f64) {
+    let end = settings.loop_end.unwrap_or(length);
+    let (start, end) = if settings.loop_start < end && end <= length {
+        (settings.loop_start, end)
+    } else {
+        (0., length)
+    };
+    // Mixing over the whole region would leave nothing to loop over.
+    let mix = settings.loop_mix_time.min(end - start - frame_time).max(0.);
+    (start, end, mix)
+}
+
 struct SharedState {
     position: AtomicF64,
+    /// Pause state as last applied by the renderer.
     paused: AtomicBool,
+    /// Pause state requested through the handle in the lowest bit, whether
+    /// that pause is a fade out still running in the next one, whether
+    /// playback reached the end of the clip in the third, and a generation
+    /// bumped by every request in the others. The renderer only overwrites
+    /// it when nothing was requested since it last looked.
+    intent: AtomicU64,
+    keep_time_when_silent: bool,
+    looping: AtomicBool,
+    /// Forward loops left before the last pass, `u32::MAX` when looping
+    /// forever.
+    loops_left: AtomicU32,
+    /// End of the loop region in [`LoopMode::Forward`], NaN in other modes.
+    loop_end: AtomicF64,
+    /// Seconds of the clip played per forward loop.
+    loop_pass: AtomicF64,
+    /// Playback rate as last requested through the handle.
+    playback_rate: AtomicF64,
+
+    // Transport commands that did not fit in the transport lane, collapsed
+    // into the latest requested state.
+    /// One of the `OVERFLOW_*` states in the lowest two bits and the push
+    /// order of the command in the others.
+    overflow_paused: AtomicU64,
+    overflow_seek: AtomicF64,
+    overflow_seek_keep_fade: AtomicBool,
+    overflow_seek_order: AtomicU64,
+    /// Whether setters that do not fit in the general lane are coalesced,
+    /// see [`CommandOverflow::Coalesce`].
+    coalesce_setters: bool,
+    /// Values of the setters coalesced on overflow, NaN if none is
+    /// pending, see [`setter_slot`].
+    overflow_setters: [AtomicF64; SETTER_SLOTS],
+
+    /// Whether a stop has been pushed and not applied yet, during which the
+    /// position reads 0.
+    stopping: AtomicBool,
+
+    /// Commands pushed before this epoch are discarded by the renderer.
+    epoch: AtomicU64,
+
+    /// Commands scheduled and not yet applied or cleared.
+    scheduled: AtomicU32,
+
+    frames_played: AtomicU64,
+    pauses: AtomicU32,
+    seeks: AtomicU32,
+    loops: AtomicU32,
+
+    // Play head at the start of the latest block, read by the renderers of
+    // tracks ducked by this one. All renderers run on the audio thread, so
+    // these are never read while being written.
+    block_time: AtomicU64,
+    block_start: AtomicF64,
+    /// Seconds of the clip advanced per output frame, 0 while paused.
+    block_step: AtomicF64,
+
+    ducks: AtomicU32,
+
+    // Timing of the latest block, see `Music::remaining`.
+    /// Mixer frame at which `position` was reached, `u64::MAX` before the
+    /// first block.
+    clock: AtomicU64,
+    sample_rate: AtomicU32,
+    /// Output seconds until the running fade out ends, infinite without
+    /// one and 0 once silent.
+    fade_out_left: AtomicF64,
+    ab_looping: AtomicBool,
+
+    // Gains of the latest block, see `Music::gain_stages`.
+    effective_gain: AtomicF32,
+    amplifier_gain: AtomicF32,
+    fade_gain: AtomicF32,
+    duck_gain: AtomicF32,
+    output_gain: AtomicF32,
+}
+/// The generation of `intent` bumped, its flags cleared.
+fn next_intent(intent: u64) -> u64 {
+    ((intent >> 3) + 1) << 3
+}
+
+impl SharedState {
+    /// Requests a pause state, keeping the finished flag.
+    fn request_paused(&self, paused: bool, fading: bool) {
+        let _ = self
+            .intent
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |it| {
+                Some(next_intent(it) | it & 4 | (fading as u64) << 1 | paused as u64)
+            });
+    }
+
+    /// Turns a pending fade out back into playing.
+    fn cancel_fade_out(&self) {
+        let _ = self
+            .intent
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |it| {
+                (it & 2 != 0).then_some(next_intent(it) | it & 4)
+            });
+    }
+
+    fn clear_finished(&self) {
+        let _ = self
+            .intent
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |it| {
+                (it & 4 != 0).then_some(next_intent(it) | it & 3)
+            });
+    }
 }
 impl Default for SharedState {
     fn default() -> Self {
         Self {
             position: AtomicF64::default(),
             paused: AtomicBool::new(true),
+            intent: AtomicU64::new(1),
+            keep_time_when_silent: false,
+            looping: AtomicBool::new(false),
+            loops_left: AtomicU32::new(u32::MAX),
+            loop_end: AtomicF64::new(f64::NAN),
+            loop_pass: AtomicF64::new(0.),
+            playback_rate: AtomicF64::new(1.),
+
+            overflow_paused: AtomicU64::new(OVERFLOW_NONE),
+            overflow_seek: AtomicF64::new(f64::NAN),
+            overflow_seek_keep_fade: AtomicBool::new(false),
+            overflow_seek_order: AtomicU64::new(0),
+            coalesce_setters: false,
+            overflow_setters: std::array::from_fn(|_| AtomicF64::new(f64::NAN)),
+
+            stopping: AtomicBool::new(false),
+
+            epoch: AtomicU64::new(0),
+
+            scheduled: AtomicU32::new(0),
+
+            frames_played: AtomicU64::new(0),
+            pauses: AtomicU32::new(0),
+            seeks: AtomicU32::new(0),
+            loops: AtomicU32::new(0),
+
+            block_time: AtomicU64::new(u64::MAX),
+            block_start: AtomicF64::new(0.),
+            block_step: AtomicF64::new(0.),
+
+            ducks: AtomicU32::new(0),
+
+            clock: AtomicU64::new(u64::MAX),
+            sample_rate: AtomicU32::new(0),
+            fade_out_left: AtomicF64::new(f64::INFINITY),
+            ab_looping: AtomicBool::new(false),
+
+            effective_gain: AtomicF32::new(0.),
+            amplifier_gain: AtomicF32::new(1.),
+            fade_gain: AtomicF32::new(1.),
+            duck_gain: AtomicF32::new(1.),
+            output_gain: AtomicF32::new(1.),
+        }
+    }
+}
+
+/// Playback counters of a track, see [`Music::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MusicStats {
+    /// Output frames rendered while playing.
+    pub frames_played: u64,
+    /// Times playback was paused, by a command or at the end of a fade out.
+    pub pauses: u32,
+    pub seeks: u32,
+    /// Times playback wrapped around, in any loop mode or A/B loop.
+    pub loops: u32,
+}
+
+/// Gains multiplied together on the way from a track to the output, as of
+/// the end of the latest block, see [`Music::gain_stages`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GainStages {
+    /// The amplifier of the track, 0 while muted or silent after a fade out.
+    pub amplifier: f32,
+    /// Fade in, fade out and automatic fade out.
+    pub fade: f32,
+    /// Product of the ducks scheduled on the track.
+    pub duck: f32,
+    /// Gains of the buses the track is in and the master volume.
+    pub output: f32,
+}
+
+impl GainStages {
+    /// Product of all stages.
+    pub fn product(&self) -> f32 {
+        self.amplifier * self.fade * self.duck * self.output
+    }
+}
+
+/// A command applied when playback reaches a position, see
+/// [`Music::schedule`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScheduledCommand {
+    SetAmplifier(f32),
+    SetLowPass(f32),
+    SetLowPassSmooth { target: f32, time: f64 },
+    FadeIn(f64),
+    FadeOut(f64),
+}
+impl From<ScheduledCommand> for MusicCommand {
+    fn from(cmd: ScheduledCommand) -> Self {
+        match cmd {
+            ScheduledCommand::SetAmplifier(amp) => Self::SetAmplifier(amp),
+            ScheduledCommand::SetLowPass(low_pass) => Self::SetLowPass(low_pass),
+            ScheduledCommand::SetLowPassSmooth { target, time } => {
+                Self::SetLowPassSmooth(target, time)
+            }
+            ScheduledCommand::FadeIn(time) => Self::FadeIn(time),
+            ScheduledCommand::FadeOut(time) => Self::FadeOut(time),
         }
     }
 }
 
-enum MusicCommand {
+/// A command as applied by the renderer, recorded in the command log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AppliedCommand {
     Pause,
     Resume,
-    SetAmplifier(f32),
+    Stop,
     SeekTo(f64),
+    SetAmplifier(f32),
+    SetMuted(bool),
+    SetPan(f32),
+    SetPlaybackRate(f64),
+    SetLowPass(f32),
+    SetLowPassSmooth { target: f32, time: f64 },
+    FadeIn(f64),
+    FadeOut(f64),
+    SetLoopMode(LoopMode),
+    SetLoopCount(Option<u32>),
+    SetLoopRegion(Option<(f64, f64)>),
+    SetRateEnvelope,
+    SetAbLoop(Option<(f64, f64)>),
+}
+
+type CommandLogEntry = (u64, AppliedCommand);
+
+/// A command with the epoch it was pushed in and its push order, shared by
+/// both lanes so that the renderer applies them in the order pushed.
+type TaggedCommand = (u64, u64, MusicCommand);
+
+pub(super) enum MusicCommand {
+    // Transport commands, routed to their own lane.
+    Pause,
+    Resume,
+    /// Pauses, rewinds and clears the fade and filter state at once.
+    Stop,
+    /// Seeks to a position, keeping a running fade out if set.
+    SeekTo(f64, bool),
+
+    SetAmplifier(f32),
+    SetMuted(bool),
+    SetPan(f32),
+    SetPlaybackRate(f64),
     SetLowPass(f32),
+    SetLowPassSmooth(f32, f64),
+    FadeIn(f64),
+    FadeOut(f64),
+    SetLoopMode(LoopMode),
+    SetLoopCount(Option<u32>),
+    SetLoopRegion(Option<(f64, f64)>),
+    SetRateEnvelope(Vec<(f64, f32)>),
+    SetAbLoop(Option<(f64, f64)>),
+    Schedule(f64, ScheduledCommand),
+    ClearSchedule,
+    SetCommandLog(Option<HeapProducer<CommandLogEntry>>),
+    AddDuck(Arc<DuckSpec>),
+    ClearDucks,
+    /// A command of a group, held until the mixer releases `seq`.
+    Group(Arc<GroupRelease>, u64, GroupCommand),
+}
+
+// Commands are moved through fixed-size ring slots, so every variant is kept
+// small: payloads that would not fit are behind a pointer allocated by the
+// handle and sent back to it to be freed, see `MusicGarbage`.
+const _: () = assert!(std::mem::size_of::<MusicCommand>() <= 40);
+
+/// A command a group can send to its members.
+#[derive(Clone, Copy)]
+pub(super) enum GroupCommand {
+    Pause,
+    Resume,
+    SeekTo(f64),
+    SetAmplifier(f32),
     FadeIn(f64),
     FadeOut(f64),
 }
+impl From<GroupCommand> for MusicCommand {
+    fn from(cmd: GroupCommand) -> Self {
+        match cmd {
+            GroupCommand::Pause => Self::Pause,
+            GroupCommand::Resume => Self::Resume,
+            GroupCommand::SeekTo(position) => Self::SeekTo(position, false),
+            GroupCommand::SetAmplifier(amp) => Self::SetAmplifier(amp),
+            GroupCommand::FadeIn(time) => Self::FadeIn(time),
+            GroupCommand::FadeOut(time) => Self::FadeOut(time),
+        }
+    }
+}
+
+/// Allocations replaced or discarded by the renderer, sent back to the
+/// handle to be freed off the audio thread.
+#[allow(dead_code)] // Only held to be dropped.
+enum MusicGarbage {
+    RateEnvelope(Vec<(f64, f32)>),
+    CommandLog(HeapProducer<CommandLogEntry>),
+    Duck(Arc<DuckSpec>),
+}
+
+/// Lowers a track while the play head of another is within a region, see
+/// [`AudioManager::schedule_duck`](crate::AudioManager::schedule_duck).
+pub(super) struct DuckSpec {
+    trigger: PlayHead,
+    start: f64,
+    end: f64,
+    gain: f32,
+    ramp: f64,
+}
+
+/// The play head of a track, followed frame by frame by other renderers on
+/// the audio thread without keeping the track alive.
+pub(crate) struct PlayHead(Weak<SharedState>);
+impl PlayHead {
+    /// Position in seconds of the clip at the start of the block rendered
+    /// at `sample_time` and seconds advanced per frame of it, or `None` once
+    /// the track has been dropped.
+    pub(crate) fn block(&self, sample_time: u64) -> Option<(f64, f64)> {
+        let state = self.0.upgrade()?;
+        // A track rendered after the caller still holds the previous block,
+        // which ended where its next one starts.
+        let base = if state.block_time.load(Ordering::Relaxed) == sample_time {
+            state.block_start.load(Ordering::Relaxed)
+        } else {
+            state.position.load(Ordering::Relaxed)
+        };
+        Some((base, state.block_step.load(Ordering::Relaxed)))
+    }
+
+    fn position(&self) -> Option<f64> {
+        Some(self.0.upgrade()?.position.load(Ordering::Relaxed))
+    }
+}
+
+struct Duck {
+    spec: Arc<DuckSpec>,
+    gain: f32,
+    /// Trigger position at the start of the block and per frame of it.
+    base: f64,
+    step: f64,
+}
+impl MusicCommand {
+    fn applied(&self) -> Option<AppliedCommand> {
+        Some(match *self {
+            Self::Pause => AppliedCommand::Pause,
+            Self::Resume => AppliedCommand::Resume,
+            Self::Stop => AppliedCommand::Stop,
+            Self::SeekTo(position, _) => AppliedCommand::SeekTo(position),
+            Self::SetAmplifier(amp) => AppliedCommand::SetAmplifier(amp),
+            Self::SetMuted(muted) => AppliedCommand::SetMuted(muted),
+            Self::SetPan(pan) => AppliedCommand::SetPan(pan),
+            Self::SetPlaybackRate(rate) => AppliedCommand::SetPlaybackRate(rate),
+            Self::SetLowPass(low_pass) => AppliedCommand::SetLowPass(low_pass),
+            Self::SetLowPassSmooth(target, time) => {
+                AppliedCommand::SetLowPassSmooth { target, time }
+            }
+            Self::FadeIn(time) => AppliedCommand::FadeIn(time),
+            Self::FadeOut(time) => AppliedCommand::FadeOut(time),
+            Self::SetLoopMode(mode) => AppliedCommand::SetLoopMode(mode),
+            Self::SetLoopCount(count) => AppliedCommand::SetLoopCount(count),
+            Self::SetLoopRegion(region) => AppliedCommand::SetLoopRegion(region),
+            Self::SetRateEnvelope(_) => AppliedCommand::SetRateEnvelope,
+            Self::SetAbLoop(region) => AppliedCommand::SetAbLoop(region),
+            Self::Schedule(..)
+            | Self::ClearSchedule
+            | Self::SetCommandLog(_)
+            | Self::AddDuck(_)
+            | Self::ClearDucks => return None,
+            Self::Group(_, _, cmd) => return MusicCommand::from(cmd).applied(),
+        })
+    }
+}
+/// A windowed grain of the time stretcher, reading the clip at unity speed.
+#[derive(Clone, Copy, Default)]
+struct Grain {
+    start: f64,
+    phase: usize,
+    direction: f64,
+}
+
 pub(crate) struct MusicRenderer {
     clip: AudioClip,
     settings: MusicParams,
     state: Weak<SharedState>,
-    cons: HeapConsumer<MusicCommand>,
+    transport: HeapConsumer<TaggedCommand>,
+    cons: HeapConsumer<TaggedCommand>,
+    garbage: HeapProducer<MusicGarbage>,
+    /// Group command popped before its release, applied first next block.
+    held: Option<TaggedCommand>,
     paused: bool,
-    index: usize,
+    muted: bool,
+    /// Paused by itself at the end of the clip or of a fade out, and not
+    /// yet reported in the intended state.
+    unreported_pause: bool,
+    /// The last pause was the end of a fade out.
+    faded_out: bool,
+    /// Faded out but still advancing, see
+    /// [`MusicParams::keep_time_when_silent`].
+    silent: bool,
+    /// Playback position in seconds of the clip.
+    position: f64,
+    loop_mode: LoopMode,
+    /// Forward loops left before the last pass, `None` when looping forever.
+    loops_left: Option<u32>,
+    /// Start, end and mix time of the loop region, see [`loop_region`].
+    region: (f64, f64, f64),
+    reversed: bool,
     last_sample_rate: u32,
     low_pass: f32,
+    low_pass_target: f32,
+    low_pass_remaining: i32,
     last_output: Frame,
+    /// Gains of the left and right channel for the pan, applied to stereo
+    /// output only so that mono output keeps its level.
+    pan_gains: (f32, f32),
+
+    /// Fade length and progress in frames, negative when fading out.
+    fade_time: i64,
+    fade_current: i64,
+
+    /// `(time, rate)` points, with the time in seconds of playback since
+    /// the envelope was set.
+    rate_envelope: Vec<(f64, f32)>,
+    rate_envelope_time: f64,
+    rate_envelope_index: usize,
+
+    /// Region `a..b` in seconds of the clip that playback cycles through.
+    ab_loop: Option<(f64, f64)>,
+
+    /// Commands sorted by the position they apply at.
+    schedule: Vec<(f64, ScheduledCommand)>,
 
-    fade_time: i32,
-    fade_current: i32,
+    grains: [Grain; 2],
+    grain_len: usize,
+
+    /// Sample without interpolation, set while the mixer is degraded.
+    degraded: bool,
+
+    /// Loops completed during the current block, added to the shared
+    /// counter once the block is done.
+    loops: u32,
+
+    ducks: Vec<Duck>,
+    /// Frames played in the current block, to follow the trigger of ducks.
+    block_frame: u64,
+
+    /// Frames rendered since creation, paused or not, counted from the
+    /// mixer clock so that blocks skipped while idle are included.
+    clock: u64,
+    /// Mixer frame of the first block rendered.
+    clock_origin: Option<u64>,
+    command_log: Option<HeapProducer<CommandLogEntry>>,
 }
 impl MusicRenderer {
     fn prepare(&mut self, sample_rate: u32) {
         if self.last_sample_rate != sample_rate {
-            let factor = sample_rate as f32 / self.last_sample_rate as f32;
-            self.index = (self.index as f32 * factor).round() as _;
+            let factor = sample_rate as f64 / self.last_sample_rate as f64;
             self.last_sample_rate = sample_rate;
-            self.fade_time = (self.fade_time as f32 * factor).round() as _;
-            self.fade_current = (self.fade_current as f32 * factor).round() as _;
+            self.fade_time = (self.fade_time as f64 * factor).round() as _;
+            self.fade_current = (self.fade_current as f64 * factor).round() as _;
+            self.low_pass_remaining = (self.low_pass_remaining as f64 * factor).round() as _;
         }
-        for cmd in self.cons.pop_iter() {
-            match cmd {
-                MusicCommand::Pause => {
-                    self.paused = true;
-                    if let Some(state) = self.state.upgrade() {
-                        state.paused.store(true, Ordering::SeqCst);
+        let Some(state) = self.state.upgrade() else {
+            return;
+        };
+        let epoch = state.epoch.load(Ordering::SeqCst);
+        // Reattaching the command log is never discarded, as the handle has
+        // already swapped its end of the log.
+        let current = |(tag, _, cmd): &TaggedCommand| {
+            *tag >= epoch || matches!(cmd, MusicCommand::SetCommandLog(_))
+        };
+        let overflow = state.overflow_paused.swap(OVERFLOW_NONE, Ordering::SeqCst);
+        let mut paused = match overflow & 3 {
+            OVERFLOW_PAUSE => Some(MusicCommand::Pause),
+            OVERFLOW_RESUME => Some(MusicCommand::Resume),
+            OVERFLOW_STOP => Some(MusicCommand::Stop),
+            _ => None,
+        }
+        .map(|cmd| (overflow >> 2, cmd));
+        let seek = state.overflow_seek.swap(f64::NAN, Ordering::SeqCst);
+        let mut seek = (!seek.is_nan()).then(|| {
+            let keep_fade = state.overflow_seek_keep_fade.load(Ordering::SeqCst);
+            let order = state.overflow_seek_order.load(Ordering::SeqCst);
+            (order, MusicCommand::SeekTo(seek, keep_fade))
+        });
+        // Both lanes and the collapsed transport commands are merged by push
+        // order. A group command waiting for its release holds back the
+        // general lane only.
+        loop {
+            if self.held.is_none() {
+                self.held = self.cons.pop();
+            }
+            let general = self
+                .held
+                .as_ref()
+                .filter(|(_, _, cmd)| match cmd {
+                    MusicCommand::Group(release, seq, _) => {
+                        release.released.load(Ordering::SeqCst) >= *seq
                     }
+                    _ => true,
+                })
+                .map(|it| it.1);
+            let transport = self.transport.iter().next().map(|it| it.1);
+            let next = [
+                general,
+                transport,
+                paused.as_ref().map(|it| it.0),
+                seek.as_ref().map(|it| it.0),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            let Some(next) = next else {
+                break;
+            };
+            if paused.as_ref().is_some_and(|it| it.0 == next) {
+                self.apply(paused.take().unwrap().1, sample_rate);
+                continue;
+            }
+            if seek.as_ref().is_some_and(|it| it.0 == next) {
+                self.apply(seek.take().unwrap().1, sample_rate);
+                continue;
+            }
+            if transport == Some(next) {
+                let cmd = self.transport.pop().unwrap();
+                if current(&cmd) {
+                    self.apply(cmd.2, sample_rate);
                 }
-                MusicCommand::Resume => {
-                    self.paused = false;
-                    if let Some(state) = self.state.upgrade() {
-                        state.paused.store(false, Ordering::SeqCst);
+                continue;
+            }
+            let cmd = self.held.take().unwrap();
+            if current(&cmd) {
+                self.apply(cmd.2, sample_rate);
+            } else {
+                match cmd.2 {
+                    MusicCommand::Schedule(..) => {
+                        state.scheduled.fetch_sub(1, Ordering::SeqCst);
+                    }
+                    MusicCommand::SetRateEnvelope(envelope) => {
+                        self.retire(MusicGarbage::RateEnvelope(envelope));
+                    }
+                    MusicCommand::AddDuck(spec) => {
+                        state.ducks.fetch_sub(1, Ordering::SeqCst);
+                        self.retire(MusicGarbage::Duck(spec));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for (slot, value) in state.overflow_setters.iter().enumerate() {
+            let value = value.swap(f64::NAN, Ordering::SeqCst);
+            if !value.is_nan() {
+                self.apply(slot_setter(slot, value), sample_rate);
+            }
+        }
+    }
+
+    fn apply(&mut self, cmd: MusicCommand, sample_rate: u32) {
+        if let MusicCommand::Group(_, _, cmd) = cmd {
+            return self.apply(cmd.into(), sample_rate);
+        }
+        if let Some(log) = &mut self.command_log {
+            if let Some(applied) = cmd.applied() {
+                let _ = log.push((self.clock, applied));
+            }
+        }
+        if matches!(
+            cmd,
+            MusicCommand::Pause
+                | MusicCommand::Resume
+                | MusicCommand::Stop
+                | MusicCommand::FadeIn(_)
+                | MusicCommand::FadeOut(_)
+        ) {
+            self.unreported_pause = false;
+        }
+        match cmd {
+            MusicCommand::Pause => {
+                if let Some(state) = self.state.upgrade() {
+                    state.paused.store(true, Ordering::SeqCst);
+                    if !self.paused {
+                        state.pauses.fetch_add(1, Ordering::Relaxed);
                     }
                 }
-                MusicCommand::SetAmplifier(amp) => {
-                    self.settings.amplifier = amp;
+                self.paused = true;
+            }
+            MusicCommand::Stop => {
+                if let Some(state) = self.state.upgrade() {
+                    state.paused.store(true, Ordering::SeqCst);
+                    state.position.store(0., Ordering::SeqCst);
+                    state.stopping.store(false, Ordering::SeqCst);
+                    if !self.paused {
+                        state.pauses.fetch_add(1, Ordering::Relaxed);
+                    }
                 }
-                MusicCommand::SeekTo(position) => {
-                    self.index = (position * sample_rate as f64 / self.settings.playback_rate)
-                        .round() as usize;
+                self.paused = true;
+                self.position = 0.;
+                self.reversed = false;
+                self.reset_loops();
+                self.fade_time = 0;
+                self.fade_current = 0;
+                self.silent = false;
+                self.faded_out = false;
+                self.last_output = Frame::default();
+            }
+            MusicCommand::Resume => {
+                self.rewind_if_ended();
+                self.paused = false;
+                self.silent = false;
+                if self.fade_time < 0 {
+                    // Turn the fade out into a fade in from the current gain.
+                    self.fade_time = -self.fade_time;
+                    self.fade_current += self.fade_time;
                 }
-                MusicCommand::SetLowPass(low_pass) => {
-                    self.low_pass = low_pass;
+                if let Some(state) = self.state.upgrade() {
+                    state.paused.store(false, Ordering::SeqCst);
                 }
-                MusicCommand::FadeIn(time) => {
-                    if self.paused {
+            }
+            MusicCommand::SetAmplifier(amp) => {
+                self.settings.amplifier = amp;
+            }
+            MusicCommand::SetPlaybackRate(rate) => {
+                self.settings.playback_rate = rate;
+            }
+            MusicCommand::SetMuted(muted) => {
+                self.muted = muted;
+            }
+            MusicCommand::SetPan(pan) => {
+                self.settings.pan = pan;
+                self.pan_gains = pan_gains(pan);
+            }
+            MusicCommand::SeekTo(position, keep_fade) => {
+                self.position = self.seek_target(position);
+                if !keep_fade {
+                    if self.fade_time < 0 {
+                        self.fade_time = 0;
+                    } else if self.faded_out && self.unreported_pause {
+                        // The fade ended after the seek was requested, as far
+                        // as the handle can tell.
+                        self.unreported_pause = false;
                         self.paused = false;
                         if let Some(state) = self.state.upgrade() {
                             state.paused.store(false, Ordering::SeqCst);
                         }
                     }
-                    self.fade_time = (time * sample_rate as f64).round() as _;
-                    self.fade_current = 0;
                 }
-                MusicCommand::FadeOut(time) => {
-                    self.fade_time = (-time * sample_rate as f64).round() as _;
-                    self.fade_current = 0;
+                if let Some(state) = self.state.upgrade() {
+                    state.seeks.fetch_add(1, Ordering::Relaxed);
                 }
             }
-        }
-    }
-
-    #[inline]
-    fn frame(&mut self, position: f64, delta: f64) -> Option<Frame> {
-        let s = &self.settings;
-        if let Some(mut frame) = self.clip.sample(position) {
-            if s.loop_mix_time >= 0. {
-                let pos = position + s.loop_mix_time - self.clip.length();
-                if pos >= 0. {
-                    if let Some(new_frame) = self.clip.sample(pos) {
-                        frame = frame + new_frame;
+            MusicCommand::SetLowPass(low_pass) => {
+                self.low_pass = low_pass;
+                self.low_pass_remaining = 0;
+            }
+            MusicCommand::SetLowPassSmooth(target, time) => {
+                self.low_pass_target = target;
+                self.low_pass_remaining = (time * sample_rate as f64).round() as _;
+                if self.low_pass_remaining <= 0 {
+                    self.low_pass = target;
+                }
+            }
+            MusicCommand::FadeIn(time) => {
+                let start = if self.paused || self.silent {
+                    0.
+                } else {
+                    self.fade_level().unwrap_or(0.)
+                };
+                self.rewind_if_ended();
+                if self.paused {
+                    self.paused = false;
+                    if let Some(state) = self.state.upgrade() {
+                        state.paused.store(false, Ordering::SeqCst);
                     }
                 }
+                self.silent = false;
+                self.fade_time = (time * sample_rate as f64).round() as _;
+                self.fade_current = (start * self.fade_time as f64).round() as _;
             }
-            self.index += 1;
-            let mut amp = s.amplifier;
-            if self.fade_time != 0 {
-                if self.fade_time > 0 {
-                    self.fade_current += 1;
-                    if self.fade_current >= self.fade_time {
-                        self.fade_time = 0;
+            MusicCommand::FadeOut(time) => {
+                let start = self.fade_level().unwrap_or(1.);
+                self.fade_time = (-time * sample_rate as f64).round() as _;
+                self.fade_current = ((1. - start) * self.fade_time as f64).round() as _;
+                if self.fade_time == 0 && !self.paused {
+                    // Shorter than a frame: ends right away, as the handle
+                    // already reports.
+                    if self.settings.keep_time_when_silent {
+                        self.silent = true;
                     } else {
-                        amp *= self.fade_current as f32 / self.fade_time as f32;
-                    }
-                } else {
-                    self.fade_current -= 1;
-                    if self.fade_current <= self.fade_time {
-                        self.fade_time = 0;
-                        self.paused = true;
+                        self.pause_by_itself(true);
                         if let Some(state) = self.state.upgrade() {
-                            state.paused.store(true, Ordering::SeqCst);
+                            state.pauses.fetch_add(1, Ordering::Relaxed);
                         }
-                        return None;
-                    } else {
-                        amp *= 1. - self.fade_current as f32 / self.fade_time as f32;
                     }
                 }
             }
-            Some(frame * amp)
-        } else if s.loop_mix_time >= 0. {
-            let position = position - self.clip.length() + s.loop_mix_time;
-            self.index = (position / delta).round() as _;
-            Some(if let Some(frame) = self.clip.sample(position) {
-                frame * s.amplifier
-            } else {
-                Frame::default()
-            })
-        } else {
-            self.paused = true;
-            None
+            MusicCommand::SetLoopMode(mode) => {
+                self.loop_mode = mode;
+                if mode != LoopMode::PingPong {
+                    self.reversed = false;
+                }
+                self.publish_looping();
+            }
+            MusicCommand::SetLoopCount(count) => {
+                self.settings.loop_count = count;
+                self.reset_loops();
+            }
+            MusicCommand::SetLoopRegion(region) => {
+                (self.settings.loop_start, self.settings.loop_end) = match region {
+                    Some((start, end)) => (start, Some(end)),
+                    None => (0., None),
+                };
+                self.region = loop_region(
+                    &self.settings,
+                    self.clip.length(),
+                    1. / self.clip.sample_rate().max(1) as f64,
+                );
+                self.publish_looping();
+            }
+            MusicCommand::SetRateEnvelope(envelope) => {
+                let previous = std::mem::replace(&mut self.rate_envelope, envelope);
+                self.retire(MusicGarbage::RateEnvelope(previous));
+                self.rate_envelope_time = 0.;
+                self.rate_envelope_index = 0;
+            }
+            MusicCommand::SetAbLoop(region) => {
+                self.ab_loop = region.map(|(a, b)| {
+                    let b = b.min(self.clip.length());
+                    (a.min(b), b)
+                });
+                if let Some((a, b)) = self.ab_loop {
+                    if !(a..b).contains(&self.position) {
+                        self.position = a;
+                    }
+                }
+            }
+            MusicCommand::Schedule(at, cmd) => {
+                let index = self.schedule.partition_point(|it| it.0 <= at);
+                self.schedule.insert(index, (at, cmd));
+            }
+            MusicCommand::ClearSchedule => {
+                if let Some(state) = self.state.upgrade() {
+                    state
+                        .scheduled
+                        .fetch_sub(self.schedule.len() as u32, Ordering::SeqCst);
+                }
+                self.schedule.clear();
+            }
+            MusicCommand::Group(..) => unreachable!(),
+            MusicCommand::AddDuck(spec) => {
+                let position = spec.trigger.position().unwrap_or(f64::NAN);
+                let gain = if spec.start <= position && position < spec.end {
+                    spec.gain
+                } else {
+                    1.
+                };
+                self.ducks.push(Duck {
+                    spec,
+                    gain,
+                    base: 0.,
+                    step: 0.,
+                });
+            }
+            MusicCommand::ClearDucks => {
+                if let Some(state) = self.state.upgrade() {
+                    state
+                        .ducks
+                        .fetch_sub(self.ducks.len() as u32, Ordering::SeqCst);
+                }
+                while let Some(duck) = self.ducks.pop() {
+                    self.retire(MusicGarbage::Duck(duck.spec));
+                }
+            }
+            MusicCommand::SetCommandLog(log) => {
+                if let Some(previous) = std::mem::replace(&mut self.command_log, log) {
+                    self.retire(MusicGarbage::CommandLog(previous));
+                }
+            }
         }
     }
 
-    #[inline]
-    fn position(&self, delta: f64) -> f64 {
-        self.index as f64 * delta
+    /// Sends an allocation back to the handle to be freed off the audio
+    /// thread. Dropped here only if the way back is full.
+    fn retire(&mut self, garbage: MusicGarbage) {
+        if let MusicGarbage::RateEnvelope(envelope) = &garbage {
+            if envelope.capacity() == 0 {
+                return;
+            }
+        }
+        let _ = self.garbage.push(garbage);
     }
 
-    #[inline(always)]
-    fn update_and_get(&mut self, frame: Frame) -> Frame {
-        self.last_output = self.last_output * self.low_pass + frame * (1. - self.low_pass);
-        self.last_output
+    /// Advances the rate envelope by one frame of `frame_time` seconds,
+    /// returning the rate to apply to it.
+    #[inline]
+    fn next_rate(&mut self, frame_time: f64) -> f64 {
+        let envelope = &self.rate_envelope;
+        let Some(&(_, last)) = envelope.last() else {
+            return 1.;
+        };
+        let time = self.rate_envelope_time;
+        self.rate_envelope_time += frame_time;
+        while self.rate_envelope_index + 1 < envelope.len()
+            && envelope[self.rate_envelope_index + 1].0 <= time
+        {
+            self.rate_envelope_index += 1;
+        }
+        let (start_time, start_rate) = envelope[self.rate_envelope_index];
+        if time <= start_time {
+            return start_rate as f64;
+        }
+        let Some(&(end_time, end_rate)) = envelope.get(self.rate_envelope_index + 1) else {
+            return last as f64;
+        };
+        let f = (time - start_time) / (end_time - start_time);
+        start_rate as f64 + (end_rate - start_rate) as f64 * f
     }
-}
 
-impl Renderer for MusicRenderer {
-    fn alive(&self) -> bool {
-        self.state.strong_count() != 0
+    #[inline]
+    fn sample(&self, position: f64) -> Option<Frame> {
+        if self.degraded {
+            self.clip.sample_nearest(position)
+        } else if self.wraps_forward() {
+            // Interpolate over the loop point: into the head of the clip
+            // when it starts right away, or out to silence when the tail
+            // is mixed over the head.
+            let (start, _, mix) = self.region;
+            let after = if mix > 0. {
+                Frame::default()
+            } else {
+                self.clip.sample(start).unwrap_or_default()
+            };
+            self.clip.sample_then(position, after)
+        } else {
+            self.clip.sample(position)
+        }
     }
 
-    fn render_mono(&mut self, sample_rate: u32, data: &mut [f32]) {
-        self.prepare(sample_rate);
-        if !self.paused {
-            let delta = 1. / sample_rate as f64 * self.settings.playback_rate as f64;
-            let mut position = self.index as f64 * delta;
-            for sample in data.iter_mut() {
-                if let Some(frame) = self.frame(position, delta) {
-                    *sample += self.update_and_get(frame).avg();
-                } else {
-                    break;
-                }
-                position += delta;
-            }
-            if let Some(state) = self.state.upgrade() {
-                state
-                    .position
-                    .store(self.position(delta), Ordering::SeqCst);
-            }
+    /// Keeps a seek inside the clip: past the end, a looping track wraps
+    /// around and any other lands on the last frame.
+    fn seek_target(&self, position: f64) -> f64 {
+        let frames = self.clip.frame_count();
+        if frames == 0 {
+            return 0.;
+        }
+        let length = self.clip.length();
+        if position < length {
+            position
+        } else if !self.loops_at_end() {
+            (frames - 1) as f64 / self.clip.sample_rate() as f64
+        } else {
+            let (start, end, _) = self.region;
+            start + (position - start) % (end - start)
         }
     }
 
-    fn render_stereo(&mut self, sample_rate: u32, data: &mut [f32]) {
-        self.prepare(sample_rate);
-        if !self.paused {
-            let delta = 1. / sample_rate as f64 * self.settings.playback_rate as f64;
-            let mut position = self.index as f64 * delta;
-            for sample in data.chunks_exact_mut(2) {
-                if let Some(frame) = self.frame(position, delta) {
-                    let frame = self.update_and_get(frame);
-                    sample[0] += frame.0;
-                    sample[1] += frame.1;
-                } else {
-                    break;
+    /// Samples the clip, mixing in the head of the loop region over the
+    /// loop mix time.
+    #[inline]
+    fn source(&self, position: f64) -> Option<Frame> {
+        let mut frame = self.sample(position)?;
+        if self.wraps_forward() {
+            let (start, end, mix) = self.region;
+            let pos = position + mix - end;
+            if pos >= 0. && position < end {
+                if let Some(new_frame) = self.sample(start + pos) {
+                    frame += new_frame;
                 }
-                position += delta;
-            }
-            if let Some(state) = self.state.upgrade() {
-                state
-                    .position
-                    .store(self.position(delta), Ordering::SeqCst);
             }
         }
+        Some(frame)
     }
-}
-
-pub struct Music {
-    arc: Arc<SharedState>,
-    prod: HeapProducer<MusicCommand>,
-}
-impl Music {
-    pub(crate) fn new(clip: AudioClip, settings: MusicParams) -> (Music, MusicRenderer) {
-        let (prod, cons) = HeapRb::new(settings.command_buffer_size).split();
-        let arc = Arc::default();
-        let renderer = MusicRenderer {
-            clip,
-            settings,
-            state: Arc::downgrade(&arc),
-            cons,
-            paused: true,
-            index: 0,
-            last_sample_rate: 1,
-            low_pass: 0.,
-            last_output: Frame(0., 0.),
 
-            fade_time: 0,
-            fade_current: 0,
-        };
-        (Self { arc, prod }, renderer)
+    /// Reads the clip for a grain, wrapping around the end when looping.
+    fn grain_source(&self, position: f64) -> Frame {
+        if position < 0. {
+            return Frame::default();
+        }
+        self.source(position)
+            .or_else(|| {
+                self.wraps_forward().then(|| {
+                    let (start, end, mix) = self.region;
+                    self.sample(start + position - end + mix)
+                        .unwrap_or_default()
+                })
+            })
+            .unwrap_or_default()
     }
 
-    pub fn play(&mut self) -> Result<()> {
-        self.prod
-            .push(MusicCommand::Resume)
-            .map_err(buffer_is_full)
-            .context("play music")
+    /// Picks the start of a new grain near `position` whose waveform best
+    /// matches `continuation`, the clip time the outgoing grain is about to
+    /// play.
+    fn find_grain_start(&self, position: f64, continuation: f64) -> f64 {
+        let frames = self.clip.frames();
+        let rate = self.clip.sample_rate() as f64;
+        let window = (STRETCH_GRAIN / 4. * rate) as isize;
+        let target = (continuation * rate) as isize;
+        let base = (position * rate) as isize;
+        let get = |index: isize| {
+            usize::try_from(index)
+                .ok()
+                .and_then(|it| frames.get(it))
+                .map_or(0., Frame::avg)
+        };
+        let mut best = (f32::NEG_INFINITY, 0);
+        for offset in (-window..=window).step_by(2) {
+            let score: f32 = (0..window)
+                .step_by(2)
+                .map(|n| get(base + offset + n) * get(target + n))
+                .sum();
+            if score > best.0 {
+                best = (score, offset);
+            }
+        }
+        position + best.1 as f64 / rate
     }
 
-    pub fn pause(&mut self) -> Result<()> {
-        self.prod
-            .push(MusicCommand::Pause)
-            .map_err(buffer_is_full)
-            .context("pause")
+    /// Renders one frame at `position` with the pitch of the clip, by
+    /// overlap-adding two grains half a grain apart.
+    fn stretched(&mut self, position: f64, frame_time: f64) -> Frame {
+        let grain_len = ((STRETCH_GRAIN / frame_time).round() as usize).max(2);
+        let direction = if self.reversed { -1. } else { 1. };
+        if self.grain_len != grain_len {
+            self.grain_len = grain_len;
+            let half = grain_len / 2;
+            self.grains = [
+                Grain {
+                    start: position,
+                    phase: 0,
+                    direction,
+                },
+                Grain {
+                    start: position - direction * half as f64 * frame_time,
+                    phase: half,
+                    direction,
+                },
+            ];
+        }
+        let mut output = Frame::default();
+        for i in 0..2 {
+            if self.grains[i].phase >= grain_len {
+                let other = self.grains[1 - i];
+                let continuation = other.start + other.direction * other.phase as f64 * frame_time;
+                let start = if self.reversed {
+                    position
+                } else {
+                    self.find_grain_start(position, continuation)
+                };
+                self.grains[i] = Grain {
+                    start,
+                    phase: 0,
+                    direction,
+                };
+            }
+            let grain = self.grains[i];
+            let window = (std::f64::consts::PI * grain.phase as f64 / grain_len as f64).sin();
+            let frame =
+                self.grain_source(grain.start + grain.direction * grain.phase as f64 * frame_time);
+            output += frame * (window * window) as f32;
+            self.grains[i].phase += 1;
+        }
+        output
+    }
+
+    #[inline]
+    fn frame(&mut self, delta: f64, frame_time: f64) -> Option<Frame> {
+        while self
+            .schedule
+            .first()
+            .is_some_and(|it| it.0 <= self.position + POSITION_EPSILON)
+        {
+            let (_, cmd) = self.schedule.remove(0);
+            if let Some(state) = self.state.upgrade() {
+                state.scheduled.fetch_sub(1, Ordering::SeqCst);
+            }
+            self.apply(cmd.into(), self.last_sample_rate);
+        }
+        let step = delta * self.next_rate(frame_time);
+        let position = self.position;
+        let frame = if let Some(frame) = self.source(position) {
+            frame
+        } else {
+            match self.loop_mode {
+                // An empty clip has nothing to loop over.
+                _ if self.clip.frame_count() == 0 => {
+                    self.pause_by_itself(false);
+                    return None;
+                }
+                LoopMode::Off => {
+                    self.pause_by_itself(false);
+                    return None;
+                }
+                // The last counted pass ends like a track that does not
+                // loop, and so does one played past its loop region.
+                LoopMode::Forward
+                    if self.loops_left == Some(0) || self.region.1 < self.clip.length() =>
+                {
+                    self.pause_by_itself(false);
+                    return None;
+                }
+                LoopMode::Forward => {
+                    let (start, end, mix) = self.region;
+                    let position = start + position - end + mix;
+                    self.position = position;
+                    self.count_loop();
+                    self.sample(position).unwrap_or_default()
+                }
+                LoopMode::PingPong => {
+                    self.reversed = true;
+                    self.position = (self.clip.length() - step).max(0.);
+                    self.sample(self.position).unwrap_or_default()
+                }
+            }
+        };
+        let frame = if self.settings.preserve_pitch {
+            self.stretched(self.position, frame_time)
+        } else {
+            frame
+        };
+        if self.reversed {
+            self.position -= step;
+            if self.position < 0. {
+                self.reversed = false;
+                self.position = -self.position;
+                self.loops += 1;
+            }
+        } else {
+            self.position += step;
+            // Wrapping at the end of the clip waits for the clip to run out
+            // above, so that sampling it never reads past its end.
+            let (start, end, mix) = self.region;
+            if position < end - POSITION_EPSILON
+                && self.position >= end - POSITION_EPSILON
+                && end < self.clip.length()
+                && self.wraps_forward()
+            {
+                self.position = (self.position - end).max(0.) + start + mix;
+                self.count_loop();
+            }
+        }
+        if let Some((a, b)) = self.ab_loop.filter(|(a, b)| b > a) {
+            let length = b - a;
+            // Stepping by frames lands a rounding error short of `b` on the
+            // frame that should wrap, as for the loop region above.
+            if self.position >= b - POSITION_EPSILON {
+                self.position = a + (self.position - b).max(0.) % length;
+                self.loops += 1;
+            } else if self.position < a && self.reversed {
+                self.position = b - (a - self.position) % length;
+                self.loops += 1;
+            }
+        }
+        let duck = if self.ducks.is_empty() {
+            1.
+        } else {
+            self.duck_gain(frame_time)
+        };
+        let s = &self.settings;
+        let mut amp = if self.muted || self.silent {
+            0.
+        } else {
+            s.amplifier * duck
+        };
+        if s.auto_fade_out > 0. && !self.loops_at_end() {
+            let remaining = self.clip.length() - position;
+            if remaining < s.auto_fade_out {
+                amp *= (remaining / s.auto_fade_out).max(0.) as f32;
+            }
+        }
+        if self.fade_time != 0 {
+            if self.fade_time > 0 {
+                self.fade_current += 1;
+                if self.fade_current >= self.fade_time {
+                    self.fade_time = 0;
+                } else {
+                    amp = apply_fade(amp, self.fade_current, self.fade_time, false);
+                }
+            } else {
+                self.fade_current -= 1;
+                if self.fade_current <= self.fade_time {
+                    self.fade_time = 0;
+                    if self.settings.keep_time_when_silent {
+                        self.silent = true;
+                        return Some(Frame::default());
+                    }
+                    self.pause_by_itself(true);
+                    if let Some(state) = self.state.upgrade() {
+                        state.pauses.fetch_add(1, Ordering::Relaxed);
+                    }
+                    return None;
+                } else {
+                    amp = apply_fade(amp, self.fade_current, self.fade_time, true);
+                }
+            }
+        }
+        Some(frame * amp)
+    }
+
+    /// Whether the end of the clip wraps around to its head.
+    #[inline]
+    fn wraps_forward(&self) -> bool {
+        self.loop_mode == LoopMode::Forward && self.loops_left != Some(0)
+    }
+
+    /// Whether playback wraps around at the end of the clip, i.e. loops and
+    /// is not on its last counted pass.
+    fn loops_at_end(&self) -> bool {
+        match self.loop_mode {
+            LoopMode::Off => false,
+            LoopMode::Forward => self.loops_left != Some(0),
+            LoopMode::PingPong => true,
+        }
+    }
+
+    /// Counts a forward wrap around the loop region.
+    fn count_loop(&mut self) {
+        self.loops += 1;
+        if let Some(left) = &mut self.loops_left {
+            *left -= 1;
+            self.publish_looping();
+        }
+    }
+
+    /// Restarts the count of [`MusicParams::loop_count`].
+    fn reset_loops(&mut self) {
+        self.loops_left = self
+            .settings
+            .loop_count
+            .filter(|&it| it != 0)
+            .map(|it| it - 1);
+        self.publish_looping();
+    }
+
+    fn publish_looping(&self) {
+        if let Some(state) = self.state.upgrade() {
+            state.looping.store(self.loops_at_end(), Ordering::SeqCst);
+            state
+                .loops_left
+                .store(self.loops_left.unwrap_or(u32::MAX), Ordering::Relaxed);
+            let (start, end, mix) = self.region;
+            let end = if self.loop_mode == LoopMode::Forward {
+                end
+            } else {
+                f64::NAN
+            };
+            state.loop_end.store(end, Ordering::Relaxed);
+            state.loop_pass.store(end - start - mix, Ordering::Relaxed);
+        }
+    }
+
+    /// Gain from 0 to 1 of the fade in progress, if any.
+    fn fade_level(&self) -> Option<f64> {
+        let ratio = match self.fade_time {
+            0 => return None,
+            time => (self.fade_current as f64 / time as f64).clamp(0., 1.),
+        };
+        Some(if self.fade_time > 0 {
+            ratio
+        } else {
+            1. - ratio
+        })
+    }
+
+    /// Restarts a track that stopped at the end of the clip, so that playing
+    /// it again is never undone by the end of clip on the same block.
+    /// Commands are applied before the block is rendered, so a track still
+    /// short of the end plays the rest of the clip and then pauses.
+    fn rewind_if_ended(&mut self) {
+        if self.paused
+            && !self.loops_at_end()
+            && self.clip.frame_count() != 0
+            && self.sample(self.position).is_none()
+        {
+            self.position = 0.;
+            self.reset_loops();
+        }
+    }
+
+    fn pause_by_itself(&mut self, faded_out: bool) {
+        self.paused = true;
+        self.faded_out = faded_out;
+        self.unreported_pause = true;
+        if let Some(state) = self.state.upgrade() {
+            state.paused.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Publishes the play head for tracks ducked by this one and picks up
+    /// the play heads of the triggers of this one's ducks.
+    fn begin_block(&mut self, ctx: &RenderContext) {
+        self.block_frame = 0;
+        if let Some(state) = self.state.upgrade() {
+            let step = if self.paused {
+                0.
+            } else {
+                self.settings.playback_rate / ctx.sample_rate as f64
+            };
+            state.block_start.store(self.position, Ordering::Relaxed);
+            state.block_step.store(step, Ordering::Relaxed);
+            state.block_time.store(ctx.sample_time, Ordering::Relaxed);
+        }
+        for duck in &mut self.ducks {
+            (duck.base, duck.step) = duck
+                .spec
+                .trigger
+                .block(ctx.sample_time)
+                .unwrap_or((f64::NAN, 0.));
+        }
+    }
+
+    /// Gain of the ducks for the next frame, ramping each towards its depth
+    /// while the trigger is within its region and back to 1 outside.
+    #[inline]
+    fn duck_gain(&mut self, frame_time: f64) -> f32 {
+        let frame = self.block_frame;
+        self.block_frame += 1;
+        let mut gain = 1.;
+        for duck in &mut self.ducks {
+            let spec = &duck.spec;
+            let position = duck.base + duck.step * frame as f64;
+            let target = if spec.start <= position && position < spec.end {
+                spec.gain
+            } else {
+                1.
+            };
+            if spec.ramp <= 0. {
+                duck.gain = target;
+            } else if duck.gain != target {
+                let delta = ((1. - spec.gain) as f64 * frame_time / spec.ramp) as f32;
+                duck.gain = if duck.gain < target {
+                    (duck.gain + delta).min(target)
+                } else {
+                    (duck.gain - delta).max(target)
+                };
+            }
+            gain *= duck.gain;
+        }
+        gain
+    }
+
+    /// Publishes the gains of the latest frame, the effective one being 0
+    /// while paused.
+    fn publish_gain(&self, ctx: &RenderContext) {
+        let Some(state) = self.state.upgrade() else {
+            return;
+        };
+        let s = &self.settings;
+        let amplifier = if self.muted || self.silent {
+            0.
+        } else {
+            s.amplifier
+        };
+        let mut fade = 1.;
+        if s.auto_fade_out > 0. && !self.loops_at_end() {
+            let remaining = self.clip.length() - self.position;
+            if remaining < s.auto_fade_out {
+                fade *= (remaining / s.auto_fade_out).max(0.) as f32;
+            }
+        }
+        if self.fade_time != 0 {
+            fade = apply_fade(fade, self.fade_current, self.fade_time, self.fade_time < 0);
+        }
+        let duck = self.ducks.iter().map(|it| it.gain).product::<f32>();
+        let stages = [amplifier, fade, duck, ctx.output_gain];
+        let effective = if self.paused {
+            0.
+        } else {
+            stages.iter().product()
+        };
+        state.effective_gain.store(effective, Ordering::Relaxed);
+        state.amplifier_gain.store(amplifier, Ordering::Relaxed);
+        state.fade_gain.store(fade, Ordering::Relaxed);
+        state.duck_gain.store(duck, Ordering::Relaxed);
+        state.output_gain.store(ctx.output_gain, Ordering::Relaxed);
+    }
+
+    /// Publishes what the handle needs to tell when playback ends, after a
+    /// block of `frames` frames.
+    fn publish_timing(&self, ctx: &RenderContext, frames: u64) {
+        let Some(state) = self.state.upgrade() else {
+            return;
+        };
+        let fade_out_left = if self.silent {
+            0.
+        } else if self.fade_time < 0 {
+            (self.fade_current - self.fade_time) as f64 / ctx.sample_rate as f64
+        } else {
+            f64::INFINITY
+        };
+        state.position.store(self.position, Ordering::SeqCst);
+        state.fade_out_left.store(fade_out_left, Ordering::Relaxed);
+        state
+            .ab_looping
+            .store(self.ab_loop.is_some_and(|(a, b)| b > a), Ordering::Relaxed);
+        state.sample_rate.store(ctx.sample_rate, Ordering::Relaxed);
+        state
+            .clock
+            .store(ctx.sample_time + frames, Ordering::Relaxed);
+    }
+
+    /// Marks the intended state as paused after pausing by itself, retried
+    /// every block until no command is pending.
+    fn report_pause(&mut self) {
+        if !self.unreported_pause {
+            return;
+        }
+        let Some(state) = self.state.upgrade() else {
+            return;
+        };
+        // Handles update the intent after pushing, so if no command is
+        // pending here, a request made since the load fails the exchange.
+        let intent = state.intent.load(Ordering::SeqCst);
+        if self.held.is_none() && self.transport.is_empty() && self.cons.is_empty() {
+            self.unreported_pause = false;
+            let finished = if self.faded_out { 0 } else { 4 };
+            let _ = state.intent.compare_exchange(
+                intent,
+                (intent | 1 | finished) & !2,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+        }
+    }
+
+    /// Stores the counters of a block that played `played` frames.
+    fn publish(&mut self, played: u64) {
+        let loops = std::mem::take(&mut self.loops);
+        if let Some(state) = self.state.upgrade() {
+            state.frames_played.fetch_add(played, Ordering::Relaxed);
+            if loops != 0 {
+                state.loops.fetch_add(loops, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Resets filter state that a non-finite sample got stuck in.
+    fn sanitize(&mut self) {
+        if !self.last_output.is_finite() {
+            self.last_output = Frame::default();
+        }
+        if !self.low_pass.is_finite() {
+            self.low_pass = 0.;
+        }
+    }
+
+    #[inline(always)]
+    fn update_and_get(&mut self, frame: Frame) -> Frame {
+        if self.low_pass_remaining > 0 {
+            self.low_pass +=
+                (self.low_pass_target - self.low_pass) / self.low_pass_remaining as f32;
+            self.low_pass_remaining -= 1;
+        }
+        self.last_output = self.last_output * self.low_pass + frame * (1. - self.low_pass);
+        self.last_output
+    }
+}
+
+impl Renderer for MusicRenderer {
+    fn alive(&self) -> bool {
+        self.state.strong_count() != 0
+    }
+
+    fn reset_filters(&mut self) {
+        self.last_output = Frame::default();
+    }
+
+    fn render_mono(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+        let sample_rate = ctx.sample_rate;
+        self.degraded = ctx.degraded;
+        self.clock = ctx.sample_time - *self.clock_origin.get_or_insert(ctx.sample_time);
+        self.prepare(sample_rate);
+        self.begin_block(ctx);
+        self.sanitize();
+        if !self.paused {
+            let frame_time = 1. / sample_rate as f64;
+            let delta = frame_time * self.settings.playback_rate;
+            let mut played = 0;
+            for sample in data.iter_mut() {
+                if let Some(frame) = self.frame(delta, frame_time) {
+                    *sample += self.update_and_get(frame).avg();
+                    played += 1;
+                } else {
+                    break;
+                }
+            }
+            self.publish(played);
+        }
+        self.publish_gain(ctx);
+        self.publish_timing(ctx, data.len() as u64);
+        self.report_pause();
+    }
+
+    /// A paused track stays silent until a command resumes it. Scheduled
+    /// commands are keyed on the play head, so they never wake it, and the
+    /// mixer skips the track meanwhile.
+    fn next_activity(&self, _now: u64) -> Option<u64> {
+        let pending = self.held.is_some() || !self.transport.is_empty() || !self.cons.is_empty();
+        // Rendered once to start the clock.
+        let started = self.clock_origin.is_some();
+        (self.paused && started && !pending && !self.unreported_pause).then_some(u64::MAX)
+    }
+
+    fn render_stereo(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+        let sample_rate = ctx.sample_rate;
+        self.degraded = ctx.degraded;
+        self.clock = ctx.sample_time - *self.clock_origin.get_or_insert(ctx.sample_time);
+        self.prepare(sample_rate);
+        self.begin_block(ctx);
+        self.sanitize();
+        if !self.paused {
+            let frame_time = 1. / sample_rate as f64;
+            let delta = frame_time * self.settings.playback_rate;
+            let mut played = 0;
+            for sample in data.chunks_exact_mut(2) {
+                if let Some(frame) = self.frame(delta, frame_time) {
+                    let frame = self.update_and_get(frame);
+                    sample[0] += frame.0 * self.pan_gains.0;
+                    sample[1] += frame.1 * self.pan_gains.1;
+                    played += 1;
+                } else {
+                    break;
+                }
+            }
+            self.publish(played);
+        }
+        self.publish_gain(ctx);
+        self.publish_timing(ctx, data.len() as u64 / 2);
+        self.report_pause();
+    }
+}
+
+struct Lanes {
+    transport: HeapProducer<TaggedCommand>,
+    general: HeapProducer<TaggedCommand>,
+    /// Push order of the next command.
+    order: u64,
+    /// Emptied on every push.
+    garbage: HeapConsumer<MusicGarbage>,
+}
+
+fn push_lanes(state: &SharedState, lanes: &Mutex<Lanes>, cmd: MusicCommand) -> Result<()> {
+    let mut lanes = lanes.lock().unwrap_or_else(|it| it.into_inner());
+    let epoch = state.epoch.load(Ordering::SeqCst);
+    while lanes.garbage.pop().is_some() {}
+    let fades = matches!(
+        cmd,
+        MusicCommand::FadeOut(_) | MusicCommand::Group(_, _, GroupCommand::FadeOut(_))
+    ) && !state.keep_time_when_silent;
+    let intent = match cmd {
+        MusicCommand::Pause
+        | MusicCommand::Stop
+        | MusicCommand::Group(_, _, GroupCommand::Pause) => Some(true),
+        MusicCommand::Resume
+        | MusicCommand::FadeIn(_)
+        | MusicCommand::Group(_, _, GroupCommand::Resume | GroupCommand::FadeIn(_)) => Some(false),
+        MusicCommand::FadeOut(_) | MusicCommand::Group(_, _, GroupCommand::FadeOut(_)) => {
+            Some(!state.keep_time_when_silent)
+        }
+        _ => None,
+    };
+    let cancels_fade = matches!(
+        cmd,
+        MusicCommand::SeekTo(_, false) | MusicCommand::Group(_, _, GroupCommand::SeekTo(_))
+    );
+    let restarts = matches!(
+        cmd,
+        MusicCommand::Resume
+            | MusicCommand::Stop
+            | MusicCommand::FadeIn(_)
+            | MusicCommand::SeekTo(..)
+            | MusicCommand::Group(
+                _,
+                _,
+                GroupCommand::Resume | GroupCommand::FadeIn(_) | GroupCommand::SeekTo(_)
+            )
+    );
+    if matches!(cmd, MusicCommand::Stop) {
+        // Set before pushing, as the renderer clears it once applied.
+        state.stopping.store(true, Ordering::SeqCst);
+    }
+    let order = lanes.order;
+    lanes.order += 1;
+    let result = push_command(state, &mut lanes, (epoch, order), cmd);
+    if result.is_ok() {
+        match intent {
+            Some(paused) => state.request_paused(paused, fades),
+            None if cancels_fade => state.cancel_fade_out(),
+            None => {}
+        }
+        if restarts {
+            state.clear_finished();
+        }
+    }
+    result
+}
+
+fn push_command(
+    state: &SharedState,
+    lanes: &mut Lanes,
+    (epoch, order): (u64, u64),
+    cmd: MusicCommand,
+) -> Result<()> {
+    match cmd {
+        MusicCommand::Pause
+        | MusicCommand::Resume
+        | MusicCommand::Stop
+        | MusicCommand::SeekTo(..) => {
+            if let Err((_, _, cmd)) = lanes.transport.push((epoch, order, cmd)) {
+                let paused = |kind| order << 2 | kind;
+                match cmd {
+                    MusicCommand::Pause => {
+                        state
+                            .overflow_paused
+                            .store(paused(OVERFLOW_PAUSE), Ordering::SeqCst);
+                    }
+                    MusicCommand::Resume => {
+                        state
+                            .overflow_paused
+                            .store(paused(OVERFLOW_RESUME), Ordering::SeqCst);
+                    }
+                    MusicCommand::Stop => {
+                        // Seeks pushed before are overridden by the rewind.
+                        state.overflow_seek.store(f64::NAN, Ordering::SeqCst);
+                        state
+                            .overflow_paused
+                            .store(paused(OVERFLOW_STOP), Ordering::SeqCst);
+                    }
+                    MusicCommand::SeekTo(position, keep_fade) => {
+                        state
+                            .overflow_seek_keep_fade
+                            .store(keep_fade, Ordering::SeqCst);
+                        state.overflow_seek_order.store(order, Ordering::SeqCst);
+                        state.overflow_seek.store(position, Ordering::SeqCst);
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            Ok(())
+        }
+        cmd => {
+            if let Some((slot, value)) = setter_slot(&cmd).filter(|_| state.coalesce_setters) {
+                let slot = &state.overflow_setters[slot];
+                // Once a value waits in its slot, later ones must replace it,
+                // as it is applied after the lane.
+                if slot.load(Ordering::SeqCst).is_nan()
+                    && lanes.general.push((epoch, order, cmd)).is_ok()
+                {
+                    return Ok(());
+                }
+                slot.store(value, Ordering::SeqCst);
+                return Ok(());
+            }
+            lanes
+                .general
+                .push((epoch, order, cmd))
+                .map_err(buffer_is_full)
+        }
+    }
+}
+
+/// Slot in `SharedState::overflow_setters` and value of a setter that can
+/// be coalesced.
+fn setter_slot(cmd: &MusicCommand) -> Option<(usize, f64)> {
+    match *cmd {
+        MusicCommand::SetAmplifier(amp) => Some((0, amp as f64)),
+        MusicCommand::SetPan(pan) => Some((1, pan as f64)),
+        MusicCommand::SetPlaybackRate(rate) => Some((2, rate)),
+        MusicCommand::SetLowPass(low_pass) => Some((3, low_pass as f64)),
+        _ => None,
+    }
+}
+
+/// The setter of a slot of `SharedState::overflow_setters`.
+fn slot_setter(slot: usize, value: f64) -> MusicCommand {
+    match slot {
+        0 => MusicCommand::SetAmplifier(value as f32),
+        1 => MusicCommand::SetPan(value as f32),
+        2 => MusicCommand::SetPlaybackRate(value),
+        _ => MusicCommand::SetLowPass(value as f32),
+    }
+}
+
+/// Pushes commands to a track without keeping it alive.
+pub(super) struct MusicLink {
+    arc: Weak<SharedState>,
+    lanes: Weak<Mutex<Lanes>>,
+}
+impl MusicLink {
+    /// Returns `None` once the [`Music`] has been dropped.
+    pub(super) fn push(&self, cmd: MusicCommand) -> Option<Result<()>> {
+        let (arc, lanes) = (self.arc.upgrade()?, self.lanes.upgrade()?);
+        Some(push_lanes(&arc, &lanes, cmd))
+    }
+
+    pub(super) fn links(&self, music: &Music) -> bool {
+        self.arc.as_ptr() == Arc::as_ptr(&music.arc)
+    }
+}
+
+/// Control handle of a music track.
+///
+/// `Music` is `Send + Sync`: commands are pushed through an internal lock
+/// that is only held for the duration of a push, so a shared reference can be
+/// used from several threads.
+pub struct Music {
+    arc: Arc<SharedState>,
+    lanes: Arc<Mutex<Lanes>>,
+    connected: Arc<AtomicBool>,
+    latency: Arc<AtomicF64>,
+    length: f64,
+    length_samples: usize,
+    /// Loop mix time, clamped to the clip.
+    loop_mix_time: f64,
+    frame_time: f64,
+    loop_length: AtomicF64,
+    tempo_map: TempoMap,
+    command_log: Mutex<Option<HeapConsumer<CommandLogEntry>>>,
+}
+impl Music {
+    pub(crate) fn new(
+        clip: AudioClip,
+        settings: MusicParams,
+        connected: Arc<AtomicBool>,
+        latency: Arc<AtomicF64>,
+    ) -> (Music, MusicRenderer) {
+        let mut settings = settings;
+        // Mixing over the whole clip would leave nothing to loop over, and
+        // over more would read past its end when wrapping.
+        let frame_time = 1. / clip.sample_rate().max(1) as f64;
+        let last_frame = clip.length() - frame_time;
+        settings.loop_mix_time = settings.loop_mix_time.min(last_frame);
+        let clip_mix_time = settings.loop_mix_time;
+        let length = clip.length();
+        let length_samples = clip.frame_count();
+        let (transport_prod, transport) = HeapRb::new(TRANSPORT_LANE_SIZE).split();
+        let (prod, cons) = HeapRb::new(settings.command_buffer_size).split();
+        let (garbage_prod, garbage) = HeapRb::new(settings.command_buffer_size + 4).split();
+        let arc = Arc::new(SharedState {
+            keep_time_when_silent: settings.keep_time_when_silent,
+            playback_rate: AtomicF64::new(settings.playback_rate),
+            coalesce_setters: settings.command_overflow == CommandOverflow::Coalesce,
+            ..SharedState::default()
+        });
+        let loop_mode = if settings.loop_mix_time >= 0. {
+            LoopMode::Forward
+        } else {
+            LoopMode::Off
+        };
+        let loops_left = settings.loop_count.filter(|&it| it != 0).map(|it| it - 1);
+        let region = loop_region(&settings, length, frame_time);
+        let loop_length = region.1 - region.0 - region.2;
+        arc.looping.store(
+            loop_mode != LoopMode::Off && loops_left != Some(0),
+            Ordering::SeqCst,
+        );
+        arc.loops_left
+            .store(loops_left.unwrap_or(u32::MAX), Ordering::Relaxed);
+        if loop_mode == LoopMode::Forward {
+            arc.loop_end.store(region.1, Ordering::Relaxed);
+        }
+        arc.loop_pass.store(loop_length, Ordering::Relaxed);
+        let pan_gains = pan_gains(settings.pan);
+        let renderer = MusicRenderer {
+            clip,
+            settings,
+            state: Arc::downgrade(&arc),
+            transport,
+            cons,
+            garbage: garbage_prod,
+            held: None,
+            paused: true,
+            unreported_pause: false,
+            faded_out: false,
+            muted: false,
+            silent: false,
+            position: 0.,
+            loop_mode,
+            loops_left,
+            region,
+            reversed: false,
+            last_sample_rate: 1,
+            low_pass: 0.,
+            low_pass_target: 0.,
+            low_pass_remaining: 0,
+            last_output: Frame(0., 0.),
+            pan_gains,
+
+            fade_time: 0,
+            fade_current: 0,
+
+            rate_envelope: Vec::new(),
+            rate_envelope_time: 0.,
+            rate_envelope_index: 0,
+
+            ab_loop: None,
+
+            schedule: Vec::with_capacity(MAX_SCHEDULED as usize),
+
+            grains: [Grain::default(); 2],
+            grain_len: 0,
+
+            degraded: false,
+
+            loops: 0,
+
+            ducks: Vec::with_capacity(MAX_DUCKS as usize),
+            block_frame: 0,
+
+            clock: 0,
+            clock_origin: None,
+            command_log: None,
+        };
+        (
+            Self {
+                arc,
+                lanes: Arc::new(Mutex::new(Lanes {
+                    transport: transport_prod,
+                    general: prod,
+                    order: 0,
+                    garbage,
+                })),
+                connected,
+                latency,
+                length,
+                length_samples,
+                loop_mix_time: clip_mix_time,
+                frame_time,
+                loop_length: AtomicF64::new(loop_length),
+                tempo_map: TempoMap::default(),
+                command_log: Mutex::new(None),
+            },
+            renderer,
+        )
+    }
+
+    /// Pushes a command, routing transport commands to their own lane so
+    /// that a burst of other commands can not starve them.
+    ///
+    /// Transport commands never fail: if their lane is full, they are
+    /// collapsed into the latest requested pause state and seek target.
+    fn push(&self, cmd: MusicCommand) -> Result<()> {
+        check_connected(&self.connected)?;
+        push_lanes(&self.arc, &self.lanes, cmd)
+    }
+
+    pub(crate) fn probe(&self) -> Probe {
+        let state = Arc::downgrade(&self.arc);
+        Box::new(move || {
+            let state = state.upgrade()?;
+            Some(Probed {
+                playing: !state.paused.load(Ordering::SeqCst),
+                position: Some(state.position.load(Ordering::SeqCst)),
+                gain: Some(state.effective_gain.load(Ordering::Relaxed)),
+                finished: state.intent.load(Ordering::SeqCst) & 4 != 0,
+            })
+        })
+    }
+
+    /// Ducks this track by `gain` while the play head of `trigger` is between
+    /// `start` and `end` seconds, see
+    /// [`AudioManager::schedule_duck`](crate::AudioManager::schedule_duck).
+    pub(crate) fn add_duck(
+        &self,
+        trigger: &Music,
+        start: f64,
+        end: f64,
+        gain: f32,
+        ramp: f64,
+    ) -> Result<()> {
+        if self
+            .arc
+            .ducks
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |it| {
+                (it < MAX_DUCKS).then_some(it + 1)
+            })
+            .is_err()
+        {
+            bail!("too many ducks");
+        }
+        let spec = Arc::new(DuckSpec {
+            trigger: trigger.play_head(),
+            start,
+            end,
+            gain,
+            ramp,
+        });
+        let result = self.push(MusicCommand::AddDuck(spec));
+        if result.is_err() {
+            self.arc.ducks.fetch_sub(1, Ordering::SeqCst);
+        }
+        result
+    }
+
+    /// Removes every duck registered on this track with
+    /// [`AudioManager::schedule_duck`](crate::AudioManager::schedule_duck).
+    pub fn clear_ducks(&self) -> Result<()> {
+        self.push(MusicCommand::ClearDucks).context("clear ducks")
+    }
+
+    pub(crate) fn play_head(&self) -> PlayHead {
+        PlayHead(Arc::downgrade(&self.arc))
+    }
+
+    /// A weak reference for pushing commands on behalf of a group.
+    pub(super) fn link(&self) -> MusicLink {
+        MusicLink {
+            arc: Arc::downgrade(&self.arc),
+            lanes: Arc::downgrade(&self.lanes),
+        }
+    }
+
+    /// Discards every command pushed so far that the renderer has not
+    /// applied yet. Commands pushed afterwards are applied as usual.
+    pub fn clear_pending(&self) -> Result<()> {
+        check_connected(&self.connected)?;
+        let _lanes = self.lanes.lock().unwrap_or_else(|it| it.into_inner());
+        self.arc.epoch.fetch_add(1, Ordering::SeqCst);
+        self.arc
+            .overflow_paused
+            .store(OVERFLOW_NONE, Ordering::SeqCst);
+        self.arc.overflow_seek.store(f64::NAN, Ordering::SeqCst);
+        for slot in &self.arc.overflow_setters {
+            slot.store(f64::NAN, Ordering::SeqCst);
+        }
+        self.arc.stopping.store(false, Ordering::SeqCst);
+        self.arc
+            .request_paused(self.arc.paused.load(Ordering::SeqCst), false);
+        Ok(())
+    }
+
+    /// Resumes playback, from the start of the clip if it has played to the
+    /// end without looping.
+    pub fn play(&self) -> Result<()> {
+        self.push(MusicCommand::Resume).context("play music")
+    }
+
+    pub fn pause(&self) -> Result<()> {
+        self.push(MusicCommand::Pause).context("pause")
+    }
+
+    /// Pauses and rewinds to the start of the clip in a single command,
+    /// cancelling any fade and clearing the low-pass state, so the next play
+    /// starts cleanly. [`Music::position`] reads 0 as soon as this returns.
+    pub fn stop(&self) -> Result<()> {
+        self.push(MusicCommand::Stop).context("stop music")
+    }
+
+    /// Whether the track is paused as requested by the latest call to
+    /// [`Music::play`], [`Music::pause`], a fade or a group, so it reflects
+    /// a call as soon as it returns. A pending fade out counts as paused,
+    /// unless [`MusicParams::keep_time_when_silent`] is set; playing during
+    /// it fades back in from the current gain, and [`Music::seek_to`]
+    /// cancels it.
+    ///
+    /// Playback stopping by itself at the end of the clip or of a fade out
+    /// is reflected once the renderer gets there, unless a command is
+    /// pushed in the meantime.
+    pub fn paused(&self) -> bool {
+        self.arc.intent.load(Ordering::SeqCst) & 1 != 0
+    }
+
+    /// Whether playback stopped by itself at the end of the clip, as opposed
+    /// to being paused or fading out. Like [`Music::paused`], it is set
+    /// once the renderer gets there unless a command is pushed in the
+    /// meantime, and cleared as soon as [`Music::play`], a fade in, a seek
+    /// or [`Music::stop`] is requested. Never set while looping.
+    pub fn finished(&self) -> bool {
+        self.arc.intent.load(Ordering::SeqCst) & 4 != 0
+    }
+
+    /// Whether the renderer is paused at the moment, lagging behind
+    /// [`Music::paused`] by up to a block.
+    pub fn renderer_paused(&self) -> bool {
+        self.arc.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn set_amplifier(&self, amp: f32) -> Result<()> {
+        let amp = validate("amplifier", amp as f64, f64::MIN, f64::MAX).context("set amplifier")?;
+        self.push(MusicCommand::SetAmplifier(amp as f32))
+            .context("set amplifier")
+    }
+
+    /// Changes the playback rate from the next block on, keeping the play
+    /// head where it is, as positions are counted in seconds of the clip.
+    /// A rate of 0 holds the play head in place. Does not resume a paused
+    /// track.
+    pub fn set_playback_rate(&self, rate: f64) -> Result<()> {
+        let rate = validate("playback rate", rate, 0., f64::MAX).context("set playback rate")?;
+        self.push(MusicCommand::SetPlaybackRate(rate))
+            .context("set playback rate")?;
+        self.arc.playback_rate.store(rate, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Playback rate as set on creation or by the latest call to
+    /// [`Music::set_playback_rate`].
+    pub fn playback_rate(&self) -> f64 {
+        self.arc.playback_rate.load(Ordering::SeqCst)
+    }
+
+    /// Sets the amplifier from decibels, see
+    /// [`db_to_amplitude`](crate::db_to_amplitude).
+    pub fn set_volume_db(&self, db: f32) -> Result<()> {
+        let db = validate("volume", db as f64, f64::MIN, f64::MAX).context("set volume")?;
+        self.set_amplifier(crate::db_to_amplitude(db as f32))
+    }
+
+    /// Balances the track from -1, full left, to 1, full right, with
+    /// constant power so that its loudness does not change. Mono output is
+    /// not affected.
+    pub fn set_pan(&self, pan: f32) -> Result<()> {
+        let pan = validate("pan", pan as f64, -1., 1.).context("set pan")?;
+        self.push(MusicCommand::SetPan(pan as f32))
+            .context("set pan")
+    }
+
+    /// Silences the track without touching its amplifier.
+    pub fn set_muted(&self, muted: bool) -> Result<()> {
+        self.push(MusicCommand::SetMuted(muted))
+            .context("set muted")
+    }
+
+    /// Moves the play head to `position` seconds of the clip.
+    ///
+    /// Positions past the end wrap around when the track loops, and land on
+    /// the last frame otherwise.
+    ///
+    /// A fade out in progress is cancelled and the track keeps playing at
+    /// its gain from before the fade, while a fade in carries on from its
+    /// current gain. Use [`Music::seek_to_keep_fade`] to let a fade out
+    /// finish.
+    pub fn seek_to(&self, position: f64) -> Result<()> {
+        let position = validate("position", position, 0., f64::MAX).context("seek to")?;
+        self.push(MusicCommand::SeekTo(position, false))
+            .context("seek to")
+    }
+
+    /// Like [`Music::seek_to`], letting a fade out in progress finish and
+    /// pause the track.
+    pub fn seek_to_keep_fade(&self, position: f64) -> Result<()> {
+        let position = validate("position", position, 0., f64::MAX).context("seek to")?;
+        self.push(MusicCommand::SeekTo(position, true))
+            .context("seek to")
+    }
+
+    pub fn set_low_pass(&self, low_pass: f32) -> Result<()> {
+        let low_pass = validate("low pass", low_pass as f64, 0., 1.).context("set low pass")?;
+        self.push(MusicCommand::SetLowPass(low_pass as f32))
+            .context("set low pass")
+    }
+
+    /// Ramps the low pass coefficient to `target` over `time` seconds,
+    /// replacing any ramp in progress.
+    pub fn set_low_pass_smooth(&self, target: f32, time: f64) -> Result<()> {
+        let target = validate("low pass", target as f64, 0., 1.).context("set low pass smooth")?;
+        let time = validate("time", time, 0., f64::MAX).context("set low pass smooth")?;
+        self.push(MusicCommand::SetLowPassSmooth(target as f32, time))
+            .context("set low pass smooth")
     }
 
-    pub fn paused(&mut self) -> bool {
-        self.arc.paused.load(Ordering::SeqCst)
+    /// Resumes the track and ramps it up to full gain over `time` seconds.
+    ///
+    /// A fade in progress is picked up at its current gain, and carries on
+    /// at the slope of this one, so a fade out cut short fades back in
+    /// without a jump.
+    pub fn fade_in(&self, time: f64) -> Result<()> {
+        let time = validate("time", time, 0., f64::MAX).context("fade in")?;
+        self.push(MusicCommand::FadeIn(time)).context("fade in")
     }
 
-    pub fn set_amplifier(&mut self, amp: f32) -> Result<()> {
-        self.prod
-            .push(MusicCommand::SetAmplifier(amp))
-            .map_err(buffer_is_full)
-            .context("set amplifier")
+    /// Ramps the track down to silence over `time` seconds, then pauses it
+    /// where it stopped, or keeps it running silently with
+    /// [`MusicParams::keep_time_when_silent`]. Like [`Music::fade_in`], a
+    /// fade in progress is picked up at its current gain.
+    pub fn fade_out(&self, time: f64) -> Result<()> {
+        let time = validate("time", time, 0., f64::MAX).context("fade out")?;
+        self.push(MusicCommand::FadeOut(time)).context("fade out")
     }
 
-    pub fn seek_to(&mut self, position: f64) -> Result<()> {
-        self.prod
-            .push(MusicCommand::SeekTo(position))
-            .map_err(buffer_is_full)
-            .context("seek to")
+    /// Like [`Music::fade_in`], taking a [`Duration`]. The renderer converts
+    /// it to samples at the current stream rate.
+    pub fn fade_in_duration(&self, duration: Duration) -> Result<()> {
+        self.fade_in(duration.as_secs_f64())
     }
 
-    pub fn set_low_pass(&mut self, low_pass: f32) -> Result<()> {
-        self.prod
-            .push(MusicCommand::SetLowPass(low_pass))
-            .map_err(buffer_is_full)
-            .context("set low pass")
+    /// Like [`Music::fade_out`], taking a [`Duration`].
+    pub fn fade_out_duration(&self, duration: Duration) -> Result<()> {
+        self.fade_out(duration.as_secs_f64())
+    }
+
+    /// Scripts the playback rate over time as `(time, rate)` points, e.g. a
+    /// ramp from 1 to 0 for a tape stop. Time is counted in seconds of
+    /// playback from when the renderer applies the envelope; the rate is
+    /// interpolated linearly between points and holds the last value after
+    /// them. It multiplies [`MusicParams::playback_rate`].
+    ///
+    /// An empty envelope restores the normal rate.
+    pub fn set_rate_envelope(&self, mut points: Vec<(f64, f32)>) -> Result<()> {
+        for (time, rate) in &mut points {
+            validate("time", *time, f64::MIN, f64::MAX).context("set rate envelope")?;
+            *rate =
+                validate("rate", *rate as f64, 0., f64::MAX).context("set rate envelope")? as f32;
+        }
+        if points.windows(2).any(|it| it[0].0 > it[1].0) {
+            bail!("rate envelope points must be sorted by time");
+        }
+        self.push(MusicCommand::SetRateEnvelope(points))
+            .context("set rate envelope")
+    }
+
+    /// Loops playback between `a` and `b` seconds of the clip, regardless of
+    /// the loop mode, wrapping with sub-sample accuracy. The play head
+    /// jumps to `a` if it is outside the region.
+    pub fn set_ab_loop(&self, a: f64, b: f64) -> Result<()> {
+        let a = validate("a", a, 0., f64::MAX).context("set ab loop")?;
+        let b = validate("b", b, 0., f64::MAX).context("set ab loop")?;
+        if b <= a {
+            bail!("loop end {b} must be after loop start {a}");
+        }
+        self.push(MusicCommand::SetAbLoop(Some((a, b))))
+            .context("set ab loop")
+    }
+
+    pub fn clear_ab_loop(&self) -> Result<()> {
+        self.push(MusicCommand::SetAbLoop(None))
+            .context("clear ab loop")
+    }
+
+    /// Makes [`LoopMode::Forward`] wrap from `end` back to `start` seconds of
+    /// the clip, mixing the head of the region over its tail. Playback
+    /// still starts from the beginning of the clip, and once past `end`
+    /// plays out to the end of the clip unless it seeks back into the
+    /// region. A region not within the clip loops over the whole clip.
+    pub fn set_loop_region(&self, start: f64, end: f64) -> Result<()> {
+        let start = validate("loop start", start, 0., f64::MAX).context("set loop region")?;
+        let end = validate("loop end", end, 0., f64::MAX).context("set loop region")?;
+        self.push(MusicCommand::SetLoopRegion(Some((start, end))))
+            .context("set loop region")?;
+        self.store_loop_length(start, Some(end));
+        Ok(())
+    }
+
+    /// Loops over the whole clip again.
+    pub fn clear_loop_region(&self) -> Result<()> {
+        self.push(MusicCommand::SetLoopRegion(None))
+            .context("clear loop region")?;
+        self.store_loop_length(0., None);
+        Ok(())
+    }
+
+    fn store_loop_length(&self, loop_start: f64, loop_end: Option<f64>) {
+        let settings = MusicParams {
+            loop_mix_time: self.loop_mix_time,
+            loop_start,
+            loop_end,
+            ..MusicParams::default()
+        };
+        let (start, end, mix) = loop_region(&settings, self.length, self.frame_time);
+        self.loop_length.store(end - start - mix, Ordering::Relaxed);
+    }
+
+    /// Applies `cmd` on the first frame played at or past `at` seconds of
+    /// the clip, or on the next frame if playback is already past it.
+    ///
+    /// At most 32 commands can wait at a time.
+    pub fn schedule(&self, at: f64, cmd: ScheduledCommand) -> Result<()> {
+        let at = validate("position", at, 0., f64::MAX).context("schedule")?;
+        let cmd = match cmd {
+            ScheduledCommand::SetAmplifier(amp) => ScheduledCommand::SetAmplifier(
+                validate("amplifier", amp as f64, f64::MIN, f64::MAX).context("schedule")? as f32,
+            ),
+            ScheduledCommand::SetLowPass(low_pass) => ScheduledCommand::SetLowPass(
+                validate("low pass", low_pass as f64, 0., 1.).context("schedule")? as f32,
+            ),
+            ScheduledCommand::SetLowPassSmooth { target, time } => {
+                ScheduledCommand::SetLowPassSmooth {
+                    target: validate("low pass", target as f64, 0., 1.).context("schedule")? as f32,
+                    time: validate("time", time, 0., f64::MAX).context("schedule")?,
+                }
+            }
+            ScheduledCommand::FadeIn(time) => {
+                ScheduledCommand::FadeIn(validate("time", time, 0., f64::MAX).context("schedule")?)
+            }
+            ScheduledCommand::FadeOut(time) => {
+                ScheduledCommand::FadeOut(validate("time", time, 0., f64::MAX).context("schedule")?)
+            }
+        };
+        if self
+            .arc
+            .scheduled
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |it| {
+                (it < MAX_SCHEDULED).then_some(it + 1)
+            })
+            .is_err()
+        {
+            bail!("schedule is full");
+        }
+        let result = self.push(MusicCommand::Schedule(at, cmd));
+        if result.is_err() {
+            self.arc.scheduled.fetch_sub(1, Ordering::SeqCst);
+        }
+        result.context("schedule")
+    }
+
+    /// Like [`Music::schedule`], at a beat of the tempo map.
+    pub fn schedule_at_beat(&self, beat: f64, cmd: ScheduledCommand) -> Result<()> {
+        let at = self
+            .tempo_map
+            .time_at_beat(beat)
+            .ok_or_else(|| anyhow!("no tempo map"))
+            .context("schedule at beat")?;
+        self.schedule(at.max(0.), cmd)
     }
 
-    pub fn fade_in(&mut self, time: f64) -> Result<()> {
-        self.prod
-            .push(MusicCommand::FadeIn(time))
-            .map_err(buffer_is_full)
-            .context("fade in")
+    /// Drops every scheduled command not applied yet.
+    pub fn clear_schedule(&self) -> Result<()> {
+        self.push(MusicCommand::ClearSchedule)
+            .context("clear schedule")
     }
 
-    pub fn fade_out(&mut self, time: f64) -> Result<()> {
-        self.prod
-            .push(MusicCommand::FadeOut(time))
-            .map_err(buffer_is_full)
-            .context("fade out")
+    pub fn set_loop_mode(&self, mode: LoopMode) -> Result<()> {
+        self.push(MusicCommand::SetLoopMode(mode))
+            .context("set loop mode")
     }
 
+    /// Sets [`MusicParams::loop_count`] and restarts the count from the
+    /// current pass. Seeking never changes the count, only wrapping around
+    /// does.
+    pub fn set_loop_count(&self, count: Option<u32>) -> Result<()> {
+        self.push(MusicCommand::SetLoopCount(count))
+            .context("set loop count")
+    }
+
+    /// Seconds of the clip between two wraps in [`LoopMode::Forward`], i.e.
+    /// the length of the loop region minus [`MusicParams::loop_mix_time`].
+    ///
+    /// This does not depend on the playback rate; divide by it to get the
+    /// output time between two wraps.
+    pub fn effective_loop_length(&self) -> f64 {
+        self.loop_length.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> MusicStats {
+        MusicStats {
+            frames_played: self.arc.frames_played.load(Ordering::Relaxed),
+            pauses: self.arc.pauses.load(Ordering::Relaxed),
+            seeks: self.arc.seeks.load(Ordering::Relaxed),
+            loops: self.arc.loops.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn reset_stats(&self) {
+        self.arc.frames_played.store(0, Ordering::Relaxed);
+        self.arc.pauses.store(0, Ordering::Relaxed);
+        self.arc.seeks.store(0, Ordering::Relaxed);
+        self.arc.loops.store(0, Ordering::Relaxed);
+    }
+
+    /// Gain applied to the clip in the latest block with every stage
+    /// composed, 0 while paused. Filters are not accounted for.
+    pub fn effective_gain(&self) -> f32 {
+        self.arc.effective_gain.load(Ordering::Relaxed)
+    }
+
+    /// The stages composing [`Music::effective_gain`]. They are published
+    /// separately, so a read racing the end of a block may mix two blocks.
+    pub fn gain_stages(&self) -> GainStages {
+        GainStages {
+            amplifier: self.arc.amplifier_gain.load(Ordering::Relaxed),
+            fade: self.arc.fade_gain.load(Ordering::Relaxed),
+            duck: self.arc.duck_gain.load(Ordering::Relaxed),
+            output: self.arc.output_gain.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether the track currently loops, as last applied by the renderer.
+    pub fn is_looping(&self) -> bool {
+        self.arc.looping.load(Ordering::SeqCst)
+    }
+
+    /// Times the track still wraps around before its last pass under
+    /// [`MusicParams::loop_count`], or `None` if the count is unlimited.
+    /// Only counts in [`LoopMode::Forward`].
+    pub fn loops_left(&self) -> Option<u32> {
+        match self.arc.loops_left.load(Ordering::Relaxed) {
+            u32::MAX => None,
+            left => Some(left),
+        }
+    }
+
+    /// Playback position in seconds of the clip.
+    ///
+    /// Positions are kept as `f64` seconds from the API down to the
+    /// renderer, which stays within a nanosecond for clips of up to a
+    /// hundred hours, far below a sample at any supported rate.
     pub fn position(&self) -> f64 {
+        if self.arc.stopping.load(Ordering::SeqCst) {
+            return 0.;
+        }
         self.arc.position.load(Ordering::SeqCst)
     }
+
+    /// Length of the clip in seconds, the end of [`Music::position`].
+    pub fn duration(&self) -> f64 {
+        self.length
+    }
+
+    /// Length of the clip in frames, at the output sample rate if it was
+    /// resampled on creation, see
+    /// [`AudioManagerSettings::auto_resample`](crate::AudioManagerSettings::auto_resample).
+    pub fn length_samples(&self) -> usize {
+        self.length_samples
+    }
+
+    /// Output seconds until playback ends if it keeps playing, as of the
+    /// latest block: the end of the clip at the playback rate, after the
+    /// loops left under [`MusicParams::loop_count`], or of a running fade
+    /// out if it ends sooner. Infinite while looping forever, in
+    /// [`LoopMode::PingPong`] or an A/B loop, or at a rate of 0. Rate
+    /// envelopes are not accounted for.
+    pub fn remaining(&self) -> f32 {
+        let state = &self.arc;
+        let fade_out = state.fade_out_left.load(Ordering::Relaxed);
+        if state.ab_looping.load(Ordering::Relaxed) {
+            return fade_out as f32;
+        }
+        let looping = state.looping.load(Ordering::SeqCst);
+        let loops_left = state.loops_left.load(Ordering::Relaxed);
+        let loop_end = state.loop_end.load(Ordering::Relaxed);
+        if looping && (loops_left == u32::MAX || loop_end.is_nan()) {
+            return fade_out as f32;
+        }
+        let rate = self.playback_rate();
+        if rate == 0. {
+            return fade_out as f32;
+        }
+        let position = self.position();
+        let mut clip = (self.length - position).max(0.);
+        // A track played past an inner loop region no longer loops.
+        if looping && (position < loop_end || loop_end >= self.length) {
+            clip += loops_left as f64 * state.loop_pass.load(Ordering::Relaxed);
+        }
+        (clip / rate).min(fade_out) as f32
+    }
+
+    /// Mixer frame at which playback ends if it keeps playing, in the clock
+    /// of [`RenderContext::sample_time`], or `None` if it never ends or no
+    /// block has been rendered yet. See [`Music::remaining`].
+    pub fn ends_at_sample_time(&self) -> Option<u64> {
+        let clock = self.arc.clock.load(Ordering::Relaxed);
+        let remaining = self.remaining() as f64;
+        if clock == u64::MAX || remaining.is_infinite() {
+            return None;
+        }
+        let sample_rate = self.arc.sample_rate.load(Ordering::Relaxed);
+        Some(clock + (remaining * sample_rate as f64).round() as u64)
+    }
+
+    /// Position currently reaching the listener, i.e. [`Music::position`]
+    /// compensated by the estimated output latency.
+    pub fn audible_position(&self) -> f64 {
+        let latency = self.latency.load(Ordering::SeqCst) * self.playback_rate();
+        (self.position() - latency).max(0.)
+    }
+
+    /// Sets the tempo map as `(time, bpm)` points. An empty map disables the
+    /// beat helpers.
+    pub fn set_tempo_map(&mut self, map: Vec<(f64, f64)>) -> Result<()> {
+        self.tempo_map = TempoMap::new(map).context("set tempo map")?;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn tempo_map(&self) -> &TempoMap {
+        &self.tempo_map
+    }
+
+    /// Audible position in beats, or `None` without a tempo map.
+    pub fn position_beats(&self) -> Option<f64> {
+        self.tempo_map.beat_at(self.audible_position())
+    }
+
+    pub fn seek_to_beat(&self, beat: f64) -> Result<()> {
+        let time = self
+            .tempo_map
+            .time_at_beat(beat)
+            .ok_or_else(|| anyhow!("no tempo map"))
+            .context("seek to beat")?;
+        self.seek_to(time.max(0.))
+    }
+
+    /// Clip time of the next whole beat after the audible position, or
+    /// `None` without a tempo map.
+    pub fn next_beat_time(&self) -> Option<f64> {
+        let beat = self.position_beats()?;
+        self.tempo_map.time_at_beat(beat.floor() + 1.)
+    }
+
+    /// Starts recording every command applied by the renderer, together
+    /// with the renderer clock (frames rendered since the track was created)
+    /// at which it took effect. At most `capacity` entries are kept between
+    /// two calls to [`Music::drain_command_log`]; later ones are dropped.
+    pub fn enable_command_log(&self, capacity: usize) -> Result<()> {
+        let (prod, cons) = HeapRb::new(capacity).split();
+        self.push(MusicCommand::SetCommandLog(Some(prod)))
+            .context("enable command log")?;
+        *self.command_log.lock().unwrap_or_else(|it| it.into_inner()) = Some(cons);
+        Ok(())
+    }
+
+    pub fn disable_command_log(&self) -> Result<()> {
+        self.push(MusicCommand::SetCommandLog(None))
+            .context("disable command log")?;
+        *self.command_log.lock().unwrap_or_else(|it| it.into_inner()) = None;
+        Ok(())
+    }
+
+    pub fn drain_command_log(&self) -> Vec<(u64, AppliedCommand)> {
+        self.command_log
+            .lock()
+            .unwrap_or_else(|it| it.into_inner())
+            .as_mut()
+            .map_or_else(Vec::new, |it| it.pop_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::offline::{OfflineRenderer, OfflineSettings},
+        test_util::{
+            constant, manager, manager_with, peak, render, settings, sine, BLOCK_SIZE, SAMPLE_RATE,
+        },
+        AudioManagerSettings,
+    };
+
+    fn track(manager: &mut crate::AudioManager) -> Music {
+        manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize * 2),
+                MusicParams::default(),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn pause_after_fade_in_wins() {
+        let (mut manager, mut out) = manager();
+        let music = track(&mut manager);
+        music.fade_in(1.).unwrap();
+        music.pause().unwrap();
+        assert!(peak(&render(&mut out, 1024)) == 0.);
+        assert!(music.paused());
+        assert!(music.renderer_paused());
+    }
+
+    #[test]
+    fn fade_in_after_pause_wins() {
+        let (mut manager, mut out) = manager();
+        let music = track(&mut manager);
+        music.play().unwrap();
+        render(&mut out, 1024);
+        music.pause().unwrap();
+        music.fade_in(0.01).unwrap();
+        assert!(peak(&render(&mut out, 1024)) > 0.);
+        assert!(!music.paused());
+        assert!(!music.renderer_paused());
+    }
+
+    #[test]
+    fn stop_after_fade_in_wins() {
+        let (mut manager, mut out) = manager();
+        let music = track(&mut manager);
+        music.play().unwrap();
+        render(&mut out, BLOCK_SIZE * 8);
+        music.fade_in(1.).unwrap();
+        music.stop().unwrap();
+        assert!(peak(&render(&mut out, 1024)) == 0.);
+        assert!(music.renderer_paused());
+        assert_eq!(music.position(), 0.);
+    }
+
+    #[test]
+    fn fade_in_after_stop_wins() {
+        let (mut manager, mut out) = manager();
+        let music = track(&mut manager);
+        music.play().unwrap();
+        render(&mut out, BLOCK_SIZE * 8);
+        music.stop().unwrap();
+        music.fade_in(0.01).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert!(!music.renderer_paused());
+        assert!((music.position() - BLOCK_SIZE as f64 / SAMPLE_RATE as f64).abs() < 1e-9);
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-4, "{a} != {b}");
+    }
+
+    #[test]
+    fn remaining_counts_the_loops_left() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize),
+                MusicParams {
+                    loop_mix_time: 0.,
+                    loop_count: Some(3),
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        music.play().unwrap();
+        render(&mut out, BLOCK_SIZE);
+        let played = BLOCK_SIZE as f64 / SAMPLE_RATE as f64;
+        assert_close(music.remaining() as f64, 3. - played);
+        let end = music.ends_at_sample_time().unwrap();
+        assert_close(end as f64, 3. * SAMPLE_RATE as f64);
+
+        // Played to the end of the last pass within a block of the estimate.
+        while !music.finished() {
+            render(&mut out, BLOCK_SIZE);
+        }
+        let ended = manager.sample_time();
+        assert!(ended >= end && ended <= end + BLOCK_SIZE as u64);
+
+        music.set_loop_count(None).unwrap();
+        music.play().unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert!(music.remaining().is_infinite());
+        assert_eq!(music.ends_at_sample_time(), None);
+    }
+
+    #[test]
+    fn remaining_counts_loops_of_an_inner_region() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize),
+                MusicParams {
+                    loop_mix_time: 0.,
+                    loop_count: Some(3),
+                    loop_start: 0.25,
+                    loop_end: Some(0.75),
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        music.play().unwrap();
+        render(&mut out, BLOCK_SIZE);
+        let played = BLOCK_SIZE as f64 / SAMPLE_RATE as f64;
+        assert_close(music.remaining() as f64, 1. + 2. * 0.5 - played);
+        music.set_playback_rate(2.).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        let position = music.position();
+        assert_close(music.remaining() as f64, (2. - position) / 2.);
+
+        // Past the region, only the rest of the clip is left.
+        music.seek_to(0.8).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert_close(music.remaining() as f64, (1. - music.position()) / 2.);
+
+        music.set_loop_mode(LoopMode::PingPong).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert!(music.remaining().is_infinite());
+    }
+
+    #[test]
+    fn overflowed_transport_keeps_push_order() {
+        let (mut manager, mut out) = manager();
+        let music = track(&mut manager);
+        for _ in 0..TRANSPORT_LANE_SIZE {
+            music.seek_to(0.5).unwrap();
+        }
+        // Overflows the transport lane, then is overridden by the fade in.
+        music.pause().unwrap();
+        music.fade_in(0.01).unwrap();
+        render(&mut out, 1024);
+        assert!(!music.renderer_paused());
+        music.fade_out(0.01).unwrap();
+        for _ in 0..TRANSPORT_LANE_SIZE {
+            music.seek_to(0.5).unwrap();
+        }
+        music.play().unwrap();
+        music.pause().unwrap();
+        render(&mut out, 1024);
+        assert!(music.renderer_paused());
+    }
+
+    #[test]
+    fn commands_from_several_threads_are_all_applied_in_order() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize),
+                MusicParams {
+                    command_buffer_size: 64,
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        music.enable_command_log(64).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let music = &music;
+                scope.spawn(move || {
+                    for i in 0..8 {
+                        music.set_amplifier((thread * 8 + i) as f32).unwrap();
+                    }
+                });
+            }
+        });
+        render(&mut out, BLOCK_SIZE);
+        let applied: Vec<_> = music
+            .drain_command_log()
+            .into_iter()
+            .filter_map(|it| match it.1 {
+                AppliedCommand::SetAmplifier(amp) => Some(amp as usize),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(applied.len(), 32);
+        for thread in 0..4 {
+            let pushed: Vec<_> = applied.iter().filter(|it| *it / 8 == thread).collect();
+            assert!(pushed.windows(2).all(|it| it[0] < it[1]));
+        }
+    }
+
+    #[test]
+    fn switching_to_ping_pong_reverses_at_the_next_end() {
+        let (mut manager, mut out) = manager();
+        let frames = 4800;
+        let ramp = AudioClip::from_raw(
+            (0..frames)
+                .map(|i| Frame::from_mono(i as f32 / frames as f32))
+                .collect(),
+            SAMPLE_RATE,
+        );
+        let music = manager
+            .create_music(
+                ramp,
+                MusicParams {
+                    loop_mix_time: 0.,
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        music.play().unwrap();
+        render(&mut out, BLOCK_SIZE * 4);
+        music.set_loop_mode(LoopMode::PingPong).unwrap();
+        // Still going forward until the end of the clip.
+        let data = render(&mut out, BLOCK_SIZE * 8);
+        let left: Vec<f32> = data.iter().step_by(2).copied().collect();
+        let end = frames - BLOCK_SIZE * 4;
+        assert!(left[..end].windows(2).all(|it| it[1] > it[0]));
+        // Then back down from the end rather than wrapping to the start.
+        // The last frame is held while turning around.
+        assert!(left[end..].windows(2).all(|it| it[1] <= it[0]));
+        let back = (left.len() - end) as f32 / frames as f32;
+        assert!((left[left.len() - 1] - (1. - back)).abs() < 4. / frames as f32);
+        assert!(music.is_looping());
+    }
+
+    #[test]
+    fn auto_fade_out_silences_the_end() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize * 2),
+                MusicParams {
+                    auto_fade_out: 1.,
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        music.play().unwrap();
+        let data = render(&mut out, SAMPLE_RATE as usize * 2);
+        let left: Vec<f32> = data.iter().step_by(2).copied().collect();
+        let second = SAMPLE_RATE as usize;
+        assert_eq!(peak(&left[..second]), 0.5);
+        assert!((left[second + second / 2] - 0.25).abs() < 0.01);
+        assert!(left[second..].windows(2).all(|it| it[1] <= it[0]));
+        assert!(peak(&left[left.len() - 16..]) < 1e-3);
+    }
+
+    #[test]
+    fn looping_follows_the_applied_loop_mode() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(
+                constant(0.5, BLOCK_SIZE * 4),
+                MusicParams {
+                    loop_mix_time: 0.,
+                    loop_count: Some(2),
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        assert!(music.is_looping());
+        music.set_loop_mode(LoopMode::Off).unwrap();
+        // Only read back once applied.
+        assert!(music.is_looping());
+        render(&mut out, BLOCK_SIZE);
+        assert!(!music.is_looping());
+        music.set_loop_mode(LoopMode::PingPong).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert!(music.is_looping());
+
+        // The last counted pass no longer loops.
+        music.set_loop_mode(LoopMode::Forward).unwrap();
+        music.play().unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert!(music.is_looping());
+        render(&mut out, BLOCK_SIZE * 4);
+        assert_eq!(music.loops_left(), Some(0));
+        assert!(!music.is_looping());
+    }
+
+    #[test]
+    fn duration_fades_ramp_over_the_exact_sample_count() {
+        let (mut manager, mut out) = manager();
+        let music = track(&mut manager);
+        music.fade_in_duration(Duration::from_millis(250)).unwrap();
+        let data = render(&mut out, BLOCK_SIZE * 24);
+        let left: Vec<f32> = data.iter().step_by(2).copied().collect();
+        let ramp = SAMPLE_RATE as usize / 4;
+        // One step of the ramp per frame, the last one at full level. The
+        // Q15 fade rounds each step to a multiple of 2^-15.
+        let step = 0.5 / ramp as f32;
+        for (i, sample) in left[..ramp].iter().enumerate() {
+            assert!((sample - step * (i + 1) as f32).abs() < 1. / 32768., "{i}");
+        }
+        assert!(left[ramp - 2] < 0.5);
+        assert!(left[ramp - 1..].iter().all(|it| *it == 0.5));
+    }
+
+    #[test]
+    fn command_log_records_when_commands_took_effect() {
+        let (mut manager, mut out) = manager();
+        let music = track(&mut manager);
+        music.enable_command_log(2).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        music.play().unwrap();
+        render(&mut out, BLOCK_SIZE * 2);
+        music.set_pan(0.5).unwrap();
+        music.fade_out(0.5).unwrap();
+        // Past the capacity, dropped until the log is drained.
+        music.set_amplifier(0.5).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        let block = BLOCK_SIZE as u64;
+        assert_eq!(
+            music.drain_command_log(),
+            [
+                (block, AppliedCommand::Resume),
+                (block * 3, AppliedCommand::SetPan(0.5))
+            ]
+        );
+        music.seek_to(1.).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert_eq!(
+            music.drain_command_log(),
+            [(block * 4, AppliedCommand::SeekTo(1.))]
+        );
+        music.disable_command_log().unwrap();
+        music.pause().unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert!(music.drain_command_log().is_empty());
+    }
+
+    #[test]
+    fn negative_amplifier_inverts_the_phase() {
+        let (mut manager, mut out) = manager();
+        let clip = sine(440., 0.5, 1.);
+        let music = manager
+            .create_music(clip.clone(), MusicParams::default())
+            .unwrap();
+        music.set_amplifier(-1.).unwrap();
+        music.play().unwrap();
+        let data = render(&mut out, BLOCK_SIZE * 4);
+        for (frame, sample) in clip.frames().iter().zip(data.chunks_exact(2)) {
+            assert!((sample[0] + frame.0).abs() < 1e-6 && (sample[1] + frame.1).abs() < 1e-6);
+        }
+
+        // Muting keeps the amplifier for when the track is unmuted.
+        music.set_muted(true).unwrap();
+        assert_eq!(peak(&render(&mut out, BLOCK_SIZE)), 0.);
+        music.set_muted(false).unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        let frames = &clip.frames()[BLOCK_SIZE * 5..];
+        assert!((data[0] + frames[0].0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn rate_envelope_ramping_to_zero_stops_like_tape() {
+        let (mut manager, mut out) = manager();
+        let music = track(&mut manager);
+        music.set_rate_envelope(vec![(0., 1.), (1., 0.)]).unwrap();
+        music.play().unwrap();
+        let step = BLOCK_SIZE * 24;
+        let mut last = 0.;
+        let mut advances = Vec::new();
+        for i in 1..=5 {
+            render(&mut out, step);
+            // The integral of the rate, which falls linearly to 0 at 1 s.
+            let time = (i * step) as f64 / SAMPLE_RATE as f64;
+            let expected = if time < 1. {
+                time - time * time / 2.
+            } else {
+                0.5
+            };
+            assert_close(music.position(), expected);
+            advances.push(music.position() - last);
+            last = music.position();
+        }
+        assert!(advances.windows(2).all(|it| it[1] < it[0]));
+        assert_eq!(advances[4], 0.);
+    }
+
+    #[test]
+    fn preserve_pitch_keeps_the_frequency_at_a_slower_rate() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(
+                sine(440., 0.5, 2.),
+                MusicParams {
+                    playback_rate: 0.8,
+                    preserve_pitch: true,
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        music.play().unwrap();
+        let frames = BLOCK_SIZE * 94;
+        let data = render(&mut out, frames);
+        let left: Vec<f32> = data.iter().step_by(2).copied().collect();
+        // Rising zero crossings per second, past the first grain.
+        let steady = &left[BLOCK_SIZE * 4..];
+        let crossings = steady
+            .windows(2)
+            .filter(|it| it[0] < 0. && it[1] >= 0.)
+            .count() as f64;
+        let frequency = crossings * SAMPLE_RATE as f64 / steady.len() as f64;
+        assert!((frequency - 440.).abs() < 5., "{frequency} Hz");
+
+        let time = frames as f64 / SAMPLE_RATE as f64;
+        assert_close(music.position(), time * 0.8);
+        assert_close(music.remaining() as f64, (2. - time * 0.8) / 0.8);
+    }
+
+    #[test]
+    fn non_finite_inputs_are_rejected_and_filters_recover() {
+        let (mut manager, mut out) = manager();
+        let mut frames = vec![Frame::from_mono(0.5); SAMPLE_RATE as usize];
+        frames[100] = Frame::from_mono(f32::NAN);
+        let music = manager
+            .create_music(
+                AudioClip::from_raw(frames, SAMPLE_RATE),
+                MusicParams::default(),
+            )
+            .unwrap();
+        let err = music.set_amplifier(f32::NAN).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<crate::Error>(),
+            Some(&crate::Error::NonFinite("amplifier"))
+        );
+        assert!(music.set_pan(f32::INFINITY).is_err());
+        // Out of range values are clamped instead.
+        music.set_low_pass(2.).unwrap();
+        music.set_low_pass(0.5).unwrap();
+        music.play().unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        assert!(data[..100].iter().all(|it| *it > 0.));
+        assert!(data[200].is_nan() && data[data.len() - 1].is_nan());
+        // The NaN stuck in the filter is cleared on the next block.
+        let data = render(&mut out, BLOCK_SIZE * 16);
+        assert!(data.iter().all(|it| it.is_finite()));
+        assert!((data[data.len() - 1] - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn cleared_commands_do_not_take_effect() {
+        let (mut manager, mut out) = manager();
+        let music = track(&mut manager);
+        music.play().unwrap();
+        render(&mut out, BLOCK_SIZE);
+        music.set_amplifier(0.).unwrap();
+        music.seek_to(1.5).unwrap();
+        music.set_pan(1.).unwrap();
+        music.pause().unwrap();
+        music.clear_pending().unwrap();
+        assert!(!music.paused());
+        let data = render(&mut out, BLOCK_SIZE);
+        assert!(data.iter().all(|it| *it == 0.5));
+        assert_close(
+            music.position(),
+            2. * BLOCK_SIZE as f64 / SAMPLE_RATE as f64,
+        );
+
+        // Later commands are applied again.
+        music.set_amplifier(0.5).unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        assert!(data.iter().all(|it| *it == 0.25));
+    }
+
+    #[test]
+    fn ab_loop_cycles_between_its_points() {
+        let (mut manager, mut out) = manager();
+        let frames = SAMPLE_RATE as usize;
+        let ramp = AudioClip::from_raw(
+            (0..frames)
+                .map(|i| Frame::from_mono(i as f32 / frames as f32))
+                .collect(),
+            SAMPLE_RATE,
+        );
+        let music = manager.create_music(ramp, MusicParams::default()).unwrap();
+        music.play().unwrap();
+        // 480 frames from 0.1 s on.
+        music.set_ab_loop(0.1, 0.11).unwrap();
+        assert!(music.set_ab_loop(0.2, 0.1).is_err());
+        let data = render(&mut out, BLOCK_SIZE * 4);
+        for (i, sample) in data.iter().step_by(2).enumerate() {
+            let expected = (4800 + i % 480) as f32 / frames as f32;
+            assert!(
+                (sample - expected).abs() < 1e-6,
+                "{i}: {sample} != {expected}"
+            );
+        }
+
+        music.clear_ab_loop().unwrap();
+        let data = render(&mut out, BLOCK_SIZE * 4);
+        assert!(data.iter().step_by(2).any(|it| *it > 0.11));
+    }
+
+    #[test]
+    fn loops_wrap_at_the_same_source_sample_at_any_rate() {
+        let frames = SAMPLE_RATE as usize / 2;
+        let ramp = AudioClip::from_raw(
+            (0..frames)
+                .map(|i| Frame::from_mono(i as f32 / frames as f32))
+                .collect(),
+            SAMPLE_RATE,
+        );
+        for rate in [0.5, 1.5] {
+            let (mut manager, mut out) = manager();
+            let music = manager
+                .create_music(
+                    ramp.clone(),
+                    MusicParams {
+                        loop_mix_time: 0.,
+                        loop_start: 0.1,
+                        loop_end: Some(0.4),
+                        playback_rate: rate,
+                        ..MusicParams::default()
+                    },
+                )
+                .unwrap();
+            assert_close(music.effective_loop_length(), 0.3);
+            music.play().unwrap();
+            let data = render(&mut out, (0.45 / rate * SAMPLE_RATE as f64) as usize);
+            let left: Vec<f32> = data.iter().step_by(2).copied().collect();
+            let wrap = left.windows(2).position(|it| it[1] < it[0]).unwrap() + 1;
+            // The last frame before the wrap is within a step of `loop_end`,
+            // and the first one after it as far past `loop_start`.
+            let step = rate as f32 / frames as f32 * 0.5;
+            let (before, after) = (left[wrap - 1], left[wrap]);
+            assert!(
+                before < 0.8 && before >= 0.8 - step * 2.,
+                "{rate}: {before}"
+            );
+            assert!(after >= 0.2 && after < 0.2 + step * 2., "{rate}: {after}");
+            assert!(
+                (wrap as f64 - 0.4 / rate * SAMPLE_RATE as f64).abs() <= 1.,
+                "{rate}"
+            );
+        }
+    }
+
+    #[test]
+    fn stats_count_a_scripted_session() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(
+                constant(0.5, BLOCK_SIZE * 4),
+                MusicParams {
+                    loop_mix_time: 0.,
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        music.play().unwrap();
+        render(&mut out, BLOCK_SIZE * 3);
+        music.pause().unwrap();
+        render(&mut out, BLOCK_SIZE * 2);
+        music.play().unwrap();
+        music.seek_to(0.).unwrap();
+        // Wraps around once, after four blocks.
+        render(&mut out, BLOCK_SIZE * 6);
+        assert_eq!(
+            music.stats(),
+            MusicStats {
+                frames_played: BLOCK_SIZE as u64 * 9,
+                pauses: 1,
+                seeks: 1,
+                loops: 1,
+            }
+        );
+        music.reset_stats();
+        assert_eq!(music.stats(), MusicStats::default());
+        render(&mut out, BLOCK_SIZE);
+        assert_eq!(music.stats().frames_played, BLOCK_SIZE as u64);
+    }
+
+    #[test]
+    fn scheduled_commands_land_on_their_frames() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(
+                constant(1., SAMPLE_RATE as usize),
+                MusicParams {
+                    command_buffer_size: 64,
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        let at = |frame: usize| frame as f64 / SAMPLE_RATE as f64;
+        for (frame, amp) in [(3000, 1.), (1000, 0.5), (2000, 0.25)] {
+            music
+                .schedule(at(frame), ScheduledCommand::SetAmplifier(amp))
+                .unwrap();
+        }
+        music.play().unwrap();
+        let data = render(&mut out, BLOCK_SIZE * 8);
+        for (i, sample) in data.iter().step_by(2).enumerate() {
+            let expected = match i {
+                0..=999 => 1.,
+                1000..=1999 => 0.5,
+                2000..=2999 => 0.25,
+                _ => 1.,
+            };
+            assert_eq!(*sample, expected, "{i}");
+        }
+
+        for _ in 0..MAX_SCHEDULED {
+            music.schedule(0.9, ScheduledCommand::FadeOut(0.)).unwrap();
+        }
+        assert!(music.schedule(0.9, ScheduledCommand::FadeOut(0.)).is_err());
+        music.clear_schedule().unwrap();
+        render(&mut out, BLOCK_SIZE);
+        music.schedule(0.9, ScheduledCommand::FadeOut(0.)).unwrap();
+    }
+
+    #[test]
+    fn position_does_not_drift_over_a_long_render() {
+        // A low rate keeps ten minutes of output quick to render.
+        let (sample_rate, block_size) = (8000, 400);
+        let (mut manager, mut out) = manager_with(
+            settings(),
+            OfflineSettings {
+                sample_rate,
+                channels: 2,
+                block_size,
+            },
+        );
+        let frames = sample_rate as usize;
+        let ramp = AudioClip::from_raw(
+            (0..frames)
+                .map(|i| Frame::from_mono(i as f32 / frames as f32))
+                .collect(),
+            sample_rate,
+        );
+        let rate = 1.23;
+        let music = manager
+            .create_music(
+                ramp,
+                MusicParams {
+                    loop_mix_time: 0.,
+                    playback_rate: rate,
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        music.play().unwrap();
+        let chunk = block_size * 100;
+        let mut rendered = 0;
+        while rendered < sample_rate as usize * 600 {
+            let data = render(&mut out, chunk);
+            rendered += chunk;
+            // Source frames consumed, wrapped into the clip.
+            let consumed = (rendered as f64 * rate) % frames as f64;
+            let position = music.position() * sample_rate as f64;
+            let error = (position - consumed + frames as f64 / 2.).rem_euclid(frames as f64)
+                - frames as f64 / 2.;
+            assert!(error.abs() < 1., "{rendered}: {position} != {consumed}");
+            let last = (consumed - rate).rem_euclid(frames as f64) / frames as f64;
+            assert!((data[data.len() - 1] as f64 - last).abs() < 1. / frames as f64);
+        }
+    }
+
+    #[test]
+    fn silent_tracks_keep_time_with_audible_ones() {
+        let (mut manager, mut out) = manager();
+        let looping = |keep_time_when_silent| MusicParams {
+            loop_mix_time: 0.,
+            keep_time_when_silent,
+            ..MusicParams::default()
+        };
+        let clip = constant(0.25, SAMPLE_RATE as usize);
+        let audible = manager.create_music(clip.clone(), looping(false)).unwrap();
+        let faded = manager.create_music(clip.clone(), looping(true)).unwrap();
+        let muted = manager.create_music(clip.clone(), looping(true)).unwrap();
+        let paused = manager.create_music(clip, looping(false)).unwrap();
+        for music in [&audible, &faded, &muted, &paused] {
+            music.play().unwrap();
+        }
+        faded.fade_out(0.1).unwrap();
+        muted.set_muted(true).unwrap();
+        paused.fade_out(0.1).unwrap();
+        render(&mut out, BLOCK_SIZE * 16);
+        assert!(!faded.paused() && paused.paused());
+        assert_eq!(faded.effective_gain(), 0.);
+
+        for _ in 0..30 {
+            render(&mut out, SAMPLE_RATE as usize / BLOCK_SIZE * BLOCK_SIZE);
+        }
+        assert_eq!(faded.position(), audible.position());
+        assert_eq!(muted.position(), audible.position());
+        assert_ne!(paused.position(), audible.position());
+        let data = render(&mut out, BLOCK_SIZE);
+        assert!(data.iter().all(|it| *it == 0.25));
+
+        // Fading back in carries on from the shared position.
+        faded.fade_in(0.01).unwrap();
+        render(&mut out, BLOCK_SIZE * 2);
+        assert_eq!(faded.position(), audible.position());
+        assert_eq!(faded.effective_gain(), 1.);
+    }
+
+    #[test]
+    fn toggling_reads_back_the_latest_intent() {
+        let (mut manager, mut out) = manager();
+        let music = track(&mut manager);
+        assert!(music.paused());
+        // A toggle button pressed several times within one block.
+        for i in 0..7 {
+            if music.paused() {
+                music.play().unwrap();
+            } else {
+                music.pause().unwrap();
+            }
+            assert_eq!(music.paused(), i % 2 == 1);
+        }
+        assert!(music.renderer_paused());
+        render(&mut out, BLOCK_SIZE);
+        assert!(!music.paused() && !music.renderer_paused());
+
+        // A pending fade out counts as paused, and playing wins over it.
+        music.fade_out(1.).unwrap();
+        assert!(music.paused());
+        render(&mut out, BLOCK_SIZE);
+        assert!(!music.renderer_paused());
+        music.play().unwrap();
+        assert!(!music.paused());
+        music.fade_out(0.001).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert!(music.paused() && music.renderer_paused());
+    }
+
+    #[test]
+    fn seeks_and_fades_hours_into_a_long_clip() {
+        let (mut manager, mut out) = manager_with(
+            AudioManagerSettings {
+                auto_resample: false,
+                ..settings()
+            },
+            OfflineSettings {
+                sample_rate: SAMPLE_RATE,
+                channels: 2,
+                block_size: BLOCK_SIZE,
+            },
+        );
+        // Three hours at a low rate, so that the clip stays small. Each
+        // frame holds its index modulo 1000 in thousandths.
+        let rate = 100;
+        let frames = rate as usize * 3 * 3600;
+        let clip = AudioClip::from_raw(
+            (0..frames)
+                .map(|i| Frame::from_mono((i % 1000) as f32 / 1000.))
+                .collect(),
+            rate,
+        );
+        let music = manager.create_music(clip, MusicParams::default()).unwrap();
+        assert_close(music.duration(), 3. * 3600.);
+        let at = 2.5 * 3600.;
+        music.seek_to(at).unwrap();
+        music.play().unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        assert_eq!(data[0], 0.);
+        let played = BLOCK_SIZE as f64 / SAMPLE_RATE as f64;
+        assert_close(music.position(), at + played);
+        let step = 1. / SAMPLE_RATE as f64 * rate as f64;
+        let last = (BLOCK_SIZE - 1) as f64 * step / 1000.;
+        assert!((data[data.len() - 1] as f64 - last).abs() < 1e-6);
+
+        music.fade_out(0.5).unwrap();
+        let blocks = SAMPLE_RATE as usize / 2 / BLOCK_SIZE + 1;
+        render(&mut out, BLOCK_SIZE * blocks);
+        assert!(music.paused() && music.renderer_paused());
+        assert_close(music.position(), at + played + 0.5);
+    }
+
+    #[test]
+    fn replaced_allocations_return_to_the_handle() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize * 60),
+                MusicParams {
+                    command_buffer_size: 8,
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        music.play().unwrap();
+        let garbage = || music.lanes.lock().unwrap().garbage.len();
+        for i in 0..200 {
+            let rate = 1. + (i % 4) as f32 / 8.;
+            music
+                .set_rate_envelope(vec![(0., rate), (1., rate)])
+                .unwrap();
+            music.set_rate_envelope(vec![(0., rate)]).unwrap();
+            music.enable_command_log(4).unwrap();
+            render(&mut out, BLOCK_SIZE);
+            // The replaced envelopes and log, emptied by the next push. The
+            // default envelope holds no allocation.
+            assert_eq!(garbage(), if i == 0 { 1 } else { 3 });
+        }
+
+        // Discarded commands are reclaimed too.
+        music.set_rate_envelope(vec![(0., 1.)]).unwrap();
+        assert_eq!(garbage(), 0);
+        music.clear_pending().unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert_eq!(garbage(), 1);
+        music.disable_command_log().unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert_eq!(garbage(), 1);
+    }
+
+    #[test]
+    fn seeks_cancel_fade_outs_and_keep_fade_ins() {
+        let blocks = |seconds: f64| (seconds * SAMPLE_RATE as f64) as usize / BLOCK_SIZE;
+        for (fade_in, keep_fade) in [(false, false), (false, true), (true, false), (true, true)] {
+            let what = format!("fade in: {fade_in}, keep fade: {keep_fade}");
+            let (mut manager, mut out) = manager();
+            let music = track(&mut manager);
+            if fade_in {
+                music.fade_in(0.5).unwrap();
+            } else {
+                music.play().unwrap();
+                render(&mut out, BLOCK_SIZE);
+                music.fade_out(0.5).unwrap();
+            }
+            let before = render(&mut out, BLOCK_SIZE * blocks(0.25));
+            let gain = before[before.len() - 1];
+            assert!(gain > 0.1 && gain < 0.4, "{what}");
+            if keep_fade {
+                music.seek_to_keep_fade(1.).unwrap();
+            } else {
+                music.seek_to(1.).unwrap();
+            }
+            let after = render(&mut out, BLOCK_SIZE);
+            assert!(music.position() < 1.1, "{what}");
+            if fade_in || keep_fade {
+                // The fade goes on from where it was.
+                assert!((after[0] - gain).abs() < 0.01, "{what}");
+            } else {
+                assert_eq!(after[0], 0.5, "{what}");
+            }
+            let rest = render(&mut out, BLOCK_SIZE * blocks(0.5));
+            if !fade_in && keep_fade {
+                assert_eq!(rest[rest.len() - 1], 0., "{what}");
+                assert!(music.paused() && music.renderer_paused(), "{what}");
+            } else {
+                assert_eq!(rest[rest.len() - 1], 0.5, "{what}");
+                assert!(!music.paused() && !music.renderer_paused(), "{what}");
+            }
+        }
+    }
+
+    #[test]
+    fn playing_again_after_the_end_rewinds() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(
+                constant(0.5, BLOCK_SIZE * 2),
+                MusicParams {
+                    loop_mix_time: -1.,
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        let length = music.duration();
+        music.play().unwrap();
+        assert_eq!(peak(&render(&mut out, BLOCK_SIZE)), 0.5);
+        // Pressed again short of the end: plays the rest, then pauses.
+        music.play().unwrap();
+        assert_eq!(peak(&render(&mut out, BLOCK_SIZE)), 0.5);
+        assert_close(music.position(), length);
+        // Pressed again at the end, which the renderer only reaches on the
+        // next block: the track is finished, not playing.
+        music.play().unwrap();
+        assert_eq!(peak(&render(&mut out, BLOCK_SIZE)), 0.);
+        assert!(music.paused() && music.renderer_paused() && music.finished());
+        assert_close(music.position(), length);
+
+        music.play().unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        assert_eq!(data[0], 0.5);
+        assert!(!music.paused() && !music.renderer_paused() && !music.finished());
+        assert_close(music.position(), length / 2.);
+        render(&mut out, BLOCK_SIZE * 2);
+        assert!(music.paused() && music.finished());
+
+        music.fade_in(0.001).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert!(!music.paused() && !music.finished());
+        assert_close(music.position(), length / 2.);
+    }
+
+    #[test]
+    fn fades_ramp_from_the_current_gain() {
+        let (mut manager, mut out) = manager();
+        let music = track(&mut manager);
+        let left = |data: Vec<f32>| data.into_iter().step_by(2).collect::<Vec<_>>();
+        let quarter = SAMPLE_RATE as usize / 4 / BLOCK_SIZE * BLOCK_SIZE;
+
+        music.fade_in(0.5).unwrap();
+        let rising = left(render(&mut out, quarter));
+        assert!(rising[0] < 0.01);
+        assert!(rising.windows(2).all(|it| it[1] > it[0]));
+        let gain = rising[rising.len() - 1];
+        assert!((gain - 0.25).abs() < 0.01, "{gain}");
+
+        // Reversed halfway: down from where the fade in got to, at the same
+        // rate, so over the same time.
+        music.fade_out(0.5).unwrap();
+        let falling = left(render(&mut out, quarter));
+        assert!((falling[0] - gain).abs() < 0.01);
+        assert!(falling.windows(2).all(|it| it[1] < it[0]));
+        assert!(falling[falling.len() - 1] < 0.01);
+
+        // A completed fade out leaves the position where it stopped.
+        assert_eq!(peak(&render(&mut out, quarter)), 0.);
+        assert!(music.paused() && music.renderer_paused());
+        let position = music.position();
+        assert_close(position, 2. * quarter as f64 / SAMPLE_RATE as f64);
+        render(&mut out, quarter);
+        assert_eq!(music.position(), position);
+        music.fade_in(0.5).unwrap();
+        let rising = left(render(&mut out, quarter));
+        assert!(rising[0] < 0.01);
+        assert!(rising.windows(2).all(|it| it[1] > it[0]));
+        assert_close(
+            music.position(),
+            position + quarter as f64 / SAMPLE_RATE as f64,
+        );
+    }
+
+    #[test]
+    fn position_counts_the_rendered_frames() {
+        for rate in [1., 0.75, 1.5] {
+            let (mut manager, mut out) = manager();
+            let music = manager
+                .create_music(
+                    constant(0.5, SAMPLE_RATE as usize * 10),
+                    MusicParams {
+                        playback_rate: rate,
+                        ..MusicParams::default()
+                    },
+                )
+                .unwrap();
+            music.seek_to(0.123_456_7).unwrap();
+            music.play().unwrap();
+            let mut frames = 0;
+            for blocks in [1, 3, 7, 40] {
+                render(&mut out, BLOCK_SIZE * blocks);
+                frames += BLOCK_SIZE * blocks;
+                let expected = 0.123_456_7 + frames as f64 / SAMPLE_RATE as f64 * rate;
+                assert!((music.position() - expected).abs() < 1e-9, "{rate}");
+            }
+            // Nothing to compensate on the offline backend.
+            assert_eq!(manager.estimate_latency(), 0.);
+            assert_eq!(music.audible_position(), music.position());
+        }
+    }
+
+    #[test]
+    fn zero_fade_outs_pause_right_away() {
+        let (mut manager, mut out) = manager();
+        let music = track(&mut manager);
+        let grouped = track(&mut manager);
+        let silent = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize * 2),
+                MusicParams {
+                    keep_time_when_silent: true,
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        let mut group = manager.create_group(&[&grouped]).unwrap();
+        for music in [&music, &grouped, &silent] {
+            music.play().unwrap();
+        }
+        assert_eq!(peak(&render(&mut out, BLOCK_SIZE)), 1.5);
+
+        music.fade_out(0.).unwrap();
+        group.fade_out(0.).unwrap();
+        silent.fade_out(0.).unwrap();
+        assert!(music.paused() && grouped.paused() && !silent.paused());
+        for _ in 0..2 {
+            assert_eq!(peak(&render(&mut out, BLOCK_SIZE)), 0.);
+            assert!(music.paused() && music.renderer_paused());
+            assert!(grouped.paused() && grouped.renderer_paused());
+            assert!(!silent.paused() && !silent.renderer_paused());
+        }
+        let played = BLOCK_SIZE as f64 / SAMPLE_RATE as f64;
+        assert_close(music.position(), played);
+        assert_close(grouped.position(), played);
+        assert_close(silent.position(), played * 3.);
+    }
+
+    /// Harmonics 1 to 64 of a 512-frame period, with scattered phases, so
+    /// that every block of [`BLOCK_SIZE`] frames holds the same spectrum.
+    fn harmonics(seconds: f64) -> AudioClip {
+        let frames = (seconds * SAMPLE_RATE as f64) as usize;
+        AudioClip::from_raw(
+            (0..frames)
+                .map(|i| {
+                    let t = (i % BLOCK_SIZE) as f32 / BLOCK_SIZE as f32;
+                    let sum: f32 = (1..=64)
+                        .map(|k| (std::f32::consts::TAU * (k as f32 * t + k as f32 * 0.37)).sin())
+                        .sum();
+                    Frame::from_mono(sum / 64.)
+                })
+                .collect(),
+            SAMPLE_RATE,
+        )
+    }
+
+    /// Spectral centroid, in bins, of the left channel of a block.
+    fn centroid(block: &[f32]) -> f64 {
+        let samples: Vec<f64> = block.iter().step_by(2).map(|it| *it as f64).collect();
+        let (mut weighted, mut total) = (0., 0.);
+        for k in 1..samples.len() / 2 {
+            let (mut re, mut im) = (0., 0.);
+            for (i, sample) in samples.iter().enumerate() {
+                let phase = std::f64::consts::TAU * (k * i) as f64 / samples.len() as f64;
+                re += sample * phase.cos();
+                im -= sample * phase.sin();
+            }
+            let magnitude = (re * re + im * im).sqrt();
+            weighted += k as f64 * magnitude;
+            total += magnitude;
+        }
+        weighted / total
+    }
+
+    fn centroids(out: &mut OfflineRenderer, blocks: usize) -> Vec<f64> {
+        (0..blocks)
+            .map(|_| centroid(&render(out, BLOCK_SIZE)))
+            .collect()
+    }
+
+    fn assert_falling(centroids: &[f64]) {
+        for pair in centroids.windows(2) {
+            assert!(pair[1] < pair[0], "{centroids:?}");
+        }
+    }
+
+    fn assert_steady(centroids: &[f64]) {
+        for pair in centroids.windows(2) {
+            assert!((pair[1] - pair[0]).abs() < 1e-3 * pair[0], "{centroids:?}");
+        }
+    }
+
+    #[test]
+    fn smooth_low_pass_sweeps_down() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(harmonics(1.), MusicParams::default())
+            .unwrap();
+        music.play().unwrap();
+        let open = centroid(&render(&mut out, BLOCK_SIZE));
+
+        // Ramps over 24 blocks, then holds.
+        music
+            .set_low_pass_smooth(0.95, (24 * BLOCK_SIZE) as f64 / SAMPLE_RATE as f64)
+            .unwrap();
+        let mut sweep = vec![open];
+        sweep.extend(centroids(&mut out, 24));
+        assert_falling(&sweep);
+        assert!(sweep[24] < open * 0.75, "{sweep:?}");
+        // The filter state settles within a block once the ramp is over.
+        render(&mut out, BLOCK_SIZE);
+        assert_steady(&centroids(&mut out, 8));
+    }
+
+    #[test]
+    fn smooth_low_pass_keeps_its_length_across_rate_changes() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(harmonics(1.), MusicParams::default())
+            .unwrap();
+        music.play().unwrap();
+        render(&mut out, BLOCK_SIZE);
+        music
+            .set_low_pass_smooth(0.9, (20 * BLOCK_SIZE) as f64 / SAMPLE_RATE as f64)
+            .unwrap();
+        assert_falling(&centroids(&mut out, 10));
+
+        // Half the ramp is left, which takes 5 blocks at half the rate.
+        manager
+            .reconfigure(AudioManagerSettings {
+                sample_rate: Some(SAMPLE_RATE / 2),
+                ..settings()
+            })
+            .unwrap();
+        assert_falling(&centroids(&mut out, 5));
+        render(&mut out, BLOCK_SIZE);
+        assert_steady(&centroids(&mut out, 8));
+    }
+
+    #[test]
+    fn smooth_low_pass_replaces_the_ramp_in_progress() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(harmonics(1.), MusicParams::default())
+            .unwrap();
+        let reference = manager
+            .create_music(harmonics(1.), MusicParams::default())
+            .unwrap();
+        reference.set_low_pass(0.5).unwrap();
+        render(&mut out, BLOCK_SIZE);
+
+        music.play().unwrap();
+        music.set_low_pass_smooth(0.95, 1.).unwrap();
+        assert_falling(&centroids(&mut out, 5));
+        // Jumps to the new target and stays there instead of carrying on
+        // towards the old one.
+        music.set_low_pass_smooth(0.5, 0.).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        let held = centroids(&mut out, 8);
+        assert_steady(&held);
+        music.pause().unwrap();
+        render(&mut out, BLOCK_SIZE);
+
+        reference.play().unwrap();
+        render(&mut out, BLOCK_SIZE);
+        let expected = centroid(&render(&mut out, BLOCK_SIZE));
+        assert!(
+            (held[0] - expected).abs() < 1e-3 * expected,
+            "{held:?} {expected}"
+        );
+
+        // A plain set cancels a ramp as well.
+        reference.set_low_pass_smooth(0., 1.).unwrap();
+        render(&mut out, BLOCK_SIZE * 4);
+        reference.set_low_pass(0.5).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert_steady(&centroids(&mut out, 8));
+    }
+
+    #[test]
+    fn coalesced_setters_never_fail() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize * 10),
+                MusicParams {
+                    command_buffer_size: 4,
+                    command_overflow: CommandOverflow::Coalesce,
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        music.play().unwrap();
+        render(&mut out, BLOCK_SIZE);
+
+        for i in 1..=100 {
+            music.seek_to(i as f64 * 0.05).unwrap();
+            music.set_amplifier(i as f32 / 100.).unwrap();
+            music.set_playback_rate(1. + i as f64 / 100.).unwrap();
+        }
+        music.set_amplifier(0.5).unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        // Only the latest values are applied, all on the same block.
+        assert_eq!(peak(&data), 0.25);
+        assert!(data.iter().all(|it| *it == 0.25));
+        assert_close(
+            music.position(),
+            5. + 2. * BLOCK_SIZE as f64 / SAMPLE_RATE as f64,
+        );
+
+        let data = render(&mut out, BLOCK_SIZE);
+        assert!(data.iter().all(|it| *it == 0.25));
+        assert_close(
+            music.position(),
+            5. + 4. * BLOCK_SIZE as f64 / SAMPLE_RATE as f64,
+        );
+    }
+
+    #[test]
+    fn failing_setters_report_a_full_buffer() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize),
+                MusicParams {
+                    command_buffer_size: 4,
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        music.play().unwrap();
+        let mut applied = 0.;
+        for i in 1..=100 {
+            if music.set_amplifier(i as f32 / 100.).is_err() {
+                break;
+            }
+            applied = i as f32 / 100.;
+        }
+        assert_eq!(applied, 0.04);
+        assert_eq!(peak(&render(&mut out, BLOCK_SIZE)), 0.5 * applied);
+    }
+
+    #[test]
+    fn transport_skips_a_full_general_lane() {
+        let (mut manager, mut out) = manager();
+        let music = track(&mut manager);
+        music.play().unwrap();
+        render(&mut out, BLOCK_SIZE);
+
+        let mut pushed = 0;
+        while music.set_low_pass(0.5).is_ok() {
+            pushed += 1;
+        }
+        assert_eq!(pushed, MusicParams::default().command_buffer_size);
+        music.pause().unwrap();
+        assert_eq!(peak(&render(&mut out, BLOCK_SIZE)), 0.);
+        assert!(music.renderer_paused());
+
+        while music.set_amplifier(0.5).is_ok() {}
+        music.play().unwrap();
+        assert!(peak(&render(&mut out, BLOCK_SIZE)) > 0.);
+        assert!(!music.renderer_paused());
+    }
+
+    #[test]
+    fn pans_are_left_out_of_mono_output() {
+        let (mut manager, mut out) = manager();
+        let music = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize),
+                MusicParams {
+                    pan: 1.,
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        music.play().unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        let right = 0.5 * std::f32::consts::SQRT_2;
+        assert!(data
+            .chunks_exact(2)
+            .all(|it| it[0] == 0. && (it[1] - right).abs() < 1e-6));
+
+        // Mono output has no side to pan to, the track plays as centered.
+        out.set_channels(1).unwrap();
+        let mut mono = vec![0.; BLOCK_SIZE];
+        out.render(&mut mono);
+        assert!(mono.iter().all(|it| *it == 0.5));
+    }
 }
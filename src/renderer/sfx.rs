@@ -1,89 +1,1265 @@
-use crate::{buffer_is_full, AudioClip, Renderer};
-use anyhow::{Context, Result};
+use super::music::PlayHead;
+use crate::{
+    buffer_is_full, check_connected,
+    mixer::StreamInfo,
+    rng::Rng,
+    snapshot::{Probe, Probed},
+    validate, AudioClip, Music, RenderContext, Renderer,
+};
+use anyhow::{anyhow, bail, Context, Result};
 use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
-use std::sync::{Arc, Weak};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    Arc, Weak,
+};
+
+/// Voices kept per [`Sfx`] while the mixer is degraded, the quietest ones
+/// being stopped.
+const DEGRADED_VOICES: usize = 8;
+
+/// Capacity of the lane used by [`Sfx::play_instant`].
+const INSTANT_LANE_SIZE: usize = 4;
+
+/// Most plays a batch can carry, see [`Sfx::schedule_batch`].
+const MAX_BATCH_PLAYS: usize = 1 << 16;
 
 #[derive(Debug, Clone)]
 pub struct PlaySfxParams {
+    /// Linear gain. Negative values invert the phase.
     pub amplifier: f32,
+    /// Delay in seconds before the instance starts playing.
+    pub delay: f64,
+    /// Position in seconds of the clip to start playing from, clamped to
+    /// its length.
+    pub start_offset: f64,
+    /// Seconds the gain ramps up from 0 over when the instance starts.
+    pub attack: f32,
+    /// Seconds the gain ramps down to 0 over when the instance is stopped,
+    /// instead of cutting it off.
+    pub release: f32,
+    pub humanize: Humanize,
 }
 impl Default for PlaySfxParams {
     fn default() -> Self {
-        Self { amplifier: 1. }
+        Self {
+            amplifier: 1.,
+            delay: 0.,
+            start_offset: 0.,
+            attack: 0.,
+            release: 0.,
+            humanize: Humanize::default(),
+        }
+    }
+}
+
+/// Random variation applied to each play, so that repeated triggers do not
+/// sound identical.
+///
+/// Draws come from the engine's seeded generator, one sequence per [`Sfx`],
+/// so the same plays vary the same way on every run. A zero jitter draws
+/// nothing.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Humanize {
+    /// The amplifier is scaled by a factor uniform within `1 ± amp_jitter`,
+    /// from 0 to 1.
+    pub amp_jitter: f32,
+    /// The start is delayed further by up to this many seconds.
+    pub time_jitter: f32,
+}
+
+/// A play of a batch, see [`Sfx::schedule_batch`].
+#[derive(Debug, Clone)]
+pub struct ScheduledPlay {
+    /// Seconds on the clock of the batch at which the instance starts, on
+    /// the nearest frame. [`PlaySfxParams::delay`] is added to it.
+    pub at: f64,
+    /// Index of the clip to play among the clips of the batch.
+    pub clip: usize,
+    pub params: PlaySfxParams,
+}
+
+enum BatchClock {
+    /// Seconds of [`RenderContext::sample_time`].
+    Mixer,
+    /// Positions of a track.
+    Music(PlayHead),
+}
+
+/// Plays sorted by time, walked by the renderer block by block.
+struct Batch {
+    clock: BatchClock,
+    /// Clips the plays refer to by index, held by their voices as well.
+    clips: Vec<AudioClip>,
+    plays: Vec<ScheduledPlay>,
+    /// Index of the next play to start.
+    next: usize,
+    /// Clock at the start of the next block if it runs on, to detect
+    /// seeks of the track; NaN before the first block.
+    expected: f64,
+}
+
+/// Allocations released by the renderer, sent back to the handle to be
+/// freed off the audio thread.
+#[allow(dead_code)] // Only held to be dropped.
+enum SfxGarbage {
+    Clip(AudioClip),
+    Batch(Box<Batch>),
+}
+
+/// Handle to a single playing instance of a [`Sfx`].
+///
+/// Slots in the voice table are recycled, so each handle also carries the
+/// generation of the slot it was issued for. A stale handle never matches a
+/// newer voice occupying the same slot, and a handle passed to another
+/// [`Sfx`] than the one that issued it matches none of its voices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SfxInstance {
+    owner: u32,
+    slot: u32,
+    generation: u32,
+}
+
+/// Identifies the [`Sfx`] that issued an [`SfxInstance`].
+static NEXT_OWNER: AtomicU32 = AtomicU32::new(0);
+
+/// Per-slot state shared between [`Sfx`] and its renderer: the generation in
+/// the high bits and a "live" flag in the lowest bit.
+struct VoiceSlots {
+    owner: u32,
+    states: Box<[AtomicU32]>,
+}
+impl VoiceSlots {
+    fn new(capacity: usize) -> Self {
+        Self {
+            owner: NEXT_OWNER.fetch_add(1, Ordering::Relaxed),
+            states: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    /// The state of the slot of `instance`, if it was issued for these
+    /// slots.
+    fn state(&self, instance: SfxInstance) -> Option<&AtomicU32> {
+        if instance.owner != self.owner {
+            return None;
+        }
+        self.states.get(instance.slot as usize)
+    }
+
+    fn acquire(&self) -> Option<SfxInstance> {
+        for (slot, state) in self.states.iter().enumerate() {
+            let current = state.load(Ordering::Acquire);
+            if current & 1 != 0 {
+                continue;
+            }
+            let generation = (current >> 1).wrapping_add(1);
+            if state
+                .compare_exchange(
+                    current,
+                    generation << 1 | 1,
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                return Some(SfxInstance {
+                    owner: self.owner,
+                    slot: slot as u32,
+                    generation,
+                });
+            }
+        }
+        None
+    }
+
+    fn release(&self, instance: SfxInstance) {
+        if let Some(state) = self.state(instance) {
+            let _ = state.compare_exchange(
+                instance.generation << 1 | 1,
+                instance.generation << 1,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    fn any_live(&self) -> bool {
+        self.states
+            .iter()
+            .any(|it| it.load(Ordering::Acquire) & 1 != 0)
+    }
+
+    fn is_live(&self, instance: SfxInstance) -> bool {
+        self.state(instance)
+            .is_some_and(|it| it.load(Ordering::Acquire) == instance.generation << 1 | 1)
+    }
+}
+
+enum SfxCommand {
+    Play(SfxInstance, PlaySfxParams),
+    Stop(SfxInstance),
+    StopAll(f32),
+    SwapClip(AudioClip),
+    SetBatch(Option<Box<Batch>>),
+}
+
+struct Voice {
+    generation: u32,
+    /// Clip the voice was started with, kept across [`Sfx::swap_clip`].
+    clip: AudioClip,
+    /// Output frames left before the voice starts.
+    wait: u64,
+    /// Seconds played since the voice started.
+    position: f64,
+    /// Read position in frames of the clip. Kept in frames so that clips at
+    /// the output rate are read exactly one frame per output frame.
+    index: f64,
+    params: PlaySfxParams,
+    gain: f32,
+    fade_out: f32,
+}
+
+impl Voice {
+    /// Advances the voice by one frame, returning its gain or `None` once it
+    /// has faded out.
+    #[inline]
+    fn advance(&mut self, delta: f64, step: f64) -> Option<f32> {
+        self.position += delta;
+        self.index += step;
+        if self.fade_out > 0. {
+            self.gain -= delta as f32 / self.fade_out;
+            if self.gain <= 0. {
+                return None;
+            }
+        }
+        Some(self.gain)
+    }
+
+    /// Gain used to pick the voices to stop when degraded.
+    fn loudness(&self) -> f32 {
+        (self.params.amplifier * self.gain).abs()
+    }
+
+    /// Current gain including the attack ramp.
+    #[inline]
+    fn envelope(&self) -> f32 {
+        let attack = self.params.attack;
+        if attack > 0. && self.position < attack as f64 {
+            self.gain * (self.position as f32 / attack)
+        } else {
+            self.gain
+        }
     }
 }
 
 pub(crate) struct SfxRenderer {
     clip: AudioClip,
+    /// Clips and batches no longer in use, sent back to be dropped off the
+    /// audio thread.
+    garbage: HeapProducer<SfxGarbage>,
     arc: Weak<()>,
-    cons: HeapConsumer<(f64, PlaySfxParams)>,
+    slots: Arc<VoiceSlots>,
+    cons: HeapConsumer<SfxCommand>,
+    instant: HeapConsumer<(SfxInstance, PlaySfxParams)>,
+    voices: Vec<Option<Voice>>,
+    live: usize,
+    batch: Option<Box<Batch>>,
+    batch_progress: Arc<AtomicUsize>,
+    /// Sample rate of the latest block, 0 before the first one.
+    sample_rate: u32,
+    /// Held by [`AudioManager::pause_sfx`](crate::AudioManager::pause_sfx).
+    paused: bool,
 }
 
-impl Renderer for SfxRenderer {
-    fn alive(&self) -> bool {
-        !self.cons.is_empty() || self.arc.strong_count() != 0
+impl SfxRenderer {
+    /// Starts a voice for `instance`, `offset` frames into the block on top
+    /// of its delay.
+    fn start(
+        &mut self,
+        instance: SfxInstance,
+        clip: AudioClip,
+        params: PlaySfxParams,
+        sample_rate: u32,
+        offset: u64,
+    ) {
+        let voice = &mut self.voices[instance.slot as usize];
+        if voice.is_none() {
+            self.live += 1;
+        }
+        *voice = Some(Voice {
+            generation: instance.generation,
+            wait: offset + (params.delay * sample_rate as f64).round() as u64,
+            position: 0.,
+            index: params.start_offset.clamp(0., clip.length()) * clip.sample_rate() as f64,
+            clip,
+            params,
+            gain: 1.,
+            fade_out: 0.,
+        });
     }
 
-    fn render_mono(&mut self, sample_rate: u32, data: &mut [f32]) {
-        let delta = 1. / sample_rate as f64;
-        let mut pop_count = 0;
-        for (position, params) in self.cons.iter_mut() {
-            for sample in data.iter_mut() {
-                if let Some(frame) = self.clip.sample(*position) {
-                    *sample += frame.avg() * params.amplifier;
-                } else {
-                    pop_count += 1;
-                    break;
+    fn prepare(&mut self, sample_rate: u32) {
+        // Before the queue, so that a stop pushed after an instant play in
+        // the same block finds its voice.
+        while let Some((instance, params)) = self.instant.pop() {
+            self.start(instance, self.clip.clone(), params, sample_rate, 0);
+        }
+        while let Some(cmd) = self.cons.pop() {
+            match cmd {
+                SfxCommand::Play(instance, params) => {
+                    self.start(instance, self.clip.clone(), params, sample_rate, 0)
+                }
+                SfxCommand::Stop(instance) => {
+                    let Some(current) = self
+                        .voices
+                        .get_mut(instance.slot as usize)
+                        .and_then(Option::as_mut)
+                        .filter(|it| it.generation == instance.generation)
+                    else {
+                        continue;
+                    };
+                    if current.params.release > 0. && current.wait == 0 {
+                        current.fade_out = current.params.release / current.gain.max(f32::EPSILON);
+                    } else {
+                        self.finish(instance.slot as usize);
+                    }
+                }
+                SfxCommand::StopAll(fade) => self.stop_all_sfx(fade),
+                SfxCommand::SwapClip(clip) => {
+                    let old = std::mem::replace(&mut self.clip, clip);
+                    self.retire(SfxGarbage::Clip(old));
+                }
+                SfxCommand::SetBatch(batch) => {
+                    if let Some(old) = std::mem::replace(&mut self.batch, batch) {
+                        self.retire(SfxGarbage::Batch(old));
+                    }
+                    self.batch_progress.store(0, Ordering::Relaxed);
                 }
-                *position += delta;
             }
         }
-        unsafe {
-            self.cons.advance(pop_count);
+    }
+
+    /// Starts the plays of the batch falling within the next `frames`
+    /// frames on their exact frame. Plays the clock has already passed when
+    /// the batch arrives or the track seeks past are skipped, not started
+    /// all at once.
+    fn start_batch(&mut self, ctx: &RenderContext, frames: usize) {
+        let Some(mut batch) = self.batch.take() else {
+            return;
+        };
+        let (base, step) = match &batch.clock {
+            BatchClock::Mixer => (
+                ctx.sample_time as f64 / ctx.sample_rate as f64,
+                1. / ctx.sample_rate as f64,
+            ),
+            BatchClock::Music(head) => match head.block(ctx.sample_time) {
+                Some(it) => it,
+                None => {
+                    self.retire(SfxGarbage::Batch(batch));
+                    return;
+                }
+            },
+        };
+        if batch.expected.is_nan() || (base - batch.expected).abs() > step {
+            batch.next = batch.plays.partition_point(|it| it.at < base);
+        }
+        batch.expected = base + step * frames as f64;
+        if step > 0. {
+            while let Some(play) = batch.plays.get(batch.next) {
+                let offset = ((play.at - base) / step).round().max(0.);
+                if offset >= frames as f64 {
+                    break;
+                }
+                batch.next += 1;
+                // Skipped if every voice is taken, like a play would fail.
+                if let Some(instance) = self.slots.acquire() {
+                    self.start(
+                        instance,
+                        batch.clips[play.clip].clone(),
+                        play.params.clone(),
+                        ctx.sample_rate,
+                        offset as u64,
+                    );
+                }
+            }
         }
+        self.batch_progress.store(batch.next, Ordering::Relaxed);
+        self.batch = Some(batch);
     }
 
-    fn render_stereo(&mut self, sample_rate: u32, data: &mut [f32]) {
+    /// Mixes every voice into `data`. A voice is rendered from the exact
+    /// frame its delay ends on, and released in the block it reaches the
+    /// end of the clip, so clips shorter than a block are played in full
+    /// within it.
+    fn render(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+        let sample_rate = ctx.sample_rate;
+        self.sample_rate = sample_rate;
+        self.prepare(sample_rate);
+        if self.paused {
+            return;
+        }
         let delta = 1. / sample_rate as f64;
-        let mut pop_count = 0;
-        for (position, params) in self.cons.iter_mut() {
-            for sample in data.chunks_exact_mut(2) {
-                if let Some(frame) = self.clip.sample(*position) {
-                    sample[0] += frame.0 * params.amplifier;
-                    sample[1] += frame.1 * params.amplifier;
+        let channels = ctx.channels as usize;
+        let frames = data.len() / channels;
+        self.start_batch(ctx, frames);
+        if ctx.degraded {
+            self.cull(DEGRADED_VOICES);
+        }
+        for slot in 0..self.voices.len() {
+            let Some(voice) = &mut self.voices[slot] else {
+                continue;
+            };
+            let clip = &voice.clip;
+            let step = clip.sample_rate() as f64 * delta;
+            let end = clip.frame_count() as f64;
+            let skip = voice.wait.min(frames as u64);
+            voice.wait -= skip;
+            let mut finished = false;
+            for sample in data.chunks_exact_mut(channels).skip(skip as usize) {
+                let frame = if ctx.degraded {
+                    voice.clip.get(voice.index as usize)
+                } else {
+                    voice.clip.sample_index(voice.index)
+                };
+                let Some(frame) = frame else {
+                    finished = true;
+                    break;
+                };
+                let amp = voice.params.amplifier * voice.envelope();
+                if channels == 1 {
+                    sample[0] += frame.avg() * amp;
                 } else {
-                    pop_count += 1;
+                    sample[0] += frame.0 * amp;
+                    sample[1] += frame.1 * amp;
+                }
+                if voice.advance(delta, step).is_none() {
+                    finished = true;
                     break;
                 }
-                *position += delta;
             }
+            if finished || (voice.wait == 0 && voice.index >= end) {
+                self.finish(slot);
+            }
+        }
+    }
+
+    /// Stops the quietest voices until at most `limit` are left.
+    fn cull(&mut self, limit: usize) {
+        while self.live > limit {
+            let Some(slot) = (0..self.voices.len())
+                .filter_map(|slot| Some((slot, self.voices[slot].as_ref()?.loudness())))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|it| it.0)
+            else {
+                break;
+            };
+            self.finish(slot);
+        }
+    }
+
+    #[inline]
+    fn finish(&mut self, slot: usize) {
+        if let Some(voice) = self.voices[slot].take() {
+            self.live -= 1;
+            self.slots.release(SfxInstance {
+                owner: self.slots.owner,
+                slot: slot as u32,
+                generation: voice.generation,
+            });
+            if !voice.clip.ptr_eq(&self.clip) {
+                self.retire(SfxGarbage::Clip(voice.clip));
+            }
+        }
+    }
+
+    /// Hands `garbage` back to the [`Sfx`] so that freeing it never happens
+    /// on the audio thread. Dropped here only if the way back is full.
+    fn retire(&mut self, garbage: SfxGarbage) {
+        let _ = self.garbage.push(garbage);
+    }
+}
+
+impl Renderer for SfxRenderer {
+    fn alive(&self) -> bool {
+        self.live != 0
+            || !self.cons.is_empty()
+            || !self.instant.is_empty()
+            || self
+                .batch
+                .as_ref()
+                .is_some_and(|it| it.next < it.plays.len())
+            || self.arc.strong_count() != 0
+    }
+
+    fn stop_all_sfx(&mut self, fade: f32) {
+        for slot in 0..self.voices.len() {
+            let Some(voice) = &mut self.voices[slot] else {
+                continue;
+            };
+            if fade > 0. && voice.wait == 0 {
+                voice.fade_out = fade / voice.gain.max(f32::EPSILON);
+            } else {
+                self.finish(slot);
+            }
+        }
+    }
+
+    fn set_sfx_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Woken by the first delayed voice or play of a batch on the mixer
+    /// clock. A batch following a track is followed block by block.
+    fn next_activity(&self, now: u64) -> Option<u64> {
+        if !self.cons.is_empty() || !self.instant.is_empty() {
+            return None;
+        }
+        if self.paused {
+            return Some(u64::MAX);
+        }
+        let mut at = u64::MAX;
+        for voice in self.voices.iter().flatten() {
+            if voice.wait == 0 {
+                return None;
+            }
+            at = at.min(now + voice.wait);
         }
-        unsafe {
-            self.cons.advance(pop_count);
+        if let Some(batch) = &self.batch {
+            if let Some(play) = batch.plays.get(batch.next) {
+                if !matches!(batch.clock, BatchClock::Mixer) || self.sample_rate == 0 {
+                    return None;
+                }
+                let frame = (play.at * self.sample_rate as f64).round().max(0.) as u64;
+                at = at.min(frame.max(now));
+            }
         }
+        Some(at)
+    }
+
+    fn render_mono(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+        self.render(ctx, data);
+    }
+
+    fn render_stereo(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+        self.render(ctx, data);
     }
 }
 
 pub struct Sfx {
-    _arc: Arc<()>,
-    prod: HeapProducer<(f64, PlaySfxParams)>,
+    arc: Arc<()>,
+    slots: Arc<VoiceSlots>,
+    prod: HeapProducer<SfxCommand>,
+    instant: HeapProducer<(SfxInstance, PlaySfxParams)>,
+    garbage: HeapConsumer<SfxGarbage>,
+    batch_progress: Arc<AtomicUsize>,
+    rng: Rng,
+    connected: Arc<AtomicBool>,
+    /// Stream whose sample rate swapped clips are converted to, if the
+    /// manager resamples clips.
+    resample_to: Option<Arc<StreamInfo>>,
 }
 impl Sfx {
-    pub(crate) fn new(clip: AudioClip, buffer_size: Option<usize>) -> (Sfx, SfxRenderer) {
-        let (prod, cons) = HeapRb::new(buffer_size.unwrap_or(64)).split();
+    pub(crate) fn new(
+        clip: AudioClip,
+        buffer_size: Option<usize>,
+        rng: Rng,
+        connected: Arc<AtomicBool>,
+        resample_to: Option<Arc<StreamInfo>>,
+    ) -> (Sfx, SfxRenderer) {
+        let capacity = buffer_size.unwrap_or(64);
+        let (prod, cons) = HeapRb::new(capacity).split();
         let arc = Arc::new(());
+        let slots = Arc::new(VoiceSlots::new(capacity));
+        let (garbage_prod, garbage) = HeapRb::new(capacity + 4).split();
+        let (instant_prod, instant) = HeapRb::new(INSTANT_LANE_SIZE).split();
+        let batch_progress: Arc<AtomicUsize> = Arc::default();
         let renderer = SfxRenderer {
             clip,
+            garbage: garbage_prod,
             arc: Arc::downgrade(&arc),
+            slots: Arc::clone(&slots),
             cons,
+            instant,
+            voices: (0..capacity).map(|_| None).collect(),
+            live: 0,
+            batch: None,
+            batch_progress: Arc::clone(&batch_progress),
+            sample_rate: 0,
+            paused: false,
         };
-        (Self { _arc: arc, prod }, renderer)
+        (
+            Self {
+                arc,
+                slots,
+                prod,
+                instant: instant_prod,
+                garbage,
+                batch_progress,
+                rng,
+                connected,
+                resample_to,
+            },
+            renderer,
+        )
     }
 
-    pub fn play(&mut self, params: PlaySfxParams) -> Result<()> {
+    /// Plays `clip` for every instance started from now on. Instances
+    /// already playing finish with the previous clip.
+    ///
+    /// Like clips given to [`AudioManager::create_sfx`](crate::AudioManager::create_sfx),
+    /// the clip is converted to the output sample rate under
+    /// [`AudioManagerSettings::auto_resample`](crate::AudioManagerSettings::auto_resample).
+    pub fn swap_clip(&mut self, clip: AudioClip) -> Result<()> {
+        check_connected(&self.connected)?;
+        self.collect_garbage();
+        let clip = self.resample(clip);
         self.prod
-            .push((0., params))
+            .push(SfxCommand::SwapClip(clip))
             .map_err(buffer_is_full)
-            .context("play sfx")
+            .context("swap sfx clip")
+    }
+
+    fn resample(&self, clip: AudioClip) -> AudioClip {
+        match &self.resample_to {
+            Some(stream) => clip.resample(stream.sample_rate.load(Ordering::Relaxed)),
+            None => clip,
+        }
+    }
+
+    fn collect_garbage(&mut self) {
+        while self.garbage.pop().is_some() {}
+    }
+
+    fn validate_params(mut params: PlaySfxParams) -> Result<PlaySfxParams> {
+        params.amplifier = validate("amplifier", params.amplifier as f64, f64::MIN, f64::MAX)
+            .context("play sfx")? as f32;
+        params.delay = validate("delay", params.delay, 0., f64::MAX).context("play sfx")?;
+        params.attack =
+            validate("attack", params.attack as f64, 0., f64::MAX).context("play sfx")? as f32;
+        params.release =
+            validate("release", params.release as f64, 0., f64::MAX).context("play sfx")? as f32;
+        params.start_offset =
+            validate("start offset", params.start_offset, 0., f64::MAX).context("play sfx")?;
+        params.humanize.amp_jitter = validate(
+            "amplitude jitter",
+            params.humanize.amp_jitter as f64,
+            0.,
+            1.,
+        )
+        .context("play sfx")? as f32;
+        params.humanize.time_jitter = validate(
+            "time jitter",
+            params.humanize.time_jitter as f64,
+            0.,
+            f64::MAX,
+        )
+        .context("play sfx")? as f32;
+        Ok(params)
+    }
+
+    /// Applies the humanization of `params`, leaving it zeroed.
+    fn humanize(&mut self, mut params: PlaySfxParams) -> PlaySfxParams {
+        let Humanize {
+            amp_jitter,
+            time_jitter,
+        } = std::mem::take(&mut params.humanize);
+        if amp_jitter > 0. {
+            params.amplifier *= 1. + amp_jitter * self.rng.next_signed() as f32;
+        }
+        if time_jitter > 0. {
+            params.delay += time_jitter as f64 * self.rng.next_f64();
+        }
+        params
+    }
+
+    pub fn play(&mut self, params: PlaySfxParams) -> Result<SfxInstance> {
+        check_connected(&self.connected)?;
+        self.collect_garbage();
+        let params = Self::validate_params(params)?;
+        let params = self.humanize(params);
+        let instance = self
+            .slots
+            .acquire()
+            .ok_or_else(|| anyhow!("too many playing instances"))
+            .context("play sfx")?;
+        if let Err(err) = self.prod.push(SfxCommand::Play(instance, params)) {
+            self.slots.release(instance);
+            return Err(buffer_is_full(err)).context("play sfx");
+        }
+        Ok(instance)
+    }
+
+    /// Like [`Sfx::play`], through a small lane of its own, for feedback
+    /// sounds that must not wait behind other commands.
+    ///
+    /// Both paths start the instance at the beginning of the next block the
+    /// mixer renders, i.e. within one buffer of the call, but this one still
+    /// does when the regular queue is full. At most 4 instant plays can be
+    /// pending at a time.
+    pub fn play_instant(&mut self, params: PlaySfxParams) -> Result<SfxInstance> {
+        check_connected(&self.connected)?;
+        self.collect_garbage();
+        let params = Self::validate_params(params).context("play sfx instantly")?;
+        let params = self.humanize(params);
+        let instance = self
+            .slots
+            .acquire()
+            .ok_or_else(|| anyhow!("too many playing instances"))
+            .context("play sfx instantly")?;
+        if let Err(err) = self.instant.push((instance, params)) {
+            self.slots.release(instance);
+            return Err(buffer_is_full(err)).context("play sfx instantly");
+        }
+        Ok(instance)
+    }
+
+    /// Hands a whole list of plays to the renderer at once, e.g. every hit
+    /// sound of a chart, replacing any previous batch. Each play picks its
+    /// clip by index in `clips`, which are converted like clips given to
+    /// [`Sfx::swap_clip`]. `at` is counted in seconds of the mixer clock, see
+    /// [`AudioManager::sample_time`](crate::AudioManager::sample_time);
+    /// plays already past when the batch arrives are skipped.
+    ///
+    /// Plays are started on their exact frame as long as a voice is free,
+    /// without further allocation on the audio thread. A batch carries at
+    /// most 65536 plays.
+    pub fn schedule_batch(
+        &mut self,
+        clips: Vec<AudioClip>,
+        plays: Vec<ScheduledPlay>,
+    ) -> Result<()> {
+        self.set_batch(BatchClock::Mixer, clips, plays)
+            .context("schedule sfx batch")
+    }
+
+    /// Like [`Sfx::schedule_batch`], with `at` counted in positions of
+    /// `music`. The batch follows the track through pauses, rate changes
+    /// and seeks, skipping the plays a seek jumps over, and is dropped with
+    /// the track.
+    pub fn schedule_batch_on(
+        &mut self,
+        music: &Music,
+        clips: Vec<AudioClip>,
+        plays: Vec<ScheduledPlay>,
+    ) -> Result<()> {
+        self.set_batch(BatchClock::Music(music.play_head()), clips, plays)
+            .context("schedule sfx batch")
+    }
+
+    fn set_batch(
+        &mut self,
+        clock: BatchClock,
+        clips: Vec<AudioClip>,
+        mut plays: Vec<ScheduledPlay>,
+    ) -> Result<()> {
+        check_connected(&self.connected)?;
+        self.collect_garbage();
+        if plays.len() > MAX_BATCH_PLAYS {
+            bail!(
+                "{} plays, at most {MAX_BATCH_PLAYS} are allowed",
+                plays.len()
+            );
+        }
+        for play in &mut plays {
+            play.at = validate("time", play.at, 0., f64::MAX)?;
+            if play.clip >= clips.len() {
+                bail!("clip {} of a batch of {} clips", play.clip, clips.len());
+            }
+            let params = Self::validate_params(std::mem::take(&mut play.params))?;
+            play.params = self.humanize(params);
+        }
+        plays.sort_by(|a, b| a.at.total_cmp(&b.at));
+        let batch = Box::new(Batch {
+            clock,
+            clips: clips.into_iter().map(|it| self.resample(it)).collect(),
+            plays,
+            next: 0,
+            expected: f64::NAN,
+        });
+        self.prod
+            .push(SfxCommand::SetBatch(Some(batch)))
+            .map_err(buffer_is_full)
+    }
+
+    /// Index in the sorted current batch of the next play to start, i.e. the
+    /// number of plays started or skipped so far.
+    pub fn batch_progress(&self) -> usize {
+        self.batch_progress.load(Ordering::Relaxed)
+    }
+
+    /// Drops the current batch. Instances it has started keep playing.
+    pub fn clear_batch(&mut self) -> Result<()> {
+        check_connected(&self.connected)?;
+        self.collect_garbage();
+        self.prod
+            .push(SfxCommand::SetBatch(None))
+            .map_err(buffer_is_full)
+            .context("clear sfx batch")
+    }
+
+    /// Stops `instance`, fading it out over its release. Does nothing if it
+    /// has already finished or was issued by another [`Sfx`].
+    pub fn stop(&mut self, instance: SfxInstance) -> Result<()> {
+        check_connected(&self.connected)?;
+        if instance.owner != self.slots.owner {
+            return Ok(());
+        }
+        self.prod
+            .push(SfxCommand::Stop(instance))
+            .map_err(buffer_is_full)
+            .context("stop sfx")
+    }
+
+    /// Fades out every playing instance over `fade` seconds and cancels the
+    /// ones that have not started yet.
+    pub fn stop_all(&mut self, fade: f32) -> Result<()> {
+        check_connected(&self.connected)?;
+        let fade = validate("fade", fade as f64, 0., f64::MAX).context("stop all sfx")? as f32;
+        self.prod
+            .push(SfxCommand::StopAll(fade))
+            .map_err(buffer_is_full)
+            .context("stop all sfx")
+    }
+
+    pub fn is_playing(&self, instance: SfxInstance) -> bool {
+        self.slots.is_live(instance)
+    }
+
+    pub(crate) fn probe(&self) -> Probe {
+        let arc = Arc::downgrade(&self.arc);
+        let slots = Arc::downgrade(&self.slots);
+        Box::new(move || {
+            arc.upgrade()?;
+            let playing = slots.upgrade()?.any_live();
+            Some(Probed {
+                playing,
+                position: None,
+                gain: None,
+                finished: !playing,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::offline::OfflineSettings,
+        test_util::{
+            constant, manager, manager_with, peak, render, settings, BLOCK_SIZE, SAMPLE_RATE,
+        },
+        AudioManagerSettings, Frame,
+    };
+    use std::collections::VecDeque;
+
+    #[test]
+    fn foreign_handles_are_ignored() {
+        let (mut manager, mut out) = manager();
+        let clip = constant(0.25, SAMPLE_RATE as usize);
+        let mut a = manager.create_sfx(clip.clone(), None).unwrap();
+        let mut b = manager.create_sfx(clip, Some(1)).unwrap();
+        let from_a: Vec<_> = (0..4)
+            .map(|_| a.play(PlaySfxParams::default()).unwrap())
+            .collect();
+        let from_b = b.play(PlaySfxParams::default()).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        for &instance in &from_a {
+            assert!(!b.is_playing(instance));
+            b.stop(instance).unwrap();
+        }
+        assert!(!a.is_playing(from_b));
+        a.stop(from_b).unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        assert!(from_a.iter().all(|&it| a.is_playing(it)));
+        assert!(b.is_playing(from_b));
+        assert_eq!(data[data.len() - 1], 1.25);
+    }
+
+    #[test]
+    fn stale_handles_do_not_stop_new_instances() {
+        let (mut manager, mut out) = manager();
+        let mut sfx = manager
+            .create_sfx(constant(0.25, SAMPLE_RATE as usize), Some(2))
+            .unwrap();
+        let old = sfx.play(PlaySfxParams::default()).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        sfx.stop(old).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert!(!sfx.is_playing(old));
+        let new = sfx.play(PlaySfxParams::default()).unwrap();
+        assert_ne!(old, new);
+        sfx.stop(old).unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        assert!(sfx.is_playing(new));
+        assert!(!sfx.is_playing(old));
+        assert_eq!(data[data.len() - 1], 0.25);
+    }
+
+    #[test]
+    fn thousands_of_plays_and_stops_do_not_cross() {
+        let (mut manager, mut out) = manager();
+        let mut sfx = manager
+            .create_sfx(constant(0.125, SAMPLE_RATE as usize), Some(16))
+            .unwrap();
+        let mut live = VecDeque::new();
+        let mut stopped = Vec::new();
+        let mut stale = Vec::new();
+        for round in 0..1000 {
+            for _ in 0..4 {
+                live.push_back(sfx.play(PlaySfxParams::default()).unwrap());
+                if live.len() > 6 {
+                    let instance = live.pop_front().unwrap();
+                    sfx.stop(instance).unwrap();
+                    stopped.push(instance);
+                }
+            }
+            // Their slots are likely taken by newer instances by now.
+            for &instance in &stale {
+                sfx.stop(instance).unwrap();
+            }
+            let data = render(&mut out, BLOCK_SIZE);
+            assert!(stopped.iter().all(|&it| !sfx.is_playing(it)), "{round}");
+            assert!(live.iter().all(|&it| sfx.is_playing(it)), "{round}");
+            assert_eq!(data[data.len() - 1], 0.125 * live.len() as f32, "{round}");
+            stale = std::mem::take(&mut stopped);
+        }
+    }
+
+    #[test]
+    fn swapped_clips_are_resampled() {
+        let (mut manager, mut out) = manager();
+        let mut sfx = manager
+            .create_sfx(constant(0.5, SAMPLE_RATE as usize), None)
+            .unwrap();
+        let half_rate = AudioClip::from_raw(vec![Frame::from_mono(0.25); 1024], SAMPLE_RATE / 2);
+        sfx.swap_clip(half_rate).unwrap();
+        sfx.play(PlaySfxParams::default()).unwrap();
+        let data = render(&mut out, BLOCK_SIZE * 8);
+        let played = data.chunks_exact(2).filter(|it| it[0] != 0.).count();
+        assert_eq!(played, 2048);
+        assert!(data[..2048 * 2].iter().all(|it| *it == 0.25));
+    }
+
+    #[test]
+    fn stop_all_cancels_delayed_instances() {
+        let (mut manager, mut out) = manager();
+        let clip = constant(0.01, SAMPLE_RATE as usize);
+        let mut sfx = manager.create_sfx(clip.clone(), Some(32)).unwrap();
+        let mut other = manager.create_sfx(clip, None).unwrap();
+        let delayed = PlaySfxParams {
+            delay: 0.5,
+            ..PlaySfxParams::default()
+        };
+        let mut instances: Vec<_> = (0..10)
+            .flat_map(|_| {
+                [
+                    sfx.play(delayed.clone()).unwrap(),
+                    sfx.play(PlaySfxParams::default()).unwrap(),
+                ]
+            })
+            .collect();
+        render(&mut out, BLOCK_SIZE);
+        sfx.stop_all(0.005).unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        assert!(data[0] > 0.09 && data[data.len() - 1] == 0.);
+        assert!(instances.iter().all(|&it| !sfx.is_playing(it)));
+        // Nothing starts once the delay would have run out.
+        let data = render(&mut out, BLOCK_SIZE * 100);
+        assert!(data.iter().all(|it| *it == 0.));
+
+        instances.push(sfx.play(delayed).unwrap());
+        let instance = other.play(PlaySfxParams::default()).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        manager.stop_all_sfx(0.).unwrap();
+        let data = render(&mut out, BLOCK_SIZE * 100);
+        assert!(data.iter().all(|it| *it == 0.));
+        assert!(!sfx.is_playing(instances[20]) && !other.is_playing(instance));
+    }
+
+    #[test]
+    fn start_offset_skips_into_the_clip() {
+        let (mut manager, mut out) = manager();
+        // Silent for 960 frames, then audible.
+        let mut frames = vec![Frame::default(); 960];
+        frames.resize(4800, Frame::from_mono(0.5));
+        let clip = AudioClip::from_raw(frames, SAMPLE_RATE);
+        let mut sfx = manager.create_sfx(clip, None).unwrap();
+        let first_audible = |data: Vec<f32>| data.chunks_exact(2).position(|it| it[0] != 0.);
+        sfx.play(PlaySfxParams::default()).unwrap();
+        assert_eq!(first_audible(render(&mut out, BLOCK_SIZE * 4)), Some(960));
+        render(&mut out, BLOCK_SIZE * 8);
+
+        sfx.play(PlaySfxParams {
+            start_offset: 0.01,
+            ..PlaySfxParams::default()
+        })
+        .unwrap();
+        assert_eq!(first_audible(render(&mut out, BLOCK_SIZE * 4)), Some(480));
+        render(&mut out, BLOCK_SIZE * 8);
+
+        // Clamped to the end, so nothing is left to play.
+        let instance = sfx
+            .play(PlaySfxParams {
+                start_offset: 10.,
+                ..PlaySfxParams::default()
+            })
+            .unwrap();
+        assert_eq!(first_audible(render(&mut out, BLOCK_SIZE * 4)), None);
+        assert!(!sfx.is_playing(instance));
+    }
+
+    #[test]
+    fn attack_and_release_shape_the_instance() {
+        let (mut manager, mut out) = manager();
+        let square = (0..SAMPLE_RATE as usize)
+            .map(|i| Frame::from_mono(if i / 24 % 2 == 0 { 0.5 } else { -0.5 }))
+            .collect();
+        let mut sfx = manager
+            .create_sfx(AudioClip::from_raw(square, SAMPLE_RATE), None)
+            .unwrap();
+        let instance = sfx
+            .play(PlaySfxParams {
+                attack: 0.01,
+                release: 0.005,
+                ..PlaySfxParams::default()
+            })
+            .unwrap();
+        let data = render(&mut out, BLOCK_SIZE * 2);
+        let left: Vec<f32> = data.iter().step_by(2).map(|it| it.abs()).collect();
+        for (i, sample) in left[..480].iter().enumerate() {
+            assert!((sample - 0.5 * i as f32 / 480.).abs() < 1e-4, "{i}");
+        }
+        assert!(left[480..].iter().all(|it| *it == 0.5));
+
+        // Stopping ramps down over the release, then frees the instance.
+        sfx.stop(instance).unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        let left: Vec<f32> = data.iter().step_by(2).map(|it| it.abs()).collect();
+        assert!(left[..240].windows(2).all(|it| it[1] < it[0]));
+        assert!((left[120] - 0.25).abs() < 0.01);
+        assert!(left[240..].iter().all(|it| *it < 1e-6));
+        assert!(!sfx.is_playing(instance));
+    }
+
+    #[test]
+    fn short_clips_play_within_their_block() {
+        let (mut manager, mut out) = manager();
+        for frames in [1, 17, BLOCK_SIZE] {
+            for delay in [0, 100] {
+                let mut sfx = manager.create_sfx(constant(0.5, frames), None).unwrap();
+                let instance = sfx
+                    .play(PlaySfxParams {
+                        delay: delay as f64 / SAMPLE_RATE as f64,
+                        ..PlaySfxParams::default()
+                    })
+                    .unwrap();
+                let mut data = render(&mut out, BLOCK_SIZE);
+                // Cleaned up by the end of the block it ended in.
+                assert_eq!(sfx.is_playing(instance), delay + frames > BLOCK_SIZE);
+                data.extend(render(&mut out, BLOCK_SIZE));
+                for (i, sample) in data.chunks_exact(2).enumerate() {
+                    let audible = (delay..delay + frames).contains(&i);
+                    assert_eq!(
+                        sample[0],
+                        if audible { 0.5 } else { 0. },
+                        "{frames} {delay} {i}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn instant_plays_skip_a_full_queue() {
+        let (mut manager, mut out) = manager();
+        let mut sfx = manager
+            .create_sfx(constant(0.5, BLOCK_SIZE), Some(8))
+            .unwrap();
+        let first = |data: &[f32]| data.iter().position(|it| *it != 0.);
+
+        // With room in the queue, both paths start at the next block.
+        let instance = sfx.play(PlaySfxParams::default()).unwrap();
+        assert_eq!(first(&render(&mut out, BLOCK_SIZE)), Some(0));
+        sfx.play_instant(PlaySfxParams::default()).unwrap();
+        assert_eq!(first(&render(&mut out, BLOCK_SIZE)), Some(0));
+        assert_eq!(first(&render(&mut out, BLOCK_SIZE)), None);
+
+        // Stops of a finished instance fill the queue without taking slots.
+        while sfx.stop(instance).is_ok() {}
+        assert!(sfx.play(PlaySfxParams::default()).is_err());
+        for _ in 0..INSTANT_LANE_SIZE {
+            sfx.play_instant(PlaySfxParams::default()).unwrap();
+        }
+        assert!(sfx.play_instant(PlaySfxParams::default()).is_err());
+        let data = render(&mut out, BLOCK_SIZE);
+        assert_eq!(first(&data), Some(0));
+        assert_eq!(peak(&data), 0.5 * INSTANT_LANE_SIZE as f32);
+    }
+
+    #[test]
+    fn humanize_jitters_within_its_ranges() {
+        let sfx = |rng_seed: u64| {
+            let (mut manager, _out) = manager_with(
+                AudioManagerSettings {
+                    rng_seed,
+                    ..settings()
+                },
+                OfflineSettings::default(),
+            );
+            manager.create_sfx(constant(0.5, 16), None).unwrap()
+        };
+        let jittered = PlaySfxParams {
+            amplifier: 0.8,
+            delay: 0.1,
+            humanize: Humanize {
+                amp_jitter: 0.25,
+                time_jitter: 0.05,
+            },
+            ..PlaySfxParams::default()
+        };
+        let draws = |sfx: &mut Sfx| {
+            (0..1000)
+                .map(|_| {
+                    let params = sfx.humanize(jittered.clone());
+                    (params.amplifier, params.delay)
+                })
+                .collect::<Vec<_>>()
+        };
+        let (mut a, mut b) = (sfx(3), sfx(3));
+        // Without jitter, the generator is left alone.
+        for _ in 0..10 {
+            let params = a.humanize(PlaySfxParams {
+                amplifier: 0.8,
+                ..PlaySfxParams::default()
+            });
+            assert_eq!((params.amplifier, params.delay), (0.8, 0.));
+        }
+        let sequence = draws(&mut a);
+        assert_eq!(sequence, draws(&mut b));
+        assert_ne!(sequence, draws(&mut sfx(4)));
+        for &(amplifier, delay) in &sequence {
+            assert!((0.6..=1.).contains(&amplifier), "{amplifier}");
+            assert!((0.1..0.15).contains(&delay), "{delay}");
+        }
+        let mean = sequence.iter().map(|it| it.0 as f64).sum::<f64>() / 1000.;
+        assert!((mean - 0.8).abs() < 0.02, "{mean}");
+    }
+
+    #[test]
+    fn stops_reach_instant_plays_of_the_same_block() {
+        let (mut manager, mut out) = manager();
+        let mut sfx = manager
+            .create_sfx(constant(0.5, BLOCK_SIZE * 4), None)
+            .unwrap();
+
+        let instance = sfx.play_instant(PlaySfxParams::default()).unwrap();
+        sfx.stop(instance).unwrap();
+        assert_eq!(peak(&render(&mut out, BLOCK_SIZE)), 0.);
+        assert!(!sfx.is_playing(instance));
+
+        sfx.play_instant(PlaySfxParams::default()).unwrap();
+        sfx.play_instant(PlaySfxParams::default()).unwrap();
+        sfx.stop_all(0.).unwrap();
+        assert_eq!(peak(&render(&mut out, BLOCK_SIZE)), 0.);
+        assert_eq!(peak(&render(&mut out, BLOCK_SIZE)), 0.);
+
+        // A play after the stops still starts.
+        sfx.play_instant(PlaySfxParams::default()).unwrap();
+        assert_eq!(peak(&render(&mut out, BLOCK_SIZE)), 0.5);
+    }
+
+    /// A play of clip `clip` of a batch at `frame` of its clock.
+    fn play_at(frame: usize, clip: usize) -> ScheduledPlay {
+        ScheduledPlay {
+            at: frame as f64 / SAMPLE_RATE as f64,
+            clip,
+            params: PlaySfxParams::default(),
+        }
+    }
+
+    /// Frames of the left channel of `data` at which a sound starts, with
+    /// its first sample.
+    fn onsets(data: &[f32]) -> Vec<(usize, f32)> {
+        let left: Vec<f32> = data.iter().step_by(2).copied().collect();
+        (0..left.len())
+            .filter(|&i| left[i] != 0. && (i == 0 || left[i - 1] == 0.))
+            .map(|i| (i, left[i]))
+            .collect()
+    }
+
+    #[test]
+    fn batches_start_their_plays_on_exact_frames() {
+        let (mut manager, mut out) = manager();
+        let mut sfx = manager.create_sfx(constant(0.5, 8), None).unwrap();
+        let clips = vec![constant(0.5, 8), constant(0.25, 8)];
+        // Sorted by the renderer.
+        let plays = vec![play_at(1500, 0), play_at(100, 0), play_at(700, 1)];
+        sfx.schedule_batch(clips.clone(), plays).unwrap();
+        let mut data = Vec::new();
+        for progress in 1..=3 {
+            data.extend(render(&mut out, BLOCK_SIZE));
+            assert_eq!(sfx.batch_progress(), progress);
+        }
+        assert_eq!(onsets(&data), [(100, 0.5), (700, 0.25), (1500, 0.5)]);
+
+        // Plays the clock passed before the batch arrived are skipped.
+        let now = 4 * BLOCK_SIZE;
+        let plays = vec![play_at(200, 0), play_at(now + 10, 1)];
+        render(&mut out, BLOCK_SIZE);
+        sfx.schedule_batch(clips.clone(), plays).unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        assert_eq!(onsets(&data), [(10, 0.25)]);
+        assert_eq!(sfx.batch_progress(), 2);
+
+        let plays = vec![play_at(0, 2)];
+        assert!(sfx.schedule_batch(clips, plays).is_err());
+    }
+
+    #[test]
+    fn cleared_batches_leave_their_voices_playing() {
+        let (mut manager, mut out) = manager();
+        let mut sfx = manager.create_sfx(constant(0.5, 8), None).unwrap();
+        let clips = vec![constant(0.5, BLOCK_SIZE * 4)];
+        let plays = vec![play_at(100, 0), play_at(BLOCK_SIZE * 2, 0)];
+        sfx.schedule_batch(clips, plays).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert_eq!(sfx.batch_progress(), 1);
+
+        sfx.clear_batch().unwrap();
+        let data = render(&mut out, BLOCK_SIZE * 3);
+        assert_eq!(sfx.batch_progress(), 0);
+        // The first play carries on, the second never joins it.
+        assert!(data[..BLOCK_SIZE * 5].iter().all(|it| *it == 0.5));
+        assert_eq!(peak(&data), 0.5);
+    }
+
+    #[test]
+    fn music_batches_skip_the_plays_a_seek_jumps_over() {
+        let (mut manager, mut out) = manager();
+        // Silent, only its position matters.
+        let music = manager
+            .create_music(
+                constant(0.5, SAMPLE_RATE as usize * 4),
+                crate::MusicParams {
+                    amplifier: 0.,
+                    ..crate::MusicParams::default()
+                },
+            )
+            .unwrap();
+        let mut sfx = manager.create_sfx(constant(0.5, 8), None).unwrap();
+        let plays = vec![play_at(1000, 0), play_at(24000, 0), play_at(36000, 0)];
+        sfx.schedule_batch_on(&music, vec![constant(0.5, 8)], plays)
+            .unwrap();
+        music.play().unwrap();
+        let data = render(&mut out, BLOCK_SIZE * 4);
+        assert_eq!(onsets(&data), [(1000, 0.5)]);
+        assert_eq!(sfx.batch_progress(), 1);
+
+        // Jumps over the play at 0.5 s to 0.6 s, the last play is 0.15 s
+        // further.
+        music.seek_to(0.6).unwrap();
+        let data = render(&mut out, BLOCK_SIZE * 16);
+        assert_eq!(sfx.batch_progress(), 3);
+        assert_eq!(onsets(&data), [(7200, 0.5)]);
     }
 }
@@ -0,0 +1,387 @@
+use crate::{
+    buffer_is_full, check_connected, validate, AudioClip, Frame, MusicParams, RenderContext,
+    Renderer,
+};
+use anyhow::{bail, Context, Result};
+use atomic_float::AtomicF64;
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Weak,
+};
+
+struct SharedState {
+    position: AtomicF64,
+    paused: AtomicBool,
+}
+
+enum SyncedCommand {
+    Pause,
+    Resume,
+    SeekTo(f64),
+    SetAmplifier(f32),
+    SetStemAmplifier(usize, f32),
+    SetStemMuted(usize, bool),
+}
+
+struct Stem {
+    clip: AudioClip,
+    amplifier: f32,
+    muted: bool,
+}
+
+pub(crate) struct SyncedRenderer {
+    stems: Vec<Stem>,
+    settings: MusicParams,
+    /// Length in seconds of the longest stem.
+    length: f64,
+    state: Weak<SharedState>,
+    cons: HeapConsumer<SyncedCommand>,
+    paused: bool,
+    position: f64,
+}
+
+impl SyncedRenderer {
+    fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        if let Some(state) = self.state.upgrade() {
+            state.paused.store(paused, Ordering::SeqCst);
+        }
+    }
+
+    fn prepare(&mut self) {
+        while let Some(cmd) = self.cons.pop() {
+            match cmd {
+                SyncedCommand::Pause => self.set_paused(true),
+                SyncedCommand::Resume => self.set_paused(false),
+                SyncedCommand::SeekTo(position) => self.position = position,
+                SyncedCommand::SetAmplifier(amp) => self.settings.amplifier = amp,
+                // The handle checks the index, stems are never removed.
+                SyncedCommand::SetStemAmplifier(index, amp) => {
+                    if let Some(stem) = self.stems.get_mut(index) {
+                        stem.amplifier = amp;
+                    }
+                }
+                SyncedCommand::SetStemMuted(index, muted) => {
+                    if let Some(stem) = self.stems.get_mut(index) {
+                        stem.muted = muted;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Mixes every audible stem at `position`, with the head of the clips
+    /// over the loop mix time.
+    fn sample(&self, position: f64) -> Frame {
+        let mix = self.settings.loop_mix_time;
+        let head = position + mix - self.length;
+        let mut frame = Frame::default();
+        for stem in self.stems.iter().filter(|it| !it.muted) {
+            let mut sample = stem.clip.sample(position).unwrap_or_default();
+            if mix >= 0. && head >= 0. {
+                sample += stem.clip.sample(head).unwrap_or_default();
+            }
+            frame += sample * stem.amplifier;
+        }
+        frame
+    }
+
+    #[inline]
+    fn frame(&mut self, delta: f64) -> Option<Frame> {
+        if self.position >= self.length {
+            if self.settings.loop_mix_time < 0. {
+                self.set_paused(true);
+                return None;
+            }
+            self.position = (self.position - self.length + self.settings.loop_mix_time).max(0.);
+        }
+        let frame = self.sample(self.position);
+        self.position += delta;
+        Some(frame * self.settings.amplifier)
+    }
+
+    fn render(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+        self.prepare();
+        if self.paused {
+            return;
+        }
+        let delta = self.settings.playback_rate / ctx.sample_rate as f64;
+        let channels = ctx.channels as usize;
+        for sample in data.chunks_exact_mut(channels) {
+            let Some(frame) = self.frame(delta) else {
+                break;
+            };
+            if channels == 1 {
+                sample[0] += frame.avg();
+            } else {
+                sample[0] += frame.0;
+                sample[1] += frame.1;
+            }
+        }
+        if let Some(state) = self.state.upgrade() {
+            state.position.store(self.position, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Renderer for SyncedRenderer {
+    fn alive(&self) -> bool {
+        self.state.strong_count() != 0
+    }
+
+    fn next_activity(&self, _now: u64) -> Option<u64> {
+        (self.paused && self.cons.is_empty()).then_some(u64::MAX)
+    }
+
+    fn render_mono(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+        self.render(ctx, data);
+    }
+
+    fn render_stereo(&mut self, ctx: &RenderContext, data: &mut [f32]) {
+        self.render(ctx, data);
+    }
+}
+
+/// Control handle of stems played in lockstep by a single renderer, see
+/// [`AudioManager::create_synced_music`](crate::AudioManager::create_synced_music).
+///
+/// All stems share one play head, so muting or changing the gain of a stem
+/// never shifts it against the others.
+pub struct SyncedMusic {
+    arc: Arc<SharedState>,
+    prod: HeapProducer<SyncedCommand>,
+    stems: usize,
+    connected: Arc<AtomicBool>,
+}
+
+impl SyncedMusic {
+    pub(crate) fn new(
+        clips: Vec<AudioClip>,
+        settings: MusicParams,
+        connected: Arc<AtomicBool>,
+    ) -> Result<(SyncedMusic, SyncedRenderer)> {
+        let Some(first) = clips.first() else {
+            bail!("synced music needs at least one stem");
+        };
+        let sample_rate = first.sample_rate();
+        if let Some(clip) = clips.iter().find(|it| it.sample_rate() != sample_rate) {
+            bail!(
+                "stem sample rates differ: {} and {}",
+                sample_rate,
+                clip.sample_rate()
+            );
+        }
+        let length = clips.iter().map(AudioClip::length).fold(0., f64::max);
+        let (prod, cons) = HeapRb::new(settings.command_buffer_size).split();
+        let arc = Arc::new(SharedState {
+            position: AtomicF64::default(),
+            paused: AtomicBool::new(true),
+        });
+        let stems = clips.len();
+        let renderer = SyncedRenderer {
+            stems: clips
+                .into_iter()
+                .map(|clip| Stem {
+                    clip,
+                    amplifier: 1.,
+                    muted: false,
+                })
+                .collect(),
+            settings,
+            length,
+            state: Arc::downgrade(&arc),
+            cons,
+            paused: true,
+            position: 0.,
+        };
+        Ok((
+            Self {
+                arc,
+                prod,
+                stems,
+                connected,
+            },
+            renderer,
+        ))
+    }
+
+    fn push(&mut self, cmd: SyncedCommand) -> Result<()> {
+        check_connected(&self.connected)?;
+        self.prod.push(cmd).map_err(buffer_is_full)
+    }
+
+    fn check_stem(&self, index: usize) -> Result<()> {
+        if index >= self.stems {
+            bail!("stem {index} out of range, there are {} stems", self.stems);
+        }
+        Ok(())
+    }
+
+    pub fn stem_count(&self) -> usize {
+        self.stems
+    }
+
+    pub fn play(&mut self) -> Result<()> {
+        self.push(SyncedCommand::Resume)
+            .context("play synced music")
+    }
+
+    pub fn pause(&mut self) -> Result<()> {
+        self.push(SyncedCommand::Pause)
+            .context("pause synced music")
+    }
+
+    pub fn paused(&self) -> bool {
+        self.arc.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn seek_to(&mut self, position: f64) -> Result<()> {
+        let position = validate("position", position, 0., f64::MAX).context("seek synced music")?;
+        self.push(SyncedCommand::SeekTo(position))
+            .context("seek synced music")
+    }
+
+    pub fn position(&self) -> f64 {
+        self.arc.position.load(Ordering::SeqCst)
+    }
+
+    /// Sets the gain applied to the mix of all stems.
+    pub fn set_amplifier(&mut self, amp: f32) -> Result<()> {
+        let amp = validate("amplifier", amp as f64, f64::MIN, f64::MAX)
+            .context("set synced music amplifier")?;
+        self.push(SyncedCommand::SetAmplifier(amp as f32))
+            .context("set synced music amplifier")
+    }
+
+    pub fn set_stem_amplifier(&mut self, index: usize, amp: f32) -> Result<()> {
+        self.check_stem(index).context("set stem amplifier")?;
+        let amp =
+            validate("amplifier", amp as f64, f64::MIN, f64::MAX).context("set stem amplifier")?;
+        self.push(SyncedCommand::SetStemAmplifier(index, amp as f32))
+            .context("set stem amplifier")
+    }
+
+    pub fn set_stem_muted(&mut self, index: usize, muted: bool) -> Result<()> {
+        self.check_stem(index).context("set stem muted")?;
+        self.push(SyncedCommand::SetStemMuted(index, muted))
+            .context("set stem muted")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::offline::OfflineSettings,
+        test_util::{manager, manager_with, render, settings, BLOCK_SIZE, SAMPLE_RATE},
+        AudioManagerSettings,
+    };
+
+    /// A clip whose frame `i` is `i * scale`, so that the output tells
+    /// which frame of each stem was played.
+    fn ramp(scale: f32, sample_rate: u32) -> AudioClip {
+        let frames = sample_rate as usize * 2;
+        AudioClip::from_raw(
+            (0..frames)
+                .map(|i| Frame::from_mono(i as f32 * scale))
+                .collect(),
+            sample_rate,
+        )
+    }
+
+    const SCALE: f32 = 1e-5;
+
+    /// Asserts that frame `i` of the left channel of `data` is `gain` times
+    /// frame `start + i` of the stems.
+    fn assert_frames(data: &[f32], start: usize, gain: f32) {
+        for (i, sample) in data.iter().step_by(2).enumerate() {
+            let expected = (start + i) as f32 * SCALE * gain;
+            assert!(
+                (sample - expected).abs() < 1e-5,
+                "frame {i}: {sample} != {expected}"
+            );
+        }
+    }
+
+    fn music(manager: &mut crate::AudioManager) -> SyncedMusic {
+        let clips = vec![ramp(SCALE, SAMPLE_RATE), ramp(2. * SCALE, SAMPLE_RATE)];
+        manager
+            .create_synced_music(clips, MusicParams::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn stems_stay_aligned_when_muted_or_regained() {
+        let (mut manager, mut out) = manager();
+        let mut music = music(&mut manager);
+        music.play().unwrap();
+        assert_frames(&render(&mut out, BLOCK_SIZE * 3), 0, 3.);
+
+        music.set_stem_muted(1, true).unwrap();
+        assert_frames(&render(&mut out, BLOCK_SIZE * 3), BLOCK_SIZE * 3, 1.);
+        music.set_stem_muted(1, false).unwrap();
+        music.set_stem_amplifier(1, 0.5).unwrap();
+        assert_frames(&render(&mut out, BLOCK_SIZE * 3), BLOCK_SIZE * 6, 2.);
+        music.set_stem_muted(0, true).unwrap();
+        assert_frames(&render(&mut out, BLOCK_SIZE * 3), BLOCK_SIZE * 9, 1.);
+
+        assert!(music.set_stem_muted(2, true).is_err());
+        assert!(music.set_stem_amplifier(2, 0.5).is_err());
+        // Past the handle, an unknown stem is ignored.
+        music.push(SyncedCommand::SetStemMuted(2, false)).unwrap();
+        music.push(SyncedCommand::SetStemAmplifier(2, 1.)).unwrap();
+        assert_frames(&render(&mut out, BLOCK_SIZE), BLOCK_SIZE * 12, 1.);
+    }
+
+    #[test]
+    fn seeks_and_pauses_move_every_stem() {
+        let (mut manager, mut out) = manager();
+        let mut music = music(&mut manager);
+        music.play().unwrap();
+        render(&mut out, BLOCK_SIZE * 2);
+
+        music.seek_to(1.).unwrap();
+        assert_frames(&render(&mut out, BLOCK_SIZE), SAMPLE_RATE as usize, 3.);
+        let position = 1. + BLOCK_SIZE as f64 / SAMPLE_RATE as f64;
+        assert!((music.position() - position).abs() < 1e-9);
+
+        music.pause().unwrap();
+        assert!(render(&mut out, BLOCK_SIZE * 2).iter().all(|it| *it == 0.));
+        assert!(music.paused());
+        assert!((music.position() - position).abs() < 1e-9);
+        music.play().unwrap();
+        let data = render(&mut out, BLOCK_SIZE);
+        assert_frames(&data, SAMPLE_RATE as usize + BLOCK_SIZE, 3.);
+        assert!(!music.paused());
+
+        assert!(music.seek_to(f64::NAN).is_err());
+    }
+
+    #[test]
+    fn stems_must_share_their_sample_rate() {
+        // The second stem steps twice as fast per frame at half the rate.
+        let clips = || vec![ramp(SCALE, SAMPLE_RATE), ramp(4. * SCALE, SAMPLE_RATE / 2)];
+        let (mut strict, _out) = manager_with(
+            AudioManagerSettings {
+                auto_resample: false,
+                ..settings()
+            },
+            OfflineSettings::default(),
+        );
+        assert!(strict
+            .create_synced_music(clips(), MusicParams::default())
+            .is_err());
+        assert!(strict
+            .create_synced_music(Vec::new(), MusicParams::default())
+            .is_err());
+
+        // Converted to the output rate first, the stems line up again.
+        let (mut manager, mut out) = manager();
+        let mut music = manager
+            .create_synced_music(clips(), MusicParams::default())
+            .unwrap();
+        music.play().unwrap();
+        assert_frames(&render(&mut out, BLOCK_SIZE * 4), 0, 3.);
+    }
+}
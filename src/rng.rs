@@ -0,0 +1,35 @@
+/// Small deterministic generator (SplitMix64) behind every random choice of
+/// the engine, seeded from
+/// [`AudioManagerSettings::rng_seed`](crate::AudioManagerSettings::rng_seed).
+#[derive(Debug, Clone)]
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform in `[-1, 1)`.
+    pub(crate) fn next_signed(&mut self) -> f64 {
+        self.next_f64() * 2. - 1.
+    }
+
+    /// A generator of its own for a new handle, so that handles created in
+    /// the same order draw the same sequences whatever the others do.
+    pub(crate) fn fork(&mut self) -> Rng {
+        Rng(self.next_u64())
+    }
+}
@@ -0,0 +1,227 @@
+//! Real-time safety checks.
+//!
+//! Everything run from the audio callback, including custom [`Renderer`]s
+//! and the output tap, must not allocate, free or lock: any of these may
+//! block for longer than a block lasts and cause a glitch.
+//!
+//! With the `rt-checks` feature, code running in a real-time section (the
+//! mixer's callback or [`assert_realtime_safe`]) counts every call to the
+//! allocator, provided [`CheckedAlloc`] is installed as the global
+//! allocator:
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOC: sasa::rt::CheckedAlloc = sasa::rt::CheckedAlloc(std::alloc::System);
+//! ```
+//!
+//! The mixer logs allocations made by a renderer, the output tap or itself
+//! after the offending call returns; [`assert_realtime_safe`] panics
+//! instead. Without the feature, none of this is checked and sections cost
+//! nothing. Locks are not detected. The mixer itself still allocates when
+//! the output format changes or its list of renderers grows.
+//!
+//! [`Renderer`]: crate::Renderer
+
+#[cfg(feature = "rt-checks")]
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+#[cfg(feature = "rt-checks")]
+thread_local! {
+    /// Real-time sections entered and not yet left on this thread.
+    static DEPTH: Cell<u32> = const { Cell::new(0) };
+    /// Allocator calls made in a real-time section on this thread.
+    static CALLS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Global allocator counting the calls made in a real-time section, see
+/// the [module documentation](self). Calls are forwarded to the wrapped
+/// allocator.
+#[cfg(feature = "rt-checks")]
+pub struct CheckedAlloc<A = System>(pub A);
+
+#[cfg(feature = "rt-checks")]
+impl<A> CheckedAlloc<A> {
+    #[inline]
+    fn count(&self) {
+        // Accessing the thread locals never allocates, but they may already
+        // be destroyed while the thread exits.
+        let _ = DEPTH.try_with(|depth| {
+            if depth.get() != 0 {
+                CALLS.with(|calls| calls.set(calls.get() + 1));
+            }
+        });
+    }
+}
+
+#[cfg(feature = "rt-checks")]
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CheckedAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.count();
+        self.0.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.count();
+        self.0.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.count();
+        self.0.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.count();
+        self.0.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Leaves the section when dropped, so that a panic caught further up does
+/// not leave the thread marked as real-time.
+#[cfg(feature = "rt-checks")]
+struct Section {
+    calls: usize,
+}
+
+#[cfg(feature = "rt-checks")]
+impl Section {
+    fn enter() -> Self {
+        DEPTH.with(|depth| depth.set(depth.get() + 1));
+        Self {
+            calls: CALLS.with(Cell::get),
+        }
+    }
+
+    /// Allocator calls made since entering, which are no longer counted
+    /// against enclosing sections.
+    fn take_calls(&self) -> usize {
+        CALLS.with(|calls| calls.replace(self.calls)) - self.calls
+    }
+}
+
+#[cfg(feature = "rt-checks")]
+impl Drop for Section {
+    fn drop(&mut self) {
+        DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+/// Runs `f` in a real-time section and returns the allocator calls it made.
+#[cfg(feature = "rt-checks")]
+fn section<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let section = Section::enter();
+    let result = f();
+    let calls = section.take_calls();
+    drop(section);
+    (result, calls)
+}
+
+/// Whether this thread is in a real-time section. Always false without the
+/// `rt-checks` feature.
+pub fn in_realtime() -> bool {
+    #[cfg(feature = "rt-checks")]
+    {
+        DEPTH.with(Cell::get) != 0
+    }
+    #[cfg(not(feature = "rt-checks"))]
+    {
+        false
+    }
+}
+
+/// Runs `f` as if from the audio callback, panicking if it called the
+/// allocator. Meant for testing custom renderers and effects; without the
+/// `rt-checks` feature and [`CheckedAlloc`] installed, this only runs `f`.
+pub fn assert_realtime_safe<R>(f: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "rt-checks")]
+    {
+        let (result, calls) = section(f);
+        assert!(calls == 0, "{calls} allocator calls in a real-time section");
+        result
+    }
+    #[cfg(not(feature = "rt-checks"))]
+    {
+        f()
+    }
+}
+
+/// Runs `what` from the audio callback, logging it if it called the
+/// allocator.
+#[inline]
+pub(crate) fn checked<R>(what: &str, f: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "rt-checks")]
+    {
+        let (result, calls) = section(f);
+        if calls != 0 {
+            eprintln!("{calls} allocator calls in the {what} on the audio thread");
+        }
+        result
+    }
+    #[cfg(not(feature = "rt-checks"))]
+    {
+        let _ = what;
+        f()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "rt-checks")]
+    #[global_allocator]
+    static ALLOC: CheckedAlloc = CheckedAlloc(System);
+
+    #[test]
+    fn clean_sections_pass() {
+        assert!(!in_realtime());
+        let mut buf = [0.; 16];
+        let peak = assert_realtime_safe(|| {
+            assert_eq!(in_realtime(), cfg!(feature = "rt-checks"));
+            buf.iter_mut().for_each(|it| *it = 0.5);
+            checked("test", || buf.iter().fold(0f32, |a, b| a.max(*b)))
+        });
+        assert_eq!(peak, 0.5);
+        assert!(!in_realtime());
+    }
+
+    #[cfg(feature = "rt-checks")]
+    #[test]
+    fn allocating_effects_are_detected() {
+        let effect = |data: &mut [f32]| {
+            let copy = data.to_vec();
+            data.iter_mut()
+                .zip(copy)
+                .for_each(|(it, dry)| *it = dry * 0.5);
+        };
+        let mut buf = vec![1.; 16];
+        let (_, calls) = section(|| effect(&mut buf));
+        // One allocation and one free.
+        assert_eq!(calls, 2);
+        assert!(!in_realtime());
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            assert_realtime_safe(|| effect(&mut buf))
+        }));
+        let message = result.unwrap_err();
+        let message = message.downcast_ref::<String>().unwrap();
+        assert_eq!(message, "2 allocator calls in a real-time section");
+        // Left on unwinding.
+        assert!(!in_realtime());
+    }
+
+    #[cfg(feature = "rt-checks")]
+    #[test]
+    fn nested_sections_count_their_own_calls() {
+        let (((), inner), outer) = section(|| {
+            let boxed = Box::new(1);
+            let inner = section(|| drop(Box::new(2)));
+            drop(boxed);
+            inner
+        });
+        assert_eq!((inner, outer), (2, 2));
+    }
+}
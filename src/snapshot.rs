@@ -0,0 +1,64 @@
+/// Reports the state of a handle, or `None` once the handle has been
+/// dropped.
+pub(crate) type Probe = Box<dyn Fn() -> Option<Probed> + Send + Sync>;
+
+pub(crate) struct Probed {
+    pub(crate) playing: bool,
+    pub(crate) position: Option<f64>,
+    pub(crate) gain: Option<f32>,
+    /// For music, whether playback stopped at the end of the clip; for
+    /// sound effects, whether no instance is playing.
+    pub(crate) finished: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RendererKind {
+    Music,
+    Sfx,
+}
+
+/// State of a labeled handle, see
+/// [`AudioManager::debug_snapshot`](crate::AudioManager::debug_snapshot).
+#[derive(Debug, Clone, PartialEq)]
+pub struct RendererSnapshot {
+    pub label: String,
+    pub kind: RendererKind,
+    /// For music, whether the renderer is playing; for sound effects,
+    /// whether any instance is.
+    pub playing: bool,
+    /// Position in seconds, for music.
+    pub position: Option<f64>,
+    /// Effective gain, for music, see
+    /// [`Music::effective_gain`](crate::Music::effective_gain).
+    pub gain: Option<f32>,
+}
+
+/// A label kept by the manager, never seen by the audio thread.
+pub(crate) struct Labeled {
+    pub(crate) label: String,
+    pub(crate) kind: RendererKind,
+    pub(crate) probe: Probe,
+    /// Whether the handle was finished when last probed.
+    pub(crate) finished: bool,
+}
+
+impl Labeled {
+    pub(crate) fn snapshot(&self) -> Option<RendererSnapshot> {
+        let probed = (self.probe)()?;
+        Some(RendererSnapshot {
+            label: self.label.clone(),
+            kind: self.kind,
+            playing: probed.playing,
+            position: probed.position,
+            gain: probed.gain,
+        })
+    }
+
+    /// Probes the handle, returning whether it finished since the last call,
+    /// or `None` once the handle has been dropped.
+    pub(crate) fn poll_finished(&mut self) -> Option<bool> {
+        let finished = (self.probe)()?.finished;
+        let was_finished = std::mem::replace(&mut self.finished, finished);
+        Some(finished && !was_finished)
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::{bail, Result};
+
+/// Piecewise-constant tempo map used to convert between clip time and beats.
+///
+/// Each point `(time, bpm)` sets the tempo from `time` (in seconds) until the
+/// next point. The first tempo also applies before the first point, and beat
+/// zero is at time zero.
+#[derive(Debug, Clone, Default)]
+pub struct TempoMap {
+    /// Points sorted by time, with the beat count at each point.
+    points: Vec<(f64, f64, f64)>,
+}
+
+impl TempoMap {
+    pub fn new(mut map: Vec<(f64, f64)>) -> Result<Self> {
+        if let Some((_, bpm)) = map.iter().find(|(_, bpm)| !(*bpm > 0. && bpm.is_finite())) {
+            bail!("invalid bpm {bpm}");
+        }
+        if map.iter().any(|(time, _)| !time.is_finite()) {
+            bail!("invalid tempo map time");
+        }
+        map.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut points = Vec::with_capacity(map.len());
+        let mut last: Option<(f64, f64, f64)> = None;
+        for (time, bpm) in map {
+            let beat = match last {
+                Some((last_time, last_bpm, last_beat)) => {
+                    last_beat + (time - last_time) * last_bpm / 60.
+                }
+                None => time * bpm / 60.,
+            };
+            let point = (time, bpm, beat);
+            points.push(point);
+            last = Some(point);
+        }
+        Ok(Self { points })
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Beat count at the given time, or `None` if the map is empty.
+    pub fn beat_at(&self, time: f64) -> Option<f64> {
+        let first = self.points.first()?;
+        let index = self.points.partition_point(|it| it.0 <= time);
+        let (point_time, bpm, beat) = if index == 0 {
+            (0., first.1, 0.)
+        } else {
+            self.points[index - 1]
+        };
+        Some(beat + (time - point_time) * bpm / 60.)
+    }
+
+    /// Time at which the given beat occurs, or `None` if the map is empty.
+    pub fn time_at_beat(&self, beat: f64) -> Option<f64> {
+        let first = self.points.first()?;
+        let index = self.points.partition_point(|it| it.2 <= beat);
+        let (point_time, bpm, point_beat) = if index == 0 {
+            (0., first.1, 0.)
+        } else {
+            self.points[index - 1]
+        };
+        Some(point_time + (beat - point_beat) * 60. / bpm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{a} != {b}");
+    }
+
+    #[test]
+    fn converts_across_tempo_changes() {
+        // 20 beats at 120 bpm, 10 at 60, then 180 bpm; given out of order.
+        let map = TempoMap::new(vec![(20., 180.), (0., 120.), (10., 60.)]).unwrap();
+        for (time, beat) in [
+            (-1., -2.),
+            (5., 10.),
+            (10., 20.),
+            (15., 25.),
+            (20., 30.),
+            (22., 36.),
+        ] {
+            assert_close(map.beat_at(time).unwrap(), beat);
+            assert_close(map.time_at_beat(beat).unwrap(), time);
+        }
+
+        // The first tempo applies before a first point later than zero.
+        let map = TempoMap::new(vec![(4., 60.), (2., 90.)]).unwrap();
+        assert_close(map.beat_at(1.).unwrap(), 1.5);
+        assert_close(map.beat_at(6.).unwrap(), 8.);
+        assert_close(map.time_at_beat(8.).unwrap(), 6.);
+    }
+
+    #[test]
+    fn rejects_invalid_points() {
+        assert!(TempoMap::new(vec![(0., 0.)]).is_err());
+        assert!(TempoMap::new(vec![(0., f64::NAN)]).is_err());
+        assert!(TempoMap::new(vec![(f64::INFINITY, 120.)]).is_err());
+        let empty = TempoMap::new(Vec::new()).unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(empty.beat_at(1.), None);
+        assert_eq!(empty.time_at_beat(1.), None);
+    }
+}
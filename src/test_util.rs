@@ -0,0 +1,73 @@
+//! Helpers shared by the unit tests.
+
+use crate::{
+    backend::offline::{OfflineBackend, OfflineRenderer, OfflineSettings},
+    AudioClip, AudioManager, AudioManagerSettings, Frame,
+};
+
+pub(crate) const SAMPLE_RATE: u32 = 48000;
+pub(crate) const BLOCK_SIZE: usize = 512;
+
+/// Settings leaving the output untouched: no limiter and no soft start.
+pub(crate) fn settings() -> AudioManagerSettings {
+    AudioManagerSettings {
+        limiter: None,
+        soft_start: 0.,
+        ..AudioManagerSettings::default()
+    }
+}
+
+/// A stereo manager on the offline backend at [`SAMPLE_RATE`], rendering
+/// blocks of [`BLOCK_SIZE`] frames.
+pub(crate) fn manager() -> (AudioManager, OfflineRenderer) {
+    manager_with(
+        settings(),
+        OfflineSettings {
+            sample_rate: SAMPLE_RATE,
+            channels: 2,
+            block_size: BLOCK_SIZE,
+        },
+    )
+}
+
+pub(crate) fn manager_with(
+    settings: AudioManagerSettings,
+    offline: OfflineSettings,
+) -> (AudioManager, OfflineRenderer) {
+    let (backend, renderer) = OfflineBackend::new(offline);
+    (
+        AudioManager::with_settings(backend, settings).unwrap(),
+        renderer,
+    )
+}
+
+/// A clip of `frames` frames all equal to `value`.
+pub(crate) fn constant(value: f32, frames: usize) -> AudioClip {
+    AudioClip::from_raw(vec![Frame::from_mono(value); frames], SAMPLE_RATE)
+}
+
+/// A sine of `frequency` Hz and `amplitude`, `seconds` long.
+pub(crate) fn sine(frequency: f32, amplitude: f32, seconds: f64) -> AudioClip {
+    let frames = (seconds * SAMPLE_RATE as f64) as usize;
+    AudioClip::from_raw(
+        (0..frames)
+            .map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                Frame::from_mono((t * frequency * std::f32::consts::TAU).sin() * amplitude)
+            })
+            .collect(),
+        SAMPLE_RATE,
+    )
+}
+
+/// Renders `frames` interleaved stereo frames.
+pub(crate) fn render(renderer: &mut OfflineRenderer, frames: usize) -> Vec<f32> {
+    let mut data = vec![0.; frames * 2];
+    renderer.render(&mut data);
+    data
+}
+
+/// Largest absolute sample of `data`.
+pub(crate) fn peak(data: &[f32]) -> f32 {
+    data.iter().fold(0., |peak, it| peak.max(it.abs()))
+}
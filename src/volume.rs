@@ -0,0 +1,145 @@
+/// Level at or below which volumes in decibels are treated as silence.
+pub const SILENCE_DB: f32 = -60.;
+
+/// Converts decibels relative to full scale to a linear amplitude, `0` at
+/// or below [`SILENCE_DB`].
+pub fn db_to_amplitude(db: f32) -> f32 {
+    if db <= SILENCE_DB {
+        0.
+    } else {
+        10f32.powf(db / 20.)
+    }
+}
+
+/// Converts a linear amplitude to decibels, clamped to [`SILENCE_DB`]. The
+/// sign of the amplitude is ignored.
+pub fn amplitude_to_db(amplitude: f32) -> f32 {
+    let amplitude = amplitude.abs();
+    if amplitude > 0. {
+        (20. * amplitude.log10()).max(SILENCE_DB)
+    } else {
+        SILENCE_DB
+    }
+}
+
+/// Mapping between a volume slider in percent and a linear amplitude.
+///
+/// The mappings are part of the public contract: a stored percentage maps
+/// to the same amplitude in every release.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VolumeCurve {
+    /// `(percent / 100) ^ exponent`. An exponent of 1 is linear, 2 to 3
+    /// follows perceived loudness roughly.
+    Power(f32),
+    /// Linear in decibels, from [`SILENCE_DB`] at 0 to full scale at 100.
+    Decibels,
+}
+
+impl Default for VolumeCurve {
+    fn default() -> Self {
+        Self::Power(2.)
+    }
+}
+
+impl VolumeCurve {
+    /// Amplitude for `percent`, clamped to 0..=100.
+    pub fn percent_to_amplitude(&self, percent: f32) -> f32 {
+        let fraction = (percent / 100.).clamp(0., 1.);
+        match *self {
+            Self::Power(exponent) => fraction.powf(exponent),
+            Self::Decibels => db_to_amplitude(SILENCE_DB * (1. - fraction)),
+        }
+    }
+
+    /// Inverse of [`VolumeCurve::percent_to_amplitude`], with amplitudes
+    /// above 1 mapped to 100.
+    pub fn amplitude_to_percent(&self, amplitude: f32) -> f32 {
+        let amplitude = amplitude.abs().min(1.);
+        let fraction = match *self {
+            Self::Power(exponent) => amplitude.powf(1. / exponent),
+            Self::Decibels => 1. - amplitude_to_db(amplitude) / SILENCE_DB,
+        };
+        fraction.clamp(0., 1.) * 100.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{constant, manager, peak, render, BLOCK_SIZE, SAMPLE_RATE},
+        MusicParams,
+    };
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() <= b.abs() * 1e-5, "{a} != {b}");
+    }
+
+    // Stored volumes must keep their loudness: these values never change.
+    #[test]
+    fn curves_are_pinned() {
+        let table = [
+            (0., 0., 0., 0.),
+            (10., 0.01, 0.001, 0.001_995_262),
+            (25., 0.0625, 0.015625, 0.005_623_413),
+            (50., 0.25, 0.125, 0.031_622_78),
+            (75., 0.5625, 0.421875, 0.177_827_9),
+            (100., 1., 1., 1.),
+        ];
+        for (percent, square, cube, decibels) in table {
+            assert_close(VolumeCurve::default().percent_to_amplitude(percent), square);
+            assert_close(VolumeCurve::Power(3.).percent_to_amplitude(percent), cube);
+            assert_close(
+                VolumeCurve::Decibels.percent_to_amplitude(percent),
+                decibels,
+            );
+        }
+        assert_eq!(VolumeCurve::default().percent_to_amplitude(150.), 1.);
+        assert_eq!(VolumeCurve::Decibels.percent_to_amplitude(-10.), 0.);
+    }
+
+    #[test]
+    fn conversions_round_trip() {
+        for curve in [
+            VolumeCurve::Power(1.),
+            VolumeCurve::default(),
+            VolumeCurve::Decibels,
+        ] {
+            for percent in [1., 10., 33., 50., 99., 100.] {
+                let amplitude = curve.percent_to_amplitude(percent);
+                assert!((curve.amplitude_to_percent(amplitude) - percent).abs() < 1e-3);
+            }
+            assert_eq!(curve.amplitude_to_percent(2.), 100.);
+            assert_eq!(curve.amplitude_to_percent(0.), 0.);
+        }
+        assert_close(db_to_amplitude(-6.), 0.501_187_2);
+        assert_close(amplitude_to_db(-0.5), -6.0206);
+        assert_eq!(db_to_amplitude(SILENCE_DB), 0.);
+        assert_eq!(amplitude_to_db(0.), SILENCE_DB);
+        assert_eq!(amplitude_to_db(1e-9), SILENCE_DB);
+    }
+
+    #[test]
+    fn decibel_setters_agree() {
+        let (mut manager, mut out) = manager();
+        let mut bus = manager.create_bus().unwrap();
+        let music = manager
+            .create_music_in(
+                &mut bus,
+                constant(0.5, SAMPLE_RATE as usize),
+                MusicParams::default(),
+            )
+            .unwrap();
+        music.play().unwrap();
+        music.set_volume_db(-6.).unwrap();
+        bus.set_gain_db(-6., 0.).unwrap();
+        manager.set_master_volume_db(-12., 0.).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        let level = peak(&render(&mut out, BLOCK_SIZE));
+        assert_close(level, 0.5 * db_to_amplitude(-24.));
+
+        music.set_volume_db(SILENCE_DB).unwrap();
+        render(&mut out, BLOCK_SIZE);
+        assert_eq!(peak(&render(&mut out, BLOCK_SIZE)), 0.);
+    }
+}
@@ -0,0 +1,186 @@
+//! Golden tests of the decode path: every fixture in `tests/fixtures` must
+//! decode to exactly the frames recorded in [`FIXTURES`].
+//!
+//! The fixtures are small synthetic files: tones written as 16-bit and
+//! 8-bit PCM WAV, and Ogg Vorbis streams of short blocks holding a few
+//! spectral lines. A dependency update that changes a single decoded
+//! sample, or the number of frames, fails here instead of silently
+//! shifting chart offsets. Two more cover broken sources: the mono Ogg
+//! Vorbis stream cut in the middle of a page, and a WAV without any frame.
+//!
+//! Goldens are only updated on purpose. When a change of the decoded
+//! output is intended, e.g. a decoder fix, run
+//! `cargo test --test decode -- --nocapture`, check the new frame counts
+//! and the reason for the change, and update the entries with the printed
+//! values in the same commit as the change, saying why in its message.
+//! Fixtures themselves are never regenerated; a new case gets a new file.
+
+use sasa::{AudioClip, Error, Frame};
+
+struct Fixture {
+    name: &'static str,
+    sample_rate: u32,
+    channels: u16,
+    frames: usize,
+    /// SHA-256 of the decoded frames, as little-endian `f32` left and right
+    /// samples.
+    sha256: &'static str,
+}
+
+const FIXTURES: &[Fixture] = &[
+    Fixture {
+        name: "tone_stereo_16bit.wav",
+        sample_rate: 22050,
+        channels: 2,
+        frames: 2205,
+        sha256: "3e85e16431993605d4d92b49b2b1289efce3c8d7e4c9f739736799be06e6e058",
+    },
+    Fixture {
+        name: "tone_mono_8bit.wav",
+        sample_rate: 8000,
+        channels: 1,
+        frames: 1001,
+        sha256: "417c858feb64f2fc7c3b4c3590104f567260dab1c0e07f3e3c0f26119215fdda",
+    },
+    Fixture {
+        name: "tone_mono.ogg",
+        sample_rate: 22050,
+        channels: 1,
+        frames: 4992,
+        sha256: "710f14b3f0e15cda567e1d83e03af75ce9b3ab420315542ed14b74459a50e9c3",
+    },
+    Fixture {
+        name: "tone_stereo.ogg",
+        sample_rate: 44100,
+        channels: 2,
+        frames: 4096,
+        sha256: "d77f3ab40a95fc433f6aabd53f0dccc8c6e8c951f213199ff1bcd0e00760f261",
+    },
+];
+
+/// SHA-256 as specified by FIPS 180-4, as lowercase hex.
+fn sha256(data: &[u8]) -> String {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        for (state, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+    state.iter().map(|it| format!("{it:08x}")).collect()
+}
+
+fn pcm_hash(frames: &[Frame]) -> String {
+    let bytes: Vec<u8> = frames
+        .iter()
+        .flat_map(|it| [it.0.to_le_bytes(), it.1.to_le_bytes()])
+        .flatten()
+        .collect();
+    sha256(&bytes)
+}
+
+fn load(name: &str) -> Vec<u8> {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    std::fs::read(&path).unwrap_or_else(|err| panic!("{}: {err}", path.display()))
+}
+
+#[test]
+fn sha256_matches_known_digests() {
+    assert_eq!(
+        sha256(b""),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        sha256(b"abc"),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(
+        sha256(&[b'a'; 1000]),
+        "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+    );
+}
+
+#[test]
+fn fixtures_decode_to_their_goldens() {
+    for fixture in FIXTURES {
+        let clip = AudioClip::new(load(fixture.name)).unwrap();
+        let name = fixture.name;
+        let hash = pcm_hash(clip.frames());
+        println!(
+            "{name}: {} Hz, {} channels, {} frames, {hash}",
+            clip.sample_rate(),
+            clip.channels(),
+            clip.frame_count()
+        );
+        assert_eq!(clip.sample_rate(), fixture.sample_rate, "{name}");
+        assert_eq!(clip.channels(), fixture.channels, "{name}");
+        assert_eq!(clip.frame_count(), fixture.frames, "{name}");
+        assert_eq!(hash, fixture.sha256, "{name}");
+    }
+}
+
+#[test]
+fn truncated_streams_keep_the_frames_before_the_cut() {
+    let full = AudioClip::new(load("tone_mono.ogg")).unwrap();
+    let truncated = AudioClip::new(load("tone_mono_truncated.ogg")).unwrap();
+    // The packets of the page cut short are dropped.
+    assert_eq!(truncated.frame_count(), 2432);
+    assert_eq!(truncated.length(), 2432. / 22050.);
+    assert_eq!(truncated.frames(), &full.frames()[..2432]);
+}
+
+#[test]
+fn sources_without_frames_are_rejected() {
+    let Err(err) = AudioClip::new(load("empty.wav")) else {
+        panic!("decoded an empty source");
+    };
+    assert_eq!(err.downcast_ref::<Error>(), Some(&Error::EmptyClip));
+}
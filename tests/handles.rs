@@ -0,0 +1,15 @@
+//! Compile-time checks that the handles can be shared with other threads,
+//! e.g. a game thread driving music while a loader thread swaps clips. A
+//! change making one of them `!Send` or `!Sync` fails to build here.
+
+use sasa::{BusHandle, DirectHandle, GroupHandle, Music, Sfx, SyncedMusic};
+
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Music>();
+    assert_send_sync::<Sfx>();
+    assert_send_sync::<SyncedMusic>();
+    assert_send_sync::<GroupHandle>();
+    assert_send_sync::<BusHandle>();
+    assert_send_sync::<DirectHandle>();
+};
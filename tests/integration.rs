@@ -0,0 +1,322 @@
+//! A demo scene driving the public API the way a game does, on the offline
+//! backend.
+//!
+//! The scene runs [`TIMELINE`]: each step is run once the output has been
+//! rendered up to its time, and may send commands and check invariants.
+//! New features add their steps to the same timeline, then update
+//! [`GOLDEN_HASH`], with and without the `fixed_point` feature, after
+//! checking the new output by ear or by its invariants.
+
+use sasa::{
+    backend::offline::{OfflineBackend, OfflineRenderer, OfflineSettings},
+    AudioClip, AudioManager, AudioManagerSettings, BusHandle, Frame, Music, MusicParams,
+    PlaySfxParams, Sfx, SfxInstance,
+};
+
+const SAMPLE_RATE: u32 = 48000;
+/// Rate the device switches to in the middle of the scene.
+const OTHER_SAMPLE_RATE: u32 = 44100;
+
+/// FNV-1a hash of the whole output, quantized to 16 bits.
+#[cfg(not(feature = "fixed_point"))]
+const GOLDEN_HASH: u64 = 918303203636930469;
+/// The Q15 fades and amplifiers round the output differently.
+#[cfg(feature = "fixed_point")]
+const GOLDEN_HASH: u64 = 14986582029714765049;
+
+fn sine(frequency: f32, amplitude: f32, seconds: f64) -> AudioClip {
+    let frames = (seconds * SAMPLE_RATE as f64) as usize;
+    AudioClip::from_raw(
+        (0..frames)
+            .map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                Frame::from_mono((t * frequency * std::f32::consts::TAU).sin() * amplitude)
+            })
+            .collect(),
+        SAMPLE_RATE,
+    )
+}
+
+struct Scene {
+    manager: AudioManager,
+    out: OfflineRenderer,
+    /// Played twice, wrapping around once, then to its end.
+    theme: Music,
+    /// A track playing through `bus`.
+    ambience: Music,
+    bus: BusHandle,
+    click: Sfx,
+    hit: Sfx,
+    whoosh: Sfx,
+    instances: Vec<SfxInstance>,
+    /// Seconds rendered so far.
+    time: f64,
+    /// Peak of the output rendered since the previous step.
+    peak: f32,
+    output: Vec<f32>,
+    /// Name of the step being run, for assertion messages.
+    step: &'static str,
+}
+
+impl Scene {
+    fn new() -> Self {
+        let (backend, out) = OfflineBackend::new(OfflineSettings {
+            sample_rate: SAMPLE_RATE,
+            channels: 2,
+            block_size: 512,
+        });
+        let mut manager = AudioManager::with_settings(
+            backend,
+            AudioManagerSettings {
+                limiter: None,
+                soft_start: 0.,
+                ..AudioManagerSettings::default()
+            },
+        )
+        .unwrap();
+        let theme = manager
+            .create_music(
+                sine(220., 0.3, 1.5),
+                MusicParams {
+                    loop_mix_time: 0.,
+                    loop_count: Some(2),
+                    ..MusicParams::default()
+                },
+            )
+            .unwrap();
+        let mut bus = manager.create_bus().unwrap();
+        let ambience = manager
+            .create_music_in(&mut bus, sine(330., 0.2, 6.), MusicParams::default())
+            .unwrap();
+        let click = manager.create_sfx(sine(2000., 0.2, 0.05), None).unwrap();
+        let hit = manager.create_sfx(sine(110., 0.3, 0.2), None).unwrap();
+        let whoosh = manager.create_sfx(sine(660., 0.1, 0.5), None).unwrap();
+        Self {
+            manager,
+            out,
+            theme,
+            ambience,
+            bus,
+            click,
+            hit,
+            whoosh,
+            instances: Vec::new(),
+            time: 0.,
+            peak: 0.,
+            output: Vec::new(),
+            step: "",
+        }
+    }
+
+    /// Renders the output up to `time` seconds.
+    fn render_to(&mut self, time: f64) {
+        let sample_rate = self.manager.sample_rate() as f64;
+        let frames = ((time - self.time) * sample_rate).round().max(0.) as usize;
+        let mut data = vec![0.; frames * 2];
+        self.out.render(&mut data);
+        self.time += frames as f64 / sample_rate;
+        self.peak = data.iter().fold(0., |peak, it| peak.max(it.abs()));
+        self.output.extend(data);
+    }
+
+    /// Renders up to the time of `step`, then runs it.
+    fn run(&mut self, step: &Step) {
+        self.render_to(step.at);
+        self.step = step.name;
+        (step.run)(self);
+    }
+
+    fn assert_near(&self, actual: f64, expected: f64, what: &str) {
+        assert!(
+            (actual - expected).abs() < 0.02,
+            "{}: {what} is {actual}, expected {expected}",
+            self.step
+        );
+    }
+
+    fn hash(&self) -> u64 {
+        self.output.iter().fold(0xcbf29ce484222325, |hash, it| {
+            let sample = (it.clamp(-1., 1.) * i16::MAX as f32) as i16;
+            sample.to_le_bytes().iter().fold(hash, |hash, byte| {
+                (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+            })
+        })
+    }
+}
+
+struct Step {
+    at: f64,
+    name: &'static str,
+    run: fn(&mut Scene),
+}
+
+const TIMELINE: &[Step] = &[
+    Step {
+        at: 0.,
+        name: "start the music",
+        run: |scene| {
+            scene.theme.play().unwrap();
+            scene.ambience.fade_in(1.).unwrap();
+            assert_eq!(scene.theme.loops_left(), Some(1), "{}", scene.step);
+        },
+    },
+    Step {
+        at: 0.25,
+        name: "click while fading in",
+        run: |scene| {
+            scene.assert_near(scene.theme.position(), 0.25, "theme position");
+            assert!(scene.peak > 0.2 && scene.peak < 0.6, "{}", scene.step);
+            let click = scene.click.play(PlaySfxParams::default()).unwrap();
+            scene.instances.push(click);
+        },
+    },
+    Step {
+        at: 0.5,
+        name: "seek the bus track during its fade",
+        run: |scene| {
+            assert!(
+                !scene.click.is_playing(scene.instances[0]),
+                "{}",
+                scene.step
+            );
+            scene.ambience.seek_to_keep_fade(3.).unwrap();
+        },
+    },
+    Step {
+        at: 1.,
+        name: "hit and whoosh over the music",
+        run: |scene| {
+            scene.assert_near(scene.ambience.position(), 3.5, "ambience position");
+            let hit = scene.hit.play(PlaySfxParams::default()).unwrap();
+            let whoosh = scene.whoosh.play(PlaySfxParams::default()).unwrap();
+            scene.instances.extend([hit, whoosh]);
+        },
+    },
+    Step {
+        at: 1.3,
+        name: "lower the bus",
+        run: |scene| {
+            assert!(!scene.hit.is_playing(scene.instances[1]), "{}", scene.step);
+            assert!(
+                scene.whoosh.is_playing(scene.instances[2]),
+                "{}",
+                scene.step
+            );
+            assert!(scene.peak < 1., "{}", scene.step);
+            scene.bus.set_gain(0.5, 0.1).unwrap();
+        },
+    },
+    Step {
+        at: 2.,
+        name: "theme wrapped around",
+        run: |scene| {
+            assert!(
+                !scene.whoosh.is_playing(scene.instances[2]),
+                "{}",
+                scene.step
+            );
+            scene.assert_near(scene.theme.position(), 0.5, "theme position");
+            assert_eq!(scene.theme.loops_left(), Some(0), "{}", scene.step);
+        },
+    },
+    Step {
+        at: 2.5,
+        name: "switch the device rate",
+        run: |scene| {
+            let (sample_rate, channels) = scene
+                .manager
+                .reconfigure(AudioManagerSettings {
+                    sample_rate: Some(OTHER_SAMPLE_RATE),
+                    limiter: None,
+                    soft_start: 0.,
+                    ..AudioManagerSettings::default()
+                })
+                .unwrap();
+            assert_eq!(
+                (sample_rate, channels),
+                (OTHER_SAMPLE_RATE, 2),
+                "{}",
+                scene.step
+            );
+        },
+    },
+    Step {
+        at: 3.,
+        name: "fade the bus track out",
+        run: |scene| {
+            scene.assert_near(scene.ambience.position(), 5.5, "ambience position");
+            scene.ambience.fade_out(0.25).unwrap();
+        },
+    },
+    Step {
+        at: 3.5,
+        name: "everything ended",
+        run: |scene| {
+            assert!(scene.theme.finished(), "{}", scene.step);
+            assert!(
+                scene.ambience.paused() && !scene.ambience.finished(),
+                "{}",
+                scene.step
+            );
+            assert!(scene.peak > 0.05 && scene.peak < 0.15, "{}", scene.step);
+        },
+    },
+    Step {
+        at: 4.,
+        name: "replay the theme",
+        run: |scene| {
+            assert!(scene.peak < 1e-6, "{}", scene.step);
+            scene.theme.play().unwrap();
+        },
+    },
+    Step {
+        at: 4.4,
+        name: "stop the theme",
+        run: |scene| {
+            scene.assert_near(scene.theme.position(), 0.4, "theme position");
+            assert!(scene.peak > 0.25 && scene.peak < 0.35, "{}", scene.step);
+            scene.theme.stop().unwrap();
+            assert_eq!(scene.theme.position(), 0., "{}", scene.step);
+        },
+    },
+    Step {
+        at: 5.,
+        name: "theme stopped",
+        run: |scene| {
+            assert!(
+                scene.theme.paused() && !scene.theme.finished(),
+                "{}",
+                scene.step
+            );
+            assert!(scene.theme.renderer_paused(), "{}", scene.step);
+        },
+    },
+    Step {
+        at: 5.5,
+        name: "silence after stopping",
+        run: |scene| assert_eq!(scene.peak, 0., "{}", scene.step),
+    },
+];
+
+#[test]
+fn demo_scene() {
+    let mut scene = Scene::new();
+    for step in TIMELINE {
+        scene.run(step);
+    }
+    assert_eq!(scene.hash(), GOLDEN_HASH, "output changed");
+}
+
+#[test]
+fn demo_scene_is_deterministic() {
+    let hashes: Vec<_> = (0..2)
+        .map(|_| {
+            let mut scene = Scene::new();
+            for step in TIMELINE {
+                scene.run(step);
+            }
+            scene.hash()
+        })
+        .collect();
+    assert_eq!(hashes[0], hashes[1]);
+}